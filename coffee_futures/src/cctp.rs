@@ -0,0 +1,82 @@
+// Circle CCTP payout bridging.
+// `settle_and_bridge` cash-settles a deal and burns the farmer's USDC payout through the CCTP
+// TokenMessengerMinter, so it is minted to the farmer's configured address on the destination domain.
+// Called by raw CPI (no CCTP crate): `deposit_for_burn` is an Anchor instruction taking
+// DepositForBurnParams { amount: u64, destination_domain: u32, mint_recipient: Pubkey }.
+use super::*;
+
+// sha256("global:deposit_for_burn")[..8]
+pub const DEPOSIT_FOR_BURN_DISCRIMINATOR: [u8; 8] = [215, 60, 61, 46, 114, 55, 128, 176];
+
+pub fn deposit_for_burn_data(amount: u64, destination_domain: u32, mint_recipient: &[u8; 32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + 8 + 4 + 32);
+    data.extend_from_slice(&DEPOSIT_FOR_BURN_DISCRIMINATOR);
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&destination_domain.to_le_bytes());
+    data.extend_from_slice(mint_recipient);
+    data
+}
+
+// Burn `amount` out of the farmer's receive account; the farmer signs as owner and pays the event rent
+pub fn deposit_for_burn(accounts: &SettleAndBridge, amount: u64) -> Result<()> {
+    let deal = &accounts.settle.deal;
+    let ix = solana_program::instruction::Instruction {
+        program_id: accounts.cctp_token_messenger_minter_program.key(),
+        accounts: vec![
+            solana_program::instruction::AccountMeta::new_readonly(accounts.farmer.key(), true),
+            solana_program::instruction::AccountMeta::new(accounts.farmer.key(), true),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_sender_authority.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.settle.farmer_receive.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.cctp_message_transmitter.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_token_messenger.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_remote_token_messenger.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_token_minter.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.cctp_local_token.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.quote_mint.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.cctp_message_sent_event_data.key(), true),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_message_transmitter_program.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_token_messenger_minter_program.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.settle.token_program.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.system_program.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_event_authority.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.cctp_token_messenger_minter_program.key(), false),
+        ],
+        data: deposit_for_burn_data(amount, deal.payout_domain, &deal.payout_recipient),
+    };
+    solana_program::program::invoke(
+        &ix,
+        &[
+            accounts.farmer.to_account_info(),
+            accounts.cctp_sender_authority.to_account_info(),
+            accounts.settle.farmer_receive.to_account_info(),
+            accounts.cctp_message_transmitter.to_account_info(),
+            accounts.cctp_token_messenger.to_account_info(),
+            accounts.cctp_remote_token_messenger.to_account_info(),
+            accounts.cctp_token_minter.to_account_info(),
+            accounts.cctp_local_token.to_account_info(),
+            accounts.quote_mint.to_account_info(),
+            accounts.cctp_message_sent_event_data.to_account_info(),
+            accounts.cctp_message_transmitter_program.to_account_info(),
+            accounts.cctp_token_messenger_minter_program.to_account_info(),
+            accounts.settle.token_program.to_account_info(),
+            accounts.system_program.to_account_info(),
+            accounts.cctp_event_authority.to_account_info(),
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deposit_for_burn_data_layout() {
+        let data = deposit_for_burn_data(1_500_000, 6, &[0xcd; 32]); // 1.5 USDC to Base
+        assert_eq!(data.len(), 8 + 8 + 4 + 32);
+        assert_eq!(&data[..8], &DEPOSIT_FOR_BURN_DISCRIMINATOR);
+        assert_eq!(&data[8..16], &1_500_000u64.to_le_bytes());
+        assert_eq!(&data[16..20], &6u32.to_le_bytes());
+        assert_eq!(&data[20..], &[0xcd; 32]);
+    }
+}
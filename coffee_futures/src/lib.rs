@@ -1,18 +1,247 @@
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token::{self, Mint, MintTo, Token, TokenAccount, Transfer};
+use anchor_spl::token::{self, Burn, CloseAccount, FreezeAccount, Mint, MintTo, SetAuthority, ThawAccount, Token, TokenAccount, Transfer};
+use solana_program::program_option::COption;
 use solana_program::rent::Rent;
+use solana_program::sysvar::instructions::{load_current_index_checked, load_instruction_at_checked};
+use spl_account_compression::{program::SplAccountCompression, Noop};
+
+// Protocol events are logged with emit! by default. With the `event-cpi` feature they go out as a
+// self-CPI through the event authority PDA instead, so indexers read them from inner instructions,
+// which survive log truncation. Pass `ctx`, or the accounts and bumps when ctx has been split up.
+macro_rules! emit_event {
+    ($ctx:expr, $event:expr $(,)?) => {
+        emit_event!($ctx.accounts, $ctx.bumps, $event)
+    };
+    ($accounts:expr, $bumps:expr, $event:expr $(,)?) => {{
+        #[cfg(feature = "event-cpi")]
+        emit_self_cpi(&$event, &$accounts.event_authority, $bumps.event_authority)?;
+        #[cfg(not(feature = "event-cpi"))]
+        emit!($event);
+    }};
+}
+
+// Pure arithmetic shared with off-chain clients; `features = ["client"]` exposes it as `coffee_futures::client`
+pub mod math;
+#[cfg(feature = "client")]
+pub use math as client;
+// Settlement strategies and the shared fee / PnL / residual plumbing
+pub mod settlement;
+use settlement::SettlementStrategy;
+// Settlement outcomes posted through the Wormhole core bridge for buyers settling off Solana
+pub mod wormhole;
+// Circle CCTP burn-and-bridge of farmer payouts
+pub mod cctp;
+// PDA derivation from the exported seed constants, for clients
+pub mod pda;
 
 declare_id!("AGJPgLjrChocPMmhzH8oiumrChZEaJKHDZcb83r5C1f9");
 
 // ------------------------- Config constants -------------------------
+#[constant]
 pub const PROGRAM_VERSION: u8 = 1;
+#[constant]
+pub const FX_RATE_SCALE: u64 = 1_000_000; // FxOracle rates: local currency units per quote unit * 1e6
+#[constant]
+pub const EVENT_SCHEMA_VERSION: u8 = 1; // bump when any event layout changes
+#[constant]
 pub const MAX_ASSETS: usize = 4;
+#[constant]
+pub const MAX_APPROVED_ASSETS: usize = 16;
+#[constant]
 pub const EMPTY_MERKLE_ROOT: [u8; 32] = [0u8; 32];
+#[constant]
 pub const MAX_PROOF_HASHES: usize = 16;
+#[constant]
 pub const MAX_PROOF_BYTES: usize = MAX_PROOF_HASHES * 32;
-pub const SEED_PREFIX: &[u8] = b"v1"; // PDA seed versioning prefix
+#[constant]
+pub const MAX_BATCH_LOTS: usize = 8; // lots per verify_and_settle_physical_batch
+#[constant]
+pub const MAX_BATCH_PROOF_HASHES: usize = 64; // proof hashes across a whole batch (compute bound)
+#[constant]
+pub const MAX_ATTESTATIONS_PER_TX: usize = 16; // compressed delivery leaves appended per call
+#[constant]
 pub const MIN_TWAP_WINDOW: u64 = 1; // seconds minimal twap window
+#[constant]
+pub const MAX_FEE_TIERS: usize = 4;
+#[constant]
+pub const MAX_MARGIN_STEPS: usize = 4;
+#[constant]
+pub const MAX_RISK_DEALS: usize = 16; // deals per compute_portfolio_risk call
+#[constant]
+pub const MAX_NET_SETTLE_DEALS: usize = 8; // deals per net_settle_pair call
+#[constant]
+pub const NET_SETTLE_ACCOUNTS_PER_DEAL: usize = 4; // deal, vault_auth, farmer and buyer margin vaults
+#[constant]
+pub const MAX_BATCH_DEALS: usize = 8; // member deals per open_deals_batch call
+#[constant]
+pub const BATCH_ACCOUNTS_PER_DEAL: usize = 4; // agent_grant, deal, vault_auth, margin vault
+#[constant]
+pub const VOL_EWMA_SPAN: u64 = 20; // oracle updates; weight of the newest return is 1/span
+#[constant]
+pub const TRADER_STATS_WINDOW_SEC: u64 = 30 * 24 * 60 * 60; // rolling volume window (30 days)
+#[constant]
+pub const OPEN_RATE_WINDOW_SEC: i64 = 60 * 60; // window for Market::max_opens_per_hour
+#[constant]
+pub const SANDBOX_FAUCET_MAX: u64 = 1_000_000_000_000; // dummy base units per sandbox_faucet call
+#[constant]
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000; // acc_reward_per_share scaling
+#[constant]
+pub const ADL_INDEX_PRECISION: u128 = 1_000_000_000_000; // loss_socialization_index scaling
+#[constant]
+pub const FUNDING_INTERVAL_SEC: i64 = 60 * 60; // perpetual funding accrues hourly
+#[constant]
+pub const FUNDING_PERIODS_PER_DAY: i128 = 24; // premium converges over a day of intervals
+#[constant]
+pub const FUNDING_PRECISION: i128 = 1_000_000; // cumulative_funding_per_kg scaling
+#[constant]
+pub const MARKET_CLOSE_TIMELOCK_SEC: i64 = 7 * 24 * 60 * 60; // schedule_market_close -> close_market
+#[constant]
+pub const MAX_ACL_MEMBERS: usize = 16;
+#[constant]
+pub const SQUADS_THRESHOLD_OFFSET: usize = 8 + 32 + 32; // discriminator + create_key + config_authority
+#[constant]
+pub const MAX_SESSION_SEC: i64 = 7 * 24 * 60 * 60; // longest a keeper session key may live
+#[constant]
+pub const PERMIT_DOMAIN: &[u8] = b"coffee_futures:permit:v1"; // prefix of every signed permit message
+#[constant]
+pub const CFT_DECIMALS: u8 = 3; // decimals of the CFT mints the program creates
+#[constant]
+pub const SHIPMENT_MILESTONES: usize = 5; // ShipmentStatus values after NotShipped
+#[constant]
+pub const MAX_LIQUIDATION_QUEUE: usize = 16; // flagged deals a market's LiquidationQueue holds
+#[constant]
+pub const MAX_SETTLEMENT_QUEUE: usize = 32; // deals a market's SettlementQueue holds
+#[constant]
+pub const PARTICIPANT_INDEX_PAGE_LEN: usize = 32; // deal addresses per ParticipantIndex page
+#[constant]
+pub const ACTION_LOG_CAPACITY: usize = 32; // entries an ActionLog holds between archives
+#[constant]
+pub const MAX_BPS: u16 = 10_000; // basis-point denominator; every bps setting is capped at 100%
+const _: () = assert!(MAX_BPS as u128 == math::BPS_DENOMINATOR);
+#[constant]
+pub const MAX_FEE_BPS: u16 = 1_000; // trading fee ceiling (10% of notional), flat rate and every tier
+#[constant]
+pub const MAX_OPERATOR_FEE_WAIVER_BPS: u16 = 5_000; // largest waiver a market operator may issue; full waivers need Admin
+#[constant]
+pub const MAX_PRICE_EXPONENT: i8 = 12; // |Market::price_exponent| bound, keeps the scaling in u128
+
+// ------------------------- PDA seeds -------------------------
+// Every PDA is [SEED_PREFIX, <kind seed>, ..keys]; `pda` derives each one the way the accounts below do
+#[constant]
+pub const SEED_PREFIX: &[u8] = b"v1"; // PDA seed versioning prefix
+#[constant]
+pub const SEED_PREFIX_V2: &[u8] = b"v2"; // migrated deals (seeds add deal_id) and markets
+#[constant]
+pub const ACTION_LOG_SEED: &[u8] = b"action_log";
+#[constant]
+pub const ACCESS_SEED: &[u8] = b"access";
+#[constant]
+pub const AGENT_SEED: &[u8] = b"agent";
+#[constant]
+pub const APPROVED_ASSETS_SEED: &[u8] = b"approved_assets";
+#[constant]
+pub const AUCTION_SEED: &[u8] = b"auction";
+#[constant]
+pub const AUCTION_BID_SEED: &[u8] = b"auction_bid";
+#[constant]
+pub const BATCH_AUTH_SEED: &[u8] = b"batch_auth";
+#[constant]
+pub const BUYER_VAULT_SEED: &[u8] = b"buyer_vault";
+#[constant]
+pub const CFT_ALLOW_SEED: &[u8] = b"cft_allow";
+#[constant]
+pub const CFT_AUTH_SEED: &[u8] = b"cft_auth";
+#[constant]
+pub const CREDIT_LINE_SEED: &[u8] = b"credit_line";
+#[constant]
+pub const CROP_INSURANCE_SEED: &[u8] = b"crop_insurance";
+#[constant]
+pub const CROP_POLICY_SEED: &[u8] = b"crop_policy";
+#[constant]
+pub const DEAL_SEED: &[u8] = b"deal";
+#[constant]
+pub const DEBT_SEED: &[u8] = b"debt";
+#[constant]
+pub const DELIVERY_SEED: &[u8] = b"delivery";
+#[constant]
+pub const EMITTER_SEED: &[u8] = b"emitter";
+#[constant]
+pub const ESCROW_SEED: &[u8] = b"escrow";
+#[constant]
+pub const FEE_WAIVER_SEED: &[u8] = b"fee_waiver";
+#[constant]
+pub const FX_ORACLE_SEED: &[u8] = b"fx_oracle";
+#[constant]
+pub const GOVERNANCE_SEED: &[u8] = b"governance";
+#[constant]
+pub const INDEX_FEED_SEED: &[u8] = b"index_feed";
+#[constant]
+pub const INSURANCE_POOL_SEED: &[u8] = b"insurance_pool";
+#[constant]
+pub const INSURANCE_STAKE_SEED: &[u8] = b"insurance_stake";
+#[constant]
+pub const LIQUIDATION_QUEUE_SEED: &[u8] = b"liq_queue";
+#[constant]
+pub const LIQUIDATOR_LICENSE_SEED: &[u8] = b"liq_license";
+#[constant]
+pub const MARGIN_ACCOUNT_SEED: &[u8] = b"margin_account";
+#[constant]
+pub const MARGIN_POOL_SEED: &[u8] = b"margin_pool";
+#[constant]
+pub const MARKET_SEED: &[u8] = b"market";
+#[constant]
+pub const MARKET_CFT_SEED: &[u8] = b"market_cft";
+#[constant]
+pub const OMNIBUS_SEED: &[u8] = b"omnibus";
+#[constant]
+pub const OMNIBUS_VAULT_SEED: &[u8] = b"omnibus_vault";
+#[constant]
+pub const PARTICIPANT_INDEX_SEED: &[u8] = b"participant_index";
+#[constant]
+pub const PERMIT_AUTHORITY_SEED: &[u8] = b"permit_authority";
+#[constant]
+pub const PERMIT_NONCE_SEED: &[u8] = b"permit_nonce";
+#[constant]
+pub const PRICE_CHALLENGE_SEED: &[u8] = b"price_challenge";
+#[constant]
+pub const PRICE_TRIGGER_SEED: &[u8] = b"price_trigger";
+#[constant]
+pub const PROPOSAL_SEED: &[u8] = b"proposal";
+#[constant]
+pub const RISK_SEED: &[u8] = b"risk";
+#[constant]
+pub const SAMPLE_SEED: &[u8] = b"sample";
+#[constant]
+pub const SANDBOX_SEED: &[u8] = b"sandbox";
+#[constant]
+pub const SANDBOX_MINT_SEED: &[u8] = b"sandbox_mint";
+#[constant]
+pub const SESSION_SEED: &[u8] = b"session";
+#[constant]
+pub const SETTLEMENT_QUEUE_SEED: &[u8] = b"settle_queue";
+#[constant]
+pub const STAKE_SEED: &[u8] = b"stake";
+#[constant]
+pub const STAKE_POOL_SEED: &[u8] = b"stake_pool";
+#[constant]
+pub const STANDING_ORDER_SEED: &[u8] = b"standing_order";
+#[constant]
+pub const TEMPLATE_SEED: &[u8] = b"template";
+#[constant]
+pub const TRADER_STATS_SEED: &[u8] = b"trader_stats";
+#[constant]
+pub const TREE_AUTH_SEED: &[u8] = b"tree_auth";
+#[constant]
+pub const VAULT_AUTH_SEED: &[u8] = b"vault_auth";
+#[constant]
+pub const VESTING_SEED: &[u8] = b"vesting";
+#[constant]
+pub const VESTING_VAULT_SEED: &[u8] = b"vesting_vault";
+#[constant]
+pub const VOTE_SEED: &[u8] = b"vote";
+#[constant]
+pub const WORMHOLE_MSG_SEED: &[u8] = b"wormhole_msg";
 
 // Settlement price mode
 #[repr(u8)]
@@ -21,6 +250,211 @@ pub enum PriceMode {
     TWAP = 1,
 }
 
+// Currency a deal's settlement fees are paid in
+#[repr(u8)]
+pub enum FeeCurrency {
+    Quote = 0,
+    Cft = 1, // burned from the traders' CFT ATAs (vault_auth must be an approved delegate)
+}
+
+// Governance proposal payload type
+#[repr(u8)]
+pub enum ProposalKind {
+    ParamsUpdate = 0,
+    RotateOracle = 1,
+    RotateVerifier = 2,
+}
+
+// Role selector for set_authority_config
+#[repr(u8)]
+pub enum ConfiguredRole {
+    Authority = 0,
+    Verifier = 1,
+}
+
+// Delegable operational roles stored as bits in AccessControl.role_masks
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Admin = 0,
+    Oracle = 1,
+    Verifier = 2,
+    Pauser = 3,
+    Liquidator = 4,
+    Arbiter = 5,
+    Compliance = 6, // CFT freeze/thaw and recipient allowlist
+    Exporter = 7,   // verified exporter: may publish deal templates
+}
+
+// Reason codes recorded by settle_force_majeure
+#[repr(u8)]
+pub enum ForceMajeureReason {
+    CropFailure = 1,
+    ExportBan = 2,
+    PortClosure = 3,
+    Other = 4,
+}
+
+// Delivery terms recorded on template deals (Incoterms 2020); informational for settlement
+#[repr(u8)]
+pub enum Incoterm {
+    Unspecified = 0,
+    Exw = 1,
+    Fca = 2,
+    Fob = 3,
+    Cfr = 4,
+    Cif = 5,
+    Dap = 6,
+    Ddp = 7,
+}
+
+// Physical shipment progress, advanced by the verifier; never moves backwards
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShipmentStatus {
+    NotShipped = 0,
+    AtMill = 1,
+    AtPort = 2,
+    OnVessel = 3,
+    Arrived = 4,
+    CustomsCleared = 5,
+}
+
+impl ShipmentStatus {
+    pub fn from_u8(v: u8) -> Result<Self> {
+        Ok(match v {
+            0 => ShipmentStatus::NotShipped,
+            1 => ShipmentStatus::AtMill,
+            2 => ShipmentStatus::AtPort,
+            3 => ShipmentStatus::OnVessel,
+            4 => ShipmentStatus::Arrived,
+            5 => ShipmentStatus::CustomsCleared,
+            _ => return err!(CoffeeError::InvalidShipmentStatus),
+        })
+    }
+
+    // milestone at which risk passes to the buyer under the deal's Incoterm; None when unspecified
+    pub fn risk_transfer(incoterm: u8) -> Option<Self> {
+        match incoterm {
+            x if x == Incoterm::Exw as u8 => Some(ShipmentStatus::AtMill),
+            x if x == Incoterm::Fca as u8 => Some(ShipmentStatus::AtPort),
+            x if x == Incoterm::Fob as u8 || x == Incoterm::Cfr as u8 || x == Incoterm::Cif as u8 => Some(ShipmentStatus::OnVessel),
+            x if x == Incoterm::Dap as u8 => Some(ShipmentStatus::Arrived),
+            x if x == Incoterm::Ddp as u8 => Some(ShipmentStatus::CustomsCleared),
+            _ => None,
+        }
+    }
+}
+
+// Non-price series carried by an IndexFeed
+#[repr(u8)]
+pub enum IndexKind {
+    Rainfall = 1,      // rainfall deficit vs seasonal norm, mm
+    Frost = 2,         // frost index, degree-hours below 0C
+    RegionalYield = 3, // regional yield estimate, kg/ha
+}
+
+// Dated markets settle at settlement_ts; perpetual markets never expire and pay funding instead
+#[repr(u8)]
+pub enum MarketKind {
+    Dated = 0,
+    Perpetual = 1,
+}
+
+// Convention prices are quoted in off-chain and for tick size and banding; deal math is always per kg
+#[repr(u8)]
+pub enum PriceConvention {
+    PerKg = 0,
+    CentsPerLb = 1, // hundredths of a US cent per pound, see math::cents_lb_to_price_per_kg
+}
+
+// Side of a StandingOrder: the farmer sells (short), the buyer buys (long). Also the side a deal's
+// farmer took at open (Deal::farmer_side)
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderSide {
+    Sell = 0,
+    Buy = 1,
+}
+
+impl OrderSide {
+    pub fn from_u8(v: u8) -> Result<Self> {
+        Ok(match v {
+            0 => OrderSide::Sell,
+            1 => OrderSide::Buy,
+            _ => return err!(CoffeeError::InvalidOrderSide),
+        })
+    }
+}
+
+// Which way the oracle price has to cross a PriceTrigger's threshold
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerDirection {
+    Above = 0, // fires at or above the threshold
+    Below = 1, // fires at or below the threshold
+}
+
+impl TriggerDirection {
+    pub fn from_u8(v: u8) -> Result<Self> {
+        Ok(match v {
+            0 => TriggerDirection::Above,
+            1 => TriggerDirection::Below,
+            _ => return err!(CoffeeError::InvalidTriggerDirection),
+        })
+    }
+}
+
+// How verify_and_settle_physical handles a payout vault that can't cover the delivery
+#[repr(u8)]
+pub enum PaymentPolicy {
+    CapAtBalance = 0, // legacy: pay what is there
+    RequireFull = 1,  // reject the delivery
+    RecordDebt = 2,   // pay what is there and record the rest as owed
+}
+impl Role {
+    pub fn from_u8(v: u8) -> Result<Role> {
+        Ok(match v {
+            0 => Role::Admin,
+            1 => Role::Oracle,
+            2 => Role::Verifier,
+            3 => Role::Pauser,
+            4 => Role::Liquidator,
+            5 => Role::Arbiter,
+            6 => Role::Compliance,
+            7 => Role::Exporter,
+            _ => return err!(CoffeeError::InvalidRole),
+        })
+    }
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+// Keeper instructions a session key may be scoped to (bits in SessionKey.scopes). mark_to_market,
+// settle_cash and apply_funding are permissionless cranks and need no key at all.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionScope {
+    PublishPrice = 0,
+    OracleHeartbeat = 1, // refreshes the TWAP without a new price
+    MarginCall = 2,
+}
+impl SessionScope {
+    pub const ALL: u8 = 0b111;
+
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+    // operator role a scope exercises; the session's creator must hold it
+    pub fn role(self) -> Role {
+        match self {
+            SessionScope::PublishPrice | SessionScope::OracleHeartbeat => Role::Oracle,
+            SessionScope::MarginCall => Role::Liquidator,
+        }
+    }
+}
+
 // ------------------------- Program -------------------------
 #[program]
 pub mod coffee_futures {
@@ -48,7 +482,8 @@ pub mod coffee_futures {
         // optionally verify decimals
         require!(decimals == ctx.accounts.cft_mint.decimals, CoffeeError::MintDecimalsMismatch);
 
-        emit!(CftMintInitialized {
+        emit_event!(ctx, CftMintInitialized {
+            schema_version: EVENT_SCHEMA_VERSION,
             cft_mint: ctx.accounts.cft_mint.key(),
             authority: ctx.accounts.payer.key(),
             decimals,
@@ -56,6 +491,44 @@ pub mod coffee_futures {
         Ok(())
     }
 
+    // One-time: create the program's dummy quote mint. Markets created with it as quote_mint are
+    // sandboxes, for integrators to dry-run flows without real funds
+    pub fn init_sandbox_mint(ctx: Context<InitSandboxMint>, decimals: u8) -> Result<()> {
+        ctx.accounts.sandbox_auth.bump = ctx.bumps.sandbox_auth;
+        emit_event!(ctx, SandboxMintInitialized {
+            schema_version: EVENT_SCHEMA_VERSION,
+            sandbox_mint: ctx.accounts.sandbox_mint.key(),
+            decimals,
+        });
+        Ok(())
+    }
+
+    // Mint dummy quote tokens to any token account of the sandbox mint (permissionless)
+    pub fn sandbox_faucet(ctx: Context<SandboxFaucet>, amount: u64) -> Result<()> {
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        require_gte!(SANDBOX_FAUCET_MAX, amount, CoffeeError::SandboxFaucetCapExceeded);
+        let bump = ctx.accounts.sandbox_auth.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, SANDBOX_SEED, &[bump]]];
+        token::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.sandbox_mint.to_account_info(),
+                    to: ctx.accounts.to.to_account_info(),
+                    authority: ctx.accounts.sandbox_auth.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            amount,
+        )?;
+        emit_event!(ctx, SandboxFaucetMinted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            to: ctx.accounts.to.key(),
+            amount,
+        });
+        Ok(())
+    }
+
     // Create a per-harvest market (admin)
     #[allow(clippy::too_many_arguments)]
     pub fn create_market(
@@ -80,7 +553,22 @@ pub mod coffee_futures {
         let market_key = ctx.accounts.market.key();
 
         let market = &mut ctx.accounts.market;
-        require!(initial_margin_bps >= maintenance_margin_bps, CoffeeError::BadMarginParams);
+        // same bounds as a params update, so a market can never be created in a state it couldn't be moved to
+        MarketParams {
+            initial_margin_bps,
+            maintenance_margin_bps,
+            fee_bps,
+            farmer_fee_bps,
+            buyer_fee_bps,
+            insurance_bps,
+            max_notional_per_deal,
+            max_qty_per_deal,
+            max_oracle_age_sec,
+            default_margin_call_grace_sec: 0,
+            min_transfer_amount,
+        }
+        .validate()?;
+        require!(settlement_ts > Clock::get()?.unix_timestamp, CoffeeError::SettlementInPast);
         require!(contract_size_kg > 0, CoffeeError::ZeroQty);
         require!(twap_window_sec >= MIN_TWAP_WINDOW, CoffeeError::InvalidTwapWindow);
 
@@ -92,6 +580,20 @@ pub mod coffee_futures {
         market.pending_oracle_effective_ts = 0;
         market.cft_mint = ctx.accounts.cft_mint.key();
         market.quote_mint = ctx.accounts.quote_mint.key();
+        // per-market mode: this harvest's CFT is its own mint, derived from the market, with its own supply ledger
+        if let Some(mint) = ctx.accounts.market_cft_mint.as_ref() {
+            let auth = ctx.accounts.market_cft_auth.as_mut().ok_or(CoffeeError::MarketCftAccountsMissing)?;
+            auth.bump = ctx.bumps.market_cft_auth.ok_or(CoffeeError::MarketCftAccountsMissing)?;
+            market.cft_mint = mint.key();
+            market.cft_per_market = true;
+        } else {
+            require!(ctx.accounts.market_cft_auth.is_none(), CoffeeError::MarketCftAccountsMissing);
+            market.cft_per_market = false;
+        }
+        // sandbox mode: quoting in the dummy mint runs every check and ledger update as usual, only the
+        // tokens are worthless. The shared CFT mint is real, so sandboxes must mint their own
+        market.sandbox = market.quote_mint == crate::pda::sandbox_mint().0;
+        require!(!market.sandbox || market.cft_per_market, CoffeeError::SandboxUnsupported);
         market.settlement_ts = settlement_ts;
         market.contract_size_kg = contract_size_kg;
         market.initial_margin_bps = initial_margin_bps;
@@ -117,8 +619,80 @@ pub mod coffee_futures {
         market.default_margin_call_grace_sec = 0;
         market.insurance_treasury_authority = Pubkey::default();
         market.program_version = PROGRAM_VERSION;
+        market.fee_tier_count = 0;
+        market.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        market.cft_fee_discount_bps = 0;
+        market.pending_verifier = Pubkey::default();
+        market.pending_verifier_effective_ts = 0;
+        market.pending_params = MarketParams::default();
+        market.pending_params_effective_ts = 0;
+        market.governance = Pubkey::default();
+        market.authority_config = AuthorityConfig::SingleKey;
+        market.verifier_config = AuthorityConfig::SingleKey;
+        market.default_penalty_bps = 0;
+        market.physical_payment_policy = PaymentPolicy::CapAtBalance as u8;
+        market.debt_claim_wait_sec = 0;
+        market.total_open_notional_kg = 0;
+        market.loss_socialization_index = 0;
+        market.socialized_loss_total = 0;
+        market.total_open_deals = 0;
+        market.total_margin_locked = 0;
+        market.max_open_deals = 0;
+        market.max_open_notional_kg = 0;
+        market.market_kind = MarketKind::Dated as u8;
+        market.funding_cap_bps = 0;
+        market.last_funding_ts = 0;
+        market.cumulative_funding_per_kg = 0;
+        market.open_price_qty_sum = 0;
+        market.whole_contracts_only = false;
+        market.tick_size = 0;
+        market.unclosed_deals = 0;
+        market.close_after_ts = 0;
+        market.deal_seq = 0;
+        market.require_fresh_price_for_open = false;
+        market.max_deal_price_deviation_bps = 0;
+        market.delivery_tree = Pubkey::default();
+        market.cft_allowlist_required = false;
+        market.margin_step_count = 0;
+        market.margin_steps = [MarginStep::default(); MAX_MARGIN_STEPS];
+        market.realized_var_bps2 = 0;
+        market.vol_ref_bps = 0;
+        market.max_maintenance_margin_bps = 0;
+        market.insurance_upfront = false;
+        market.total_insurance_premiums = 0;
+        market.insurance_stake_pool = Pubkey::default();
+        market.risk_officer = Pubkey::default();
+        market.large_deal_notional = 0;
+        market.reduce_break_fee_bps = 0;
+        market.cft_sale_treasury = Pubkey::default();
+        market.cft_expiry_ts = 0;
+        market.cft_floor_price_per_kg = 0;
+        market.cft_minted = 0;
+        market.cft_redeemed = 0;
+        market.sample_required = false;
+        market.shipment_tranche_bps = [0u16; SHIPMENT_MILESTONES];
+        market.wormhole_program = Pubkey::default();
+        market.cctp_program = Pubkey::default();
+        market.operator = Pubkey::default();
+        market.operator_fee_bps = 0;
+        market.operator_treasury = Pubkey::default();
+        market.seed_version = 1;
+        market.migrated_to = Pubkey::default();
+        market.licensed_liquidators = false;
+        market.liquidator_min_bond = 0;
+        market.liquidator_slash_bps = 0;
+        market.margin_cure_buffer_bps = 0;
 
-        emit!(MarketCreated {
+        if market.cft_per_market {
+            emit_event!(ctx, CftMintInitialized {
+                schema_version: EVENT_SCHEMA_VERSION,
+                cft_mint: market.cft_mint,
+                authority: market.authority,
+                decimals: CFT_DECIMALS,
+            });
+        }
+        emit_event!(ctx, MarketCreated {
+            schema_version: EVENT_SCHEMA_VERSION,
             market: market_key,
             authority: market.authority,
             cft_mint: market.cft_mint,
@@ -131,35 +705,52 @@ pub mod coffee_futures {
     // Oracle publishes a price; includes nonce and performs staleness / price-band checks
     pub fn publish_price(ctx: Context<PublishPrice>, price_per_kg: u64, nonce: u64) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
-        assert_is_oracle(&ctx.accounts.market, &ctx.accounts.oracle_publisher)?;
+        require_role_or_session(
+            &ctx.accounts.market,
+            ctx.accounts.access_control.as_ref(),
+            &ctx.accounts.oracle_publisher.key(),
+            ctx.accounts.session.as_ref(),
+            SessionScope::PublishPrice,
+            &[],
+        )?;
 
-        // replay/nonce protection
         let market = &mut ctx.accounts.market;
-        require!(nonce > market.last_price_nonce, CoffeeError::ReplayOrStaleNonce);
-        require!(price_per_kg > 0, CoffeeError::ZeroPrice);
-
+        require!(!market.commit_reveal, CoffeeError::PriceCommitRequired);
         let now_ts = Clock::get()?.unix_timestamp;
+        apply_price(market, price_per_kg, nonce, now_ts)?;
 
-        // staleness: if last update exists, ensure age <= max
-        if market.last_oracle_update_ts > 0 && market.max_oracle_age_sec > 0 {
-            let age_u64 = abs_i64_to_u64(now_ts - market.last_oracle_update_ts);
-            require!(age_u64 <= market.max_oracle_age_sec, CoffeeError::OracleStale);
-        }
+        emit_event!(ctx, PricePublished {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: ctx.accounts.market.key(),
+            price_per_kg,
+            publisher: ctx.accounts.oracle_publisher.key(),
+            ts: now_ts,
+            nonce,
+        });
 
-        // price-band check against previous price (if present)
-        if market.prev_price_per_kg > 0 {
-            is_price_band_ok(market.prev_price_per_kg, price_per_kg, 2_500 /* 25% demo cap */)?;
-        }
+        Ok(())
+    }
 
-        // Update TWAP (time-weighted)
-        update_twap(market, now_ts)?;
+    // publish_price with the price in the market's quoted convention (e.g. ¢/lb); stored per kg
+    pub fn publish_price_quoted(ctx: Context<PublishPrice>, quoted_price: u64, nonce: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role_or_session(
+            &ctx.accounts.market,
+            ctx.accounts.access_control.as_ref(),
+            &ctx.accounts.oracle_publisher.key(),
+            ctx.accounts.session.as_ref(),
+            SessionScope::PublishPrice,
+            &[],
+        )?;
 
-        market.prev_price_per_kg = market.last_price_per_kg;
-        market.last_price_per_kg = price_per_kg;
-        market.last_oracle_update_ts = now_ts;
-        market.last_price_nonce = nonce;
+        let market = &mut ctx.accounts.market;
+        require!(!market.commit_reveal, CoffeeError::PriceCommitRequired);
+        let price_per_kg = market.price_from_quoted(quoted_price)?;
+        let now_ts = Clock::get()?.unix_timestamp;
+        apply_price(market, price_per_kg, nonce, now_ts)?;
 
-        emit!(PricePublished {
+        emit_event!(ctx, PricePublished {
+            schema_version: EVENT_SCHEMA_VERSION,
             market: ctx.accounts.market.key(),
             price_per_kg,
             publisher: ctx.accounts.oracle_publisher.key(),
@@ -170,7 +761,114 @@ pub mod coffee_futures {
         Ok(())
     }
 
-    // Open a bilateral deal (farmer short, buyer long), both deposit initial margin
+    // Commit-reveal publishing, step 1: the oracle commits to cpi_interface::price_commitment of the
+    // next price. Nothing about the price is public until reveal_price; a new commit replaces one pending
+    pub fn commit_price(ctx: Context<PublishPrice>, commitment: [u8; 32]) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role_or_session(
+            &ctx.accounts.market,
+            ctx.accounts.access_control.as_ref(),
+            &ctx.accounts.oracle_publisher.key(),
+            ctx.accounts.session.as_ref(),
+            SessionScope::PublishPrice,
+            &[],
+        )?;
+        require!(commitment != [0u8; 32], CoffeeError::NoPriceCommit);
+
+        let clock = Clock::get()?;
+        let market = &mut ctx.accounts.market;
+        market.price_commit = commitment;
+        market.price_commit_ts = clock.unix_timestamp;
+        market.price_commit_slot = clock.slot;
+        emit_event!(ctx, PriceCommitted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            publisher: ctx.accounts.oracle_publisher.key(),
+            commitment,
+            ts: clock.unix_timestamp,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    // Step 2: publish the committed price, in a later slot and at least min_reveal_delay_sec after the
+    // commit. The price goes through the publish_price checks, and the TWAP accrues from reveal time
+    pub fn reveal_price(ctx: Context<PublishPrice>, price_per_kg: u64, salt: [u8; 32], nonce: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role_or_session(
+            &ctx.accounts.market,
+            ctx.accounts.access_control.as_ref(),
+            &ctx.accounts.oracle_publisher.key(),
+            ctx.accounts.session.as_ref(),
+            SessionScope::PublishPrice,
+            &[],
+        )?;
+
+        let clock = Clock::get()?;
+        let market_key = ctx.accounts.market.key();
+        let market = &mut ctx.accounts.market;
+        check_price_reveal(market, clock.unix_timestamp, clock.slot)?;
+        require!(
+            cpi_interface::price_commitment(&market_key, price_per_kg, nonce, &salt) == market.price_commit,
+            CoffeeError::PriceCommitMismatch
+        );
+        let commitment = market.price_commit;
+        let commit_ts = market.price_commit_ts;
+        market.price_commit = [0; 32];
+        apply_price(market, price_per_kg, nonce, clock.unix_timestamp)?;
+
+        emit_event!(ctx, PricePublished {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market_key,
+            price_per_kg,
+            publisher: ctx.accounts.oracle_publisher.key(),
+            ts: clock.unix_timestamp,
+            nonce,
+        });
+        emit_event!(ctx, PriceRevealed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market_key,
+            commitment,
+            commit_ts,
+            ts: clock.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    // Oracle confirms the current price is unchanged: refreshes freshness and the TWAP without a new price
+    pub fn oracle_heartbeat(ctx: Context<PublishPrice>, nonce: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role_or_session(
+            &ctx.accounts.market,
+            ctx.accounts.access_control.as_ref(),
+            &ctx.accounts.oracle_publisher.key(),
+            ctx.accounts.session.as_ref(),
+            SessionScope::OracleHeartbeat,
+            &[],
+        )?;
+
+        let market = &mut ctx.accounts.market;
+        let now_ts = Clock::get()?.unix_timestamp;
+        check_oracle_update(market, nonce, now_ts)?;
+        require!(market.last_price_per_kg > 0, CoffeeError::ZeroPrice);
+
+        // accrue the unchanged price over the elapsed time; prev/last price stay as they are
+        update_twap(market, now_ts)?;
+        market.last_price_nonce = nonce;
+
+        emit_event!(ctx, OracleHeartbeat {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            price_per_kg: market.last_price_per_kg,
+            publisher: ctx.accounts.oracle_publisher.key(),
+            ts: now_ts,
+            nonce,
+        });
+        Ok(())
+    }
+
+    // Open a bilateral deal, both deposit initial margin. The farmer sells (short) unless farmer_side is
+    // OrderSide::Buy; the buyer takes the other side
     #[allow(clippy::too_many_arguments)]
     pub fn open_deal(
         ctx: Context<OpenDeal>,
@@ -183,27 +881,92 @@ pub mod coffee_futures {
         merkle_root: Option<[u8; 32]>,
         referrer: Option<Pubkey>,
         fee_split_bps: Option<u16>,
+        margin_override: Option<MarginOverride>, // higher per-side margin both parties agree to
+        farmer_side: u8,                         // OrderSide the farmer takes; the buyer takes the other
     ) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
         let market = &ctx.accounts.market;
         require!(!market.paused, CoffeeError::MarketPaused);
+        require!(market.close_after_ts == 0, CoffeeError::MarketClosing);
+        require!(market.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated);
+        require!(
+            !market.require_fresh_price_for_open || market.oracle_is_fresh(Clock::get()?.unix_timestamp),
+            CoffeeError::OracleStale
+        );
         require!(agreed_price_per_kg > 0, CoffeeError::ZeroPrice);
+        require!(market.is_on_tick(agreed_price_per_kg), CoffeeError::PriceNotOnTick);
+        if market.max_deal_price_deviation_bps > 0 {
+            // off-market guard: agreed price must sit within the band around the oracle mark
+            let deviation = market.price_band_bps(mark_price(market)?, agreed_price_per_kg)?;
+            require_gte!(market.max_deal_price_deviation_bps as u128, deviation, CoffeeError::DealPriceDeviationExceeded);
+        }
         require!(quantity_kg > 0, CoffeeError::ZeroQty);
+        // the short delivers, and delivery, baskets and crop cover follow the farmer, so a physical farmer sells
+        require!(
+            OrderSide::from_u8(farmer_side)? == OrderSide::Sell || !physical_delivery,
+            CoffeeError::PhysicalFarmerMustSell
+        );
         require!(assets.len() == asset_qty.len(), CoffeeError::InvalidAssetBasket);
         require!(assets.len() <= MAX_ASSETS, CoffeeError::TooManyAssets);
-        require!(quantity_kg <= market.max_qty_per_deal, CoffeeError::DealQtyExceedsLimit);
+        let asset_units_per_kg = validate_basket(market, ctx.accounts.approved_assets.as_ref(), &assets, &asset_qty, quantity_kg)?;
+        require_gte!(market.max_qty_per_deal, quantity_kg, CoffeeError::DealQtyExceedsLimit);
+        require!(
+            !market.whole_contracts_only || market.contracts_for_qty(quantity_kg).is_some(),
+            CoffeeError::NotWholeContracts
+        );
 
         // compute notional and check cap
-        let notional = (agreed_price_per_kg as u128)
-            .checked_mul(quantity_kg as u128)
-            .ok_or(CoffeeError::MathOverflow)?;
-        require!(notional <= market.max_notional_per_deal as u128, CoffeeError::DealNotionalExceedsLimit);
+        let notional = market.notional(agreed_price_per_kg, quantity_kg)?;
+        require_gte!(market.max_notional_per_deal as u128, notional, CoffeeError::DealNotionalExceedsLimit);
+        // maker-checker: oversized deals also need the risk officer's signature
+        if market.requires_risk_cosign(notional) {
+            let officer = ctx.accounts.risk_officer.as_ref().ok_or(CoffeeError::RiskOfficerSignatureRequired)?;
+            require_keys_eq!(officer.key(), market.risk_officer, CoffeeError::RiskOfficerSignatureRequired);
+        }
+
+        // specialty workflow: the buyer approved a sample before margins are pulled; open_deal consumes it
+        if market.sample_required || ctx.accounts.sample.is_some() {
+            let sample = ctx.accounts.sample.as_ref().ok_or(CoffeeError::SampleApprovalRequired)?;
+            require!(sample.approved, CoffeeError::SampleApprovalRequired);
+            require!(!sample.lapsed(Clock::get()?.unix_timestamp), CoffeeError::SampleWindowLapsed);
+        }
+
+        // the farmer signs, or an agent opens within its grant's notional cap
+        let farmer_signer = farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        if !ctx.accounts.farmer.is_signer {
+            if let Some(grant) = ctx.accounts.agent_grant.as_mut() {
+                grant.use_notional(notional)?;
+            }
+        }
+
+        // the open counts against both wallets' hourly limit
+        if market.max_opens_per_hour > 0 {
+            let now = Clock::get()?.unix_timestamp;
+            for stats in [ctx.accounts.farmer_stats.as_mut(), ctx.accounts.buyer_stats.as_mut()] {
+                stats.ok_or(CoffeeError::TraderStatsRequired)?.record_opens(1, market.max_opens_per_hour, now)?;
+            }
+        }
+
+        let mut fee_waiver_bps = 0;
+        let mut waiver_redeemed = None;
+        if let Some(waiver) = ctx.accounts.fee_waiver.as_mut() {
+            fee_waiver_bps = waiver.redeem(&ctx.accounts.farmer.key(), &ctx.accounts.buyer.key(), Clock::get()?.unix_timestamp)?;
+            waiver_redeemed = Some((waiver.key(), waiver.max_uses - waiver.uses));
+        }
 
         // persist vault_auth bump
         ctx.accounts.vault_auth.bump = ctx.bumps.vault_auth;
 
         // avoid borrow conflict: capture deal key before mut borrow
         let deal_key = ctx.accounts.deal.key();
+        for index in [ctx.accounts.farmer_index.as_mut(), ctx.accounts.buyer_index.as_mut()].into_iter().flatten() {
+            index.push(deal_key)?;
+        }
         let deal = &mut ctx.accounts.deal;
 
         deal.version = PROGRAM_VERSION;
@@ -214,6 +977,7 @@ pub mod coffee_futures {
         deal.quantity_kg = quantity_kg;
         deal.initial_margin_each = 0; // set after transfers
         deal.physical_delivery = physical_delivery;
+        deal.farmer_side = farmer_side;
         deal.settled = false;
         deal.settling = false;
         deal.liquidated = false;
@@ -225,17 +989,123 @@ pub mod coffee_futures {
         deal.margin_call_grace_sec = 0;
         deal.referrer = referrer.unwrap_or_default();
         deal.fee_split_bps = fee_split_bps.unwrap_or(0);
+        deal.fee_currency = FeeCurrency::Quote as u8;
+        deal.voided = false;
+        deal.force_majeure_reason = 0;
+        deal.prepaid = false;
+        deal.escrow_balance = 0;
+        deal.unpaid_owed = 0;
+        deal.debt_creditor = Pubkey::default();
+        deal.debt_opened = false;
+        deal.loss_index_checkpoint = market.loss_socialization_index;
+        deal.adl_haircut = 0;
+        deal.margin_locked = 0;
+        deal.funding_checkpoint = market.cumulative_funding_per_kg;
+        deal.funding_paid_long = 0;
+        deal.deal_id = market.deal_seq.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        deal.merkle_root_version = 0;
+        deal.delivery_leaves = 0;
+        deal.delivery_digest = [0u8; 32];
+        deal.farmer_margin_account = Pubkey::default();
+        deal.buyer_margin_account = Pubkey::default();
+        deal.insurance_prepaid = 0;
+        deal.template = Pubkey::default();
+        deal.quality_grade = 0;
+        deal.incoterm = Incoterm::Unspecified as u8;
+        deal.delivery_start_ts = 0;
+        deal.cft_payout = false;
+        deal.shipment_status = ShipmentStatus::NotShipped as u8;
+        deal.shipment_updated_ts = 0;
+        deal.tranches_advanced = 0;
+        deal.crosschain_chain = 0;
+        deal.crosschain_recipient = [0u8; 32];
+        deal.crosschain_posted = false;
+        deal.payout_domain = 0;
+        deal.payout_recipient = [0u8; 32];
+        deal.fx_oracle = Pubkey::default();
+        deal.fx_notional = 0;
+        deal.fx_locked_rate = 0;
+        deal.fx_settled = false;
+        deal.final_price_per_kg = 0;
+        deal.realized_pnl_farmer = 0;
+        deal.realized_pnl_buyer = 0;
+        deal.fees_paid = 0;
+        deal.insurance_drawn = 0;
+        deal.settlement_step = settlement::SettlementStep::NotStarted as u8;
+        deal.settle_fees = [0; 4];
+        deal.settle_pnl = [0; 3];
+        deal.seed_version = 1;
+        deal.health_bps = u32::MAX;
+        deal.health_updated_ts = 0;
+        deal.margin_override = margin_override;
+        deal.credit_used = 0;
+        deal.omnibus = false;
+        deal.omnibus_balance = [0; 2];
+        deal.fee_waiver_bps = fee_waiver_bps;
+        deal.sponsor = Pubkey::default();
+        deal.sponsored_margin = 0;
+        deal.farmer_own_margin = 0;
+        deal.split_vaults = false;
 
         deal.asset_count = assets.len() as u8;
         for i in 0..assets.len() {
             deal.assets[i] = assets[i];
             deal.asset_qty[i] = asset_qty[i];
+            deal.asset_units_per_kg[i] = asset_units_per_kg[i];
         }
         deal.merkle_root = merkle_root.unwrap_or(EMPTY_MERKLE_ROOT);
 
-        // compute initial margin
-        let req_margin = bps_mul_u128(notional, market.initial_margin_bps)?;
-        let req_margin_u64: u64 = req_margin.try_into().map_err(|_| CoffeeError::MathOverflow)?;
+        // a template pins the standardized terms; anything else must match it
+        if let Some(template) = ctx.accounts.template.as_ref() {
+            require!(template.active, CoffeeError::TemplateInactive);
+            require!(
+                template.quantity_kg == quantity_kg
+                    && template.physical_delivery == physical_delivery
+                    && template.delivery_end_ts == deadline_ts,
+                CoffeeError::TemplateTermsMismatch
+            );
+            deal.template = template.key();
+            deal.quality_grade = template.quality_grade;
+            deal.incoterm = template.incoterm;
+            deal.delivery_start_ts = template.delivery_start_ts;
+        }
+
+        // compute initial margin; each side posts at least the market rate, more where both agreed to it
+        let now = Clock::get()?.unix_timestamp;
+        let initial_margin_bps = market.initial_margin_bps_at(now);
+        let margin_override = margin_override.unwrap_or_default();
+        margin_override.check(initial_margin_bps, market.maintenance_margin_bps_at(now))?;
+        let req_margin_u64 = market.required_margin(agreed_price_per_kg, quantity_kg, initial_margin_bps)?;
+        let side_margin = |side: math::Side| {
+            market.required_margin(agreed_price_per_kg, quantity_kg, margin_override.initial_bps(side, initial_margin_bps))
+        };
+        let farmer_margin = side_margin(math::Side::Short)?;
+        let buyer_margin = side_margin(math::Side::Long)?;
+
+        // an approved buyer may leave part of its margin unposted against its CreditLine; the unsecured
+        // part counts as buyer collateral in margin checks, and a loss it can't cover becomes a Debt
+        let credit = match ctx.accounts.credit_line.as_mut() {
+            Some(line) => Some((line.key(), line.draw(buyer_margin)?, line.used)),
+            None => None,
+        };
+        let credit_used = credit.map_or(0, |(_, drawn, _)| drawn);
+        let buyer_posted = buyer_margin - credit_used;
+
+        // omnibus markets take margin into the two market vaults and book the deal's share on the deal;
+        // otherwise the deal's own vaults are created for it
+        let vaults = if market.omnibus_vaults {
+            require!(!physical_delivery, CoffeeError::OmnibusUnsupported);
+            require!(
+                ctx.accounts.farmer_margin_vault.is_none() && ctx.accounts.buyer_margin_vault.is_none(),
+                CoffeeError::OmnibusVaultMismatch
+            );
+            (ctx.accounts.omnibus_farmer_vault.as_ref(), ctx.accounts.omnibus_buyer_vault.as_ref())
+        } else {
+            (ctx.accounts.farmer_margin_vault.as_ref(), ctx.accounts.buyer_margin_vault.as_ref())
+        };
+        let (Some(farmer_vault), Some(buyer_vault)) = vaults else {
+            return err!(CoffeeError::OmnibusVaultMismatch);
+        };
 
         // farmer -> farmer vault
         token::transfer(
@@ -243,11 +1113,11 @@ pub mod coffee_futures {
                 ctx.accounts.token_program.to_account_info(),
                 Transfer {
                     from: ctx.accounts.farmer_margin_from.to_account_info(),
-                    to: ctx.accounts.farmer_margin_vault.to_account_info(),
-                    authority: ctx.accounts.farmer.to_account_info(),
+                    to: farmer_vault.to_account_info(),
+                    authority: farmer_signer.clone(),
                 },
             ),
-            req_margin_u64,
+            farmer_margin,
         )?;
         deal.farmer_deposited = true;
 
@@ -257,78 +1127,938 @@ pub mod coffee_futures {
                 ctx.accounts.token_program.to_account_info(),
                 Transfer {
                     from: ctx.accounts.buyer_margin_from.to_account_info(),
-                    to: ctx.accounts.buyer_margin_vault.to_account_info(),
+                    to: buyer_vault.to_account_info(),
                     authority: ctx.accounts.buyer.to_account_info(),
                 },
             ),
-            req_margin_u64,
+            buyer_posted,
         )?;
         deal.buyer_deposited = true;
 
+        // up-front insurance premium: the flat-fee insurance share, farmer half / buyer the rest,
+        // paid from the traders' funding accounts on top of their margin
+        let mut premium = 0u64;
+        if market.insurance_upfront {
+            let insurance = ctx.accounts.insurance_treasury.as_ref().ok_or(CoffeeError::InsuranceTreasuryMissing)?;
+            premium = upfront_premium(market, notional, deal.fee_waiver_bps)?;
+            let from_farmer = premium / 2;
+            for (from, authority, amount) in [
+                (&ctx.accounts.farmer_margin_from, farmer_signer.clone(), from_farmer),
+                (&ctx.accounts.buyer_margin_from, ctx.accounts.buyer.to_account_info(), premium - from_farmer),
+            ] {
+                if amount > 0 {
+                    token::transfer(
+                        CpiContext::new(
+                            ctx.accounts.token_program.to_account_info(),
+                            Transfer { from: from.to_account_info(), to: insurance.to_account_info(), authority },
+                        ),
+                        amount,
+                    )?;
+                }
+            }
+            deal.insurance_prepaid = premium;
+            deal.fees_paid = premium;
+        }
+
+        // the market-rate share; release and reduce work from it, overridden sides just keep more
         deal.initial_margin_each = req_margin_u64;
+        deal.margin_locked = farmer_margin.checked_add(buyer_posted).ok_or(CoffeeError::MathOverflow)?;
+        deal.credit_used = credit_used;
+        if market.omnibus_vaults {
+            deal.omnibus = true;
+            deal.omnibus_balance = [farmer_margin, buyer_posted];
+        } else {
+            deal.split_vaults = true;
+        }
+        let margin_locked = deal.margin_locked;
 
-        emit!(DealOpened {
+        emit_event!(ctx, DealOpened {
+            schema_version: EVENT_SCHEMA_VERSION,
             deal: deal_key,
+            deal_id: deal.deal_id,
             market: market.key(),
             farmer: deal.farmer,
             buyer: deal.buyer,
             agreed_price_per_kg,
             quantity_kg,
+            farmer_side,
         });
+        if premium > 0 {
+            emit_event!(ctx, InsurancePremiumCollected { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, premium });
+        }
+        if let Some((credit_line, amount, used)) = credit.filter(|&(_, drawn, _)| drawn > 0) {
+            emit_event!(ctx, CreditLineDrawn { schema_version: EVENT_SCHEMA_VERSION, credit_line, deal: deal_key, deal_id: deal.deal_id, amount, used });
+        }
+        if margin_override != MarginOverride::default() {
+            emit_event!(ctx, MarginOverrideAgreed { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, margin_override });
+        }
+        if let Some((waiver, uses_left)) = waiver_redeemed {
+            emit_event!(ctx, FeeWaiverRedeemed {
+                schema_version: EVENT_SCHEMA_VERSION,
+                waiver,
+                deal: deal_key,
+                deal_id: deal.deal_id,
+                discount_bps: fee_waiver_bps,
+                uses_left,
+            });
+        }
 
+        let market = &mut ctx.accounts.market;
+        market.add_open_interest(quantity_kg, margin_locked)?;
+        if market.omnibus_vaults {
+            market.omnibus_open_deals = market.omnibus_open_deals.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        }
+        market.unclosed_deals = market.unclosed_deals.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        market.deal_seq = market.deal_seq.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        let price_qty = math::notional(agreed_price_per_kg, quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+        market.open_price_qty_sum = market.open_price_qty_sum.checked_add(price_qty).ok_or(CoffeeError::MathOverflow)?;
+        market.total_insurance_premiums = market.total_insurance_premiums.checked_add(premium).ok_or(CoffeeError::MathOverflow)?;
         Ok(())
     }
 
-    // Top up margin by either side
-    pub fn top_up_margin(ctx: Context<TopUpMargin>, amount: u64) -> Result<()> {
+    // Co-op bulk open: the buyer and a co-op agent open one small deal per member farmer at a shared
+    // price, the agent acting under each member's AgentGrant. remaining_accounts hold
+    // [agent_grant, deal, vault_auth, margin_vault] per member, the last three still uncreated. Both
+    // sides' aggregate margin (and up-front premium) is pulled once into the market's batch staging
+    // vault and handed out to the deal vaults from there. Batch deals take the plain open_deal terms:
+    // no basket, template, margin override or credit line
+    pub fn open_deals_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, OpenDealsBatch<'info>>,
+        agreed_price_per_kg: u64,
+        quantities_kg: Vec<u64>, // one per member, in remaining_accounts order
+        physical_delivery: bool,
+        deadline_ts: i64,
+    ) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
-        require!(amount > 0, CoffeeError::ZeroAmount);
+        let rest = ctx.remaining_accounts;
+        require!(
+            !quantities_kg.is_empty() && rest.len() == quantities_kg.len() * BATCH_ACCOUNTS_PER_DEAL,
+            CoffeeError::BatchAccountsInvalid
+        );
+        require!(quantities_kg.len() <= MAX_BATCH_DEALS, CoffeeError::TooManyBatchDeals);
+        let now = Clock::get()?.unix_timestamp;
+        let market = &ctx.accounts.market;
+        let market_key = market.key();
+        require!(!market.paused, CoffeeError::MarketPaused);
+        require!(market.close_after_ts == 0, CoffeeError::MarketClosing);
+        require!(market.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated);
+        require!(!market.require_fresh_price_for_open || market.oracle_is_fresh(now), CoffeeError::OracleStale);
+        require!(!market.sample_required, CoffeeError::SampleApprovalRequired);
+        require!(!market.omnibus_vaults, CoffeeError::OmnibusUnsupported);
+        require!(agreed_price_per_kg > 0, CoffeeError::ZeroPrice);
+        require!(market.is_on_tick(agreed_price_per_kg), CoffeeError::PriceNotOnTick);
+        if market.max_deal_price_deviation_bps > 0 {
+            let deviation = market.price_band_bps(mark_price(market)?, agreed_price_per_kg)?;
+            require_gte!(market.max_deal_price_deviation_bps as u128, deviation, CoffeeError::DealPriceDeviationExceeded);
+        }
+        let initial_margin_bps = market.initial_margin_bps_at(now);
+        if market.max_opens_per_hour > 0 {
+            let opens = u16::try_from(quantities_kg.len()).map_err(|_| CoffeeError::MathOverflow)?;
+            ctx.accounts.buyer_stats.as_mut().ok_or(CoffeeError::TraderStatsRequired)?
+                .record_opens(opens, market.max_opens_per_hour, now)?;
+        }
 
-        let who = ctx.accounts.who.key();
-        let deal = &ctx.accounts.deal;
-        assert_is_counterparty(&deal, &ctx.accounts.who)?;
+        // size every member's deal and charge its grant before anything moves
+        let buyer_key = ctx.accounts.buyer.key();
+        let agent_key = ctx.accounts.agent.key();
+        let mut members: Vec<(Account<'info, AgentGrant>, u64, u128, u64, u64, (u16, u32))> = Vec::with_capacity(quantities_kg.len()); // (grant, kg, notional, margin, premium, (waiver bps, uses left))
+        let (mut farmer_total, mut buyer_total, mut premium_total) = (0u64, 0u64, 0u64);
+        for (chunk, &quantity_kg) in rest.chunks(BATCH_ACCOUNTS_PER_DEAL).zip(quantities_kg.iter()) {
+            let mut grant: Account<'info, AgentGrant> = Account::try_from(&chunk[0])?;
+            require_keys_eq!(grant.market, market_key, CoffeeError::AgentNotAuthorized);
+            require_keys_eq!(grant.agent, agent_key, CoffeeError::AgentNotAuthorized);
+            require!(grant.is_active(now), CoffeeError::AgentGrantExpired);
+            require!(members.iter().all(|(g, ..)| g.farmer != grant.farmer), CoffeeError::BatchAccountsInvalid);
+            require!(quantity_kg > 0, CoffeeError::ZeroQty);
+            require_gte!(market.max_qty_per_deal, quantity_kg, CoffeeError::DealQtyExceedsLimit);
+            require!(
+                !market.whole_contracts_only || market.contracts_for_qty(quantity_kg).is_some(),
+                CoffeeError::NotWholeContracts
+            );
+            let notional = market.notional(agreed_price_per_kg, quantity_kg)?;
+            require_gte!(market.max_notional_per_deal as u128, notional, CoffeeError::DealNotionalExceedsLimit);
+            require!(!market.requires_risk_cosign(notional), CoffeeError::RiskOfficerSignatureRequired);
+            grant.use_notional(notional)?;
 
-        if who == deal.farmer {
+            let margin = market.required_margin(agreed_price_per_kg, quantity_kg, initial_margin_bps)?;
+            // a waiver passed with the batch is redeemed once per member deal
+            let waiver = match ctx.accounts.fee_waiver.as_mut() {
+                Some(w) => (w.redeem(&grant.farmer, &buyer_key, now)?, w.max_uses - w.uses),
+                None => (0, 0),
+            };
+            let premium = upfront_premium(market, notional, waiver.0)?;
+            let from_farmer = premium / 2;
+            farmer_total = farmer_total.checked_add(margin + from_farmer).ok_or(CoffeeError::MathOverflow)?;
+            buyer_total = buyer_total.checked_add(margin + (premium - from_farmer)).ok_or(CoffeeError::MathOverflow)?;
+            premium_total = premium_total.checked_add(premium).ok_or(CoffeeError::MathOverflow)?;
+            members.push((grant, quantity_kg, notional, margin, premium, waiver));
+        }
+
+        // one pull per side into staging
+        let staged_before = ctx.accounts.staging_vault.amount;
+        for (from, authority, amount) in [
+            (&ctx.accounts.agent_margin_from, ctx.accounts.agent.to_account_info(), farmer_total),
+            (&ctx.accounts.buyer_margin_from, ctx.accounts.buyer.to_account_info(), buyer_total),
+        ] {
             token::transfer(
                 CpiContext::new(
                     ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.from_ata.to_account_info(),
-                        to: ctx.accounts.farmer_margin_vault.to_account_info(),
-                        authority: ctx.accounts.who.to_account_info(),
-                    },
+                    Transfer { from: from.to_account_info(), to: ctx.accounts.staging_vault.to_account_info(), authority },
                 ),
                 amount,
             )?;
-        } else {
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.from_ata.to_account_info(),
-                        to: ctx.accounts.buyer_margin_vault.to_account_info(),
-                        authority: ctx.accounts.who.to_account_info(),
-                    },
-                ),
-                amount,
+        }
+        ctx.accounts.batch_auth.bump = ctx.bumps.batch_auth;
+
+        let accounts = &mut ctx.accounts;
+        let payer = accounts.buyer.to_account_info();
+        let mut deal_id = accounts.market.deal_seq;
+        let mut total_kg = 0u64;
+        for ((mut grant, quantity_kg, notional, margin, premium, (fee_waiver_bps, uses_left)), chunk) in members.into_iter().zip(rest.chunks(BATCH_ACCOUNTS_PER_DEAL)) {
+            let (deal_info, vault_auth_info, vault_info) = (&chunk[1], &chunk[2], &chunk[3]);
+            let (deal_key, deal_bump) = Pubkey::find_program_address(
+                &[SEED_PREFIX, DEAL_SEED, market_key.as_ref(), grant.farmer.as_ref(), buyer_key.as_ref()],
+                ctx.program_id,
+            );
+            require_keys_eq!(deal_info.key(), deal_key, CoffeeError::BatchAccountsInvalid);
+            let (vault_auth_key, vault_auth_bump) =
+                Pubkey::find_program_address(&[SEED_PREFIX, VAULT_AUTH_SEED, deal_key.as_ref()], ctx.program_id);
+            require_keys_eq!(vault_auth_info.key(), vault_auth_key, CoffeeError::BatchAccountsInvalid);
+            require_keys_eq!(
+                vault_info.key(),
+                anchor_spl::associated_token::get_associated_token_address(&vault_auth_key, &accounts.quote_mint.key()),
+                CoffeeError::BatchAccountsInvalid
+            );
+
+            create_pda_account(
+                &payer,
+                deal_info,
+                8 + Deal::INIT_SPACE,
+                &[SEED_PREFIX, DEAL_SEED, market_key.as_ref(), grant.farmer.as_ref(), buyer_key.as_ref(), &[deal_bump]],
+                &accounts.system_program,
+                ctx.program_id,
+            )?;
+            create_pda_account(
+                &payer,
+                vault_auth_info,
+                8 + VaultAuth::SIZE,
+                &[SEED_PREFIX, VAULT_AUTH_SEED, deal_key.as_ref(), &[vault_auth_bump]],
+                &accounts.system_program,
+                ctx.program_id,
+            )?;
+            write_account(vault_auth_info, &VaultAuth { bump: vault_auth_bump })?;
+            anchor_spl::associated_token::create(CpiContext::new(
+                accounts.associated_token_program.to_account_info(),
+                anchor_spl::associated_token::Create {
+                    payer: payer.clone(),
+                    associated_token: vault_info.clone(),
+                    authority: vault_auth_info.clone(),
+                    mint: accounts.quote_mint.to_account_info(),
+                    system_program: accounts.system_program.to_account_info(),
+                    token_program: accounts.token_program.to_account_info(),
+                },
+            ))?;
+            let margin_locked = margin.checked_mul(2).ok_or(CoffeeError::MathOverflow)?;
+            transfer_from_batch_staging(
+                margin_locked,
+                &accounts.batch_auth,
+                &accounts.staging_vault,
+                vault_info,
+                &accounts.token_program,
+                &market_key,
             )?;
+
+            deal_id = deal_id.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+            let market = &accounts.market;
+            let deal = Deal {
+                version: PROGRAM_VERSION,
+                market: market_key,
+                farmer: grant.farmer,
+                buyer: buyer_key,
+                agreed_price_per_kg,
+                quantity_kg,
+                initial_margin_each: margin,
+                physical_delivery,
+                farmer_deposited: true,
+                buyer_deposited: true,
+                deadline_ts,
+                fee_currency: FeeCurrency::Quote as u8,
+                loss_index_checkpoint: market.loss_socialization_index,
+                margin_locked,
+                funding_checkpoint: market.cumulative_funding_per_kg,
+                deal_id,
+                merkle_root: EMPTY_MERKLE_ROOT,
+                insurance_prepaid: premium,
+                fees_paid: premium,
+                incoterm: Incoterm::Unspecified as u8,
+                shipment_status: ShipmentStatus::NotShipped as u8,
+                settlement_step: settlement::SettlementStep::NotStarted as u8,
+                seed_version: 1,
+                health_bps: u32::MAX,
+                fee_waiver_bps,
+                ..Deal::default()
+            };
+            write_account(deal_info, &deal)?;
+            grant.exit(ctx.program_id)?;
+            if let Some(index) = accounts.buyer_index.as_mut() {
+                index.push(deal_key)?;
+            }
+
+            emit_event!(accounts, ctx.bumps, DealOpened {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                deal_id,
+                market: market_key,
+                farmer: deal.farmer,
+                buyer: buyer_key,
+                agreed_price_per_kg,
+                quantity_kg,
+                farmer_side: OrderSide::Sell as u8,
+            });
+            if premium > 0 {
+                emit_event!(accounts, ctx.bumps, InsurancePremiumCollected { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id, premium });
+            }
+            if let Some(waiver) = accounts.fee_waiver.as_ref() {
+                emit_event!(accounts, ctx.bumps, FeeWaiverRedeemed {
+                    schema_version: EVENT_SCHEMA_VERSION,
+                    waiver: waiver.key(),
+                    deal: deal_key,
+                    deal_id,
+                    discount_bps: fee_waiver_bps,
+                    uses_left,
+                });
+            }
+
+            let market = &mut accounts.market;
+            market.add_open_interest(quantity_kg, margin_locked)?;
+            market.unclosed_deals = market.unclosed_deals.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+            market.deal_seq = deal_id;
+            let price_qty = math::notional(agreed_price_per_kg, quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+            market.open_price_qty_sum = market.open_price_qty_sum.checked_add(price_qty).ok_or(CoffeeError::MathOverflow)?;
+            total_kg = total_kg.checked_add(quantity_kg).ok_or(CoffeeError::MathOverflow)?;
         }
 
-        emit!(MarginToppedUp {
-            deal: deal.key(),
-            who,
-            amount,
-        });
+        if premium_total > 0 {
+            let insurance = accounts.insurance_treasury.as_ref().ok_or(CoffeeError::InsuranceTreasuryMissing)?;
+            transfer_from_batch_staging(
+                premium_total,
+                &accounts.batch_auth,
+                &accounts.staging_vault,
+                &insurance.to_account_info(),
+                &accounts.token_program,
+                &market_key,
+            )?;
+            let market = &mut accounts.market;
+            market.total_insurance_premiums = market.total_insurance_premiums.checked_add(premium_total).ok_or(CoffeeError::MathOverflow)?;
+        }
+        // staging only passes margin through
+        accounts.staging_vault.reload()?;
+        settlement::check_invariant(accounts.staging_vault.amount == staged_before, "batch staging vault not drained")?;
 
+        emit_event!(accounts, ctx.bumps, DealsBatchOpened {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market_key,
+            buyer: buyer_key,
+            agent: agent_key,
+            deals: quantities_kg.len() as u8,
+            agreed_price_per_kg,
+            total_quantity_kg: total_kg,
+            farmer_margin: farmer_total,
+            buyer_margin: buyer_total,
+        });
         Ok(())
     }
 
-    // margin_call: sets a margin call timestamp and grace period; liquidation only after grace expires
-    pub fn margin_call(ctx: Context<MarginCall>, grace_sec: u64) -> Result<()> {
+    // Rest an order against the oracle: sell (farmer) at or above, or buy (buyer) at or below
+    // `limit_price_per_kg`, until `expiry_ts`. `margin` is escrowed in the order's ATA and funds the
+    // owner's side of every deal it fills
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_standing_order(
+        ctx: Context<PlaceStandingOrder>,
+        order_id: u64,
+        side: u8,
+        limit_price_per_kg: u64,
+        quantity_kg: u64,
+        expiry_ts: i64,
+        deadline_ts: i64,
+        margin: u64,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        require!(!market.paused, CoffeeError::MarketPaused);
+        require!(market.close_after_ts == 0, CoffeeError::MarketClosing);
+        require!(market.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated);
+        require!(!market.sample_required, CoffeeError::SampleApprovalRequired);
+        require!(!market.omnibus_vaults, CoffeeError::OmnibusUnsupported);
+        OrderSide::from_u8(side)?;
+        require!(limit_price_per_kg > 0, CoffeeError::ZeroPrice);
+        require!(market.is_on_tick(limit_price_per_kg), CoffeeError::PriceNotOnTick);
+        require!(quantity_kg > 0, CoffeeError::ZeroQty);
+        require_gte!(market.max_qty_per_deal, quantity_kg, CoffeeError::DealQtyExceedsLimit);
+        require!(
+            !market.whole_contracts_only || market.contracts_for_qty(quantity_kg).is_some(),
+            CoffeeError::NotWholeContracts
+        );
+        require!(margin > 0, CoffeeError::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < expiry_ts && expiry_ts <= deadline_ts, CoffeeError::InvalidOrderExpiry);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.owner_quote_ata.to_account_info(),
+                    to: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            margin,
+        )?;
+
+        let order = &mut ctx.accounts.order;
+        order.market = ctx.accounts.market.key();
+        order.owner = ctx.accounts.owner.key();
+        order.order_id = order_id;
+        order.side = side;
+        order.limit_price_per_kg = limit_price_per_kg;
+        order.remaining_kg = quantity_kg;
+        order.filled_kg = 0;
+        order.expiry_ts = expiry_ts;
+        order.deadline_ts = deadline_ts;
+        order.bump = ctx.bumps.order;
+        emit_event!(ctx, StandingOrderPlaced {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: order.market,
+            order: order.key(),
+            owner: order.owner,
+            side,
+            limit_price_per_kg,
+            quantity_kg,
+            expiry_ts,
+            deadline_ts,
+            margin,
+        });
+        Ok(())
+    }
+
+    // Close a standing order and hand back whatever margin is still escrowed (owner)
+    pub fn cancel_standing_order(ctx: Context<CancelStandingOrder>) -> Result<()> {
+        let refund = ctx.accounts.escrow.amount;
+        let order = &ctx.accounts.order;
+        transfer_from_standing_order(refund, order, &ctx.accounts.escrow, &ctx.accounts.owner_quote_ata.to_account_info(), &ctx.accounts.token_program)?;
+        let seeds: &[&[&[u8]]] = &[&[
+            SEED_PREFIX,
+            STANDING_ORDER_SEED,
+            order.market.as_ref(),
+            order.owner.as_ref(),
+            &order.order_id.to_le_bytes(),
+            &[order.bump],
+        ]];
+        token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.escrow.to_account_info(),
+                destination: ctx.accounts.owner.to_account_info(),
+                authority: order.to_account_info(),
+            },
+            seeds,
+        ))?;
+        emit_event!(ctx, StandingOrderCanceled {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: order.market,
+            order: order.key(),
+            owner: order.owner,
+            unfilled_kg: order.remaining_kg,
+            refund,
+        });
+        Ok(())
+    }
+
+    // Pair a sell and a buy standing order into a deal at the oracle mark once it sits inside both
+    // limits (anyone; the crank pays the deal's rent). Fills the smaller remaining quantity; each side's
+    // margin and half the upfront premium come out of its order's escrow. `deal_id` is market.deal_seq + 1
+    pub fn match_standing_orders(ctx: Context<MatchStandingOrders>, deal_id: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        let market = &ctx.accounts.market;
+        let market_key = market.key();
+        require!(!market.paused, CoffeeError::MarketPaused);
+        require!(market.close_after_ts == 0, CoffeeError::MarketClosing);
+        require!(market.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated);
+        require!(!market.sample_required, CoffeeError::SampleApprovalRequired);
+        require!(!market.omnibus_vaults, CoffeeError::OmnibusUnsupported);
+        require!(market.oracle_is_fresh(now), CoffeeError::OracleStale);
+        require_eq!(deal_id, market.deal_seq.checked_add(1).ok_or(CoffeeError::MathOverflow)?, CoffeeError::StaleDealId);
+
+        let price = mark_price(market)?;
+        require!(market.is_on_tick(price), CoffeeError::PriceNotOnTick);
+        let quantity_kg = plan_standing_match(&ctx.accounts.farmer_order, &ctx.accounts.buyer_order, price, now)?;
+        let notional = market.notional(price, quantity_kg)?;
+        require_gte!(market.max_notional_per_deal as u128, notional, CoffeeError::DealNotionalExceedsLimit);
+        require!(!market.requires_risk_cosign(notional), CoffeeError::RiskOfficerSignatureRequired);
+        let (margin, premium) = escrowed_open_cost(market, price, quantity_kg, market.initial_margin_bps_at(now))?;
+        let from_farmer = premium / 2;
+        let from_buyer = premium - from_farmer;
+
+        // each escrow pays its side's margin into the deal vault and its premium share to insurance
+        for (order, escrow, premium_share) in [
+            (&ctx.accounts.farmer_order, &ctx.accounts.farmer_escrow, from_farmer),
+            (&ctx.accounts.buyer_order, &ctx.accounts.buyer_escrow, from_buyer),
+        ] {
+            require_gte!(escrow.amount, margin + premium_share, CoffeeError::StandingOrderEscrowShort);
+            transfer_from_standing_order(margin, order, escrow, &ctx.accounts.vault.to_account_info(), &ctx.accounts.token_program)?;
+            if premium_share > 0 {
+                let insurance = ctx.accounts.insurance_treasury.as_ref().ok_or(CoffeeError::InsuranceTreasuryMissing)?;
+                transfer_from_standing_order(premium_share, order, escrow, &insurance.to_account_info(), &ctx.accounts.token_program)?;
+            }
+        }
+
+        let (farmer, buyer) = (ctx.accounts.farmer_order.owner, ctx.accounts.buyer_order.owner);
+        let deal = escrowed_deal(market, farmer, buyer, deal_id, price, quantity_kg, margin, premium, ctx.accounts.farmer_order.deadline_ts)?;
+        ctx.accounts.vault_auth.bump = ctx.bumps.vault_auth;
+        ctx.accounts.market.record_escrowed_open(&deal)?;
+        ctx.accounts.deal.set_inner(deal);
+        for order in [&mut ctx.accounts.farmer_order, &mut ctx.accounts.buyer_order] {
+            order.remaining_kg -= quantity_kg;
+            order.filled_kg = order.filled_kg.checked_add(quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+        }
+
+        let deal_key = ctx.accounts.deal.key();
+        emit_event!(ctx, DealOpened {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id,
+            market: market_key,
+            farmer,
+            buyer,
+            agreed_price_per_kg: price,
+            quantity_kg,
+            farmer_side: OrderSide::Sell as u8,
+        });
+        if premium > 0 {
+            emit_event!(ctx, InsurancePremiumCollected { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id, premium });
+        }
+        emit_event!(ctx, StandingOrdersMatched {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            farmer_order: ctx.accounts.farmer_order.key(),
+            buyer_order: ctx.accounts.buyer_order.key(),
+            price_per_kg: price,
+            quantity_kg,
+            margin_each: margin,
+        });
+        Ok(())
+    }
+
+    // List a harvest lot for an open ascending auction until `ends_ts` (farmer). `farmer_margin` is
+    // escrowed in the auction's ATA and must cover the farmer's side at whatever price wins; the deal
+    // finalize_auction opens runs to `deadline_ts`
+    #[allow(clippy::too_many_arguments)]
+    pub fn list_harvest_auction(
+        ctx: Context<ListHarvestAuction>,
+        auction_id: u64,
+        quantity_kg: u64,
+        physical_delivery: bool,
+        reserve_price_per_kg: u64,
+        min_increment_per_kg: u64,
+        ends_ts: i64,
+        deadline_ts: i64,
+        farmer_margin: u64,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        require!(!market.paused, CoffeeError::MarketPaused);
+        require!(market.close_after_ts == 0, CoffeeError::MarketClosing);
+        require!(market.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated);
+        require!(!market.sample_required, CoffeeError::SampleApprovalRequired);
+        require!(!market.omnibus_vaults, CoffeeError::OmnibusUnsupported);
+        require!(reserve_price_per_kg > 0, CoffeeError::ZeroPrice);
+        require!(
+            market.is_on_tick(reserve_price_per_kg) && min_increment_per_kg > 0 && market.is_on_tick(min_increment_per_kg),
+            CoffeeError::PriceNotOnTick
+        );
+        require!(quantity_kg > 0, CoffeeError::ZeroQty);
+        require_gte!(market.max_qty_per_deal, quantity_kg, CoffeeError::DealQtyExceedsLimit);
+        require!(
+            !market.whole_contracts_only || market.contracts_for_qty(quantity_kg).is_some(),
+            CoffeeError::NotWholeContracts
+        );
+        require!(farmer_margin > 0, CoffeeError::ZeroAmount);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < ends_ts && ends_ts < deadline_ts, CoffeeError::InvalidAuctionSchedule);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.farmer_quote_ata.to_account_info(),
+                    to: ctx.accounts.auction_escrow.to_account_info(),
+                    authority: ctx.accounts.farmer.to_account_info(),
+                },
+            ),
+            farmer_margin,
+        )?;
+
+        ctx.accounts.auction.set_inner(HarvestAuction {
+            market: market.key(),
+            farmer: ctx.accounts.farmer.key(),
+            auction_id,
+            quantity_kg,
+            physical_delivery,
+            reserve_price_per_kg,
+            min_increment_per_kg,
+            ends_ts,
+            deadline_ts,
+            bump: ctx.bumps.auction,
+            ..HarvestAuction::default()
+        });
+        let auction = &ctx.accounts.auction;
+        emit_event!(ctx, HarvestAuctionListed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: auction.market,
+            auction: auction.key(),
+            farmer: auction.farmer,
+            quantity_kg,
+            physical_delivery,
+            reserve_price_per_kg,
+            ends_ts,
+            deadline_ts,
+            farmer_margin,
+        });
+        Ok(())
+    }
+
+    // Bid on a harvest auction (buyer). The bid must clear the reserve, or beat the best bid by the
+    // increment. The bid's escrow is topped up to the margin and premium share of a deal at
+    // `price_per_kg`, so raising a standing bid only pays the difference
+    pub fn place_auction_bid(ctx: Context<PlaceAuctionBid>, price_per_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        require!(!market.paused, CoffeeError::MarketPaused);
+        require!(market.is_on_tick(price_per_kg), CoffeeError::PriceNotOnTick);
+        let now = Clock::get()?.unix_timestamp;
+        let bidder = ctx.accounts.bidder.key();
+        let auction = &mut ctx.accounts.auction;
+        auction.accept_bid(bidder, price_per_kg, now)?;
+
+        // margin is sized as of the close, when the deal opens
+        let (margin, premium) = escrowed_open_cost(market, price_per_kg, auction.quantity_kg, market.initial_margin_bps_at(auction.ends_ts))?;
+        let from_farmer = premium / 2;
+        require_gte!(ctx.accounts.auction_escrow.amount, margin + from_farmer, CoffeeError::AuctionEscrowShort);
+        let need = margin + (premium - from_farmer);
+        let top_up = need.saturating_sub(ctx.accounts.bid_escrow.amount);
+        if top_up > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.bidder_quote_ata.to_account_info(),
+                        to: ctx.accounts.bid_escrow.to_account_info(),
+                        authority: ctx.accounts.bidder.to_account_info(),
+                    },
+                ),
+                top_up,
+            )?;
+        }
+
+        let bid = &mut ctx.accounts.bid;
+        bid.auction = auction.key();
+        bid.bidder = bidder;
+        bid.price_per_kg = price_per_kg;
+        bid.bump = ctx.bumps.bid;
+        emit_event!(ctx, AuctionBidPlaced {
+            schema_version: EVENT_SCHEMA_VERSION,
+            auction: bid.auction,
+            bidder,
+            price_per_kg,
+            escrowed: need.max(ctx.accounts.bid_escrow.amount),
+        });
+        Ok(())
+    }
+
+    // Refund an outbid bid, or any bid once the auction is finalized, and close it (anyone; the
+    // escrow goes back to the bidder)
+    pub fn withdraw_auction_bid(ctx: Context<WithdrawAuctionBid>) -> Result<()> {
+        let auction = &ctx.accounts.auction;
+        let bid = &ctx.accounts.bid;
+        require!(auction.finalized || auction.best_bidder != bid.bidder, CoffeeError::AuctionBidLeading);
+        let refund = ctx.accounts.bid_escrow.amount;
+        transfer_from_auction_bid(refund, bid, &ctx.accounts.bid_escrow, &ctx.accounts.bidder_quote_ata.to_account_info(), &ctx.accounts.token_program)?;
+        let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, AUCTION_BID_SEED, bid.auction.as_ref(), bid.bidder.as_ref(), &[bid.bump]]];
+        token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.bid_escrow.to_account_info(),
+                destination: ctx.accounts.bidder.to_account_info(),
+                authority: bid.to_account_info(),
+            },
+            seeds,
+        ))?;
+        emit_event!(ctx, AuctionBidRefunded {
+            schema_version: EVENT_SCHEMA_VERSION,
+            auction: bid.auction,
+            bidder: bid.bidder,
+            refund,
+        });
+        Ok(())
+    }
+
+    // Open the deal for a harvest auction after `ends_ts` (anyone; the crank pays the deal's rent): the
+    // farmer against the best bidder at the clearing price, both margins and premium shares paid from
+    // their escrows, whatever is left over refunded. `deal_id` is market.deal_seq + 1. Outbid bidders
+    // reclaim their escrow with withdraw_auction_bid
+    pub fn finalize_auction(ctx: Context<FinalizeAuction>, deal_id: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        let market = &ctx.accounts.market;
+        let auction = &ctx.accounts.auction;
+        require!(!market.paused, CoffeeError::MarketPaused);
+        require!(auction_can_open(market), CoffeeError::MarketClosing);
+        require!(!auction.finalized, CoffeeError::AuctionClosed);
+        require!(now >= auction.ends_ts, CoffeeError::AuctionStillOpen);
+        require!(auction.best_bidder != Pubkey::default(), CoffeeError::AuctionUnsold);
+        require_eq!(deal_id, market.deal_seq.checked_add(1).ok_or(CoffeeError::MathOverflow)?, CoffeeError::StaleDealId);
+        let (farmer, buyer) = (auction.farmer, auction.best_bidder);
+        let (price, quantity_kg) = (auction.best_price_per_kg, auction.quantity_kg);
+        let notional = market.notional(price, quantity_kg)?;
+        require_gte!(market.max_notional_per_deal as u128, notional, CoffeeError::DealNotionalExceedsLimit);
+        require!(!market.requires_risk_cosign(notional), CoffeeError::RiskOfficerSignatureRequired);
+        // sized as of the close, as the bids were
+        let (margin, premium) = escrowed_open_cost(market, price, quantity_kg, market.initial_margin_bps_at(auction.ends_ts))?;
+        let from_farmer = premium / 2;
+        let from_buyer = premium - from_farmer;
+        let bid_escrow = &ctx.accounts.bid_escrow;
+        require_gte!(ctx.accounts.auction_escrow.amount, margin + from_farmer, CoffeeError::AuctionEscrowShort);
+        require_gte!(bid_escrow.amount, margin + from_buyer, CoffeeError::AuctionEscrowShort);
+
+        let token_program = &ctx.accounts.token_program;
+        let bid = &ctx.accounts.winning_bid;
+        let vault = ctx.accounts.vault.to_account_info();
+        transfer_from_auction(margin, auction, &ctx.accounts.auction_escrow, &vault, token_program)?;
+        transfer_from_auction_bid(margin, bid, bid_escrow, &vault, token_program)?;
+        if premium > 0 {
+            let insurance = ctx.accounts.insurance_treasury.as_ref().ok_or(CoffeeError::InsuranceTreasuryMissing)?.to_account_info();
+            transfer_from_auction(from_farmer, auction, &ctx.accounts.auction_escrow, &insurance, token_program)?;
+            transfer_from_auction_bid(from_buyer, bid, bid_escrow, &insurance, token_program)?;
+        }
+        let buyer_refund = bid_escrow.amount - margin - from_buyer;
+        transfer_from_auction_bid(buyer_refund, bid, bid_escrow, &ctx.accounts.buyer_quote_ata.to_account_info(), token_program)?;
+        ctx.accounts.auction_escrow.reload()?;
+        let farmer_refund = close_auction_escrow(auction, &ctx.accounts.auction_escrow, &ctx.accounts.farmer_quote_ata, &ctx.accounts.farmer, token_program)?;
+
+        let mut deal = escrowed_deal(market, farmer, buyer, deal_id, price, quantity_kg, margin, premium, auction.deadline_ts)?;
+        deal.physical_delivery = auction.physical_delivery;
+        ctx.accounts.vault_auth.bump = ctx.bumps.vault_auth;
+        ctx.accounts.market.record_escrowed_open(&deal)?;
+        ctx.accounts.deal.set_inner(deal);
+        let deal_key = ctx.accounts.deal.key();
+        let auction = &mut ctx.accounts.auction;
+        auction.finalized = true;
+        auction.deal = deal_key;
+
+        emit_event!(ctx, DealOpened {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id,
+            market: ctx.accounts.market.key(),
+            farmer,
+            buyer,
+            agreed_price_per_kg: price,
+            quantity_kg,
+            farmer_side: OrderSide::Sell as u8,
+        });
+        if premium > 0 {
+            emit_event!(ctx, InsurancePremiumCollected { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id, premium });
+        }
+        emit_event!(ctx, HarvestAuctionFinalized {
+            schema_version: EVENT_SCHEMA_VERSION,
+            auction: ctx.accounts.auction.key(),
+            deal: deal_key,
+            buyer,
+            clearing_price_per_kg: price,
+            quantity_kg,
+            bids: ctx.accounts.auction.bids,
+            farmer_refund,
+        });
+        Ok(())
+    }
+
+    // Close a harvest auction that can't open a deal and refund the farmer (anyone): no bids by
+    // `ends_ts`, the market stopped opening deals, or the best bid no longer fits the market's terms
+    // (pass the winning bid and its escrow). Bidders reclaim their escrow with withdraw_auction_bid
+    pub fn close_unsold_auction(ctx: Context<CloseUnsoldAuction>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let auction = &ctx.accounts.auction;
+        require!(!auction.finalized, CoffeeError::AuctionClosed);
+        let unsold = now >= auction.ends_ts && auction.best_bidder == Pubkey::default();
+        // margin or premium raised since the bids, or the deal limits lowered: finalize_auction would fail
+        let stranded = match (now >= auction.ends_ts && !unsold, ctx.accounts.bid_escrow.as_ref()) {
+            (true, Some(bid_escrow)) => {
+                !auction_winner_fits(&ctx.accounts.market, auction, ctx.accounts.auction_escrow.amount, bid_escrow.amount)?
+            }
+            _ => false,
+        };
+        require!(unsold || stranded || !auction_can_open(&ctx.accounts.market), CoffeeError::AuctionHasWinner);
+        let farmer_refund = close_auction_escrow(
+            auction,
+            &ctx.accounts.auction_escrow,
+            &ctx.accounts.farmer_quote_ata,
+            &ctx.accounts.farmer,
+            &ctx.accounts.token_program,
+        )?;
+        let auction = &mut ctx.accounts.auction;
+        auction.finalized = true;
+        emit_event!(ctx, HarvestAuctionFinalized {
+            schema_version: EVENT_SCHEMA_VERSION,
+            auction: auction.key(),
+            deal: Pubkey::default(),
+            buyer: Pubkey::default(),
+            clearing_price_per_kg: 0,
+            quantity_kg: auction.quantity_kg,
+            bids: auction.bids,
+            farmer_refund,
+        });
+        Ok(())
+    }
+
+    // Arm a one-shot price alert (anyone): fires once the oracle price reaches `threshold_price_per_kg`
+    // in `direction`. `payload` is opaque to the program and echoed in TriggerFired, e.g. the deal or
+    // order a stop-loss bot should act on
+    pub fn create_price_trigger(
+        ctx: Context<CreatePriceTrigger>,
+        trigger_id: u64,
+        direction: u8,
+        threshold_price_per_kg: u64,
+        payload: Pubkey,
+    ) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
+        TriggerDirection::from_u8(direction)?;
+        require!(threshold_price_per_kg > 0, CoffeeError::ZeroPrice);
+        let trigger = &mut ctx.accounts.trigger;
+        trigger.market = ctx.accounts.market.key();
+        trigger.owner = ctx.accounts.owner.key();
+        trigger.trigger_id = trigger_id;
+        trigger.direction = direction;
+        trigger.threshold_price_per_kg = threshold_price_per_kg;
+        trigger.payload = payload;
+        trigger.created_ts = Clock::get()?.unix_timestamp;
+        trigger.bump = ctx.bumps.trigger;
+        emit_event!(ctx, PriceTriggerCreated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: trigger.market,
+            trigger: trigger.key(),
+            owner: trigger.owner,
+            direction,
+            threshold_price_per_kg,
+            payload,
+        });
+        Ok(())
+    }
+
+    // Fire a price trigger whose condition holds at the current oracle price (anyone). Only a fresh
+    // oracle update pushed after the trigger was armed counts; the trigger closes back to its owner
+    pub fn fire_trigger(ctx: Context<FireTrigger>) -> Result<()> {
         let market = &ctx.accounts.market;
-        // only market authority can invoke
-        require!(ctx.accounts.authority.key() == market.authority, CoffeeError::Unauthorized);
+        let trigger = &ctx.accounts.trigger;
+        let now = Clock::get()?.unix_timestamp;
+        require!(market.oracle_is_fresh(now), CoffeeError::OracleStale);
+        require!(market.last_oracle_update_ts >= trigger.created_ts, CoffeeError::OracleStale);
+        let price = market.last_price_per_kg;
+        require!(trigger.is_met(price), CoffeeError::TriggerNotMet);
+        emit_event!(ctx, TriggerFired {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: trigger.market,
+            trigger: trigger.key(),
+            owner: trigger.owner,
+            direction: trigger.direction,
+            threshold_price_per_kg: trigger.threshold_price_per_kg,
+            price_per_kg: price,
+            oracle_ts: market.last_oracle_update_ts,
+            payload: trigger.payload,
+        });
+        Ok(())
+    }
+
+    // Disarm a price trigger (owner)
+    pub fn cancel_price_trigger(_ctx: Context<CancelPriceTrigger>) -> Result<()> {
+        Ok(())
+    }
+
+    // Top up margin by either side
+    pub fn top_up_margin(ctx: Context<TopUpMargin>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+
+        let who = ctx.accounts.who.key();
+        let deal = &ctx.accounts.deal;
+        let side = deal.side_of(&who).ok_or(CoffeeError::InvalidCounterparty)?;
+        check_margin_vaults(&ctx.accounts.market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+
+        if side == math::Side::Short {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.from_ata.to_account_info(),
+                        to: ctx.accounts.farmer_margin_vault.to_account_info(),
+                        authority: ctx.accounts.who.to_account_info(),
+                    },
+                ),
+                amount,
+            )?;
+        } else {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.from_ata.to_account_info(),
+                        to: ctx.accounts.buyer_margin_vault.to_account_info(),
+                        authority: ctx.accounts.who.to_account_info(),
+                    },
+                ),
+                amount,
+            )?;
+        }
+
+        emit_event!(ctx, MarginToppedUp {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            who,
+            amount,
+        });
+
+        let deal = &mut ctx.accounts.deal;
+        deal.margin_locked = deal.margin_locked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        if deal.omnibus {
+            deal.credit_omnibus(side, amount)?;
+        }
+        deal.credit_farmer_own(side, amount)?;
+        let market = &mut ctx.accounts.market;
+        market.total_margin_locked = market.total_margin_locked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        record_deal_action(&ctx.accounts.deal, ctx.accounts.action_log.as_deref_mut(), DealAction::TopUp, who, amount)?;
+
+        ctx.accounts.farmer_margin_vault.reload()?;
+        ctx.accounts.buyer_margin_vault.reload()?;
+        let (farmer_vault, buyer_vault) = (ctx.accounts.farmer_margin_vault.amount, ctx.accounts.buyer_margin_vault.amount);
+        if let Some(update) = refresh_health(&ctx.accounts.market, &mut ctx.accounts.deal, farmer_vault, buyer_vault)? {
+            emit_event!(ctx, update);
+        }
+        Ok(())
+    }
+
+    // margin_call: sets a margin call timestamp and grace period; liquidation only after grace expires
+    pub fn margin_call(ctx: Context<MarginCall>, grace_sec: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        // market authority, a delegated LIQUIDATOR, or a keeper session key of either
+        require_role_or_session(
+            &ctx.accounts.market,
+            ctx.accounts.access_control.as_ref(),
+            &ctx.accounts.authority.key(),
+            ctx.accounts.session.as_ref(),
+            SessionScope::MarginCall,
+            ctx.remaining_accounts,
+        )?;
 
         let deal = &mut ctx.accounts.deal;
         require!(!deal.settled, CoffeeError::DealAlreadySettled);
@@ -336,11 +2066,14 @@ pub mod coffee_futures {
         deal.margin_call_ts = now;
         deal.margin_call_grace_sec = grace_sec;
 
-        emit!(MarginCalled {
+        emit_event!(ctx, MarginCalled {
+            schema_version: EVENT_SCHEMA_VERSION,
             deal: deal.key(),
+            deal_id: deal.deal_id,
             ts: now,
             grace_sec,
         });
+        record_deal_action(deal, ctx.accounts.action_log.as_deref_mut(), DealAction::MarginCall, ctx.accounts.authority.key(), grace_sec)?;
         Ok(())
     }
 
@@ -350,224 +2083,243 @@ pub mod coffee_futures {
         let market = &ctx.accounts.market;
         let deal = &mut ctx.accounts.deal;
         require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        check_margin_vaults(market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
 
         // choose price by mode
-        let price = match market.price_mode {
-            0 => market.last_price_per_kg,
-            1 => {
-                require!(market.twap_time_acc > 0, CoffeeError::ZeroPrice);
-                (market.twap_acc / (market.twap_time_acc as u128)) as u64
+        let price = mark_price(market)?;
+
+        // a dead feed neither calls nor liquidates: an open margin call is given at least the default
+        // grace from now, and the checks resume once a fresh price arrives
+        let now = Clock::get()?.unix_timestamp;
+        if !market.oracle_is_fresh(now) {
+            if let Some(grace_sec) = deal.extend_margin_call_grace(now, market.default_margin_call_grace_sec) {
+                emit_event!(ctx, MarginCallGraceExtended {
+                    schema_version: EVENT_SCHEMA_VERSION,
+                    deal: deal.key(),
+                    deal_id: deal.deal_id,
+                    grace_sec,
+                    last_oracle_update_ts: market.last_oracle_update_ts,
+                });
             }
-            _ => market.last_price_per_kg,
-        };
-        require!(price > 0, CoffeeError::ZeroPrice);
+            return Ok(());
+        }
 
-        let notional_now = (price as u128)
-            .checked_mul(deal.quantity_kg as u128)
-            .ok_or(CoffeeError::MathOverflow)?;
-        let maint = bps_mul_u128(notional_now, market.maintenance_margin_bps)? as u64;
+        let notional_now = market.notional(price, deal.quantity_kg)?;
+        // maintenance follows the time-to-expiry margin schedule, raised per side by floors agreed at open
+        let maint_bps = market.maintenance_margin_bps_at(now);
+        let farmer_maint_bps = deal.margin_override.maintenance_bps(math::Side::Short, maint_bps);
+        let buyer_maint_bps = deal.margin_override.maintenance_bps(math::Side::Long, maint_bps);
+        let farmer_maint = bps_mul_u128(notional_now, farmer_maint_bps)? as u64;
+        let buyer_maint = bps_mul_u128(notional_now, buyer_maint_bps)? as u64;
+
+        // omnibus deals are measured on their ledger, not the shared market vaults
+        let (farmer_held, buyer_held) =
+            deal.margin_balances(ctx.accounts.farmer_margin_vault.amount, ctx.accounts.buyer_margin_vault.amount);
 
-        let farmer_ok = ctx.accounts.farmer_margin_vault.amount >= maint;
-        let buyer_ok = ctx.accounts.buyer_margin_vault.amount >= maint;
+        // each side holds its `bps` of notional; cross-margined sides are checked on their whole
+        // MarginAccount instead of the deal vault
+        let sides_hold = |farmer_bps: u16, buyer_bps: u16| -> Result<bool> {
+            let farmer_ok = if deal.farmer_margin_account != Pubkey::default() {
+                cross_margin_ok(
+                    deal.farmer_margin_account,
+                    ctx.accounts.farmer_margin_account.as_ref(),
+                    ctx.accounts.farmer_margin_pool.as_ref(),
+                    price,
+                    market.price_exponent,
+                    farmer_bps,
+                )?
+            } else {
+                farmer_held >= bps_mul_u128(notional_now, farmer_bps)? as u64
+            };
+            let buyer_ok = if deal.buyer_margin_account != Pubkey::default() {
+                cross_margin_ok(
+                    deal.buyer_margin_account,
+                    ctx.accounts.buyer_margin_account.as_ref(),
+                    ctx.accounts.buyer_margin_pool.as_ref(),
+                    price,
+                    market.price_exponent,
+                    buyer_bps,
+                )?
+            } else {
+                deal.buyer_collateral(buyer_held) >= bps_mul_u128(notional_now, buyer_bps)? as u64
+            };
+            Ok(farmer_ok && buyer_ok)
+        };
+        let maint_ok = sides_hold(farmer_maint_bps, buyer_maint_bps)?;
+        // hysteresis: calls trigger below maintenance but an open one only clears above the cure level,
+        // so a mark hovering at the boundary doesn't re-call (and re-grace) the deal every check
+        let cured = match (market.margin_cure_bps(farmer_maint_bps), market.margin_cure_bps(buyer_maint_bps)) {
+            (Some(farmer_cure_bps), Some(buyer_cure_bps)) if maint_ok && deal.margin_call_ts != 0 && !deal.liquidated => {
+                sides_hold(farmer_cure_bps, buyer_cure_bps)?
+            }
+            _ => false,
+        };
 
-        if !(farmer_ok && buyer_ok) {
+        if cured {
+            deal.margin_call_ts = 0;
+            deal.margin_call_grace_sec = 0;
+            emit_event!(ctx, MarginCallCured { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, ts: now });
+        } else if !maint_ok {
             // check margin call grace
             if deal.margin_call_ts == 0 {
                 // set margin call automatically with default grace
                 deal.margin_call_ts = Clock::get()?.unix_timestamp;
                 deal.margin_call_grace_sec = market.default_margin_call_grace_sec;
-                emit!(MarginCalled { deal: deal.key(), ts: deal.margin_call_ts, grace_sec: deal.margin_call_grace_sec });
+                emit_event!(ctx, MarginCalled { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, ts: deal.margin_call_ts, grace_sec: deal.margin_call_grace_sec });
             } else {
                 let now = Clock::get()?.unix_timestamp;
                 let grace_end = deal.margin_call_ts.checked_add(deal.margin_call_grace_sec as i64).ok_or(CoffeeError::MathOverflow)?;
                 if now >= grace_end {
                     deal.liquidated = true;
-                    emit!(LiquidationFlagged { deal: deal.key(), ts: now });
+                    emit_event!(ctx, LiquidationFlagged { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, ts: now });
+
+                    // queue by the larger vault shortfall against maintenance (cross-margined sides count 0)
+                    if let Some(queue) = ctx.accounts.liquidation_queue.as_mut() {
+                        let vault_shortfall = |margin_account: Pubkey, collateral: u64, maint: u64| {
+                            if margin_account == Pubkey::default() { maint.saturating_sub(collateral) } else { 0 }
+                        };
+                        let buyer_collateral = deal.buyer_collateral(buyer_held);
+                        let shortfall = vault_shortfall(deal.farmer_margin_account, farmer_held, farmer_maint)
+                            .max(vault_shortfall(deal.buyer_margin_account, buyer_collateral, buyer_maint));
+                        if queue.upsert(deal.key(), shortfall, now) {
+                            emit_event!(ctx, LiquidationQueued {
+                                schema_version: EVENT_SCHEMA_VERSION,
+                                deal: deal.key(),
+                                deal_id: deal.deal_id,
+                                shortfall,
+                                queue_len: queue.len,
+                            });
+                        }
+                    }
                 }
             }
         }
+
+        let (farmer_vault, buyer_vault) = (ctx.accounts.farmer_margin_vault.amount, ctx.accounts.buyer_margin_vault.amount);
+        if let Some(update) = refresh_health(market, deal, farmer_vault, buyer_vault)? {
+            emit_event!(ctx, update);
+        }
         Ok(())
     }
 
     // Cash settlement at/after expiry using market price or TWAP; supports fallback and insurance payouts
     pub fn settle_cash(ctx: Context<SettleCash>) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
-        let market = &ctx.accounts.market;
-        let deal_key = ctx.accounts.deal.key();
-        let deal = &mut ctx.accounts.deal;
-
-        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        check_settlement_order(&ctx.accounts.market, &ctx.accounts.deal)?;
+        require!(
+            !ctx.accounts.market.settlement_window_missed(&ctx.accounts.deal, Clock::get()?.unix_timestamp),
+            CoffeeError::SettlementWindowMissed
+        );
+        let strategy = SettlementStrategy::cash_for(&ctx.accounts.market, &ctx.accounts.deal);
+        settlement::settle_cash_deal(ctx, strategy, u8::MAX)
+    }
 
-        // allow settlement if market settled time reached OR if post-deadline auto cash fallback
-        let now = Clock::get()?.unix_timestamp;
-        require!(now >= market.settlement_ts || now >= deal.deadline_ts, CoffeeError::NotYetSettleTime);
+    // settle_cash in at most `max_steps` legs (fees, PnL, residuals) per transaction; call again to
+    // resume. settle_cash finishes a deal left part-way
+    pub fn settle_cash_step(ctx: Context<SettleCash>, max_steps: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        check_settlement_order(&ctx.accounts.market, &ctx.accounts.deal)?;
+        require!(
+            !ctx.accounts.market.settlement_window_missed(&ctx.accounts.deal, Clock::get()?.unix_timestamp),
+            CoffeeError::SettlementWindowMissed
+        );
+        let strategy = SettlementStrategy::cash_for(&ctx.accounts.market, &ctx.accounts.deal);
+        settlement::settle_cash_deal(ctx, strategy, max_steps)
+    }
 
-        // Reentrancy guard
-        deal.start_settling();
+    // Net-settle every due deal between one farmer and buyer in one pass: PnL offsets between the deals
+    // and the pooled vaults pay each leg once (anyone). Deals come in remaining_accounts
+    pub fn net_settle_pair<'info>(ctx: Context<'_, '_, 'info, 'info, NetSettlePair<'info>>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(!ctx.accounts.market.ordered_settlement, CoffeeError::SettlementQueueRequired);
+        settlement::net_settle_pair(ctx)
+    }
 
-        // choose settlement price
-        let price = match market.price_mode {
-            0 => market.last_price_per_kg,
-            1 => {
-                require!(market.twap_time_acc > 0, CoffeeError::ZeroPrice);
-                (market.twap_acc / (market.twap_time_acc as u128)) as u64
-            }
-            _ => market.last_price_per_kg,
-        };
-        require!(price > 0, CoffeeError::ZeroPrice);
+    // settle_cash, then burn whatever quote the farmer received through CCTP to the deal's payout
+    // domain/address. The farmer signs: the burn comes out of their receive account
+    pub fn settle_and_bridge(ctx: Context<SettleAndBridge>) -> Result<()> {
+        version_guard_market(&ctx.accounts.settle.market)?;
+        let market = &ctx.accounts.settle.market;
+        require!(!market.sandbox, CoffeeError::SandboxUnsupported);
+        require!(market.cctp_program != Pubkey::default(), CoffeeError::CctpNotConfigured);
+        require!(ctx.accounts.settle.deal.payout_recipient != [0u8; 32], CoffeeError::NoPayoutBridge);
+        check_settlement_order(market, &ctx.accounts.settle.deal)?;
+        require!(
+            !market.settlement_window_missed(&ctx.accounts.settle.deal, Clock::get()?.unix_timestamp),
+            CoffeeError::SettlementWindowMissed
+        );
+        // the payout is measured across this call, so earlier checkpointed steps would be missed
+        require!(
+            ctx.accounts.settle.deal.settlement_step == settlement::SettlementStep::NotStarted as u8,
+            CoffeeError::SettlementInProgress
+        );
+        let strategy = SettlementStrategy::cash_for(market, &ctx.accounts.settle.deal);
 
-        // PnL calc for buyer (long)
-        let pnl_long = signed_mul_diff(
-            deal.agreed_price_per_kg,
-            price,
-            deal.quantity_kg,
-            SignRole::Long,
-        ).ok_or(CoffeeError::MathOverflow)?;
+        let before = ctx.accounts.settle.farmer_receive.amount;
+        settlement::settle_cash_deal(
+            Context::new(ctx.program_id, &mut ctx.accounts.settle, ctx.remaining_accounts, ctx.bumps.settle),
+            strategy,
+            u8::MAX,
+        )?;
+        ctx.accounts.settle.farmer_receive.reload()?;
+        let payout = ctx.accounts.settle.farmer_receive.amount.saturating_sub(before);
+        if payout == 0 {
+            return Ok(());
+        }
 
-        // fee on notional
-        let notional = (deal.agreed_price_per_kg as u128)
-            .checked_mul(deal.quantity_kg as u128)
-            .ok_or(CoffeeError::MathOverflow)?;
-        let fee_total = bps_mul_u128(notional, market.fee_bps)? as u64;
-
-        // split fee into farmer/buyer tiers
-        let farmer_cut = bps_of_u64(fee_total, market.farmer_fee_bps)?;
-        let buyer_cut = bps_of_u64(fee_total, market.buyer_fee_bps)?;
-        // insurance slice
-        let insurance_cut = bps_of_u64(fee_total, market.insurance_bps)?;
-        let protocol_cut = fee_total
-            .checked_sub(farmer_cut).and_then(|v| v.checked_sub(buyer_cut)).and_then(|v| v.checked_sub(insurance_cut))
-            .ok_or(CoffeeError::MathOverflow)?;
+        cctp::deposit_for_burn(&ctx.accounts, payout)?;
+        let deal = &ctx.accounts.settle.deal;
+        emit_event!(ctx.accounts, ctx.bumps, PayoutBridged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            farmer: deal.farmer,
+            amount: payout,
+            domain: deal.payout_domain,
+            recipient: deal.payout_recipient,
+        });
+        Ok(())
+    }
 
-        // collect fees (capped). For brevity we try to move protocol_cut from farmer vault; adapt if needed.
-        let farmer_fee = farmer_cut.min(ctx.accounts.farmer_margin_vault.amount);
-        let buyer_fee = buyer_cut.min(ctx.accounts.buyer_margin_vault.amount);
+    // Cash-settle `reduce_kg` of a live cash deal at the mark before expiry; the rest stays open.
+    // Both parties sign, or the initiator alone pays market.reduce_break_fee_bps to the other side.
+    pub fn reduce_deal(ctx: Context<ReduceDeal>, reduce_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        settlement::reduce_cash_deal(ctx, reduce_kg)
+    }
 
-        // protocol + farmer + buyer fees -> fee_treasury (naive routing demo)
-        let proto_plus_farmer = farmer_fee.saturating_add(protocol_cut);
-        if proto_plus_farmer > 0 {
-            transfer_from_vault_to(
-                proto_plus_farmer.min(ctx.accounts.farmer_margin_vault.amount),
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.farmer_margin_vault,
-                &ctx.accounts.fee_treasury,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-        }
-        if buyer_fee > 0 {
-            transfer_from_vault_to(
-                buyer_fee.min(ctx.accounts.buyer_margin_vault.amount),
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.buyer_margin_vault,
-                &ctx.accounts.fee_treasury,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-        }
-        // insurance from buyer vault first, then farmer
-        let insurance_from_buyer = insurance_cut.min(ctx.accounts.buyer_margin_vault.amount);
-        if insurance_from_buyer > 0 {
-            transfer_from_vault_to(
-                insurance_from_buyer,
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.buyer_margin_vault,
-                &ctx.accounts.insurance_treasury,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-        }
-        let remaining_insurance = insurance_cut.saturating_sub(insurance_from_buyer);
-        if remaining_insurance > 0 {
-            transfer_from_vault_to(
-                remaining_insurance.min(ctx.accounts.farmer_margin_vault.amount),
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.farmer_margin_vault,
-                &ctx.accounts.insurance_treasury,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-        }
-
-        // compute PnL settlement (pay winner from loser vault; use insurance shortfall if any)
-        if pnl_long > 0 {
-            // buyer wins
-            let pnl = pnl_long as u64;
-            let pay = pnl.min(ctx.accounts.farmer_margin_vault.amount);
-            transfer_from_vault_to(
-                pay,
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.farmer_margin_vault,
-                &ctx.accounts.buyer_receive,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-            if pay < pnl {
-                let shortfall = pnl - pay;
-                // draw from insurance treasury directly (requires correct authority model in production)
-                let draw = shortfall.min(ctx.accounts.insurance_treasury.amount);
-                if draw > 0 {
-                    // WARNING: placeholder safeguard
-                    return err!(CoffeeError::Unauthorized);
-                }
-            }
-        } else if pnl_long < 0 {
-            // farmer wins
-            let pnl = (-pnl_long) as u64;
-            let pay = pnl.min(ctx.accounts.buyer_margin_vault.amount);
-            transfer_from_vault_to(
-                pay,
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.buyer_margin_vault,
-                &ctx.accounts.farmer_receive,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-            if pay < pnl {
-                let shortfall = pnl - pay;
-                let draw = shortfall.min(ctx.accounts.insurance_treasury.amount);
-                if draw > 0 {
-                    return err!(CoffeeError::Unauthorized);
-                }
-            }
-        }
-
-        // return residuals (respect min_transfer_amount to avoid dust)
-        let min_transfer = market.min_transfer_amount;
-        if ctx.accounts.farmer_margin_vault.amount > min_transfer {
-            let amt = ctx.accounts.farmer_margin_vault.amount;
-            transfer_from_vault_to(
-                amt,
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.farmer_margin_vault,
-                &ctx.accounts.farmer_receive,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-        }
-        if ctx.accounts.buyer_margin_vault.amount > min_transfer {
-            let amt = ctx.accounts.buyer_margin_vault.amount;
-            transfer_from_vault_to(
-                amt,
-                &ctx.accounts.vault_auth,
-                &ctx.accounts.buyer_margin_vault,
-                &ctx.accounts.buyer_receive,
-                &ctx.accounts.token_program,
-                &deal_key,
-            )?;
-        }
-
-        deal.mark_settled();
-
-        emit!(SettledCash {
+    // Pair the deal with an FX forward: the farmer sells `notional` quote at `locked_rate` local currency
+    // per quote unit, the buyer takes the other side. Both sign; once per deal
+    pub fn attach_fx_hedge(ctx: Context<AttachFxHedge>, notional: u64, locked_rate: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(notional > 0, CoffeeError::ZeroAmount);
+        require!(locked_rate > 0, CoffeeError::ZeroPrice);
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling && !deal.voided, CoffeeError::DealAlreadySettled);
+        require!(deal.fx_oracle == Pubkey::default(), CoffeeError::FxHedgeAlreadyAttached);
+        deal.fx_oracle = ctx.accounts.fx_oracle.key();
+        deal.fx_notional = notional;
+        deal.fx_locked_rate = locked_rate;
+        deal.fx_settled = false;
+        emit_event!(ctx, FxHedgeAttached {
+            schema_version: EVENT_SCHEMA_VERSION,
             deal: deal.key(),
-            market: market.key(),
-            price,
+            deal_id: deal.deal_id,
+            fx_oracle: deal.fx_oracle,
+            currency: ctx.accounts.fx_oracle.currency,
+            notional,
+            locked_rate,
         });
-
         Ok(())
     }
 
+    // Fix the FX forward at the oracle rate and pay it between the margin vaults (permissionless);
+    // must run before the deal settles
+    pub fn settle_fx_hedge(ctx: Context<SettleFxHedge>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        settlement::settle_fx_leg(ctx)
+    }
+
     // Verify physical delivery, support partial deliveries, merkle proof, minting or basket transfers
     pub fn verify_and_settle_physical(
         ctx: Context<VerifyAndSettlePhysical>,
@@ -575,110 +2327,47 @@ pub mod coffee_futures {
         proof_hashes: Vec<[u8; 32]>, // capped by MAX_PROOF_HASHES
         leaf: Option<[u8; 32]>,
     ) -> Result<()> {
-        version_guard_market(&ctx.accounts.market)?;
-        let market = &ctx.accounts.market;
-        require!(!market.paused, CoffeeError::MarketPaused);
+        let lots: Vec<MerkleLot> = leaf.map(|leaf| vec![MerkleLot { leaf, proof: proof_hashes }]).unwrap_or_default();
+        settle_physical_delivery(ctx, delivered_kg, &lots)
+    }
 
-        // cap proofs
-        require!(proof_hashes.len() <= MAX_PROOF_HASHES, CoffeeError::ProofTooLarge);
+    // Same as verify_and_settle_physical for several lots at once: every (leaf, proof) must verify
+    // against the deal's root; bounded by MAX_BATCH_LOTS and MAX_BATCH_PROOF_HASHES for compute
+    pub fn verify_and_settle_physical_batch(
+        ctx: Context<VerifyAndSettlePhysical>,
+        delivered_kg: u64,
+        lots: Vec<MerkleLot>,
+    ) -> Result<()> {
+        settle_physical_delivery(ctx, delivered_kg, &lots)
+    }
 
-        let deal_key = ctx.accounts.deal.key();
-        let deal = &mut ctx.accounts.deal;
+    // Verify one lot's proof on its own and record it in a DeliveryRecord, so a payout carrying mints
+    // and transfers doesn't also pay for a long proof; the record stands in for that lot's proof once
+    pub fn verify_delivery_proof(ctx: Context<VerifyDeliveryProof>, leaf: [u8; 32], proof: Vec<[u8; 32]>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_is_verifier(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.verifier, ctx.remaining_accounts)?;
+        let deal = &ctx.accounts.deal;
         require!(!deal.settled, CoffeeError::DealAlreadySettled);
-        require!(delivered_kg > 0, CoffeeError::ZeroQty);
-
-        // ensure verifier
-        assert_is_verifier(&market, &ctx.accounts.verifier)?;
+        require!(deal.merkle_root != EMPTY_MERKLE_ROOT, CoffeeError::NoDeliveryManifest);
+        require!(proof.len() <= MAX_PROOF_HASHES, CoffeeError::ProofTooLarge);
+        require!(verify_merkle_proof(leaf, &proof, deal.merkle_root)?, CoffeeError::MerkleProofInvalid);
 
-        // verify merkle if used
-        if deal.merkle_root != EMPTY_MERKLE_ROOT {
-            let leaf_val = leaf.ok_or(CoffeeError::MerkleProofMissing)?;
-            let ok = verify_merkle_proof(leaf_val, &proof_hashes, deal.merkle_root)?;
-            require!(ok, CoffeeError::MerkleProofInvalid);
-        }
-
-        // partial delivery logic
-        let new_total = deal.delivered_kg_total.checked_add(delivered_kg).ok_or(CoffeeError::MathOverflow)?;
-        require!(new_total <= deal.quantity_kg, CoffeeError::OverDelivery);
-
-        // reentrancy guard
-        deal.start_settling();
-
-        // bind cft key before signer seeds
-        let cft_key = ctx.accounts.cft_mint.key();
-        let cft_bump = ctx.accounts.cft_mint_auth.bump;
-        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, b"cft_auth", cft_key.as_ref(), &[cft_bump]]];
-
-        // mint CFT if present in basket
-        for i in 0..(deal.asset_count as usize) {
-            if deal.assets[i] == market.cft_mint {
-                token::mint_to(
-                    CpiContext::new_with_signer(
-                        ctx.accounts.token_program.to_account_info(),
-                        MintTo {
-                            mint: ctx.accounts.cft_mint.to_account_info(),
-                            to: ctx.accounts.buyer_cft_ata.to_account_info(),
-                            authority: ctx.accounts.cft_mint_auth.to_account_info(),
-                        },
-                        signer_seeds,
-                    ),
-                    delivered_kg,
-                )?;
-                break;
-            }
-        }
-
-        // payout to farmer: agreed_price_per_kg * delivered_kg
-        let pay = (deal.agreed_price_per_kg as u128)
-            .checked_mul(delivered_kg as u128)
-            .ok_or(CoffeeError::MathOverflow)? as u64;
-        let pay_amt = pay.min(ctx.accounts.buyer_margin_vault.amount);
-        transfer_from_vault_to(
-            pay_amt,
-            &ctx.accounts.vault_auth,
-            &ctx.accounts.buyer_margin_vault,
-            &ctx.accounts.farmer_receive,
-            &ctx.accounts.token_program,
-            &deal_key,
-        )?;
-
-        // update delivered total
-        deal.delivered_kg_total = new_total;
-
-        // return residuals on completion; else leave funds until full delivery or deadline
-        if deal.delivered_kg_total == deal.quantity_kg {
-            if ctx.accounts.farmer_margin_vault.amount > market.min_transfer_amount {
-                let amt = ctx.accounts.farmer_margin_vault.amount;
-                transfer_from_vault_to(
-                    amt,
-                    &ctx.accounts.vault_auth,
-                    &ctx.accounts.farmer_margin_vault,
-                    &ctx.accounts.farmer_receive,
-                    &ctx.accounts.token_program,
-                    &deal_key,
-                )?;
-            }
-            if ctx.accounts.buyer_margin_vault.amount > market.min_transfer_amount {
-                let amt = ctx.accounts.buyer_margin_vault.amount;
-                transfer_from_vault_to(
-                    amt,
-                    &ctx.accounts.vault_auth,
-                    &ctx.accounts.buyer_margin_vault,
-                    &ctx.accounts.buyer_receive,
-                    &ctx.accounts.token_program,
-                    &deal_key,
-                )?;
-            }
-            deal.mark_settled();
-        }
-
-        emit!(SettledPhysical {
-            deal: deal.key(),
-            market: market.key(),
-            delivered_kg,
-            total_delivered: deal.delivered_kg_total,
+        let record = &mut ctx.accounts.delivery_record;
+        record.deal = deal.key();
+        record.leaf = leaf;
+        record.merkle_root_version = deal.merkle_root_version;
+        record.verifier = ctx.accounts.verifier.key();
+        record.verified_ts = Clock::get()?.unix_timestamp;
+        record.consumed = false;
+        record.bump = ctx.bumps.delivery_record;
+        emit_event!(ctx, DeliveryProofVerified {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: record.deal,
+            deal_id: deal.deal_id,
+            leaf,
+            delivery_record: record.key(),
+            merkle_root_version: record.merkle_root_version,
         });
-
         Ok(())
     }
 
@@ -688,6 +2377,7 @@ pub mod coffee_futures {
         let deal_key = ctx.accounts.deal.key();
         let deal = &mut ctx.accounts.deal;
         require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        check_margin_vaults(&ctx.accounts.market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
 
         // allow cancel if not both deposited OR before deadline
         if deal.farmer_deposited && deal.buyer_deposited {
@@ -721,18 +2411,23 @@ pub mod coffee_futures {
         }
 
         deal.mark_settled();
-        emit!(DealCanceled { deal: deal.key(), market: ctx.accounts.market.key() });
+        emit_event!(ctx, DealCanceled { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, market: ctx.accounts.market.key() });
+        // vault balances are as loaded, i.e. what was refunded
+        let refunded = ctx.accounts.farmer_margin_vault.amount.saturating_add(ctx.accounts.buyer_margin_vault.amount);
+        record_deal_action(deal, ctx.accounts.action_log.as_deref_mut(), DealAction::Cancel, Pubkey::default(), refunded)?;
+        ctx.accounts.market.release_open_interest(&ctx.accounts.deal);
         Ok(())
     }
 
     // rotate oracle publisher (propose + activate after timelock)
     pub fn propose_rotate_oracle(ctx: Context<RotateRole>, new_oracle: Pubkey, effective_after_ts: i64) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
         let market = &mut ctx.accounts.market;
-        require!(ctx.accounts.authority.key() == market.authority, CoffeeError::Unauthorized);
+        require!(market.governance == Pubkey::default(), CoffeeError::GovernanceControlled);
         market.pending_oracle = new_oracle;
         market.pending_oracle_effective_ts = effective_after_ts;
-        emit!(RoleRotationProposed { market: market.key(), role: b"oracle".to_vec(), pending: new_oracle, effective_ts: effective_after_ts });
+        emit_event!(ctx, RoleRotationProposed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role: b"oracle".to_vec(), pending: new_oracle, effective_ts: effective_after_ts });
         Ok(())
     }
 
@@ -745,833 +2440,15673 @@ pub mod coffee_futures {
         market.oracle_publisher = market.pending_oracle;
         market.pending_oracle = Pubkey::default();
         market.pending_oracle_effective_ts = 0;
-        emit!(RoleRotationActivated { market: market.key(), role: b"oracle".to_vec(), activated: market.oracle_publisher });
+        emit_event!(ctx, RoleRotationActivated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role: b"oracle".to_vec(), activated: market.oracle_publisher });
         Ok(())
     }
 
-    // Close deal (account closed to receiver) - only when settled
-    pub fn close_deal(ctx: Context<CloseDeal>) -> Result<()> {
+    // Replace the volume fee schedule (admin). Thresholds must be strictly ascending; empty clears it.
+    pub fn set_fee_tiers(ctx: Context<MarketAdmin>, min_notional: Vec<u64>, fee_bps: Vec<u16>) -> Result<()> {
         version_guard_market(&ctx.accounts.market)?;
-        require!(ctx.accounts.deal.settled, CoffeeError::DealNotSettled);
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(min_notional.len() == fee_bps.len(), CoffeeError::InvalidFeeTiers);
+        require!(min_notional.len() <= MAX_FEE_TIERS, CoffeeError::TooManyFeeTiers);
+        for i in 1..min_notional.len() {
+            require!(min_notional[i] > min_notional[i - 1], CoffeeError::InvalidFeeTiers);
+        }
+        require!(fee_bps.iter().all(|&bps| bps <= MAX_FEE_BPS), CoffeeError::FeeBpsTooHigh);
+
+        market.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        for i in 0..min_notional.len() {
+            market.fee_tiers[i] = FeeTier { min_notional: min_notional[i], fee_bps: fee_bps[i] };
+        }
+        market.fee_tier_count = min_notional.len() as u8;
+
+        emit_event!(ctx, FeeTiersUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), tier_count: market.fee_tier_count });
         Ok(())
     }
-}
-
-// ------------------------- Accounts & State -------------------------
 
-#[derive(Accounts)]
-#[instruction(decimals: u8)]
-pub struct InitCftMint<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    // Replace the time-to-expiry margin schedule (admin). Steps run from furthest to nearest expiry:
+    // secs_to_expiry strictly descending, margin non-decreasing and at most 100%. Empty clears it.
+    pub fn set_margin_schedule(ctx: Context<MarketAdmin>, secs_to_expiry: Vec<u64>, initial_margin_bps: Vec<u16>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(secs_to_expiry.len() == initial_margin_bps.len(), CoffeeError::InvalidMarginSchedule);
+        require!(secs_to_expiry.len() <= MAX_MARGIN_STEPS, CoffeeError::TooManyMarginSteps);
+        for i in 0..secs_to_expiry.len() {
+            require!(initial_margin_bps[i] <= MAX_BPS, CoffeeError::InvalidBps);
+            if i > 0 {
+                require!(
+                    secs_to_expiry[i] < secs_to_expiry[i - 1] && initial_margin_bps[i] >= initial_margin_bps[i - 1],
+                    CoffeeError::InvalidMarginSchedule
+                );
+            }
+        }
 
-    #[account(
-        init,
-        payer = payer,
-        mint::decimals = 3, // choose alignment with decimals param if desired
-        mint::authority = cft_mint_auth,
-        mint::freeze_authority = cft_mint_auth,
-    )]
-    pub cft_mint: Account<'info, Mint>,
+        market.margin_steps = [MarginStep::default(); MAX_MARGIN_STEPS];
+        for i in 0..secs_to_expiry.len() {
+            market.margin_steps[i] = MarginStep { secs_to_expiry: secs_to_expiry[i], initial_margin_bps: initial_margin_bps[i] };
+        }
+        market.margin_step_count = secs_to_expiry.len() as u8;
 
-    #[account(
-        init,
-        payer = payer,
-        space = 8 + CftMintAuth::SIZE,
-        seeds = [SEED_PREFIX, b"cft_auth", cft_mint.key().as_ref()],
-        bump
-    )]
-    pub cft_mint_auth: Account<'info, CftMintAuth>,
+        emit_event!(ctx, MarginScheduleUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), step_count: market.margin_step_count });
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    // Scale maintenance margin with realized volatility (admin): above vol_ref_bps per update it grows
+    // proportionally, up to max_maintenance_margin_bps. max = 0 turns scaling off.
+    pub fn set_vol_margin_scaling(ctx: Context<MarketAdmin>, vol_ref_bps: u16, max_maintenance_margin_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(
+            max_maintenance_margin_bps == 0
+                || (vol_ref_bps > 0
+                    && max_maintenance_margin_bps >= market.maintenance_margin_bps
+                    && max_maintenance_margin_bps <= market.initial_margin_bps),
+            CoffeeError::InvalidVolScaling
+        );
+        market.vol_ref_bps = vol_ref_bps;
+        market.max_maintenance_margin_bps = max_maintenance_margin_bps;
+        emit_event!(ctx, VolMarginScalingUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            vol_ref_bps,
+            max_maintenance_margin_bps,
+        });
+        Ok(())
+    }
 
-#[account]
-pub struct CftMintAuth {
-    pub bump: u8,
-}
-impl CftMintAuth {
-    pub const SIZE: usize = 1 + 8;
-}
+    // Margin-call hysteresis (admin): calls still trigger below maintenance, but an open call only
+    // clears once both sides hold maintenance + `buffer_bps` of notional. 0 = calls never auto-clear
+    pub fn set_margin_cure_buffer(ctx: Context<MarketAdmin>, buffer_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require_gte!(MAX_BPS, buffer_bps, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        market.margin_cure_buffer_bps = buffer_bps;
+        emit_event!(ctx, MarginCureBufferUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), buffer_bps });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct CreateMarket<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    // Require commit-reveal price publishing (admin), with reveals at least `min_delay_sec` after their
+    // commit. Turning it off leaves a pending commit revealable
+    pub fn set_commit_reveal(ctx: Context<MarketAdmin>, required: bool, min_delay_sec: u32) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.commit_reveal = required;
+        market.min_reveal_delay_sec = min_delay_sec;
+        emit_event!(ctx, CommitRevealUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), required, min_delay_sec });
+        Ok(())
+    }
 
-    /// CHECK: multisig or authority PDA ok
-    #[account(mut)]
-    pub verifier: UncheckedAccount<'info>,
+    // Omnibus vault mode (admin): deals opened while on hold margin in two market-level vaults (one
+    // per side) and track their share on the deal, instead of creating vaults of their own. Existing
+    // deals keep the mode they opened in. Not for perpetual markets: funding moves per-deal margin
+    pub fn set_omnibus_vaults(ctx: Context<SetOmnibusVaults>, enabled: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(ctx.accounts.market.market_kind != MarketKind::Perpetual as u8, CoffeeError::OmnibusUnsupported);
+        ctx.accounts.omnibus_auth.bump = ctx.bumps.omnibus_auth;
+        let market = &mut ctx.accounts.market;
+        market.omnibus_vaults = enabled;
+        market.omnibus_farmer_vault = ctx.accounts.omnibus_farmer_vault.key();
+        market.omnibus_buyer_vault = ctx.accounts.omnibus_buyer_vault.key();
+        emit_event!(ctx, OmnibusVaultsSet {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            enabled,
+            farmer_vault: market.omnibus_farmer_vault,
+            buyer_vault: market.omnibus_buyer_vault,
+        });
+        Ok(())
+    }
 
-    /// CHECK: multisig or oracle PDA ok
-    #[account(mut)]
-    pub oracle_publisher: UncheckedAccount<'info>,
+    // Omnibus ledger audit (anyone): sums the ledger balances of the deals in remaining_accounts and
+    // compares them with the omnibus vault balances. The outcome is reported in OmnibusAudited rather
+    // than enforced, so monitoring sees the discrepancy; `complete` is set when every unsettled
+    // omnibus deal of the market was counted, the only case a nonzero discrepancy is conclusive
+    pub fn audit_omnibus<'info>(ctx: Context<'_, '_, 'info, 'info, AuditOmnibus<'info>>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let mut seen: Vec<Pubkey> = Vec::with_capacity(ctx.remaining_accounts.len());
+        let mut ledgers: Vec<[u64; 2]> = Vec::with_capacity(ctx.remaining_accounts.len());
+        let mut open_deals = 0u32;
+        for info in ctx.remaining_accounts.iter() {
+            require!(!seen.contains(info.key), CoffeeError::OmnibusAuditDealInvalid);
+            let deal: Account<'info, Deal> = Account::try_from(info)?;
+            require!(deal.market == market.key() && deal.omnibus, CoffeeError::OmnibusAuditDealInvalid);
+            if !deal.settled {
+                open_deals += 1;
+            }
+            seen.push(deal.key());
+            ledgers.push(deal.omnibus_balance);
+        }
 
-    pub cft_mint: Account<'info, Mint>,
-    pub quote_mint: Account<'info, Mint>,
+        let vault = [ctx.accounts.omnibus_farmer_vault.amount, ctx.accounts.omnibus_buyer_vault.amount];
+        let (ledger, discrepancy) = omnibus_audit(&ledgers, vault)?;
+        emit_event!(ctx, OmnibusAudited {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            deals: seen.len() as u16,
+            complete: open_deals == market.omnibus_open_deals,
+            ledger,
+            vault,
+            discrepancy,
+            balanced: discrepancy == [0; 2],
+        });
+        Ok(())
+    }
 
-    /// Insurance treasury ATA (must be ATA for quote_mint)
-    #[account(mut, constraint = insurance_treasury.mint == quote_mint.key())]
-    pub insurance_treasury: Account<'info, TokenAccount>,
+    // Move a live cash deal opened with its own vault onto the market's omnibus vaults, so the market
+    // omnibus PDA signs for its margin from then on (farmer and buyer). The deal vault holds both sides'
+    // margin without a per-side record, so the parties state the farmer's part; the rest is the buyer's.
+    // The vault is closed to the buyer, who paid its rent. Deals with physical delivery, a hedge, debt,
+    // cross-margin or a settlement under way stay as they are
+    pub fn migrate_deal_to_omnibus(ctx: Context<MigrateDealToOmnibus>, farmer_balance: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(ctx.accounts.market.omnibus_vaults, CoffeeError::OmnibusDisabled);
+        let deal = &ctx.accounts.deal;
+        require!(deal.omnibus_migratable(), CoffeeError::DealNotMigratable);
+        let vault_balance = ctx.accounts.vault.amount;
+        let buyer_vault = own_buyer_vault(deal, &ctx.accounts.buyer_vault)?;
+        // with a buyer vault of its own each side's margin is its vault's balance; a single ATA is
+        // split at farmer_balance
+        let buyer_balance = match buyer_vault.as_ref() {
+            Some(vault) => {
+                require_eq!(farmer_balance, vault_balance, CoffeeError::OmnibusSplitExceedsVault);
+                vault.amount
+            }
+            None => {
+                require_gte!(vault_balance, farmer_balance, CoffeeError::OmnibusSplitExceedsVault);
+                vault_balance - farmer_balance
+            }
+        };
 
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + Market::INIT_SPACE,
-        seeds = [SEED_PREFIX, b"market", authority.key().as_ref(), cft_mint.key().as_ref(), quote_mint.key().as_ref()],
-        bump
-    )]
-    pub market: Account<'info, Market>,
+        let deal_key = deal.key();
+        let token_program = &ctx.accounts.token_program;
+        let from_buyer = buyer_vault.as_ref().unwrap_or(&ctx.accounts.vault);
+        transfer_from_vault_to(farmer_balance, &ctx.accounts.vault_auth, &ctx.accounts.vault, &ctx.accounts.omnibus_farmer_vault, token_program, &deal_key)?;
+        transfer_from_vault_to(buyer_balance, &ctx.accounts.vault_auth, from_buyer, &ctx.accounts.omnibus_buyer_vault, token_program, &deal_key)?;
+        let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, VAULT_AUTH_SEED, deal_key.as_ref(), &[ctx.accounts.vault_auth.bump]]];
+        for vault in std::iter::once(&ctx.accounts.vault).chain(buyer_vault.as_ref()) {
+            token::close_account(CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                CloseAccount {
+                    account: vault.to_account_info(),
+                    destination: ctx.accounts.buyer.to_account_info(),
+                    authority: ctx.accounts.vault_auth.to_account_info(),
+                },
+                seeds,
+            ))?;
+        }
 
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+        let deal = &mut ctx.accounts.deal;
+        deal.omnibus = true;
+        deal.omnibus_balance = [farmer_balance, buyer_balance];
+        deal.split_vaults = false;
+        let market = &mut ctx.accounts.market;
+        market.omnibus_open_deals = market.omnibus_open_deals.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        emit_event!(ctx, DealMovedToOmnibus {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: ctx.accounts.deal.deal_id,
+            farmer_balance,
+            buyer_balance,
+        });
+        Ok(())
+    }
 
-#[account]
-pub struct Market {
-    pub version: u8,
-    pub authority: Pubkey,
-    pub verifier: Pubkey,
-    pub oracle_publisher: Pubkey,
+    // Discount (bps) granted when fees are burned in CFT instead of paid in quote (admin)
+    pub fn set_cft_fee_discount(ctx: Context<MarketAdmin>, discount_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(discount_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        market.cft_fee_discount_bps = discount_bps;
+        emit_event!(ctx, CftFeeDiscountUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), discount_bps });
+        Ok(())
+    }
 
-    // pending rotation fields
-    pub pending_oracle: Pubkey,
-    pub pending_oracle_effective_ts: i64,
+    // Collect the insurance share as a premium at open_deal instead of at settlement (admin);
+    // applies to deals opened afterwards
+    pub fn set_insurance_upfront(ctx: Context<MarketAdmin>, upfront: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.insurance_upfront = upfront;
+        emit_event!(ctx, InsuranceUpfrontUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), upfront });
+        Ok(())
+    }
 
-    pub cft_mint: Pubkey,
-    pub quote_mint: Pubkey,
-    pub insurance_treasury: Pubkey,
+    // Both counterparties choose the fee currency before settlement
+    pub fn set_fee_currency(ctx: Context<SetFeeCurrency>, fee_currency: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        require!(fee_currency <= FeeCurrency::Cft as u8, CoffeeError::InvalidFeeCurrency);
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        deal.fee_currency = fee_currency;
+        emit_event!(ctx, FeeCurrencySet { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, fee_currency });
+        Ok(())
+    }
 
-    pub settlement_ts: i64,
-    pub contract_size_kg: u64,
+    // Buyer elects to receive cash-settlement winnings as CFT minted at the settlement price
+    pub fn elect_cft_payout(ctx: Context<ElectCftPayout>, enabled: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        require!(!deal.physical_delivery, CoffeeError::WrongSettlementType);
+        require!(!enabled || market.cft_sale_treasury != Pubkey::default(), CoffeeError::CftPayoutNotOffered);
+        deal.cft_payout = enabled;
+        emit_event!(ctx, CftPayoutElected { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, enabled });
+        Ok(())
+    }
 
-    // margins & fees
-    pub initial_margin_bps: u16,
-    pub maintenance_margin_bps: u16,
-    pub fee_bps: u16,
-    pub farmer_fee_bps: u16,
-    pub buyer_fee_bps: u16,
-    pub insurance_bps: u16,
-    pub default_margin_call_grace_sec: u64,
+    // Buyer names where the settlement outcome should be delivered off Solana (Wormhole chain id and
+    // 32-byte address); chain 0 clears it
+    pub fn set_crosschain_recipient(ctx: Context<SetCrosschainRecipient>, chain: u16, recipient: [u8; 32]) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        let recipient = if chain == 0 { [0u8; 32] } else { recipient };
+        deal.crosschain_chain = chain;
+        deal.crosschain_recipient = recipient;
+        emit_event!(ctx, CrosschainRecipientSet { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, chain, recipient });
+        Ok(())
+    }
 
-    // exposure caps
-    pub max_notional_per_deal: u64,
-    pub max_qty_per_deal: u64,
+    // Farmer names the CCTP domain and address their payout is bridged to by settle_and_bridge;
+    // a zero recipient clears it
+    pub fn set_payout_bridge(ctx: Context<SetPayoutBridge>, domain: u32, recipient: [u8; 32]) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        deal.payout_domain = domain;
+        deal.payout_recipient = recipient;
+        emit_event!(ctx, PayoutBridgeSet { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, domain, recipient });
+        Ok(())
+    }
 
-    // oracle / price
-    pub last_price_per_kg: u64,
-    pub prev_price_per_kg: u64,
-    pub last_price_nonce: u64,
-    pub last_oracle_update_ts: i64,
-    pub max_oracle_age_sec: u64,
+    // Create the per-market volume tracker for a trader (anyone may pay)
+    pub fn init_trader_stats(ctx: Context<InitTraderStats>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let stats = &mut ctx.accounts.trader_stats;
+        stats.market = ctx.accounts.market.key();
+        stats.trader = ctx.accounts.trader.key();
+        stats.rolling_notional = 0;
+        stats.lifetime_notional = 0;
+        stats.last_update_ts = 0;
+        stats.bump = ctx.bumps.trader_stats;
+        stats.defaults = 0;
+        stats.opens_window_ts = 0;
+        stats.opens_in_window = 0;
+        stats.rate_limit_exempt = false;
+        Ok(())
+    }
 
-    // TWAP accumulator (time-weighted)
-    pub twap_acc: u128,     // sum(price * seconds)
-    pub twap_time_acc: u64, // sum(seconds)
-    pub twap_window_sec: u64,
-    pub price_mode: u8,
+    // Cap deals opened per wallet per hour (admin); 0 lifts the cap. While on, open_deal needs both
+    // parties' TraderStats and open_deals_batch the buyer's
+    pub fn set_open_rate_limit(ctx: Context<MarketAdmin>, max_opens_per_hour: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.max_opens_per_hour = max_opens_per_hour;
+        emit_event!(ctx, OpenRateLimitUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), max_opens_per_hour });
+        Ok(())
+    }
 
-    // operational
-    pub paused: bool,
-    pub min_transfer_amount: u64,
+    // Add a trader to (or drop it from) the open rate limit allowlist (admin)
+    pub fn set_rate_limit_exempt(ctx: Context<SetRateLimitExempt>, exempt: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let stats = &mut ctx.accounts.trader_stats;
+        stats.rate_limit_exempt = exempt;
+        emit_event!(ctx, RateLimitExemptionSet {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: ctx.accounts.market.key(),
+            trader: stats.trader,
+            exempt,
+        });
+        Ok(())
+    }
 
-    // misc
-    pub insurance_treasury_authority: Pubkey, // authority for insurance ATA transfers (hook for prod model)
-    pub program_version: u8,
-}
+    // Create the next page of a wallet's deal index (anyone pays). Page 0 comes first and tracks the
+    // last page, so a wallet app reads page 0 and then fetches pages 1..=last_page in one call
+    pub fn init_participant_index_page(ctx: Context<InitParticipantIndexPage>, page: u32) -> Result<()> {
+        if page == 0 {
+            require!(ctx.accounts.head.is_none(), CoffeeError::ParticipantIndexPageInvalid);
+        } else {
+            let head = ctx.accounts.head.as_mut().ok_or(CoffeeError::ParticipantIndexPageInvalid)?;
+            require!(head.last_page.checked_add(1) == Some(page), CoffeeError::ParticipantIndexPageInvalid);
+            head.last_page = page;
+        }
+        let index = &mut ctx.accounts.index;
+        index.wallet = ctx.accounts.wallet.key();
+        index.page = page;
+        index.last_page = page;
+        index.len = 0;
+        index.deals = [Pubkey::default(); PARTICIPANT_INDEX_PAGE_LEN];
+        index.bump = ctx.bumps.index;
+        emit_event!(ctx, ParticipantIndexPageCreated { schema_version: EVENT_SCHEMA_VERSION, wallet: index.wallet, page });
+        Ok(())
+    }
 
-impl Market {
-    // rough size; tune before production
-    pub const INIT_SPACE: usize = 1 + 32*12 + 8*12 + 2*6 + 16 + 8 + 8 + 32;
-}
+    // Create the market's liquidation queue (anyone pays); mark_to_market fills it once passed in
+    pub fn init_liquidation_queue(ctx: Context<InitLiquidationQueue>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let queue = &mut ctx.accounts.liquidation_queue;
+        queue.market = ctx.accounts.market.key();
+        queue.len = 0;
+        queue.entries = [LiquidationEntry::default(); MAX_LIQUIDATION_QUEUE];
+        queue.bump = ctx.bumps.liquidation_queue;
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct PublishPrice<'info> {
-    #[account(mut, has_one = oracle_publisher)]
-    pub market: Account<'info, Market>,
-    /// CHECK: oracle publisher signer (may be multisig PDA)
-    pub oracle_publisher: Signer<'info>,
-}
+    // Settle the head of the liquidation queue (largest shortfall) as a Liquidation and dequeue it.
+    // A head that already settled another way (e.g. settle_cash) is just dequeued
+    pub fn execute_liquidation(ctx: Context<ExecuteLiquidation>) -> Result<()> {
+        version_guard_market(&ctx.accounts.settle.market)?;
+        let deal_key = ctx.accounts.settle.deal.key();
+        let head = *ctx.accounts.liquidation_queue.head().ok_or(CoffeeError::LiquidationQueueEmpty)?;
+        require_keys_eq!(head.deal, deal_key, CoffeeError::NotLiquidationQueueHead);
 
-#[derive(Accounts)]
-#[instruction(agreed_price_per_kg: u64, quantity_kg: u64)]
-pub struct OpenDeal<'info> {
-    #[account(mut)]
-    pub farmer: Signer<'info>,
-    #[account(mut)]
-    pub buyer: Signer<'info>,
+        // licensed mode: only a bonded licensee may liquidate, and one who takes a deal whose cached
+        // health has recovered since it was flagged forfeits liquidator_slash_bps of the bond
+        let market = &ctx.accounts.settle.market;
+        let licensed = market.licensed_liquidators;
+        let mut slash = 0;
+        if licensed {
+            let (Some(liquidator), Some(license), Some(bond_vault)) =
+                (&ctx.accounts.liquidator, &ctx.accounts.liquidator_license, &ctx.accounts.bond_vault)
+            else {
+                return err!(CoffeeError::LiquidatorNotLicensed);
+            };
+            require!(license.active && license.liquidator == liquidator.key(), CoffeeError::LiquidatorNotLicensed);
+            require!(
+                bond_vault.owner == license.key() && bond_vault.mint == market.quote_mint,
+                CoffeeError::LiquidatorNotLicensed
+            );
+            require_gte!(bond_vault.amount, market.liquidator_min_bond, CoffeeError::LiquidatorBondTooLow);
+            if ctx.accounts.settle.deal.recovered_since(head.flagged_ts) {
+                slash = bps_of_u64(bond_vault.amount, market.liquidator_slash_bps)?;
+            }
+        }
+        let health_bps = ctx.accounts.settle.deal.health_bps;
 
-    #[account(mut)]
-    pub market: Account<'info, Market>,
+        if !ctx.accounts.settle.deal.settled {
+            // never liquidate off a stale mark; the queue waits for the next fresh price
+            require!(
+                ctx.accounts.settle.market.oracle_is_fresh(Clock::get()?.unix_timestamp),
+                CoffeeError::OracleStale
+            );
+            let strategy = SettlementStrategy::cash_for(&ctx.accounts.settle.market, &ctx.accounts.settle.deal);
+            settlement::settle_cash_deal(
+                Context::new(ctx.program_id, &mut ctx.accounts.settle, ctx.remaining_accounts, ctx.bumps.settle),
+                strategy,
+                u8::MAX,
+            )?;
+        }
+        let queue = &mut ctx.accounts.liquidation_queue;
+        queue.remove(&deal_key);
+        emit_event!(ctx.accounts, ctx.bumps, LiquidationExecuted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: ctx.accounts.settle.deal.deal_id,
+            shortfall: head.shortfall,
+            queue_len: ctx.accounts.liquidation_queue.len,
+        });
 
-    pub quote_mint: Account<'info, Mint>,
+        if let (true, Some(license), Some(bond_vault)) =
+            (licensed, ctx.accounts.liquidator_license.as_mut(), ctx.accounts.bond_vault.as_ref())
+        {
+            transfer_from_liquidator_bond(
+                slash,
+                license,
+                bond_vault,
+                &ctx.accounts.settle.insurance_treasury,
+                &ctx.accounts.settle.token_program,
+            )?;
+            license.liquidations = license.liquidations.saturating_add(1);
+            license.slashed_total = license.slashed_total.checked_add(slash).ok_or(CoffeeError::MathOverflow)?;
+            if slash > 0 {
+                emit_event!(ctx.accounts, ctx.bumps, LiquidatorSlashed {
+                    schema_version: EVENT_SCHEMA_VERSION,
+                    market: license.market,
+                    liquidator: license.liquidator,
+                    deal: deal_key,
+                    amount: slash,
+                    health_bps,
+                });
+            }
+        }
+        Ok(())
+    }
 
-    #[account(
-        init,
-        payer = buyer,
-        space = 8 + Deal::INIT_SPACE,
-        seeds = [SEED_PREFIX, b"deal", market.key().as_ref(), farmer.key().as_ref(), buyer.key().as_ref()],
-        bump
-    )]
-    pub deal: Account<'info, Deal>,
+    // Drop queue entries for deals passed in remaining_accounts that were settled or closed outside
+    // execute_liquidation, or whose health recovered since the flag, so a stale head can't block the
+    // queue (anyone)
+    pub fn prune_liquidation_queue(ctx: Context<PruneLiquidationQueue>) -> Result<()> {
+        let queue = &mut ctx.accounts.liquidation_queue;
+        let mut dropped = 0u8;
+        for info in ctx.remaining_accounts.iter() {
+            let flagged_ts = queue.entries().iter().find(|e| e.deal == *info.key).map_or(0, |e| e.flagged_ts);
+            let live = info.owner == ctx.program_id
+                && !info.data_is_empty()
+                && Account::<Deal>::try_from(info).map_or(false, |deal| !deal.settled && !deal.recovered_since(flagged_ts));
+            if !live && queue.remove(info.key) {
+                dropped += 1;
+            }
+        }
+        emit_event!(ctx, LiquidationQueuePruned {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: ctx.accounts.market.key(),
+            dropped,
+            queue_len: ctx.accounts.liquidation_queue.len,
+        });
+        Ok(())
+    }
 
-    #[account(
-        init,
-        payer = buyer,
-        space = 8 + VaultAuth::SIZE,
-        seeds = [SEED_PREFIX, b"vault_auth", deal.key().as_ref()],
-        bump
-    )]
-    pub vault_auth: Account<'info, VaultAuth>,
+    // Ordered settlement (admin): while on, cash deals settle only through the market's SettlementQueue,
+    // so no one can pick which deals settle around a price update. Deals register once due and settle
+    // first-in first-out after the `epoch_sec` window they registered in closes; the crank earns
+    // `rebate_bps` of the fees each settlement collects, paid from the queue's fee pot. Turning it off
+    // empties the queue, the deals settling directly again
+    pub fn set_settlement_queue(ctx: Context<SetSettlementQueue>, enabled: bool, epoch_sec: i64, rebate_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(epoch_sec > 0, CoffeeError::InvalidSettlementEpoch);
+        require_gte!(MAX_BPS, rebate_bps, CoffeeError::InvalidBps);
+        let market_key = ctx.accounts.market.key();
+        let queue = &mut ctx.accounts.settlement_queue;
+        if queue.market == Pubkey::default() {
+            queue.market = market_key;
+            queue.len = 0;
+            queue.entries = [SettlementEntry::default(); MAX_SETTLEMENT_QUEUE];
+            queue.bump = ctx.bumps.settlement_queue;
+        }
+        queue.fee_pot = ctx.accounts.fee_pot.key();
+        queue.epoch_sec = epoch_sec;
+        queue.rebate_bps = rebate_bps;
+        let dropped = if enabled { 0 } else { queue.clear() };
+        ctx.accounts.market.ordered_settlement = enabled;
+        emit_event!(ctx, SettlementQueueUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market_key,
+            enabled,
+            epoch_sec,
+            rebate_bps,
+            fee_pot: ctx.accounts.fee_pot.key(),
+            dropped,
+        });
+        Ok(())
+    }
 
-    #[account(
-        init,
-        payer = buyer,
-        associated_token::mint = quote_mint,
-        associated_token::authority = vault_auth,
-    )]
-    pub farmer_margin_vault: Account<'info, TokenAccount>,
+    // Dequeue a settlement queue head that process_settlement_queue keeps failing on (admin), e.g. a
+    // fee-token deal whose burn delegate was revoked or one held by an open dispute. The deal isn't
+    // passed, so a closed one can be dropped too; it may register again once it can settle
+    pub fn drop_settlement_queue_head(ctx: Context<DropSettlementQueueHead>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let queue = &mut ctx.accounts.settlement_queue;
+        let head = *queue.head().ok_or(CoffeeError::SettlementQueueEmpty)?;
+        // only a head that was already due, so the admin can't reorder a window still filling
+        require!(Clock::get()?.unix_timestamp >= head.closes_ts, CoffeeError::SettlementWindowOpen);
+        queue.pop_front();
+        emit_event!(ctx, SettlementQueueHeadDropped {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: ctx.accounts.market.key(),
+            deal: head.deal,
+            epoch: head.epoch,
+            queue_len: ctx.accounts.settlement_queue.len,
+        });
+        Ok(())
+    }
 
-    #[account(
-        init,
-        payer = buyer,
-        associated_token::mint = quote_mint,
-        associated_token::authority = vault_auth,
-    )]
-    pub buyer_margin_vault: Account<'info, TokenAccount>,
+    // Register a due cash deal for ordered settlement (anyone); it joins the back of the queue in the
+    // current epoch window
+    pub fn enqueue_settlement(ctx: Context<EnqueueSettlement>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let deal = &ctx.accounts.deal;
+        require!(market.ordered_settlement, CoffeeError::SettlementQueueDisabled);
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        require!(!deal.physical_delivery, CoffeeError::WrongSettlementType);
+        require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+        require!(!deal.fx_hedge_pending(), CoffeeError::FxHedgeNotSettled);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= market.settlement_ts || now >= deal.deadline_ts, CoffeeError::NotYetSettleTime);
+        require!(!market.settlement_window_missed(deal, now), CoffeeError::SettlementWindowMissed);
 
-    #[account(mut, constraint = farmer_margin_from.mint == quote_mint.key())]
-    pub farmer_margin_from: Account<'info, TokenAccount>,
+        let queue = &mut ctx.accounts.settlement_queue;
+        let (epoch, closes_ts) = queue.window(now);
+        let position = queue.push(deal.key(), epoch, closes_ts)?;
+        emit_event!(ctx, SettlementEnqueued {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            epoch,
+            closes_ts,
+            position,
+        });
+        Ok(())
+    }
 
-    #[account(mut, constraint = buyer_margin_from.mint == quote_mint.key())]
-    pub buyer_margin_from: Account<'info, TokenAccount>,
+    // Settle the head of the settlement queue once its epoch window has closed and dequeue it; the crank
+    // gets its rebate from the fee pot (up to what the pot holds). A head already settled another way
+    // (e.g. liquidated), cross-margined or hedged since it registered, or past its settlement window is
+    // just dequeued without a rebate so it can't block the queue; the cross-margined or hedged may
+    // register again
+    pub fn process_settlement_queue(ctx: Context<ProcessSettlementQueue>) -> Result<()> {
+        version_guard_market(&ctx.accounts.settle.market)?;
+        let deal_key = ctx.accounts.settle.deal.key();
+        let head = *ctx.accounts.settlement_queue.head().ok_or(CoffeeError::SettlementQueueEmpty)?;
+        require_keys_eq!(head.deal, deal_key, CoffeeError::NotSettlementQueueHead);
+        require!(Clock::get()?.unix_timestamp >= head.closes_ts, CoffeeError::SettlementWindowOpen);
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+        let deal = &ctx.accounts.settle.deal;
+        let settles = !deal.settled
+            && !deal.is_cross_margined()
+            && !deal.fx_hedge_pending()
+            && !ctx.accounts.settle.market.settlement_window_missed(deal, Clock::get()?.unix_timestamp);
+        let mut rebate = 0;
+        if settles {
+            let fees_before = ctx.accounts.settle.deal.fees_paid;
+            let strategy = SettlementStrategy::cash_for(&ctx.accounts.settle.market, &ctx.accounts.settle.deal);
+            settlement::settle_cash_deal(
+                Context::new(ctx.program_id, &mut ctx.accounts.settle, ctx.remaining_accounts, ctx.bumps.settle),
+                strategy,
+                u8::MAX,
+            )?;
+            let fees = ctx.accounts.settle.deal.fees_paid.saturating_sub(fees_before);
+            rebate = bps_of_u64(fees, ctx.accounts.settlement_queue.rebate_bps)?.min(ctx.accounts.fee_pot.amount);
+        }
 
-#[account]
-pub struct VaultAuth {
-    pub bump: u8,
-}
-impl VaultAuth {
-    pub const SIZE: usize = 1 + 8;
-}
+        let market_key = ctx.accounts.settle.market.key();
+        let queue = &mut ctx.accounts.settlement_queue;
+        queue.pop_front();
+        queue.processed = queue.processed.saturating_add(1);
+        transfer_from_settlement_queue(
+            rebate,
+            queue,
+            &ctx.accounts.fee_pot,
+            &ctx.accounts.crank_receive,
+            &ctx.accounts.settle.token_program,
+            &market_key,
+        )?;
+        emit_event!(ctx.accounts, ctx.bumps, SettlementProcessed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: ctx.accounts.settle.deal.deal_id,
+            epoch: head.epoch,
+            crank: ctx.accounts.crank.key(),
+            settled: settles,
+            rebate,
+            queue_len: ctx.accounts.settlement_queue.len,
+        });
+        Ok(())
+    }
 
-#[account]
-pub struct Deal {
-    pub version: u8,
-    pub market: Pubkey,
-    pub farmer: Pubkey,
-    pub buyer: Pubkey,
-    pub agreed_price_per_kg: u64,
-    pub quantity_kg: u64,
-    pub initial_margin_each: u64,
+    // Licensed-liquidator mode (admin): when on, execute_liquidation needs a licensee whose bond vault
+    // holds at least `min_bond`; liquidating a deal that recovered after its flag costs `slash_bps` of it
+    pub fn set_liquidator_policy(ctx: Context<MarketAdmin>, licensed: bool, min_bond: u64, slash_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require_gte!(MAX_BPS, slash_bps, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        market.licensed_liquidators = licensed;
+        market.liquidator_min_bond = min_bond;
+        market.liquidator_slash_bps = slash_bps;
+        emit_event!(ctx, LiquidatorPolicyUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            licensed,
+            min_bond,
+            slash_bps,
+        });
+        Ok(())
+    }
 
-    // settlement & lifecycle
-    pub physical_delivery: bool,
-    pub delivered_kg_total: u64,
-    pub liquidated: bool,
-    pub settled: bool,
-    pub settling: bool, // reentrancy guard
-    pub farmer_deposited: bool,
-    pub buyer_deposited: bool,
-    pub deadline_ts: i64,
-    pub margin_call_ts: i64,
-    pub margin_call_grace_sec: u64,
+    // Grant or revoke a liquidator's license (admin); a revoked licensee can still withdraw its bond
+    pub fn set_liquidator_license(ctx: Context<SetLiquidatorLicense>, liquidator: Pubkey, active: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let license = &mut ctx.accounts.license;
+        if license.liquidator == Pubkey::default() {
+            license.market = ctx.accounts.market.key();
+            license.liquidator = liquidator;
+            license.bump = ctx.bumps.license;
+        }
+        license.active = active;
+        emit_event!(ctx, LiquidatorLicenseUpdated { schema_version: EVENT_SCHEMA_VERSION, market: license.market, liquidator, active });
+        Ok(())
+    }
 
-    // optional referral & fee split
-    pub referrer: Pubkey,
-    pub fee_split_bps: u16,
+    // Add quote to the licensee's bond vault (an ATA of the license PDA)
+    pub fn post_liquidator_bond(ctx: Context<PostLiquidatorBond>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.liquidator_quote_ata.to_account_info(),
+                    to: ctx.accounts.bond_vault.to_account_info(),
+                    authority: ctx.accounts.liquidator.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        ctx.accounts.bond_vault.reload()?;
+        let license = &ctx.accounts.license;
+        emit_event!(ctx, LiquidatorBondPosted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: license.market,
+            liquidator: license.liquidator,
+            amount,
+            bond: ctx.accounts.bond_vault.amount,
+        });
+        Ok(())
+    }
 
-    // multi-asset basket (fixed arrays)
-    pub asset_count: u8,
-    pub assets: [Pubkey; MAX_ASSETS],
-    pub asset_qty: [u64; MAX_ASSETS],
+    // Take bond back out; slashing happens inside execute_liquidation, so nothing is pending here
+    pub fn withdraw_liquidator_bond(ctx: Context<WithdrawLiquidatorBond>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        require_gte!(ctx.accounts.bond_vault.amount, amount, CoffeeError::InsufficientStake);
+        transfer_from_liquidator_bond(
+            amount,
+            &ctx.accounts.license,
+            &ctx.accounts.bond_vault,
+            &ctx.accounts.liquidator_quote_ata,
+            &ctx.accounts.token_program,
+        )?;
+        ctx.accounts.bond_vault.reload()?;
+        let license = &ctx.accounts.license;
+        emit_event!(ctx, LiquidatorBondWithdrawn {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: license.market,
+            liquidator: license.liquidator,
+            amount,
+            bond: ctx.accounts.bond_vault.amount,
+        });
+        Ok(())
+    }
 
-    // merkle root for basket proof
-    pub merkle_root: [u8; 32],
-}
+    // Close deal (account closed to receiver) - only when settled
+    pub fn close_deal(ctx: Context<CloseDeal>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(ctx.accounts.deal.settled, CoffeeError::DealNotSettled);
+        // an unpaid shortfall must be carried into a Debt account before the deal disappears
+        require!(ctx.accounts.deal.unpaid_owed == 0 || ctx.accounts.deal.debt_opened, CoffeeError::DebtNotRecorded);
+        require!(ctx.accounts.deal.basket_fully_delivered(), CoffeeError::BasketNotDelivered);
+        let market = &mut ctx.accounts.market;
+        market.unclosed_deals = market.unclosed_deals.saturating_sub(1);
 
-impl Deal {
-    pub const INIT_SPACE: usize = 1 + 32*6 + 8*8 + 1*10 + (32*MAX_ASSETS) + (8*MAX_ASSETS) + 40;
-    pub fn mark_settled(&mut self) {
-        self.settled = true;
-        self.settling = false;
-    }
-    pub fn start_settling(&mut self) {
-        self.settling = true;
-    }
-}
+        // hand drawn credit back to the buyer's line; a deal that left the buyer owing counts against it
+        let credit_used = ctx.accounts.deal.credit_used;
+        if credit_used > 0 {
+            let deal = &ctx.accounts.deal;
+            let defaulted = deal.unpaid_owed > 0 && deal.debt_creditor == deal.farmer;
+            let line = ctx.accounts.credit_line.as_mut().ok_or(CoffeeError::CreditLineMissing)?;
+            line.release(credit_used, defaulted);
+            emit_event!(ctx, CreditLineReleased {
+                schema_version: EVENT_SCHEMA_VERSION,
+                credit_line: line.key(),
+                deal: deal.key(),
+                amount: credit_used,
+                used: line.used,
+                defaulted,
+            });
+        }
 
-#[derive(Accounts)]
-pub struct TopUpMargin<'info> {
-    #[account(mut)]
-    pub who: Signer<'info>,
+        // the deal's lifetime outcome in one place before the account goes away
+        let deal = &ctx.accounts.deal;
+        emit_event!(ctx, DealClosed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            market: deal.market,
+            farmer: deal.farmer,
+            buyer: deal.buyer,
+            quantity_kg: deal.quantity_kg,
+            delivered_kg: deal.delivered_kg_total,
+            agreed_price_per_kg: deal.agreed_price_per_kg,
+            final_price_per_kg: deal.final_price_per_kg,
+            realized_pnl_farmer: deal.realized_pnl_farmer,
+            realized_pnl_buyer: deal.realized_pnl_buyer,
+            fees_paid: deal.fees_paid,
+            insurance_drawn: deal.insurance_drawn,
+            unpaid_owed: deal.unpaid_owed,
+        });
+        Ok(())
+    }
 
-    pub market: Account<'info, Market>,
 
-    #[account(mut, has_one = market)]
-    pub deal: Account<'info, Deal>,
+    // Create the CFT stake pool for a market (admin); revenue_share_bps of distributed fees go to stakers
+    pub fn init_stake_pool(ctx: Context<InitStakePool>, revenue_share_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        require!(revenue_share_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.market = ctx.accounts.market.key();
+        pool.stake_vault = ctx.accounts.stake_vault.key();
+        pool.reward_vault = ctx.accounts.reward_vault.key();
+        pool.total_staked = 0;
+        pool.acc_reward_per_share = 0;
+        pool.revenue_share_bps = revenue_share_bps;
+        pool.total_distributed = 0;
+        pool.bump = ctx.bumps.stake_pool;
+        emit_event!(ctx, StakePoolInitialized { schema_version: EVENT_SCHEMA_VERSION, market: pool.market, stake_pool: pool.key(), revenue_share_bps });
+        Ok(())
+    }
 
-    #[account(seeds = [SEED_PREFIX, b"vault_auth", deal.key().as_ref()], bump)]
-    pub vault_auth: Account<'info, VaultAuth>,
+    // Stake CFT into the pool; pending rewards are checkpointed first
+    pub fn stake_cft(ctx: Context<StakeCft>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let pool = &mut ctx.accounts.stake_pool;
+        let position = &mut ctx.accounts.position;
+        if position.owner == Pubkey::default() {
+            position.owner = ctx.accounts.owner.key();
+            position.pool = pool.key();
+            position.bump = ctx.bumps.position;
+        }
+        position.checkpoint(pool)?;
 
-    #[account(mut, constraint = from_ata.mint == market.quote_mint)]
-    pub from_ata: Account<'info, TokenAccount>,
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.owner_cft_ata.to_account_info(),
+                    to: ctx.accounts.stake_vault.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
 
-    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
-    pub farmer_margin_vault: Account<'info, TokenAccount>,
+        position.amount = position.amount.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        pool.total_staked = pool.total_staked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        position.reward_debt = position.accrued(pool)?;
 
-    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
-    pub buyer_margin_vault: Account<'info, TokenAccount>,
+        emit_event!(ctx, CftStaked { schema_version: EVENT_SCHEMA_VERSION, stake_pool: pool.key(), owner: position.owner, amount, total_staked: pool.total_staked });
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-}
+    // Withdraw staked CFT; pending rewards stay claimable
+    pub fn unstake_cft(ctx: Context<UnstakeCft>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let pool = &mut ctx.accounts.stake_pool;
+        let position = &mut ctx.accounts.position;
+        require_gte!(position.amount, amount, CoffeeError::InsufficientStake);
+        position.checkpoint(pool)?;
 
-#[derive(Accounts)]
-pub struct MarginCall<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+        transfer_from_pool_vault(
+            amount,
+            pool,
+            &ctx.accounts.stake_vault,
+            &ctx.accounts.owner_cft_ata,
+            &ctx.accounts.token_program,
+        )?;
 
-    #[account(mut, has_one = market)]
-    pub deal: Account<'info, Deal>,
+        position.amount -= amount;
+        pool.total_staked = pool.total_staked.checked_sub(amount).ok_or(CoffeeError::MathOverflow)?;
+        position.reward_debt = position.accrued(pool)?;
 
-    pub market: Account<'info, Market>,
-}
+        emit_event!(ctx, CftUnstaked { schema_version: EVENT_SCHEMA_VERSION, stake_pool: pool.key(), owner: position.owner, amount, total_staked: pool.total_staked });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct MtmCheck<'info> {
-    pub market: Account<'info, Market>,
+    // Route revenue_share_bps of `amount` from the fee treasury into the pool (admin signs the treasury)
+    pub fn distribute_revenue(ctx: Context<DistributeRevenue>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        let pool = &mut ctx.accounts.stake_pool;
+        require!(pool.total_staked > 0, CoffeeError::NoStakers);
+        let share = bps_of_u64(amount, pool.revenue_share_bps)?;
+        require!(share > 0, CoffeeError::ZeroAmount);
 
-    #[account(mut, has_one = market)]
-    pub deal: Account<'info, Deal>,
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.fee_treasury.to_account_info(),
+                    to: ctx.accounts.reward_vault.to_account_info(),
+                    authority: ctx.accounts.authority.to_account_info(),
+                },
+            ),
+            share,
+        )?;
 
-    #[account(seeds = [SEED_PREFIX, b"vault_auth", deal.key().as_ref()], bump)]
-    pub vault_auth: Account<'info, VaultAuth>,
+        let per_share = (share as u128)
+            .checked_mul(REWARD_PRECISION).ok_or(CoffeeError::MathOverflow)?
+            .checked_div(pool.total_staked as u128).ok_or(CoffeeError::MathOverflow)?;
+        pool.acc_reward_per_share = pool.acc_reward_per_share.checked_add(per_share).ok_or(CoffeeError::MathOverflow)?;
+        pool.total_distributed = pool.total_distributed.checked_add(share).ok_or(CoffeeError::MathOverflow)?;
 
-    #[account(constraint = farmer_margin_vault.mint == market.quote_mint)]
-    pub farmer_margin_vault: Account<'info, TokenAccount>,
+        emit_event!(ctx, RevenueDistributed { schema_version: EVENT_SCHEMA_VERSION, stake_pool: pool.key(), amount: share, total_staked: pool.total_staked });
+        Ok(())
+    }
 
-    #[account(constraint = buyer_margin_vault.mint == market.quote_mint)]
-    pub buyer_margin_vault: Account<'info, TokenAccount>,
-}
+    // Pay out accrued quote rewards to the staker
+    pub fn claim_staking_rewards(ctx: Context<ClaimStakingRewards>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let pool = &ctx.accounts.stake_pool;
+        let position = &mut ctx.accounts.position;
+        position.checkpoint(pool)?;
+        let amount = position.pending_rewards;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        position.pending_rewards = 0;
 
-#[derive(Accounts)]
-pub struct SettleCash<'info> {
-    pub market: Account<'info, Market>,
+        transfer_from_pool_vault(
+            amount,
+            pool,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.owner_quote_ata,
+            &ctx.accounts.token_program,
+        )?;
 
-    #[account(mut, has_one = market)]
-    pub deal: Account<'info, Deal>,
+        emit_event!(ctx, StakingRewardsClaimed { schema_version: EVENT_SCHEMA_VERSION, stake_pool: pool.key(), owner: position.owner, amount });
+        Ok(())
+    }
 
-    #[account(seeds = [SEED_PREFIX, b"vault_auth", deal.key().as_ref()], bump)]
-    pub vault_auth: Account<'info, VaultAuth>,
+    // Create the insurance stake pool (admin). Stakers supply quote as first-loss capital ahead of
+    // the insurance treasury and earn premium_share_bps of distributed insurance premiums. An unstake
+    // request can be redeemed for unstake_window_secs once its cooldown ends, then lapses.
+    pub fn init_insurance_pool(ctx: Context<InitInsurancePool>, premium_share_bps: u16, cooldown_secs: i64, unstake_window_secs: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        require!(premium_share_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        require!(cooldown_secs >= 0, CoffeeError::InvalidUnstakeCooldown);
+        require!(unstake_window_secs > 0, CoffeeError::InvalidUnstakeWindow);
+        let pool = &mut ctx.accounts.insurance_pool;
+        pool.market = ctx.accounts.market.key();
+        pool.stake_vault = ctx.accounts.stake_vault.key();
+        pool.total_shares = 0;
+        pool.premium_share_bps = premium_share_bps;
+        pool.cooldown_secs = cooldown_secs;
+        pool.unstake_window_secs = unstake_window_secs;
+        pool.total_premiums = 0;
+        pool.total_losses = 0;
+        pool.bump = ctx.bumps.insurance_pool;
+        ctx.accounts.market.insurance_stake_pool = pool.key();
+        emit_event!(ctx, InsurancePoolInitialized { schema_version: EVENT_SCHEMA_VERSION, market: pool.market, insurance_pool: pool.key(), premium_share_bps, cooldown_secs, unstake_window_secs });
+        Ok(())
+    }
 
-    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
-    pub farmer_margin_vault: Account<'info, TokenAccount>,
+    // Deposit quote into the insurance pool for shares at the current share value. Quote left in a
+    // pool with no shares (rounding dust after the last unstake, or a transfer) belongs to no staker,
+    // so it goes to the insurance treasury before the first staker mints 1:1
+    pub fn stake_insurance(ctx: Context<StakeInsurance>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let pool = &mut ctx.accounts.insurance_pool;
+        let position = &mut ctx.accounts.position;
+        if position.owner == Pubkey::default() {
+            position.owner = ctx.accounts.owner.key();
+            position.pool = pool.key();
+            position.bump = ctx.bumps.position;
+        }
+        let mut assets = ctx.accounts.stake_vault.amount;
+        if pool.total_shares == 0 && assets > 0 {
+            transfer_from_insurance_pool(
+                assets,
+                pool,
+                &ctx.accounts.stake_vault,
+                &ctx.accounts.insurance_treasury,
+                &ctx.accounts.token_program,
+            )?;
+            emit_event!(ctx, InsurancePoolSwept { schema_version: EVENT_SCHEMA_VERSION, insurance_pool: pool.key(), amount: assets });
+            assets = 0;
+        }
+        let shares = pool.shares_for_deposit(amount, assets)?;
+        require!(shares > 0, CoffeeError::ZeroAmount);
 
-    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
-    pub buyer_margin_vault: Account<'info, TokenAccount>,
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.owner_quote_ata.to_account_info(),
+                    to: ctx.accounts.stake_vault.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
 
-    #[account(mut, constraint = farmer_receive.mint == market.quote_mint)]
-    pub farmer_receive: Account<'info, TokenAccount>,
+        position.shares = position.shares.checked_add(shares).ok_or(CoffeeError::MathOverflow)?;
+        pool.total_shares = pool.total_shares.checked_add(shares).ok_or(CoffeeError::MathOverflow)?;
 
-    #[account(mut, constraint = buyer_receive.mint == market.quote_mint)]
-    pub buyer_receive: Account<'info, TokenAccount>,
+        emit_event!(ctx, InsuranceStaked { schema_version: EVENT_SCHEMA_VERSION, insurance_pool: pool.key(), owner: position.owner, amount, shares, total_shares: pool.total_shares });
+        Ok(())
+    }
 
-    #[account(mut, constraint = fee_treasury.mint == market.quote_mint)]
-    pub fee_treasury: Account<'info, TokenAccount>,
+    // Start the unstake cooldown for `shares` (replacing any earlier request). Queued shares keep
+    // absorbing losses until withdrawn but stop earning premiums: they redeem at no more than their
+    // value now
+    pub fn request_unstake_insurance(ctx: Context<RequestUnstakeInsurance>, shares: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(shares > 0, CoffeeError::ZeroAmount);
+        let pool = &ctx.accounts.insurance_pool;
+        let position = &mut ctx.accounts.position;
+        require_gte!(position.shares, shares, CoffeeError::InsufficientStake);
+        let now = Clock::get()?.unix_timestamp;
+        position.pending_unstake_shares = shares;
+        position.pending_unstake_value = pool.assets_for_shares(shares, ctx.accounts.stake_vault.amount)?;
+        position.unstake_ready_ts = now.checked_add(pool.cooldown_secs).ok_or(CoffeeError::MathOverflow)?;
 
-    #[account(mut, constraint = insurance_treasury.mint == market.quote_mint)]
-    pub insurance_treasury: Account<'info, TokenAccount>,
+        emit_event!(ctx, InsuranceUnstakeRequested { schema_version: EVENT_SCHEMA_VERSION, insurance_pool: pool.key(), owner: position.owner, shares, ready_ts: position.unstake_ready_ts });
+        Ok(())
+    }
 
-    /// CHECK: authority for insurance treasury (placeholder; wire to PDA in prod)
-    pub insurance_treasury_authority: UncheckedAccount<'info>,
+    // Redeem the queued shares within the claim window after the cooldown, at the lower of their value
+    // when queued and now; what they would have earned since stays with the pool. A lapsed request has
+    // to be made again
+    pub fn unstake_insurance(ctx: Context<UnstakeInsurance>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let pool = &mut ctx.accounts.insurance_pool;
+        let position = &mut ctx.accounts.position;
+        let shares = position.pending_unstake_shares;
+        require!(shares > 0, CoffeeError::NoPendingUnstake);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= position.unstake_ready_ts, CoffeeError::UnstakeCooldownActive);
+        require!(!pool.unstake_lapsed(position.unstake_ready_ts, now), CoffeeError::UnstakeRequestLapsed);
+        let amount = pool
+            .assets_for_shares(shares, ctx.accounts.stake_vault.amount)?
+            .min(position.pending_unstake_value);
 
-    pub token_program: Program<'info, Token>,
-}
+        transfer_from_insurance_pool(
+            amount,
+            pool,
+            &ctx.accounts.stake_vault,
+            &ctx.accounts.owner_quote_ata,
+            &ctx.accounts.token_program,
+        )?;
 
-#[derive(Accounts)]
-pub struct VerifyAndSettlePhysical<'info> {
-    #[account(mut, has_one = verifier, has_one = cft_mint, has_one = quote_mint)]
-    pub market: Account<'info, Market>,
+        position.shares -= shares;
+        position.pending_unstake_shares = 0;
+        position.pending_unstake_value = 0;
+        position.unstake_ready_ts = 0;
+        pool.total_shares = pool.total_shares.checked_sub(shares).ok_or(CoffeeError::MathOverflow)?;
 
-    #[account(mut, has_one = market)]
-    pub deal: Account<'info, Deal>,
+        emit_event!(ctx, InsuranceUnstaked { schema_version: EVENT_SCHEMA_VERSION, insurance_pool: pool.key(), owner: position.owner, amount, shares, total_shares: pool.total_shares });
+        Ok(())
+    }
 
-    /// CHECK: verifier may be multisig PDA
-    #[account(mut)]
-    pub verifier: Signer<'info>,
+    // Route premium_share_bps of `amount` from the insurance treasury into the staker pool, raising
+    // the share value (the insurance authority signs the treasury)
+    pub fn distribute_insurance_premiums(ctx: Context<DistributeInsurancePremiums>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let pool = &mut ctx.accounts.insurance_pool;
+        require!(pool.total_shares > 0, CoffeeError::NoStakers);
+        let share = bps_of_u64(amount, pool.premium_share_bps)?;
+        require!(share > 0, CoffeeError::ZeroAmount);
 
-    #[account(mut)]
-    pub cft_mint: Account<'info, Mint>,
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.insurance_treasury.to_account_info(),
+                    to: ctx.accounts.stake_vault.to_account_info(),
+                    authority: ctx.accounts.insurance_authority.to_account_info(),
+                },
+            ),
+            share,
+        )?;
+        pool.total_premiums = pool.total_premiums.checked_add(share).ok_or(CoffeeError::MathOverflow)?;
 
-    #[account(seeds = [SEED_PREFIX, b"cft_auth", cft_mint.key().as_ref()], bump)]
-    pub cft_mint_auth: Account<'info, CftMintAuth>,
+        emit_event!(ctx, InsurancePremiumsDistributed { schema_version: EVENT_SCHEMA_VERSION, insurance_pool: pool.key(), amount: share, total_shares: pool.total_shares });
+        Ok(())
+    }
 
-    #[account(
-        init_if_needed,
-        payer = verifier,
-        associated_token::mint = cft_mint,
-        associated_token::authority = buyer
-    )]
-    pub buyer_cft_ata: Account<'info, TokenAccount>,
 
-    #[account(seeds = [SEED_PREFIX, b"vault_auth", deal.key().as_ref()], bump)]
-    pub vault_auth: Account<'info, VaultAuth>,
+    // Queue a parameter update behind a timelock (admin; disabled once governance is installed)
+    pub fn propose_params_update(ctx: Context<MarketAdmin>, params: MarketParams, effective_after_ts: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(market.governance == Pubkey::default(), CoffeeError::GovernanceControlled);
+        params.validate()?;
+        market.pending_params = params;
+        market.pending_params_effective_ts = effective_after_ts;
+        emit_event!(ctx, ParamsUpdateProposed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), effective_ts: effective_after_ts });
+        Ok(())
+    }
 
-    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
-    pub buyer_margin_vault: Account<'info, TokenAccount>,
+    // Apply the queued parameters once the timelock passes (permissionless)
+    pub fn activate_params_update(ctx: Context<ActivatePending>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(market.pending_params_effective_ts != 0, CoffeeError::NoPendingParams);
+        require!(now >= market.pending_params_effective_ts, CoffeeError::RotationNotEffectiveYet);
+        let params = market.pending_params;
+        market.apply_params(&params);
+        market.pending_params = MarketParams::default();
+        market.pending_params_effective_ts = 0;
+        emit_event!(ctx, ParamsUpdateActivated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), ts: now });
+        Ok(())
+    }
 
-    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
-    pub farmer_margin_vault: Account<'info, TokenAccount>,
+    // rotate verifier (propose + activate after timelock), mirrors the oracle rotation
+    pub fn propose_rotate_verifier(ctx: Context<RotateRole>, new_verifier: Pubkey, effective_after_ts: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(market.governance == Pubkey::default(), CoffeeError::GovernanceControlled);
+        market.pending_verifier = new_verifier;
+        market.pending_verifier_effective_ts = effective_after_ts;
+        emit_event!(ctx, RoleRotationProposed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role: b"verifier".to_vec(), pending: new_verifier, effective_ts: effective_after_ts });
+        Ok(())
+    }
 
-    #[account(mut, constraint = farmer_receive.mint == market.quote_mint)]
-    pub farmer_receive: Account<'info, TokenAccount>,
+    pub fn activate_rotate_verifier(ctx: Context<ActivatePending>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(market.pending_verifier != Pubkey::default(), CoffeeError::NoPendingRotation);
+        require!(now >= market.pending_verifier_effective_ts, CoffeeError::RotationNotEffectiveYet);
+        market.verifier = market.pending_verifier;
+        market.pending_verifier = Pubkey::default();
+        market.pending_verifier_effective_ts = 0;
+        emit_event!(ctx, RoleRotationActivated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role: b"verifier".to_vec(), activated: market.verifier });
+        Ok(())
+    }
 
-    #[account(mut, constraint = buyer_receive.mint == market.quote_mint)]
-    pub buyer_receive: Account<'info, TokenAccount>,
+    // Hand market administration to token-holder governance (admin, one-way)
+    pub fn init_governance(
+        ctx: Context<InitGovernance>,
+        quorum_votes: u64,
+        voting_period_sec: i64,
+        timelock_sec: i64,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        require!(quorum_votes > 0, CoffeeError::ZeroAmount);
+        require!(voting_period_sec > 0 && timelock_sec >= 0, CoffeeError::InvalidGovernanceConfig);
+        let gov = &mut ctx.accounts.governance;
+        gov.market = ctx.accounts.market.key();
+        gov.vote_mint = ctx.accounts.vote_mint.key();
+        gov.quorum_votes = quorum_votes;
+        gov.voting_period_sec = voting_period_sec;
+        gov.timelock_sec = timelock_sec;
+        gov.proposal_count = 0;
+        gov.bump = ctx.bumps.governance;
+        ctx.accounts.market.governance = gov.key();
+        emit_event!(ctx, GovernanceInitialized { schema_version: EVENT_SCHEMA_VERSION, market: gov.market, governance: gov.key(), vote_mint: gov.vote_mint, quorum_votes });
+        Ok(())
+    }
 
-    /// CHECK: only used as ATA authority
-    pub buyer: UncheckedAccount<'info>,
+    // Any vote-token holder may open a proposal
+    pub fn create_proposal(
+        ctx: Context<CreateProposal>,
+        kind: u8,
+        params: Option<MarketParams>,
+        new_key: Option<Pubkey>,
+    ) -> Result<()> {
+        require!(ctx.accounts.proposer_vote_ata.amount > 0, CoffeeError::NoVotingPower);
+        let payload_ok = match kind {
+            k if k == ProposalKind::ParamsUpdate as u8 => {
+                params.ok_or(CoffeeError::InvalidProposal)?.validate()?;
+                true
+            }
+            k if k == ProposalKind::RotateOracle as u8 || k == ProposalKind::RotateVerifier as u8 => {
+                new_key.map(|k| k != Pubkey::default()).unwrap_or(false)
+            }
+            _ => false,
+        };
+        require!(payload_ok, CoffeeError::InvalidProposal);
 
-    pub quote_mint: Account<'info, Mint>,
+        let now = Clock::get()?.unix_timestamp;
+        let gov = &mut ctx.accounts.governance;
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.governance = gov.key();
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.id = gov.proposal_count;
+        proposal.kind = kind;
+        proposal.params = params.unwrap_or_default();
+        proposal.new_key = new_key.unwrap_or_default();
+        proposal.votes_for = 0;
+        proposal.votes_against = 0;
+        proposal.voting_ends_ts = now.checked_add(gov.voting_period_sec).ok_or(CoffeeError::MathOverflow)?;
+        proposal.executed = false;
+        proposal.bump = ctx.bumps.proposal;
+        gov.proposal_count = gov.proposal_count.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+        emit_event!(ctx, ProposalCreated { schema_version: EVENT_SCHEMA_VERSION, governance: gov.key(), proposal: proposal.key(), id: proposal.id, kind, voting_ends_ts: proposal.voting_ends_ts });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct CancelDeal<'info> {
-    #[account(mut, has_one = market)]
-    pub deal: Account<'info, Deal>,
+    // One vote per wallet, weighted by its vote-mint balance at voting time
+    pub fn vote(ctx: Context<CastVote>, approve: bool) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let proposal = &mut ctx.accounts.proposal;
+        require!(now < proposal.voting_ends_ts, CoffeeError::VotingClosed);
+        let weight = ctx.accounts.voter_vote_ata.amount;
+        require!(weight > 0, CoffeeError::NoVotingPower);
 
-    #[account(seeds = [SEED_PREFIX, b"vault_auth", deal.key().as_ref()], bump)]
-    pub vault_auth: Account<'info, VaultAuth>,
+        if approve {
+            proposal.votes_for = proposal.votes_for.checked_add(weight).ok_or(CoffeeError::MathOverflow)?;
+        } else {
+            proposal.votes_against = proposal.votes_against.checked_add(weight).ok_or(CoffeeError::MathOverflow)?;
+        }
+        let record = &mut ctx.accounts.vote_record;
+        record.proposal = proposal.key();
+        record.voter = ctx.accounts.voter.key();
+        record.weight = weight;
+        record.approve = approve;
 
-    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
-    pub farmer_margin_vault: Account<'info, TokenAccount>,
+        emit_event!(ctx, VoteCast { schema_version: EVENT_SCHEMA_VERSION, proposal: proposal.key(), voter: record.voter, weight, approve });
+        Ok(())
+    }
 
-    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
-    pub buyer_margin_vault: Account<'info, TokenAccount>,
+    // Queue a passed proposal into the market's timelocked update/rotation path (permissionless)
+    pub fn execute_proposal(ctx: Context<ExecuteProposal>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        let gov = &ctx.accounts.governance;
+        let proposal = &mut ctx.accounts.proposal;
+        require!(!proposal.executed, CoffeeError::ProposalAlreadyExecuted);
+        require!(now >= proposal.voting_ends_ts, CoffeeError::VotingStillOpen);
+        require!(
+            proposal.votes_for >= gov.quorum_votes && proposal.votes_for > proposal.votes_against,
+            CoffeeError::ProposalNotPassed
+        );
 
-    #[account(mut, constraint = farmer_receive.mint == market.quote_mint)]
-    pub farmer_receive: Account<'info, TokenAccount>,
+        let effective_ts = now.checked_add(gov.timelock_sec).ok_or(CoffeeError::MathOverflow)?;
+        let market = &mut ctx.accounts.market;
+        match proposal.kind {
+            k if k == ProposalKind::ParamsUpdate as u8 => {
+                market.pending_params = proposal.params;
+                market.pending_params_effective_ts = effective_ts;
+                emit_event!(ctx, ParamsUpdateProposed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), effective_ts });
+            }
+            k if k == ProposalKind::RotateOracle as u8 => {
+                market.pending_oracle = proposal.new_key;
+                market.pending_oracle_effective_ts = effective_ts;
+                emit_event!(ctx, RoleRotationProposed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role: b"oracle".to_vec(), pending: proposal.new_key, effective_ts });
+            }
+            k if k == ProposalKind::RotateVerifier as u8 => {
+                market.pending_verifier = proposal.new_key;
+                market.pending_verifier_effective_ts = effective_ts;
+                emit_event!(ctx, RoleRotationProposed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role: b"verifier".to_vec(), pending: proposal.new_key, effective_ts });
+            }
+            _ => return err!(CoffeeError::InvalidProposal),
+        }
+        proposal.executed = true;
 
-    #[account(mut, constraint = buyer_receive.mint == market.quote_mint)]
-    pub buyer_receive: Account<'info, TokenAccount>,
+        emit_event!(ctx, ProposalExecuted { schema_version: EVENT_SCHEMA_VERSION, proposal: proposal.key(), id: proposal.id, effective_ts });
+        Ok(())
+    }
 
-    pub market: Account<'info, Market>,
 
-    pub token_program: Program<'info, Token>,
-}
+    // Switch the authority or verifier between a single key and an external multisig (admin)
+    pub fn set_authority_config(ctx: Context<MarketAdmin>, role: u8, config: AuthorityConfig) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_keys_eq!(ctx.accounts.authority.key(), ctx.accounts.market.authority, CoffeeError::Unauthorized);
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        if let AuthorityConfig::ExternalMultisig { program, multisig, min_threshold } = config {
+            require!(
+                program != Pubkey::default() && multisig != Pubkey::default() && min_threshold > 0,
+                CoffeeError::InvalidAuthorityConfig
+            );
+        }
+        let market = &mut ctx.accounts.market;
+        match role {
+            r if r == ConfiguredRole::Authority as u8 => market.authority_config = config,
+            r if r == ConfiguredRole::Verifier as u8 => market.verifier_config = config,
+            _ => return err!(CoffeeError::InvalidAuthorityConfig),
+        }
+        emit_event!(ctx, AuthorityConfigUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), role, multisig: config != AuthorityConfig::SingleKey });
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct RotateRole<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
 
-    #[account(mut)]
-    pub market: Account<'info, Market>,
-}
+    // Create the market's role table (master authority)
+    pub fn init_access_control(ctx: Context<InitAccessControl>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        let acl = &mut ctx.accounts.access_control;
+        acl.market = ctx.accounts.market.key();
+        acl.member_count = 0;
+        acl.members = [Pubkey::default(); MAX_ACL_MEMBERS];
+        acl.role_masks = [0u8; MAX_ACL_MEMBERS];
+        acl.bump = ctx.bumps.access_control;
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct CloseDeal<'info> {
-    #[account(mut, has_one = market, close = receiver)]
-    pub deal: Account<'info, Deal>,
+    // Grant a role; only the master authority may grant ADMIN
+    pub fn grant_role(ctx: Context<ManageAccess>, member: Pubkey, role: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let role = Role::from_u8(role)?;
+        let signer = ctx.accounts.authority.key();
+        if role == Role::Admin {
+            require_keys_eq!(signer, ctx.accounts.market.authority, CoffeeError::Unauthorized);
+        }
+        require_role(&ctx.accounts.market, Some(&ctx.accounts.access_control), &signer, Role::Admin, ctx.remaining_accounts)?;
+        require!(member != Pubkey::default(), CoffeeError::InvalidRole);
+        ctx.accounts.access_control.grant(member, role)?;
+        emit_event!(ctx, RoleGranted { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), member, role: role as u8 });
+        Ok(())
+    }
 
-    pub market: Account<'info, Market>,
+    pub fn revoke_role(ctx: Context<ManageAccess>, member: Pubkey, role: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let role = Role::from_u8(role)?;
+        let signer = ctx.accounts.authority.key();
+        if role == Role::Admin {
+            require_keys_eq!(signer, ctx.accounts.market.authority, CoffeeError::Unauthorized);
+        }
+        require_role(&ctx.accounts.market, Some(&ctx.accounts.access_control), &signer, Role::Admin, ctx.remaining_accounts)?;
+        ctx.accounts.access_control.revoke(&member, role);
+        emit_event!(ctx, RoleRevoked { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), member, role: role as u8 });
+        Ok(())
+    }
 
-    /// CHECK: receiver of rent lamports on close
-    #[account(mut)]
-    pub receiver: UncheckedAccount<'info>,
-}
+    // Pause / unpause new deals and physical settlement (authority or delegated PAUSER)
+    pub fn set_paused(ctx: Context<MarketAdmin>, paused: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Pauser, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.paused = paused;
+        emit_event!(ctx, MarketPauseSet { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), paused, by: ctx.accounts.authority.key() });
+        Ok(())
+    }
 
-// ------------------------- Helpers -------------------------
 
-fn version_guard_program() -> Result<()> {
-    Ok(())
-}
+    // Permissionless after deadline_ts when margins never both arrived: refund deposits and void the deal
+    pub fn expire_deal(ctx: Context<ExpireDeal>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        check_margin_vaults(&ctx.accounts.market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+        require!(!(deal.farmer_deposited && deal.buyer_deposited), CoffeeError::DealIsLive);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= deal.deadline_ts, CoffeeError::DeadlineNotReached);
 
-fn version_guard_market(market: &Account<Market>) -> Result<()> {
-    require!(market.program_version == PROGRAM_VERSION, CoffeeError::VersionMismatch);
-    Ok(())
-}
+        let farmer_refund = ctx.accounts.farmer_margin_vault.amount;
+        transfer_from_vault_to(
+            farmer_refund,
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.farmer_margin_vault,
+            &ctx.accounts.farmer_receive,
+            &ctx.accounts.token_program,
+            &deal_key,
+        )?;
+        let buyer_refund = ctx.accounts.buyer_margin_vault.amount;
+        transfer_from_vault_to(
+            buyer_refund,
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.buyer_margin_vault,
+            &ctx.accounts.buyer_receive,
+            &ctx.accounts.token_program,
+            &deal_key,
+        )?;
 
-fn assert_is_oracle(_market: &Account<Market>, _oracle: &Signer) -> Result<()> {
-    // TODO: check equality with market.oracle_publisher or multisig PDA logic
-    Ok(())
-}
-fn assert_is_verifier(_market: &Account<Market>, _verifier: &Signer) -> Result<()> {
-    // TODO: check equality with market.verifier or multisig PDA logic
-    Ok(())
-}
-fn assert_is_counterparty(deal: &Account<Deal>, signer: &Signer) -> Result<()> {
-    let k = signer.key();
-    require!(k == deal.farmer || k == deal.buyer, CoffeeError::InvalidCounterparty);
-    Ok(())
-}
+        deal.voided = true;
+        deal.mark_settled();
+        emit_event!(ctx, DealExpired { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, market: ctx.accounts.market.key(), farmer_refund, buyer_refund, ts: now });
+        ctx.accounts.market.release_open_interest(&ctx.accounts.deal);
+        Ok(())
+    }
 
-// safe multiplication by bps returning u128
-fn bps_mul_u128(x: u128, bps: u16) -> Result<u128> {
-    x.checked_mul(bps as u128)
-        .and_then(|y| y.checked_div(10_000))
-        .ok_or(CoffeeError::MathOverflow.into())
-}
 
-fn bps_of_u64(x: u64, bps: u16) -> Result<u64> {
-    let prod = (x as u128).checked_mul(bps as u128).ok_or(CoffeeError::MathOverflow)?;
-    let out = prod.checked_div(10_000).ok_or(CoffeeError::MathOverflow)?;
-    Ok(out as u64)
-}
+    // Default penalty charged on undelivered physical quantity (admin)
+    pub fn set_default_penalty(ctx: Context<MarketAdmin>, penalty_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(penalty_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        market.default_penalty_bps = penalty_bps;
+        emit_event!(ctx, DefaultPenaltyUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), penalty_bps });
+        Ok(())
+    }
 
-enum SignRole {
-    Long,
-    Short,
-}
+    // Physical deal past deadline_ts with undelivered kg: cash-settle the remainder at the fixed
+    // settlement price (a fresh mark before there is a final one), charge the farmer the default
+    // penalty in favour of the buyer, and record the default. On a vesting market the penalty goes to
+    // the deal's MarginVesting instead and vests back to the farmer.
+    pub fn default_physical_deal(ctx: Context<DefaultPhysicalDeal>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &mut ctx.accounts.deal;
+        require!(
+            !deal.settled && !deal.settling && deal.settlement_step == settlement::SettlementStep::NotStarted as u8,
+            CoffeeError::DealAlreadySettled
+        );
+        require!(deal.physical_delivery, CoffeeError::WrongSettlementType);
+        check_margin_vaults(market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= deal.deadline_ts, CoffeeError::DeadlineNotReached);
+        let undelivered_kg = deal.quantity_kg.checked_sub(deal.delivered_kg_total).ok_or(CoffeeError::MathOverflow)?;
+        require!(undelivered_kg > 0, CoffeeError::ZeroQty);
 
-// Long PnL: (mark - agreed) * qty; Short PnL is negative of long
-fn signed_mul_diff(agreed: u64, mark: u64, qty: u64, role: SignRole) -> Option<i128> {
-    let agreed = agreed as i128;
-    let mark = mark as i128;
-    let qty = qty as i128;
-    let diff = match role {
-        SignRole::Long => mark.checked_sub(agreed)?,
-        SignRole::Short => agreed.checked_sub(mark)?,
-    };
-    diff.checked_mul(qty)
-}
+        let price = market.default_price(now)?;
+        deal.start_settling();
+        // a shared vault also holds the buyer's margin, so the farmer side only pays out of its initial margin
+        let legs = settlement::VaultLegs::new(
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.farmer_margin_vault,
+            &ctx.accounts.buyer_margin_vault,
+            &ctx.accounts.token_program,
+            deal_key,
+        )
+        .split_shared(deal.initial_margin_each);
 
-/// Transfer amount from vault (PDA authoritiy) to `to_ata` using signer PDA
-fn transfer_from_vault_to<'a>(
-    amount: u64,
-    vault_auth: &Account<'a, VaultAuth>,
-    from_vault: &Account<'a, TokenAccount>,
-    to_ata: &Account<'a, TokenAccount>,
-    token_program: &Program<'a, Token>,
-    deal_key: &Pubkey,
-) -> Result<()> {
-    if amount == 0 {
-        return Ok(());
+        // cash PnL on the undelivered remainder
+        let pnl_long = deal.slot_pnl(market, price, undelivered_kg, math::Side::Long)?;
+        let (loser, winner_receive) = if pnl_long > 0 {
+            (math::Side::Short, &ctx.accounts.buyer_receive)
+        } else {
+            (math::Side::Long, &ctx.accounts.farmer_receive)
+        };
+        let pnl = settlement::plan_pnl(pnl_long, legs.available(loser), 0)?;
+        legs.pay(loser, pnl.pay, winner_receive)?;
+        if let Some(winner) = pnl.winner {
+            deal.record_realized_pnl(winner, pnl.pay, pnl.pay)?;
+            if pnl.shortfall > 0 {
+                let creditor = deal.party(winner);
+                deal.record_shortfall(creditor, pnl.shortfall)?;
+                emit_event!(ctx, SettlementShortfall { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, creditor, shortfall: pnl.shortfall });
+            }
+        }
+
+        // default penalty: farmer margin -> buyer, or back to the farmer over time on a vesting market
+        let undelivered_notional = market.notional(deal.agreed_price_per_kg, undelivered_kg)?;
+        let penalty = u64::try_from(bps_mul_u128(undelivered_notional, market.default_penalty_bps)?)
+            .map_err(|_| CoffeeError::MathOverflow)?
+            .min(legs.available(math::Side::Short));
+        let vested = market.seized_vesting_sec > 0;
+        if vested {
+            let vesting_vault = ctx.accounts.vesting_vault.as_ref().ok_or(CoffeeError::VestingAccountsRequired)?;
+            legs.pay(math::Side::Short, penalty, vesting_vault)?;
+            let vesting = ctx.accounts.vesting.as_mut().ok_or(CoffeeError::VestingAccountsRequired)?;
+            vesting.start(deal.farmer, penalty, now, market.seized_vesting_sec)?;
+        } else {
+            legs.pay(math::Side::Short, penalty, &ctx.accounts.buyer_receive)?;
+        }
+
+        // residuals back to owners
+        legs.pay(math::Side::Short, legs.available(math::Side::Short), &ctx.accounts.farmer_receive)?;
+        legs.pay(math::Side::Long, legs.available(math::Side::Long), &ctx.accounts.buyer_receive)?;
+        legs.check_drained()?;
+
+        if deal.prepaid {
+            refund_purchase_escrow(
+                deal,
+                ctx.accounts.purchase_escrow.as_mut(),
+                &ctx.accounts.vault_auth,
+                &ctx.accounts.buyer_receive,
+                &ctx.accounts.token_program,
+                &deal_key,
+            )?;
+        }
+
+        let farmer_stats = &mut ctx.accounts.farmer_stats;
+        farmer_stats.defaults = farmer_stats.defaults.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        if !vested {
+            deal.record_realized_pnl(math::Side::Long, penalty, penalty)?;
+        }
+        deal.final_price_per_kg = price;
+        deal.mark_settled();
+
+        emit_event!(ctx, PhysicalDealDefaulted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            market: market.key(),
+            undelivered_kg,
+            price,
+            pnl_long: i64::try_from(pnl_long).map_err(|_| CoffeeError::MathOverflow)?,
+            pnl_paid: pnl.pay,
+            penalty,
+            farmer_defaults: farmer_stats.defaults,
+        });
+        if let Some(vesting) = ctx.accounts.vesting.as_ref().filter(|_| vested) {
+            emit_event!(ctx, MarginVestingStarted {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                beneficiary: vesting.beneficiary,
+                amount: vesting.total,
+                end_ts: vesting.end_ts,
+            });
+        }
+        ctx.accounts.market.release_open_interest(&ctx.accounts.deal);
+        Ok(())
     }
-    let bump = vault_auth.bump;
-    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, b"vault_auth", deal_key.as_ref(), &[bump]]];
 
-    token::transfer(
-        CpiContext::new_with_signer(
-            token_program.to_account_info(),
-            Transfer {
-                from: from_vault.to_account_info(),
-                to: to_ata.to_account_info(),
-                authority: vault_auth.to_account_info(),
-            },
-            seeds,
-        ),
-        amount,
-    )?;
-    Ok(())
-}
 
-// Merkle verification (binary, keccak-based). Returns Result<bool, _> for easy use.
-fn verify_merkle_proof(mut leaf: [u8; 32], proof: &Vec<[u8; 32]>, root: [u8; 32]) -> Result<bool> {
-    for p in proof.iter() {
-        // deterministic ordering by bytes
-        let combined = if leaf <= *p {
-            [&leaf[..], &p[..]].concat()
+    // Arbiter cash-settles a due deal on the usual settlement price; the only way to settle one whose
+    // settlement window has been missed. Skips the settlement queue
+    pub fn arbiter_settle_cash(ctx: Context<ArbiterSettleCash>) -> Result<()> {
+        version_guard_market(&ctx.accounts.settle.market)?;
+        require_role(&ctx.accounts.settle.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.arbiter.key(), Role::Arbiter, ctx.remaining_accounts)?;
+        let deal = &ctx.accounts.settle.deal;
+        let window_missed = ctx.accounts.settle.market.settlement_window_missed(deal, Clock::get()?.unix_timestamp);
+        emit_event!(ctx.accounts, ctx.bumps, ArbiterSettlementStarted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            arbiter: ctx.accounts.arbiter.key(),
+            window_missed,
+        });
+        let strategy = SettlementStrategy::cash_for(&ctx.accounts.settle.market, &ctx.accounts.settle.deal);
+        settlement::settle_cash_deal(
+            Context::new(ctx.program_id, &mut ctx.accounts.settle, ctx.remaining_accounts, ctx.bumps.settle),
+            strategy,
+            u8::MAX,
+        )
+    }
+
+    // Set or clear (0) the caller's stop-loss on a cash deal (either party). The stop sits on the
+    // losing side of the agreed price: above it for the short, below it for the long. Send it in the
+    // open_deal transaction to have it from open
+    pub fn set_stop(ctx: Context<SetStop>, stop_price_per_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let party = ctx.accounts.party.key();
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling && !deal.liquidated && !deal.stopped, CoffeeError::DealAlreadySettled);
+        require!(!deal.physical_delivery && !deal.prepaid, CoffeeError::WrongSettlementType);
+        let slot = deal.side_of(&party).ok_or(CoffeeError::Unauthorized)?;
+        match deal.position(slot) {
+            math::Side::Short => require!(stop_price_per_kg == 0 || stop_price_per_kg > deal.agreed_price_per_kg, CoffeeError::InvalidStopPrice),
+            math::Side::Long => require!(stop_price_per_kg < deal.agreed_price_per_kg, CoffeeError::InvalidStopPrice),
+        }
+        match slot {
+            math::Side::Short => deal.farmer_stop_price_per_kg = stop_price_per_kg,
+            math::Side::Long => deal.buyer_stop_price_per_kg = stop_price_per_kg,
+        }
+        emit_event!(ctx, StopSet {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            party,
+            stop_price_per_kg,
+        });
+        Ok(())
+    }
+
+    // Stop a deal out before expiry once the mark crosses a side's stop (anyone): the executor is paid
+    // market.stop_bounty_bps of notional at the mark from the stopped side's vault (on a vesting market
+    // it vests back to the stopped party instead), then the deal cash-settles at the mark
+    pub fn execute_stop(ctx: Context<ExecuteStop>) -> Result<()> {
+        version_guard_market(&ctx.accounts.settle.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        let settle = &ctx.accounts.settle;
+        let market = &settle.market;
+        let deal = &settle.deal;
+        require!(
+            !deal.settled && !deal.liquidated && deal.settlement_step == settlement::SettlementStep::NotStarted as u8,
+            CoffeeError::DealAlreadySettled
+        );
+        require!(!deal.omnibus, CoffeeError::OmnibusUnsupported);
+        check_margin_vaults(market, deal, &settle.vault_auth.key(), &settle.farmer_margin_vault, &settle.buyer_margin_vault)?;
+        // past expiry the deal settles normally instead
+        require!(now < market.settlement_ts && now < deal.deadline_ts, CoffeeError::StopExpired);
+        require!(market.oracle_is_fresh(now), CoffeeError::OracleStale);
+        let price = mark_price(market)?;
+        let side = deal.stop_triggered(price).ok_or(CoffeeError::StopNotTriggered)?;
+        let party = deal.party(side);
+        let stop_price_per_kg = match side {
+            math::Side::Short => deal.farmer_stop_price_per_kg,
+            math::Side::Long => deal.buyer_stop_price_per_kg,
+        };
+
+        // the bounty never dips into what the stopped side owes the other; a shared vault also holds the
+        // other side's margin, so only the stopped side's initial margin counts there
+        let notional: u64 = market.notional(price, deal.quantity_kg)?.try_into().map_err(|_| CoffeeError::MathOverflow)?;
+        let legs = settlement::VaultLegs::new(&settle.vault_auth, &settle.farmer_margin_vault, &settle.buyer_margin_vault, &settle.token_program, deal.key());
+        let held = if settle.farmer_margin_vault.key() == settle.buyer_margin_vault.key() {
+            legs.available(side).min(deal.initial_margin_each)
         } else {
-            [&p[..], &leaf[..]].concat()
+            legs.available(side)
+        };
+        let owed = u64::try_from(deal.slot_pnl(market, price, deal.quantity_kg, side)?.min(0).unsigned_abs()).unwrap_or(u64::MAX);
+        let taken = bps_of_u64(notional, market.stop_bounty_bps)?.min(held.saturating_sub(owed));
+        // on a vesting market it vests back to the stopped party and the executor goes unpaid
+        let bounty = if market.seized_vesting_sec > 0 {
+            let vesting_vault = ctx.accounts.vesting_vault.as_ref().ok_or(CoffeeError::VestingAccountsRequired)?;
+            legs.pay(side, taken, vesting_vault)?;
+            let vesting = ctx.accounts.vesting.as_mut().ok_or(CoffeeError::VestingAccountsRequired)?;
+            vesting.start(party, taken, now, market.seized_vesting_sec)?;
+            0
+        } else {
+            legs.pay(side, taken, &ctx.accounts.executor_receive)?;
+            taken
         };
-        leaf = solana_program::keccak::hash(&combined).0;
-    }
-    Ok(leaf == root)
-}
 
-// Helper: absolute i64 to u64 (safe)
-fn abs_i64_to_u64(v: i64) -> u64 {
-    if v >= 0 { v as u64 } else { (-v) as u64 }
-}
+        let deal_key = deal.key();
+        let deal_id = deal.deal_id;
+        ctx.accounts.settle.farmer_margin_vault.reload()?;
+        ctx.accounts.settle.buyer_margin_vault.reload()?;
+        ctx.accounts.settle.deal.stopped = true;
+        emit_event!(ctx.accounts, ctx.bumps, StopExecuted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id,
+            party,
+            stop_price_per_kg,
+            mark_price_per_kg: price,
+            executor: ctx.accounts.executor.key(),
+            bounty,
+        });
+        if let Some(vesting) = ctx.accounts.vesting.as_ref().filter(|_| bounty < taken) {
+            emit_event!(ctx.accounts, ctx.bumps, MarginVestingStarted {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                beneficiary: vesting.beneficiary,
+                amount: vesting.total,
+                end_ts: vesting.end_ts,
+            });
+        }
+        let strategy = SettlementStrategy::cash_for(&ctx.accounts.settle.market, &ctx.accounts.settle.deal);
+        settlement::settle_cash_deal(
+            Context::new(ctx.program_id, &mut ctx.accounts.settle, ctx.remaining_accounts, ctx.bumps.settle),
+            strategy,
+            u8::MAX,
+        )
+    }
 
-// TWAP update: incorporate previous price over elapsed time into twap_acc / twap_time_acc.
-// This is a simple sliding-window approximation.
-fn update_twap(market: &mut Market, now_ts: i64) -> Result<()> {
-    // if no previous price/time, just set last_oracle_update_ts (no accumulation)
-    if market.last_oracle_update_ts == 0 {
-        market.last_oracle_update_ts = now_ts;
-        return Ok(());
+    // Bounty execute_stop pays out of the stopped side, in bps of notional at the mark (admin)
+    pub fn set_stop_bounty(ctx: Context<MarketAdmin>, bounty_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(bounty_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        market.stop_bounty_bps = bounty_bps;
+        emit_event!(ctx, StopBountyUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), bounty_bps });
+        Ok(())
     }
 
-    let dt_i64 = now_ts.checked_sub(market.last_oracle_update_ts).ok_or(CoffeeError::MathOverflow)?;
-    if dt_i64 <= 0 {
-        market.last_oracle_update_ts = now_ts;
-        return Ok(());
+    // Vest default penalties and stop bounties back to the party they were taken from over
+    // `vesting_sec` instead of paying them out; 0 turns it off (admin)
+    pub fn set_seized_vesting(ctx: Context<MarketAdmin>, vesting_sec: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(vesting_sec >= 0, CoffeeError::InvalidVestingPeriod);
+        let market = &mut ctx.accounts.market;
+        market.seized_vesting_sec = vesting_sec;
+        emit_event!(ctx, SeizedVestingUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), vesting_sec });
+        Ok(())
     }
-    let dt_u64 = dt_i64 as u64;
-    let add = dt_u64.min(market.twap_window_sec);
 
-    // add last_price contribution for elapsed seconds
-    let add_val = (market.last_price_per_kg as u128)
-        .checked_mul(add as u128)
-        .ok_or(CoffeeError::MathOverflow)?;
-    market.twap_acc = market.twap_acc.checked_add(add_val).ok_or(CoffeeError::MathOverflow)?;
-    market.twap_time_acc = market.twap_time_acc.checked_add(add).ok_or(CoffeeError::MathOverflow)?;
+    // Create a deal's MarginVesting and its vault ahead of default_physical_deal or execute_stop on a
+    // vesting market (anyone; close_margin_vesting returns the rent). Send it in the same transaction
+    pub fn init_margin_vesting(ctx: Context<InitMarginVesting>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(ctx.accounts.market.seized_vesting_sec > 0, CoffeeError::VestingNotEnabled);
+        require!(!ctx.accounts.deal.settled, CoffeeError::DealAlreadySettled);
+        let vesting = &mut ctx.accounts.vesting;
+        vesting.market = ctx.accounts.market.key();
+        vesting.deal = ctx.accounts.deal.key();
+        vesting.payer = ctx.accounts.payer.key();
+        vesting.bump = ctx.bumps.vesting;
+        Ok(())
+    }
 
-    // if we've exceeded window, scale-down (approximate sliding window)
-    if market.twap_time_acc > market.twap_window_sec {
-        market.twap_acc = market.twap_acc
-            .checked_mul(market.twap_window_sec as u128).ok_or(CoffeeError::MathOverflow)?
-            .checked_div(market.twap_time_acc as u128).ok_or(CoffeeError::MathOverflow)?;
-        market.twap_time_acc = market.twap_window_sec;
+    // Pay the beneficiary what has vested so far (anyone)
+    pub fn claim_vested_margin(ctx: Context<ClaimVestedMargin>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let vesting = &mut ctx.accounts.vesting;
+        let amount = vesting.claimable(Clock::get()?.unix_timestamp);
+        require!(amount > 0, CoffeeError::NothingVested);
+        transfer_from_vault_to(
+            amount,
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.vesting_vault,
+            &ctx.accounts.beneficiary_receive,
+            &ctx.accounts.token_program,
+            &vesting.deal,
+        )?;
+        vesting.claimed = vesting.claimed.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        emit_event!(ctx, VestedMarginClaimed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: vesting.deal,
+            beneficiary: vesting.beneficiary,
+            amount,
+            remaining: vesting.total - vesting.claimed,
+        });
+        Ok(())
     }
 
-    market.last_oracle_update_ts = now_ts;
-    Ok(())
-}
+    // The payer closes a MarginVesting and its vault once fully claimed, or while still unfunded (a
+    // later default or stop on a vesting market then needs init_margin_vesting again)
+    pub fn close_margin_vesting(ctx: Context<CloseMarginVesting>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let vesting = &ctx.accounts.vesting;
+        require!(vesting.claimed == vesting.total && ctx.accounts.vesting_vault.amount == 0, CoffeeError::VestingNotComplete);
+        let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, VAULT_AUTH_SEED, vesting.deal.as_ref(), &[ctx.accounts.vault_auth.bump]]];
+        token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.vesting_vault.to_account_info(),
+                destination: ctx.accounts.payer.to_account_info(),
+                authority: ctx.accounts.vault_auth.to_account_info(),
+            },
+            seeds,
+        ))?;
+        Ok(())
+    }
 
-// Simple price band check helper (returns Err on violation)
-fn is_price_band_ok(prev: u64, next: u64, max_delta_bps: u128) -> Result<()> {
-    if prev == 0 { return Ok(()); }
-    let prev_u = prev as u128;
-    let next_u = next as u128;
-    let delta = if next_u >= prev_u { next_u - prev_u } else { prev_u - next_u };
-    let delta_bps = delta.checked_mul(10_000).ok_or(CoffeeError::MathOverflow)?.checked_div(prev_u).ok_or(CoffeeError::MathOverflow)?;
-    require!(delta_bps <= max_delta_bps as u128, CoffeeError::OraclePriceBandExceeded);
-    Ok(())
-}
+    // Fix the market's cash settlement price from the feed once settlement_ts has passed (anyone). With a
+    // dispute window set, cash settlement waits until the price is final
+    pub fn fix_settlement_price(ctx: Context<FixSettlementPrice>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &mut ctx.accounts.market;
+        require!(market.price_dispute_window_sec > 0, CoffeeError::PriceDisputeDisabled);
+        require!(market.settlement_price_per_kg == 0, CoffeeError::SettlementPriceAlreadyFixed);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= market.settlement_ts, CoffeeError::NotYetSettleTime);
 
-// ------------------------- Events -------------------------
-#[event]
-pub struct CftMintInitialized {
-    pub cft_mint: Pubkey,
-    pub authority: Pubkey,
-    pub decimals: u8,
-}
+        let price = SettlementStrategy::by_price_mode(market).market_price(market)?;
+        market.settlement_price_per_kg = price;
+        market.settlement_price_fixed_ts = now;
+        let window = i64::try_from(market.price_dispute_window_sec).unwrap_or(i64::MAX);
+        emit_event!(ctx, SettlementPriceFixed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            price_per_kg: price,
+            dispute_ends_ts: now.saturating_add(window),
+        });
+        Ok(())
+    }
 
-#[event]
-pub struct MarketCreated {
-    pub market: Pubkey,
-    pub authority: Pubkey,
-    pub cft_mint: Pubkey,
-    pub quote_mint: Pubkey,
-    pub settlement_ts: i64,
-}
+    // A counterparty disputes the fixed price within the window, posting market.price_challenge_bond.
+    // Settlement then waits for the arbiter's ruling; one challenge per market
+    pub fn challenge_settlement_price(ctx: Context<ChallengeSettlementPrice>, proposed_price_per_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_is_counterparty(&ctx.accounts.deal, &ctx.accounts.challenger)?;
+        require!(proposed_price_per_kg > 0, CoffeeError::ZeroPrice);
+        let market = &ctx.accounts.market;
+        require!(market.price_challengeable(Clock::get()?.unix_timestamp), CoffeeError::PriceChallengeClosed);
 
-#[event]
-pub struct PricePublished {
-    pub market: Pubkey,
+        let bond = market.price_challenge_bond;
+        if bond > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.challenger_quote_ata.to_account_info(),
+                        to: ctx.accounts.bond_vault.to_account_info(),
+                        authority: ctx.accounts.challenger.to_account_info(),
+                    },
+                ),
+                bond,
+            )?;
+        }
+
+        let challenge = &mut ctx.accounts.price_challenge;
+        challenge.market = market.key();
+        challenge.challenger = ctx.accounts.challenger.key();
+        challenge.proposed_price_per_kg = proposed_price_per_kg;
+        challenge.bond = bond;
+        challenge.bump = ctx.bumps.price_challenge;
+        ctx.accounts.market.settlement_price_challenged = true;
+        emit_event!(ctx, SettlementPriceChallenged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: ctx.accounts.market.key(),
+            challenger: ctx.accounts.challenger.key(),
+            fixed_price_per_kg: ctx.accounts.market.settlement_price_per_kg,
+            proposed_price_per_kg,
+            bond,
+        });
+        Ok(())
+    }
+
+    // Arbiter rules on the challenge; the price is final either way. Upheld: `price_per_kg` replaces the
+    // fixed price and the bond goes back. Rejected: the price stands and the bond goes to insurance
+    pub fn rule_settlement_price(ctx: Context<RuleSettlementPrice>, upheld: bool, price_per_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.arbiter.key(), Role::Arbiter, ctx.remaining_accounts)?;
+        let market = &ctx.accounts.market;
+        require!(market.settlement_price_challenged, CoffeeError::PriceChallengeClosed);
+        if upheld {
+            require!(price_per_kg > 0, CoffeeError::ZeroPrice);
+            require!(market.is_on_tick(price_per_kg), CoffeeError::PriceNotOnTick);
+        }
+
+        let bond = ctx.accounts.bond_vault.amount;
+        let bond_to = if upheld { &ctx.accounts.challenger_receive } else { &ctx.accounts.insurance_treasury };
+        transfer_from_price_challenge(
+            bond,
+            &ctx.accounts.price_challenge,
+            &ctx.accounts.bond_vault,
+            bond_to,
+            &ctx.accounts.token_program,
+        )?;
+
+        let market = &mut ctx.accounts.market;
+        if upheld {
+            market.settlement_price_per_kg = price_per_kg;
+        }
+        market.settlement_price_challenged = false;
+        market.settlement_price_ruled = true;
+        emit_event!(ctx, SettlementPriceRuled {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            arbiter: ctx.accounts.arbiter.key(),
+            challenger: ctx.accounts.price_challenge.challenger,
+            upheld,
+            price_per_kg: market.settlement_price_per_kg,
+            bond,
+        });
+        Ok(())
+    }
+
+    // Arbiter unwinds a deal at the agreed price (no PnL); each side pays its fee share and gets the rest back
+    pub fn settle_force_majeure(ctx: Context<SettleForceMajeure>, reason: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.arbiter.key(), Role::Arbiter, ctx.remaining_accounts)?;
+        require!(
+            reason >= ForceMajeureReason::CropFailure as u8 && reason <= ForceMajeureReason::Other as u8,
+            CoffeeError::InvalidReasonCode
+        );
+        // optional index evidence (e.g. a rainfall or yield feed for CropFailure), recorded in the event
+        let now = Clock::get()?.unix_timestamp;
+        let (evidence_feed, evidence_value) = match ctx.accounts.index_feed.as_ref() {
+            Some(feed) => (feed.key(), feed.fresh_value(now)?),
+            None => (Pubkey::default(), 0),
+        };
+        let market = &ctx.accounts.market;
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+        require!(!deal.omnibus, CoffeeError::OmnibusUnsupported);
+        check_margin_vaults(market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+        deal.start_settling();
+
+        // fees on the undelivered notional only, at the strategy price (agreed_price_per_kg)
+        let open_kg = deal.quantity_kg.checked_sub(deal.delivered_kg_total).ok_or(CoffeeError::MathOverflow)?;
+        let price = SettlementStrategy::ForceMajeure.price(market, deal)?;
+        let notional = market.notional(price, open_kg)?;
+        let fee_total = math::bps_mul_up(notional, market.fee_bps).ok_or(CoffeeError::MathOverflow)? as u64;
+        let farmer_fee = bps_of_u64(fee_total, market.farmer_fee_bps)?.min(ctx.accounts.farmer_margin_vault.amount);
+        let buyer_fee = bps_of_u64(fee_total, market.buyer_fee_bps)?.min(ctx.accounts.buyer_margin_vault.amount);
+        let legs = settlement::VaultLegs::new(
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.farmer_margin_vault,
+            &ctx.accounts.buyer_margin_vault,
+            &ctx.accounts.token_program,
+            deal_key,
+        );
+        legs.pay(math::Side::Short, farmer_fee, &ctx.accounts.fee_treasury)?;
+        legs.pay(math::Side::Long, buyer_fee, &ctx.accounts.fee_treasury)?;
+        let (sponsor_returned, sponsor_loss) = legs.return_sponsor_margin(deal.sponsored_margin, deal.farmer_own_margin, ctx.accounts.sponsor_receive.as_ref())?;
+        if deal.sponsored_margin > 0 {
+            emit_event!(ctx, SponsorMarginReturned {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                deal_id: deal.deal_id,
+                sponsor: deal.sponsor,
+                returned: sponsor_returned,
+                loss: sponsor_loss,
+            });
+        }
+
+        let farmer_refund = legs.available(math::Side::Short);
+        legs.pay(math::Side::Short, farmer_refund, &ctx.accounts.farmer_receive)?;
+        let buyer_refund = legs.available(math::Side::Long);
+        legs.pay(math::Side::Long, buyer_refund, &ctx.accounts.buyer_receive)?;
+        legs.check_drained()?;
+
+        if deal.prepaid {
+            refund_purchase_escrow(
+                deal,
+                ctx.accounts.purchase_escrow.as_mut(),
+                &ctx.accounts.vault_auth,
+                &ctx.accounts.buyer_receive,
+                &ctx.accounts.token_program,
+                &deal_key,
+            )?;
+        }
+
+        deal.force_majeure_reason = reason;
+        deal.record_fees(farmer_fee + buyer_fee)?;
+        deal.final_price_per_kg = price;
+        deal.mark_settled();
+        emit_event!(ctx, SettledForceMajeure {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            market: market.key(),
+            arbiter: ctx.accounts.arbiter.key(),
+            reason,
+            farmer_fee,
+            buyer_fee,
+            farmer_refund,
+            buyer_refund,
+            evidence_feed,
+            evidence_value,
+        });
+        ctx.accounts.market.release_open_interest(&ctx.accounts.deal);
+        Ok(())
+    }
+
+
+    // Buyer escrows the full purchase price of a physical deal before any delivery
+    pub fn fund_purchase_escrow(ctx: Context<FundPurchaseEscrow>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        require!(deal.physical_delivery, CoffeeError::WrongSettlementType);
+        require!(!deal.prepaid && deal.delivered_kg_total == 0, CoffeeError::EscrowAlreadyFunded);
+
+        let amount: u64 = ctx.accounts.market.notional(deal.agreed_price_per_kg, deal.quantity_kg)?
+            .try_into()
+            .map_err(|_| CoffeeError::MathOverflow)?;
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.buyer_from.to_account_info(),
+                    to: ctx.accounts.purchase_escrow.to_account_info(),
+                    authority: ctx.accounts.buyer.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        deal.prepaid = true;
+        deal.escrow_balance = amount;
+
+        emit_event!(ctx, PurchaseEscrowFunded { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, buyer: deal.buyer, amount });
+        Ok(())
+    }
+
+
+    // Choose how physical deliveries handle payout shortfalls (admin)
+    pub fn set_physical_payment_policy(ctx: Context<MarketAdmin>, policy: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(policy <= PaymentPolicy::RecordDebt as u8, CoffeeError::InvalidPaymentPolicy);
+        let market = &mut ctx.accounts.market;
+        market.physical_payment_policy = policy;
+        emit_event!(ctx, PaymentPolicyUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), policy });
+        Ok(())
+    }
+
+
+    // Waiting period before unpaid debts can be claimed from insurance (admin)
+    pub fn set_debt_claim_wait(ctx: Context<MarketAdmin>, wait_sec: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(wait_sec >= 0, CoffeeError::InvalidDebtConfig);
+        let market = &mut ctx.accounts.market;
+        market.debt_claim_wait_sec = wait_sec;
+        Ok(())
+    }
+
+    // Approve, resize or suspend a buyer's unsecured credit (admin). Each deal opened with the line may
+    // leave `margin_discount_bps` of the buyer's initial margin unposted, up to `limit` across open deals;
+    // drawing stops while the line is inactive or its defaults exceed `max_defaults`
+    pub fn set_credit_line(
+        ctx: Context<SetCreditLine>,
+        buyer: Pubkey,
+        limit: u64,
+        margin_discount_bps: u16,
+        max_defaults: u32,
+        active: bool,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require_gte!(MAX_BPS, margin_discount_bps, CoffeeError::InvalidBps);
+        let line = &mut ctx.accounts.credit_line;
+        if line.buyer == Pubkey::default() {
+            line.market = ctx.accounts.market.key();
+            line.buyer = buyer;
+            line.bump = ctx.bumps.credit_line;
+        }
+        // lowering the limit below what is drawn just stops new draws
+        line.limit = limit;
+        line.margin_discount_bps = margin_discount_bps;
+        line.max_defaults = max_defaults;
+        line.active = active;
+        emit_event!(ctx, CreditLineUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: line.market,
+            buyer,
+            limit,
+            margin_discount_bps,
+            max_defaults,
+            active,
+        });
+        Ok(())
+    }
+
+    // Materialize a settled deal's recorded shortfall into a Debt account (anyone may pay rent)
+    pub fn open_debt(ctx: Context<OpenDebt>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let deal = &mut ctx.accounts.deal;
+        require!(deal.unpaid_owed > 0, CoffeeError::NoDebt);
+        require!(!deal.debt_opened, CoffeeError::DebtAlreadyOpened);
+        let now = Clock::get()?.unix_timestamp;
+
+        let debt = &mut ctx.accounts.debt;
+        debt.market = deal.market;
+        debt.deal = deal.key();
+        debt.creditor = deal.debt_creditor;
+        debt.debtor = deal.party(deal.side_of(&deal.debt_creditor).ok_or(CoffeeError::InvalidCounterparty)?.opposite());
+        debt.amount_owed = deal.unpaid_owed;
+        debt.amount_repaid = 0;
+        debt.insurance_paid = 0;
+        debt.created_ts = now;
+        debt.claimable_after_ts = now.checked_add(ctx.accounts.market.debt_claim_wait_sec).ok_or(CoffeeError::MathOverflow)?;
+        debt.bump = ctx.bumps.debt;
+        debt.socialized = false;
+        deal.debt_opened = true;
+
+        emit_event!(ctx, DebtOpened {
+            schema_version: EVENT_SCHEMA_VERSION,
+            debt: debt.key(),
+            deal: debt.deal,
+            deal_id: deal.deal_id,
+            debtor: debt.debtor,
+            creditor: debt.creditor,
+            amount: debt.amount_owed,
+            claimable_after_ts: debt.claimable_after_ts,
+        });
+        Ok(())
+    }
+
+    // Debtor pays down an open debt directly to the creditor
+    pub fn repay_debt(ctx: Context<RepayDebt>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let debt = &mut ctx.accounts.debt;
+        let outstanding = debt.outstanding();
+        require!(outstanding > 0, CoffeeError::NoDebt);
+        let amount = amount.min(outstanding);
+        require!(amount > 0, CoffeeError::ZeroAmount);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.debtor_from.to_account_info(),
+                    to: ctx.accounts.creditor_receive.to_account_info(),
+                    authority: ctx.accounts.debtor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        debt.amount_repaid = debt.amount_repaid.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+
+        emit_event!(ctx, DebtRepaid { schema_version: EVENT_SCHEMA_VERSION, debt: debt.key(), amount, outstanding: debt.outstanding() });
+        Ok(())
+    }
+
+    // After the waiting period the creditor is made whole from the insurance treasury (its owner co-signs)
+    pub fn claim_debt_from_insurance(ctx: Context<ClaimDebtFromInsurance>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let debt = &mut ctx.accounts.debt;
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= debt.claimable_after_ts, CoffeeError::DebtClaimNotYetAllowed);
+
+        // first loss: staked capital absorbs the shortfall before the treasury is touched
+        let staked = insurance_pool_accounts(
+            &ctx.accounts.market,
+            ctx.accounts.insurance_pool.as_ref(),
+            ctx.accounts.insurance_stake_vault.as_ref(),
+        )?;
+        let from_stakers = match staked {
+            Some((pool, vault)) => {
+                let amount = debt.outstanding().min(vault.amount);
+                transfer_from_insurance_pool(amount, pool, vault, &ctx.accounts.creditor_receive, &ctx.accounts.token_program)?;
+                amount
+            }
+            None => 0,
+        };
+        debt.insurance_paid = debt.insurance_paid.checked_add(from_stakers).ok_or(CoffeeError::MathOverflow)?;
+
+        let from_treasury = debt.outstanding().min(ctx.accounts.insurance_treasury.amount);
+        require!(from_stakers > 0 || from_treasury > 0, CoffeeError::NoDebt);
+        if from_treasury > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.insurance_treasury.to_account_info(),
+                        to: ctx.accounts.creditor_receive.to_account_info(),
+                        authority: ctx.accounts.insurance_authority.to_account_info(),
+                    },
+                ),
+                from_treasury,
+            )?;
+            debt.insurance_paid = debt.insurance_paid.checked_add(from_treasury).ok_or(CoffeeError::MathOverflow)?;
+        }
+
+        if from_stakers > 0 {
+            let pool = ctx.accounts.insurance_pool.as_mut().ok_or(CoffeeError::InsurancePoolMissing)?;
+            pool.total_losses = pool.total_losses.checked_add(from_stakers).ok_or(CoffeeError::MathOverflow)?;
+            emit_event!(ctx, InsuranceLossAbsorbed { schema_version: EVENT_SCHEMA_VERSION, insurance_pool: pool.key(), debt: debt.key(), amount: from_stakers, total_shares: pool.total_shares });
+        }
+        let amount = from_stakers + from_treasury;
+        emit_event!(ctx, DebtClaimedFromInsurance { schema_version: EVENT_SCHEMA_VERSION, debt: debt.key(), amount, outstanding: debt.outstanding() });
+        Ok(())
+    }
+
+
+    // Insurance can't cover a matured debt: spread it over the market's open interest. Winning deals
+    // settled afterwards pay their share into the insurance treasury, from which the creditor claims.
+    pub fn socialize_debt(ctx: Context<SocializeDebt>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let debt = &mut ctx.accounts.debt;
+        require!(!debt.socialized, CoffeeError::DebtAlreadySocialized);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= debt.claimable_after_ts, CoffeeError::DebtClaimNotYetAllowed);
+        let outstanding = debt.outstanding();
+        require!(outstanding > 0, CoffeeError::NoDebt);
+        // staked first-loss capital counts toward what insurance can still cover
+        let staked = insurance_pool_accounts(
+            &ctx.accounts.market,
+            ctx.accounts.insurance_pool.as_ref(),
+            ctx.accounts.insurance_stake_vault.as_ref(),
+        )?
+        .map_or(0, |(_, vault)| vault.amount);
+        let uncovered = outstanding
+            .saturating_sub(ctx.accounts.insurance_treasury.amount)
+            .saturating_sub(staked);
+        require!(uncovered > 0, CoffeeError::InsuranceCanCover);
+
+        let market = &mut ctx.accounts.market;
+        let index_delta = market.socialize_loss(uncovered)?;
+        debt.socialized = true;
+
+        emit_event!(ctx, LossSocialized {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            debt: debt.key(),
+            amount: uncovered,
+            index_delta,
+            loss_socialization_index: market.loss_socialization_index,
+            total_open_notional_kg: market.total_open_notional_kg,
+        });
+        Ok(())
+    }
+
+
+    // Market-wide exposure caps checked at open_deal (admin); 0 disables a cap
+    pub fn set_open_interest_caps(ctx: Context<MarketAdmin>, max_open_deals: u64, max_open_notional_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.max_open_deals = max_open_deals;
+        market.max_open_notional_kg = max_open_notional_kg;
+        emit_event!(ctx, OpenInterestCapsUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), max_open_deals, max_open_notional_kg });
+        Ok(())
+    }
+
+
+    // Decimal exponent between prices and the quote mint (admin): price_per_kg * kg * 10^price_exponent
+    // is an amount in quote base units. Only on an empty market, since live deals were margined at the old one
+    pub fn set_price_exponent(ctx: Context<MarketAdmin>, price_exponent: i8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(price_exponent.unsigned_abs() <= MAX_PRICE_EXPONENT as u8, CoffeeError::InvalidPriceExponent);
+        let market = &mut ctx.accounts.market;
+        require!(market.total_open_deals == 0, CoffeeError::MarketHasOpenDeals);
+        market.price_exponent = price_exponent;
+        emit_event!(ctx, PriceExponentSet { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), price_exponent });
+        Ok(())
+    }
+
+    // Convert an empty market to a perpetual: no settlement_ts, hourly funding instead (admin)
+    pub fn make_perpetual(ctx: Context<MarketAdmin>, funding_cap_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(funding_cap_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        require!(market.total_open_deals == 0, CoffeeError::MarketHasOpenDeals);
+        market.market_kind = MarketKind::Perpetual as u8;
+        market.settlement_ts = i64::MAX; // perp deals close at their own deadline_ts
+        market.funding_cap_bps = funding_cap_bps;
+        market.last_funding_ts = Clock::get()?.unix_timestamp;
+        emit_event!(ctx, MarketMadePerpetual { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), funding_cap_bps });
+        Ok(())
+    }
+
+    // Permissionless crank: accrue hourly funding on the market and move the deal's share
+    // between the long's and the short's margin vaults
+    pub fn apply_funding(ctx: Context<ApplyFunding>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(ctx.accounts.market.market_kind == MarketKind::Perpetual as u8, CoffeeError::NotPerpetual);
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.market.accrue_funding(now)?;
+
+        let deal_key = ctx.accounts.deal.key();
+        let cumulative = ctx.accounts.market.cumulative_funding_per_kg;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        check_margin_vaults(&ctx.accounts.market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+        let owed_long = ctx.accounts.market.to_quote_signed(deal.funding_owed_long(cumulative)?)?;
+        deal.funding_checkpoint = cumulative;
+        // what the buyer's vault owes the farmer's: the long's funding, from whichever slot holds it
+        let owed = if deal.position(math::Side::Long) == math::Side::Long { owed_long } else { -owed_long };
+
+        // pay what the payer's vault holds; a drained vault surfaces through mark_to_market
+        let paid: u64 = if owed > 0 {
+            let amt = (owed.min(u64::MAX as i128) as u64).min(ctx.accounts.buyer_margin_vault.amount);
+            transfer_from_vault_to(
+                amt,
+                &ctx.accounts.vault_auth,
+                &ctx.accounts.buyer_margin_vault,
+                &ctx.accounts.farmer_margin_vault,
+                &ctx.accounts.token_program,
+                &deal_key,
+            )?;
+            amt
+        } else if owed < 0 {
+            let amt = ((-owed).min(u64::MAX as i128) as u64).min(ctx.accounts.farmer_margin_vault.amount);
+            transfer_from_vault_to(
+                amt,
+                &ctx.accounts.vault_auth,
+                &ctx.accounts.farmer_margin_vault,
+                &ctx.accounts.buyer_margin_vault,
+                &ctx.accounts.token_program,
+                &deal_key,
+            )?;
+            amt
+        } else {
+            0
+        };
+        if owed != 0 {
+            let winner = if owed > 0 { math::Side::Short } else { math::Side::Long };
+            deal.record_realized_pnl(winner, paid, paid)?;
+        }
+        let signed_paid = if owed_long < 0 { -(paid as i64) } else { paid as i64 };
+        deal.funding_paid_long = deal.funding_paid_long.checked_add(signed_paid).ok_or(CoffeeError::MathOverflow)?;
+
+        emit_event!(ctx, FundingApplied {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            market: ctx.accounts.market.key(),
+            cumulative_funding_per_kg: cumulative,
+            owed_long: owed_long as i64,
+            paid: signed_paid,
+        });
+
+        ctx.accounts.farmer_margin_vault.reload()?;
+        ctx.accounts.buyer_margin_vault.reload()?;
+        let (farmer_vault, buyer_vault) = (ctx.accounts.farmer_margin_vault.amount, ctx.accounts.buyer_margin_vault.amount);
+        if let Some(update) = refresh_health(&ctx.accounts.market, &mut ctx.accounts.deal, farmer_vault, buyer_vault)? {
+            emit_event!(ctx, update);
+        }
+        Ok(())
+    }
+
+
+    // ----- read-only views: values come back through return data (Anchor calls set_return_data) -----
+
+    // Initial margin each side posts for a deal of this size
+    pub fn view_required_margin(ctx: Context<ViewMarket>, price_per_kg: u64, quantity_kg: u64) -> Result<u64> {
+        let initial_margin_bps = ctx.accounts.market.initial_margin_bps_at(Clock::get()?.unix_timestamp);
+        ctx.accounts.market.required_margin(price_per_kg, quantity_kg, initial_margin_bps)
+    }
+
+    // Buyer (long) PnL at the current mark price; the farmer's is the negation
+    pub fn view_mark_pnl(ctx: Context<ViewDeal>) -> Result<i64> {
+        let deal = &ctx.accounts.deal;
+        let price = mark_price(&ctx.accounts.market)?;
+        let pnl_long = deal.slot_pnl(&ctx.accounts.market, price, deal.quantity_kg, math::Side::Long)?;
+        Ok(pnl_long.try_into().map_err(|_| CoffeeError::MathOverflow)?)
+    }
+
+    // What settle_cash would do right now (before vault balance caps)
+    pub fn view_settlement_preview(ctx: Context<ViewSettlement>) -> Result<SettlementPreview> {
+        let market = &ctx.accounts.market;
+        let deal = &ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        let price = SettlementStrategy::cash_for(market, deal).price(market, deal)?;
+        let pnl_long = deal.slot_pnl(market, price, deal.quantity_kg, math::Side::Long)?;
+
+        let notional = market.notional(deal.agreed_price_per_kg, deal.quantity_kg)?;
+        let tier_volume = ctx.accounts.farmer_stats.as_ref().map_or(0, |s| s.rolling_notional)
+            .max(ctx.accounts.buyer_stats.as_ref().map_or(0, |s| s.rolling_notional));
+        let fees = fee_split(deal.fee_bearing_notional(notional)?, market.fee_bps_for_volume(tier_volume), market)?;
+
+        // uncapped by vault balances
+        let pnl = settlement::plan_pnl(pnl_long, u64::MAX, deal.adl_haircut_due(market.loss_socialization_index)?)?;
+
+        Ok(SettlementPreview {
+            price,
+            pnl_long: pnl_long.try_into().map_err(|_| CoffeeError::MathOverflow)?,
+            fee_total: fees.fee_total,
+            farmer_fee: fees.farmer_cut.checked_add(fees.protocol_cut).ok_or(CoffeeError::MathOverflow)?,
+            buyer_fee: fees.buyer_cut,
+            insurance_cut: if deal.insurance_prepaid > 0 { 0 } else { fees.insurance_cut },
+            adl_haircut: pnl.haircut,
+            winner_payout: pnl.paid_to_winner(),
+        })
+    }
+
+    // Dry run of open_deal's checks for wallets: nothing is created or moved, and every failing check
+    // is reported (not just the first) with the margin and premium each side must fund. Balances are
+    // only checked for the funding accounts passed in
+    #[allow(clippy::too_many_arguments)]
+    pub fn validate_deal_terms(
+        ctx: Context<ValidateDealTerms>,
+        agreed_price_per_kg: u64,
+        quantity_kg: u64,
+        physical_delivery: bool,
+        deadline_ts: i64,
+        assets: Vec<Pubkey>,
+        asset_qty: Vec<u64>,
+    ) -> Result<DealTermsCheck> {
+        let market = &ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let mut check = DealTermsCheck::default();
+
+        check.record(
+            DealCheck::MarketOpen,
+            check_that(!market.paused, CoffeeError::MarketPaused)
+                .and(check_that(market.close_after_ts == 0, CoffeeError::MarketClosing))
+                .and(check_that(market.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated)),
+        );
+        check.record(
+            DealCheck::OracleFresh,
+            check_that(!market.require_fresh_price_for_open || market.oracle_is_fresh(now), CoffeeError::OracleStale),
+        );
+        check.record(
+            DealCheck::Price,
+            check_that(agreed_price_per_kg > 0, CoffeeError::ZeroPrice)
+                .and(check_that(market.is_on_tick(agreed_price_per_kg), CoffeeError::PriceNotOnTick)),
+        );
+        if market.max_deal_price_deviation_bps > 0 {
+            let deviation = mark_price(market)
+                .and_then(|mark| market.price_band_bps(mark, agreed_price_per_kg));
+            check.record(
+                DealCheck::PriceDeviation,
+                deviation.and_then(|d| check_that(d <= market.max_deal_price_deviation_bps as u128, CoffeeError::DealPriceDeviationExceeded)),
+            );
+        }
+        // open_deal checks the basket between the zero-quantity check and the size limits
+        check.record(DealCheck::Quantity, check_that(quantity_kg > 0, CoffeeError::ZeroQty));
+        check.record(
+            DealCheck::Basket,
+            check_that(assets.len() == asset_qty.len(), CoffeeError::InvalidAssetBasket)
+                .and(check_that(assets.len() <= MAX_ASSETS, CoffeeError::TooManyAssets))
+                .and_then(|_| validate_basket(market, ctx.accounts.approved_assets.as_ref(), &assets, &asset_qty, quantity_kg).map(|_| ())),
+        );
+        check.record(
+            DealCheck::Quantity,
+            check_that(quantity_kg <= market.max_qty_per_deal, CoffeeError::DealQtyExceedsLimit).and(check_that(
+                !market.whole_contracts_only || market.contracts_for_qty(quantity_kg).is_some(),
+                CoffeeError::NotWholeContracts,
+            )),
+        );
+
+        let notional = market.notional(agreed_price_per_kg, quantity_kg)?;
+        check.record(
+            DealCheck::NotionalCap,
+            check_that(notional <= market.max_notional_per_deal as u128, CoffeeError::DealNotionalExceedsLimit),
+        );
+        check.risk_cosign_required = market.requires_risk_cosign(notional);
+
+        if market.sample_required || ctx.accounts.sample.is_some() {
+            let sample = ctx.accounts.sample.as_ref();
+            check.record(
+                DealCheck::Sample,
+                check_that(sample.map_or(false, |s| s.approved), CoffeeError::SampleApprovalRequired)
+                    .and(check_that(!sample.map_or(false, |s| s.lapsed(now)), CoffeeError::SampleWindowLapsed)),
+            );
+        }
+        if let Some(template) = ctx.accounts.template.as_ref() {
+            check.record(
+                DealCheck::Template,
+                check_that(template.active, CoffeeError::TemplateInactive).and(check_that(
+                    template.quantity_kg == quantity_kg
+                        && template.physical_delivery == physical_delivery
+                        && template.delivery_end_ts == deadline_ts,
+                    CoffeeError::TemplateTermsMismatch,
+                )),
+            );
+        }
+        check.record(DealCheck::DealExists, check_that(ctx.accounts.deal.data_is_empty(), CoffeeError::DealAlreadyExists));
+        check.record(DealCheck::OpenInterest, market.open_interest_after(quantity_kg).map(|_| ()));
+
+        // what each side's funding account must hold: margin plus its half of an up-front premium
+        let initial_margin_bps = market.initial_margin_bps_at(now);
+        check.required_margin = market.required_margin(agreed_price_per_kg, quantity_kg, initial_margin_bps)?;
+        let mut fee_waiver_bps = 0;
+        if let Some(waiver) = ctx.accounts.fee_waiver.as_ref() {
+            let discount = waiver.discount_for(&ctx.accounts.farmer.key(), &ctx.accounts.buyer.key(), now);
+            fee_waiver_bps = *discount.as_ref().unwrap_or(&0);
+            check.record(DealCheck::FeeWaiver, discount.map(|_| ()));
+        }
+        check.insurance_premium = upfront_premium(market, notional, fee_waiver_bps)?;
+        let farmer_premium = check.insurance_premium / 2;
+        let buyer_premium = check.insurance_premium - farmer_premium;
+        if let Some(from) = ctx.accounts.farmer_margin_from.as_ref() {
+            check.farmer_shortfall = check.required_margin.saturating_add(farmer_premium).saturating_sub(from.amount);
+            check.record(DealCheck::FarmerBalance, check_that(check.farmer_shortfall == 0, CoffeeError::InsufficientMarginBalance));
+        }
+        if let Some(from) = ctx.accounts.buyer_margin_from.as_ref() {
+            check.buyer_shortfall = check.required_margin.saturating_add(buyer_premium).saturating_sub(from.amount);
+            check.record(DealCheck::BuyerBalance, check_that(check.buyer_shortfall == 0, CoffeeError::InsufficientMarginBalance));
+        }
+        Ok(check)
+    }
+
+
+    // Same as open_deal with size given in standardized contracts (quantity_kg = num_contracts * contract_size_kg)
+    pub fn open_deal_contracts(
+        ctx: Context<OpenDeal>,
+        agreed_price_per_kg: u64,
+        num_contracts: u64,
+        physical_delivery: bool,
+        deadline_ts: i64,
+        assets: Vec<Pubkey>,
+        asset_qty: Vec<u64>,
+        merkle_root: Option<[u8; 32]>,
+        referrer: Option<Pubkey>,
+        fee_split_bps: Option<u16>,
+        margin_override: Option<MarginOverride>,
+        farmer_side: u8,
+    ) -> Result<()> {
+        require!(num_contracts > 0, CoffeeError::ZeroQty);
+        let quantity_kg = num_contracts
+            .checked_mul(ctx.accounts.market.contract_size_kg)
+            .ok_or(CoffeeError::MathOverflow)?;
+        open_deal(
+            ctx,
+            agreed_price_per_kg,
+            quantity_kg,
+            physical_delivery,
+            deadline_ts,
+            assets,
+            asset_qty,
+            merkle_root,
+            referrer,
+            fee_split_bps,
+            margin_override,
+            farmer_side,
+        )
+    }
+
+    // Open a deal from a DealTemplate: only the price is negotiated; physical templates deliver CFT
+    pub fn open_deal_from_template(ctx: Context<OpenDeal>, agreed_price_per_kg: u64) -> Result<()> {
+        let template = ctx.accounts.template.as_ref().ok_or(CoffeeError::TemplateMissing)?;
+        let (quantity_kg, physical_delivery, deadline_ts) = (template.quantity_kg, template.physical_delivery, template.delivery_end_ts);
+        let (assets, asset_qty) = if physical_delivery {
+            (vec![ctx.accounts.market.cft_mint], vec![quantity_kg])
+        } else {
+            (vec![], vec![])
+        };
+        open_deal(
+            ctx,
+            agreed_price_per_kg,
+            quantity_kg,
+            physical_delivery,
+            deadline_ts,
+            assets,
+            asset_qty,
+            None,
+            None,
+            None,
+            None,
+            OrderSide::Sell as u8,
+        )
+    }
+
+    // Require plain open_deal sizes to be whole contracts (admin)
+    pub fn set_whole_contracts_only(ctx: Context<MarketAdmin>, whole_contracts_only: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.whole_contracts_only = whole_contracts_only;
+        emit_event!(ctx, ContractSizingUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), contract_size_kg: market.contract_size_kg, whole_contracts_only });
+        Ok(())
+    }
+
+
+    // Minimum price increment for deal and oracle prices (admin); 0 disables
+    pub fn set_tick_size(ctx: Context<MarketAdmin>, tick_size: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.tick_size = tick_size;
+        emit_event!(ctx, TickSizeUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), tick_size });
+        Ok(())
+    }
+
+    // Quoting convention (admin). tick_size is in the convention, so it is set alongside it; for
+    // CentsPerLb `price_units_per_cent` ties per-kg prices to US cents
+    pub fn set_price_convention(ctx: Context<MarketAdmin>, convention: u8, price_units_per_cent: u64, tick_size: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(
+            convention == PriceConvention::PerKg as u8 || (convention == PriceConvention::CentsPerLb as u8 && price_units_per_cent > 0),
+            CoffeeError::InvalidPriceConvention
+        );
+        let market = &mut ctx.accounts.market;
+        market.price_convention = convention;
+        market.price_units_per_cent = price_units_per_cent;
+        market.tick_size = tick_size;
+        emit_event!(ctx, PriceConventionSet {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            convention,
+            price_units_per_cent,
+            tick_size,
+        });
+        Ok(())
+    }
+
+
+    // Start decommissioning: no new deals, and close_market becomes possible after the timelock (admin)
+    pub fn schedule_market_close(ctx: Context<MarketAdmin>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(market.close_after_ts == 0, CoffeeError::MarketClosing);
+        market.close_after_ts = Clock::get()?
+            .unix_timestamp
+            .checked_add(MARKET_CLOSE_TIMELOCK_SEC)
+            .ok_or(CoffeeError::MathOverflow)?;
+        emit_event!(ctx, MarketCloseScheduled { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), close_after_ts: market.close_after_ts });
+        Ok(())
+    }
+
+    // Close a decommissioned market once every deal is settled and closed. Any insurance balance is
+    // swept to `sweep_destination` (the treasury owner co-signs); the Market rent goes to `receiver`.
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &ctx.accounts.market;
+        require!(market.close_after_ts > 0, CoffeeError::MarketCloseNotScheduled);
+        require!(Clock::get()?.unix_timestamp >= market.close_after_ts, CoffeeError::TimelockNotElapsed);
+        require!(market.total_open_deals == 0 && market.unclosed_deals == 0, CoffeeError::MarketHasOpenDeals);
+
+        let swept = ctx.accounts.insurance_treasury.amount;
+        if swept > 0 {
+            let owner = ctx.accounts.insurance_authority.as_ref().ok_or(CoffeeError::SweepAccountsMissing)?;
+            let dest = ctx.accounts.sweep_destination.as_ref().ok_or(CoffeeError::SweepAccountsMissing)?;
+            require_keys_eq!(ctx.accounts.insurance_treasury.owner, owner.key(), CoffeeError::Unauthorized);
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.insurance_treasury.to_account_info(),
+                        to: dest.to_account_info(),
+                        authority: owner.to_account_info(),
+                    },
+                ),
+                swept,
+            )?;
+        }
+
+        emit_event!(ctx, MarketClosed { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), insurance_swept: swept, receiver: ctx.accounts.receiver.key() });
+        Ok(())
+    }
+
+    // Re-home a live deal under the v2 seeds, which add the deal id so one farmer/buyer pair can hold
+    // several deals on a market (admin). State is copied as is; the margin vault balance moves to the
+    // new vault_auth's ATA and the v1 deal, vault_auth and vault are closed to the buyer, who paid their
+    // rent. Deals with a purchase escrow, debt, cross-margin link or a settlement under way finish or
+    // unwind first; crop policies and delivery records keyed by the old address are not carried over
+    pub fn migrate_deal_v2(ctx: Context<MigrateDealV2>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let old = &ctx.accounts.old_deal;
+        require!(!old.settled && !old.settling, CoffeeError::DealNotMigratable);
+        require!(!old.omnibus, CoffeeError::OmnibusUnsupported);
+        require!(!old.prepaid && !old.debt_opened && !old.is_cross_margined(), CoffeeError::DealNotMigratable);
+
+        let old_key = old.key();
+        transfer_from_vault_to(
+            ctx.accounts.old_vault.amount,
+            &ctx.accounts.old_vault_auth,
+            &ctx.accounts.old_vault,
+            &ctx.accounts.new_vault,
+            &ctx.accounts.token_program,
+            &old_key,
+        )?;
+        // a buyer vault of its own moves to the same place under the new deal
+        let old_buyer_vault = own_buyer_vault(old, &ctx.accounts.old_buyer_vault)?;
+        if let Some(old_buyer_vault) = old_buyer_vault.as_ref() {
+            let new_key = ctx.accounts.new_deal.key();
+            create_pda_account(
+                &ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.new_buyer_vault,
+                TokenAccount::LEN,
+                &[SEED_PREFIX, BUYER_VAULT_SEED, new_key.as_ref(), &[ctx.bumps.new_buyer_vault]],
+                &ctx.accounts.system_program,
+                &token::ID,
+            )?;
+            token::initialize_account3(CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::InitializeAccount3 {
+                    account: ctx.accounts.new_buyer_vault.to_account_info(),
+                    mint: ctx.accounts.quote_mint.to_account_info(),
+                    authority: ctx.accounts.new_vault_auth.to_account_info(),
+                },
+            ))?;
+            let new_buyer_vault: Account<TokenAccount> = Account::try_from(&ctx.accounts.new_buyer_vault)?;
+            transfer_from_vault_to(
+                old_buyer_vault.amount,
+                &ctx.accounts.old_vault_auth,
+                old_buyer_vault,
+                &new_buyer_vault,
+                &ctx.accounts.token_program,
+                &old_key,
+            )?;
+        }
+        let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, VAULT_AUTH_SEED, old_key.as_ref(), &[ctx.accounts.old_vault_auth.bump]]];
+        for vault in std::iter::once(&ctx.accounts.old_vault).chain(old_buyer_vault.as_ref()) {
+            token::close_account(CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                CloseAccount {
+                    account: vault.to_account_info(),
+                    destination: ctx.accounts.buyer.to_account_info(),
+                    authority: ctx.accounts.old_vault_auth.to_account_info(),
+                },
+                seeds,
+            ))?;
+        }
+
+        ctx.accounts.new_vault_auth.bump = ctx.bumps.new_vault_auth;
+        let mut state = (**old).clone();
+        state.seed_version = 2;
+        let deal_id = state.deal_id;
+        ctx.accounts.new_deal.set_inner(state);
+        emit_event!(ctx, DealMigrated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: ctx.accounts.new_deal.key(),
+            deal_id,
+            old_deal: old_key,
+            seed_version: 2,
+        });
+        Ok(())
+    }
+
+    // Re-create a market at its v2 address, [v2, market, authority, cft_mint, quote_mint] from the
+    // current authority, with configuration and counters copied (admin). Deals are keyed to their
+    // market's address, so only a market with no unclosed deals moves. The v1 market is paused and
+    // points at its successor; market-scoped accounts (access list, approved assets, trader stats,
+    // pools) stay on the v1 address until re-created
+    pub fn migrate_market_v2(ctx: Context<MigrateMarketV2>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let old = &ctx.accounts.market;
+        require!(old.migrated_to == Pubkey::default(), CoffeeError::MarketMigrated);
+        require!(old.total_open_deals == 0 && old.unclosed_deals == 0, CoffeeError::MarketHasOpenDeals);
+
+        let mut state = (**old).clone();
+        state.seed_version = 2;
+        ctx.accounts.new_market.set_inner(state);
+        let new_market = ctx.accounts.new_market.key();
+        let market = &mut ctx.accounts.market;
+        market.paused = true;
+        market.migrated_to = new_market;
+        emit_event!(ctx, MarketMigrated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), new_market, seed_version: 2 });
+        Ok(())
+    }
+
+    // Grow a Market created under an older layout to the current size so fields appended by an upgrade
+    // are readable; they start zeroed. Market can't be deserialized before this, so the account is taken
+    // raw and checked here (owner via the constraint, discriminator, master authority at its fixed
+    // offset). The payer tops up rent. Growth per call is capped by the runtime, so very old accounts
+    // may need more than one call; at full size it's a no-op
+    pub fn resize_market(ctx: Context<ResizeMarket>) -> Result<()> {
+        let info = ctx.accounts.market.to_account_info();
+        let old_len = info.data_len();
+        {
+            let data = info.try_borrow_data()?;
+            require!(
+                old_len >= MARKET_AUTHORITY_OFFSET + 32 && data[..8] == <Market as anchor_lang::Discriminator>::DISCRIMINATOR,
+                CoffeeError::InvalidMarketAccount
+            );
+            let authority = Pubkey::try_from(&data[MARKET_AUTHORITY_OFFSET..MARKET_AUTHORITY_OFFSET + 32])
+                .map_err(|_| CoffeeError::InvalidMarketAccount)?;
+            require_keys_eq!(authority, ctx.accounts.authority.key(), CoffeeError::Unauthorized);
+        }
+        let new_len = market_resize_target(old_len);
+        if new_len == old_len {
+            return Ok(());
+        }
+
+        let rent_due = Rent::get()?.minimum_balance(new_len).saturating_sub(info.lamports());
+        if rent_due > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.payer.to_account_info(),
+                        to: info.clone(),
+                    },
+                ),
+                rent_due,
+            )?;
+        }
+        info.realloc(new_len, true)?;
+        emit_event!(ctx, MarketResized {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: info.key(),
+            old_len: old_len as u32,
+            new_len: new_len as u32,
+        });
+        Ok(())
+    }
+
+
+    // Dispute window after fix_settlement_price and the bond a challenge posts (admin); window 0 turns
+    // disputes off. Fixed before the price is
+    pub fn set_price_dispute_policy(ctx: Context<MarketAdmin>, window_sec: u64, challenge_bond: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        require!(market.settlement_price_per_kg == 0, CoffeeError::SettlementPriceAlreadyFixed);
+        market.price_dispute_window_sec = window_sec;
+        market.price_challenge_bond = challenge_bond;
+        emit_event!(ctx, PriceDisputePolicyUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), window_sec, challenge_bond });
+        Ok(())
+    }
+
+    // How long after a deal falls due anyone may still cash-settle it (admin); 0 = no limit. After that
+    // only arbiter_settle_cash settles it
+    pub fn set_settlement_window(ctx: Context<MarketAdmin>, settlement_window_sec: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.settlement_window_sec = settlement_window_sec;
+        emit_event!(ctx, SettlementWindowUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), settlement_window_sec });
+        Ok(())
+    }
+
+    // Reject new deals while the oracle is stale (admin)
+    pub fn set_require_fresh_price_for_open(ctx: Context<MarketAdmin>, required: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.require_fresh_price_for_open = required;
+        emit_event!(ctx, FreshPriceRequirementUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), required });
+        Ok(())
+    }
+
+
+    // Max distance of an agreed deal price from the oracle mark, in bps (admin); 0 disables
+    pub fn set_max_deal_price_deviation(ctx: Context<MarketAdmin>, max_deviation_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.max_deal_price_deviation_bps = max_deviation_bps;
+        emit_event!(ctx, DealPriceDeviationUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), max_deviation_bps });
+        Ok(())
+    }
+
+    // Require a risk officer co-signature on open_deal above `large_deal_notional` (admin); 0 disables
+    pub fn set_large_deal_cosign(ctx: Context<MarketAdmin>, risk_officer: Pubkey, large_deal_notional: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(large_deal_notional == 0 || risk_officer != Pubkey::default(), CoffeeError::InvalidRiskCosignConfig);
+        let market = &mut ctx.accounts.market;
+        market.risk_officer = risk_officer;
+        market.large_deal_notional = large_deal_notional;
+        emit_event!(ctx, LargeDealCosignUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), risk_officer, large_deal_notional });
+        Ok(())
+    }
+
+    // Break fee a party pays the other to reduce a deal without its co-signature (admin); 0 disables
+    pub fn set_reduce_break_fee(ctx: Context<MarketAdmin>, break_fee_bps: u16) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(break_fee_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        let market = &mut ctx.accounts.market;
+        market.reduce_break_fee_bps = break_fee_bps;
+        emit_event!(ctx, ReduceBreakFeeUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), break_fee_bps });
+        Ok(())
+    }
+
+    // Offer CFT payouts on cash settlement; the buyer's converted quote goes to `treasury` (admin).
+    // Pubkey::default() withdraws the offer and elected deals settle in quote again
+    pub fn set_cft_sale_treasury(ctx: Context<MarketAdmin>, treasury: Pubkey) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.cft_sale_treasury = treasury;
+        emit_event!(ctx, CftSaleTreasuryUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), treasury });
+        Ok(())
+    }
+
+    // Give this market's CFT an expiry (e.g. this harvest only) and the floor price it redeems at from
+    // the insurance fund afterwards (admin); 0 clears it. Fixed once the expiry has passed.
+    pub fn set_cft_expiry(ctx: Context<MarketAdmin>, expiry_ts: i64, floor_price_per_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let now = Clock::get()?.unix_timestamp;
+        let market = &mut ctx.accounts.market;
+        require!(!market.cft_expired(now), CoffeeError::CftExpired);
+        require!(expiry_ts == 0 || (expiry_ts > now && floor_price_per_kg > 0), CoffeeError::InvalidCftExpiry);
+        market.cft_expiry_ts = expiry_ts;
+        market.cft_floor_price_per_kg = floor_price_per_kg;
+        emit_event!(ctx, CftExpiryUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), expiry_ts, floor_price_per_kg });
+        Ok(())
+    }
+
+    // Require an approved sample before open_deal on this market (admin)
+    pub fn set_sample_required(ctx: Context<MarketAdmin>, required: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.sample_required = required;
+        emit_event!(ctx, SampleRequirementUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), required });
+        Ok(())
+    }
+
+    // Share of a prepaid deal's purchase escrow released to the farmer at each shipment milestone,
+    // AtMill..CustomsCleared (admin); the tranches may not exceed the whole escrow
+    pub fn set_shipment_tranches(ctx: Context<MarketAdmin>, tranche_bps: [u16; SHIPMENT_MILESTONES]) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let total: u32 = tranche_bps.iter().map(|&b| b as u32).sum();
+        require!(total <= MAX_BPS as u32, CoffeeError::InvalidShipmentTranches);
+        let market = &mut ctx.accounts.market;
+        market.shipment_tranche_bps = tranche_bps;
+        emit_event!(ctx, ShipmentTranchesUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), tranche_bps });
+        Ok(())
+    }
+
+    // Point the market at the Wormhole core bridge used for cross-chain settlement messages (admin);
+    // Pubkey::default() turns the hook off
+    pub fn set_wormhole_program(ctx: Context<MarketAdmin>, wormhole_program: Pubkey) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.wormhole_program = wormhole_program;
+        emit_event!(ctx, WormholeProgramUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), wormhole_program });
+        Ok(())
+    }
+
+    // Point the market at Circle's CCTP TokenMessengerMinter for settle_and_bridge (admin); the quote
+    // mint must be USDC. Pubkey::default() turns bridging off
+    pub fn set_cctp_program(ctx: Context<MarketAdmin>, cctp_program: Pubkey) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.cctp_program = cctp_program;
+        emit_event!(ctx, CctpProgramUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), cctp_program });
+        Ok(())
+    }
+
+    // White-label listing: a platform operating this market for a co-op earns `operator_fee_bps` of the
+    // protocol share of cash settlement fees, paid to its treasury. Default operator / zero bps = off
+    pub fn set_operator(ctx: Context<MarketAdmin>, operator: Pubkey, operator_fee_bps: u16, operator_treasury: Pubkey) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(operator_fee_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        require!(
+            operator_fee_bps == 0 || (operator != Pubkey::default() && operator_treasury != Pubkey::default()),
+            CoffeeError::OperatorNotConfigured
+        );
+        let market = &mut ctx.accounts.market;
+        market.operator = operator;
+        market.operator_fee_bps = operator_fee_bps;
+        market.operator_treasury = operator_treasury;
+        emit_event!(ctx, OperatorUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            operator,
+            operator_fee_bps,
+            operator_treasury,
+        });
+        Ok(())
+    }
+
+    // The operator moves its own fee payouts; the cut itself stays an admin setting
+    pub fn set_operator_treasury(ctx: Context<SetOperatorTreasury>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &mut ctx.accounts.market;
+        market.operator_treasury = ctx.accounts.operator_treasury.key();
+        emit_event!(ctx, OperatorUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            operator: market.operator,
+            operator_fee_bps: market.operator_fee_bps,
+            operator_treasury: market.operator_treasury,
+        });
+        Ok(())
+    }
+
+    // After the market's CFT expiry, burn CFT for quote at the floor price out of the insurance fund;
+    // the insurance authority signs the treasury
+    pub fn redeem_cft(ctx: Context<RedeemCft>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let market = &mut ctx.accounts.market;
+        require!(market.cft_expired(Clock::get()?.unix_timestamp), CoffeeError::CftNotExpired);
+        market.record_cft_redemption(amount)?;
+        let payout: u64 = market.notional(market.cft_floor_price_per_kg, amount)?
+            .try_into()
+            .map_err(|_| CoffeeError::MathOverflow)?;
+        require_gte!(ctx.accounts.insurance_treasury.amount, payout, CoffeeError::InsuranceFundInsufficient);
+
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Burn {
+                    mint: ctx.accounts.cft_mint.to_account_info(),
+                    from: ctx.accounts.holder_cft_ata.to_account_info(),
+                    authority: ctx.accounts.holder.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.insurance_treasury.to_account_info(),
+                    to: ctx.accounts.holder_receive.to_account_info(),
+                    authority: ctx.accounts.insurance_authority.to_account_info(),
+                },
+            ),
+            payout,
+        )?;
+
+        emit_event!(ctx, CftRedeemed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            holder: ctx.accounts.holder.key(),
+            amount,
+            payout,
+        });
+        Ok(())
+    }
+
+
+    // Create the market's deliverable asset registry (admin)
+    pub fn init_approved_assets(ctx: Context<InitApprovedAssets>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        let registry = &mut ctx.accounts.approved_assets;
+        registry.market = ctx.accounts.market.key();
+        registry.asset_count = 0;
+        registry.mints = [Pubkey::default(); MAX_APPROVED_ASSETS];
+        registry.units_per_kg = [0u64; MAX_APPROVED_ASSETS];
+        registry.bump = ctx.bumps.approved_assets;
+        Ok(())
+    }
+
+    // Approve a basket mint, or change its conversion; open deals keep the factor they opened with (admin)
+    pub fn set_approved_asset(ctx: Context<ManageApprovedAssets>, mint: Pubkey, units_per_kg: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(units_per_kg > 0, CoffeeError::ZeroAmount);
+        // CFT is always deliverable at 1 unit per kg
+        require!(mint != Pubkey::default() && mint != ctx.accounts.market.cft_mint, CoffeeError::InvalidAssetBasket);
+        ctx.accounts.approved_assets.upsert(mint, units_per_kg)?;
+        emit_event!(ctx, ApprovedAssetUpdated { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), mint, units_per_kg });
+        Ok(())
+    }
+
+    pub fn remove_approved_asset(ctx: Context<ManageApprovedAssets>, mint: Pubkey) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        ctx.accounts.approved_assets.remove(&mint);
+        emit_event!(ctx, ApprovedAssetUpdated { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), mint, units_per_kg: 0 });
+        Ok(())
+    }
+
+    // Farmer (or agent) escrows a non-CFT basket asset into the deal's basket vault ahead of delivery
+    pub fn deposit_basket_asset(ctx: Context<FarmerBasketAsset>, asset_index: u8, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let farmer_signer = farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let deal = &ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        basket_asset_index(deal, &ctx.accounts.market, asset_index, &ctx.accounts.asset_mint.key())?;
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.farmer_asset_ata.to_account_info(),
+                    to: ctx.accounts.basket_vault.to_account_info(),
+                    authority: farmer_signer,
+                },
+            ),
+            amount,
+        )?;
+        emit_event!(ctx, BasketAssetDeposited { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, mint: ctx.accounts.asset_mint.key(), amount });
+        Ok(())
+    }
+
+    // Permissionless: release the basket units earned by verified deliveries to the buyer
+    pub fn deliver_basket_asset(ctx: Context<DeliverBasketAsset>, asset_index: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &mut ctx.accounts.deal;
+        let i = basket_asset_index(deal, &ctx.accounts.market, asset_index, &ctx.accounts.asset_mint.key())?;
+        let amount = deal
+            .basket_units_due(i, deal.delivered_kg_total)
+            .ok_or(CoffeeError::MathOverflow)?
+            .saturating_sub(deal.asset_units_delivered[i]);
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        require!(ctx.accounts.basket_vault.amount >= amount, CoffeeError::BasketUnderfunded);
+        transfer_from_vault_to(
+            amount,
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.basket_vault,
+            &ctx.accounts.buyer_asset_ata,
+            &ctx.accounts.token_program,
+            &deal_key,
+        )?;
+        deal.asset_units_delivered[i] = deal.asset_units_delivered[i].checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        emit_event!(ctx, BasketAssetDelivered {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            mint: deal.assets[i],
+            amount,
+            total_delivered: deal.asset_units_delivered[i],
+        });
+        Ok(())
+    }
+
+    // Farmer reclaims basket units not owed to the buyer once the deal is settled
+    pub fn withdraw_basket_asset(ctx: Context<FarmerBasketAsset>, asset_index: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &ctx.accounts.deal;
+        require!(deal.settled, CoffeeError::DealNotSettled);
+        let i = basket_asset_index(deal, &ctx.accounts.market, asset_index, &ctx.accounts.asset_mint.key())?;
+        let still_owed = deal
+            .basket_units_due(i, deal.delivered_kg_total)
+            .ok_or(CoffeeError::MathOverflow)?
+            .saturating_sub(deal.asset_units_delivered[i]);
+        let amount = ctx.accounts.basket_vault.amount.saturating_sub(still_owed);
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        transfer_from_vault_to(
+            amount,
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.basket_vault,
+            &ctx.accounts.farmer_asset_ata,
+            &ctx.accounts.token_program,
+            &deal_key,
+        )?;
+        emit_event!(ctx, BasketAssetWithdrawn { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, mint: deal.assets[i], amount });
+        Ok(())
+    }
+
+
+    // Replace the shipment manifest root mid-deal; farmer, buyer and verifier must all sign
+    pub fn update_deal_merkle_root(ctx: Context<UpdateDealMerkleRoot>, new_root: [u8; 32]) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        assert_is_verifier(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.verifier, ctx.remaining_accounts)?;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        require!(new_root != deal.merkle_root, CoffeeError::MerkleRootUnchanged);
+        let old_root = deal.merkle_root;
+        deal.merkle_root = new_root;
+        deal.merkle_root_version = deal.merkle_root_version.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        emit_event!(ctx, DealMerkleRootUpdated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            old_root,
+            new_root,
+            version: deal.merkle_root_version,
+        });
+        Ok(())
+    }
+
+
+    // Register a concurrent merkle tree (SPL account compression) for the market's delivery log (admin).
+    // The client allocates `merkle_tree` owned by the compression program; the market PDA becomes its authority.
+    pub fn init_delivery_tree(ctx: Context<InitDeliveryTree>, max_depth: u32, max_buffer_size: u32) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(ctx.accounts.market.delivery_tree == Pubkey::default(), CoffeeError::DeliveryTreeAlreadySet);
+
+        let market_key = ctx.accounts.market.key();
+        let bump = ctx.bumps.tree_authority;
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, TREE_AUTH_SEED, market_key.as_ref(), &[bump]]];
+        spl_account_compression::cpi::init_empty_merkle_tree(
+            CpiContext::new_with_signer(
+                ctx.accounts.compression_program.to_account_info(),
+                spl_account_compression::cpi::accounts::Initialize {
+                    merkle_tree: ctx.accounts.merkle_tree.to_account_info(),
+                    authority: ctx.accounts.tree_authority.to_account_info(),
+                    noop: ctx.accounts.log_wrapper.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            max_depth,
+            max_buffer_size,
+        )?;
+
+        let market = &mut ctx.accounts.market;
+        market.delivery_tree = ctx.accounts.merkle_tree.key();
+        emit_event!(ctx, DeliveryTreeInitialized { schema_version: EVENT_SCHEMA_VERSION, market: market_key, tree: market.delivery_tree, max_depth, max_buffer_size });
+        Ok(())
+    }
+
+    // Verifier appends per-bag delivery attestations as compressed leaves instead of per-record PDAs.
+    // Leaf = keccak(deal || per-deal index || attestation); the deal keeps a count and a digest chain.
+    pub fn log_delivery_attestations(ctx: Context<LogDeliveryAttestations>, attestations: Vec<[u8; 32]>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_is_verifier(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.verifier, ctx.remaining_accounts)?;
+        require!(
+            !attestations.is_empty() && attestations.len() <= MAX_ATTESTATIONS_PER_TX,
+            CoffeeError::AttestationBatchSize
+        );
+
+        let market_key = ctx.accounts.market.key();
+        let deal_key = ctx.accounts.deal.key();
+        let bump = ctx.bumps.tree_authority;
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, TREE_AUTH_SEED, market_key.as_ref(), &[bump]]];
+
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        let first_index = deal.delivery_leaves;
+        for attestation in attestations.iter() {
+            let leaf = solana_program::keccak::hashv(&[
+                deal_key.as_ref(),
+                &deal.delivery_leaves.to_le_bytes(),
+                attestation,
+            ]).0;
+            spl_account_compression::cpi::append(
+                CpiContext::new_with_signer(
+                    ctx.accounts.compression_program.to_account_info(),
+                    spl_account_compression::cpi::accounts::Modify {
+                        merkle_tree: ctx.accounts.merkle_tree.to_account_info(),
+                        authority: ctx.accounts.tree_authority.to_account_info(),
+                        noop: ctx.accounts.log_wrapper.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                leaf,
+            )?;
+            deal.delivery_digest = solana_program::keccak::hashv(&[&deal.delivery_digest, &leaf]).0;
+            deal.delivery_leaves = deal.delivery_leaves.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        }
+
+        emit_event!(ctx, DeliveriesLogged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            tree: ctx.accounts.merkle_tree.key(),
+            first_index,
+            count: attestations.len() as u8,
+            digest: deal.delivery_digest,
+        });
+        Ok(())
+    }
+
+    // Start the deal's on-chain action log (either counterparty). From here on every logged action
+    // (top-ups, margin calls, reductions, settlement, cancellation) must pass it
+    pub fn init_action_log(ctx: Context<InitActionLog>, max_entries: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_is_counterparty(&ctx.accounts.deal, &ctx.accounts.party)?;
+        require!(
+            max_entries > 0 && max_entries as usize <= ACTION_LOG_CAPACITY,
+            CoffeeError::InvalidActionLogSize
+        );
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        deal.has_action_log = true;
+
+        let log = &mut ctx.accounts.action_log;
+        log.deal = deal.key();
+        log.max_entries = max_entries;
+        log.bump = ctx.bumps.action_log;
+        emit_event!(ctx, ActionLogInitialized { schema_version: EVENT_SCHEMA_VERSION, deal: log.deal, deal_id: deal.deal_id, max_entries });
+        Ok(())
+    }
+
+    // Move a deal's logged actions into the market's compressed tree, freeing the log (anyone).
+    // Leaf = keccak(deal || seq || action || actor || amount || ts)
+    pub fn archive_action_log(ctx: Context<ArchiveActionLog>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(ctx.accounts.action_log.len > 0, CoffeeError::ActionLogEmpty);
+
+        let market_key = ctx.accounts.market.key();
+        let bump = ctx.bumps.tree_authority;
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, TREE_AUTH_SEED, market_key.as_ref(), &[bump]]];
+
+        let log = &mut ctx.accounts.action_log;
+        let first_seq = log.archived;
+        let leaves = log.archive();
+        for leaf in leaves.iter() {
+            spl_account_compression::cpi::append(
+                CpiContext::new_with_signer(
+                    ctx.accounts.compression_program.to_account_info(),
+                    spl_account_compression::cpi::accounts::Modify {
+                        merkle_tree: ctx.accounts.merkle_tree.to_account_info(),
+                        authority: ctx.accounts.tree_authority.to_account_info(),
+                        noop: ctx.accounts.log_wrapper.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                *leaf,
+            )?;
+        }
+
+        emit_event!(ctx, ActionLogArchived {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: log.deal,
+            tree: ctx.accounts.merkle_tree.key(),
+            first_seq,
+            count: leaves.len() as u8,
+            digest: log.archive_digest,
+        });
+        Ok(())
+    }
+
+
+    // Freeze a CFT token account via the mint's freeze authority PDA (compliance)
+    pub fn freeze_cft_account(ctx: Context<CftCompliance>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Compliance, ctx.remaining_accounts)?;
+        let cft_key = ctx.accounts.cft_mint.key();
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, CFT_AUTH_SEED, cft_key.as_ref(), &[ctx.accounts.cft_mint_auth.bump]]];
+        token::freeze_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            FreezeAccount {
+                account: ctx.accounts.token_account.to_account_info(),
+                mint: ctx.accounts.cft_mint.to_account_info(),
+                authority: ctx.accounts.cft_mint_auth.to_account_info(),
+            },
+            signer_seeds,
+        ))?;
+        emit_event!(ctx, CftAccountFrozen { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), token_account: ctx.accounts.token_account.key(), frozen: true, by: ctx.accounts.authority.key() });
+        Ok(())
+    }
+
+    pub fn thaw_cft_account(ctx: Context<CftCompliance>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Compliance, ctx.remaining_accounts)?;
+        let cft_key = ctx.accounts.cft_mint.key();
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, CFT_AUTH_SEED, cft_key.as_ref(), &[ctx.accounts.cft_mint_auth.bump]]];
+        token::thaw_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            ThawAccount {
+                account: ctx.accounts.token_account.to_account_info(),
+                mint: ctx.accounts.cft_mint.to_account_info(),
+                authority: ctx.accounts.cft_mint_auth.to_account_info(),
+            },
+            signer_seeds,
+        ))?;
+        emit_event!(ctx, CftAccountFrozen { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), token_account: ctx.accounts.token_account.key(), frozen: false, by: ctx.accounts.authority.key() });
+        Ok(())
+    }
+
+    // Give up the program's mint authority over a retired market's own CFT mint (admin, one-way): hand
+    // it to the market's governance PDA, or drop it so supply is fixed for good. Only once the market is
+    // closing with no unclosed deals, so no settlement is left that would mint. The freeze authority
+    // stays with the program for compliance
+    pub fn finalize_cft_mint(ctx: Context<FinalizeCftMint>, to_governance: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &ctx.accounts.market;
+        require!(market.cft_per_market, CoffeeError::CftMintShared);
+        require!(market.close_after_ts > 0 && market.unclosed_deals == 0, CoffeeError::CftMintNotRetired);
+        let new_authority = if to_governance {
+            require!(market.governance != Pubkey::default(), CoffeeError::InvalidGovernanceConfig);
+            Some(market.governance)
+        } else {
+            None
+        };
+
+        let cft_key = ctx.accounts.cft_mint.key();
+        let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, CFT_AUTH_SEED, cft_key.as_ref(), &[ctx.accounts.cft_mint_auth.bump]]];
+        token::set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: ctx.accounts.cft_mint_auth.to_account_info(),
+                    account_or_mint: ctx.accounts.cft_mint.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            token::spl_token::instruction::AuthorityType::MintTokens,
+            new_authority,
+        )?;
+        emit_event!(ctx, CftMintFinalized {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            cft_mint: cft_key,
+            mint_authority: new_authority.unwrap_or_default(),
+            supply: ctx.accounts.cft_mint.supply,
+            cft_minted: market.cft_minted,
+        });
+        Ok(())
+    }
+
+    // Only mint CFT to allowlisted recipients (admin)
+    pub fn set_cft_allowlist_required(ctx: Context<MarketAdmin>, required: bool) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        let market = &mut ctx.accounts.market;
+        market.cft_allowlist_required = required;
+        emit_event!(ctx, CftAllowlistRequirementUpdated { schema_version: EVENT_SCHEMA_VERSION, market: market.key(), required });
+        Ok(())
+    }
+
+    // Allowlist a wallet as a CFT recipient (compliance)
+    pub fn add_cft_recipient(ctx: Context<AddCftRecipient>, wallet: Pubkey) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Compliance, ctx.remaining_accounts)?;
+        let entry = &mut ctx.accounts.entry;
+        entry.market = ctx.accounts.market.key();
+        entry.wallet = wallet;
+        entry.bump = ctx.bumps.entry;
+        emit_event!(ctx, CftRecipientAllowlisted { schema_version: EVENT_SCHEMA_VERSION, market: entry.market, wallet, allowed: true });
+        Ok(())
+    }
+
+    pub fn remove_cft_recipient(ctx: Context<RemoveCftRecipient>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Compliance, ctx.remaining_accounts)?;
+        emit_event!(ctx, CftRecipientAllowlisted { schema_version: EVENT_SCHEMA_VERSION, market: ctx.accounts.market.key(), wallet: ctx.accounts.entry.wallet, allowed: false });
+        Ok(())
+    }
+
+    // Register the rate feed for one local currency (ISO 4217 code) against the market's quote (admin)
+    pub fn register_fx_oracle(ctx: Context<RegisterFxOracle>, currency: [u8; 3], publisher: Pubkey, max_age_sec: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(max_age_sec > 0, CoffeeError::InvalidFxOracle);
+        let fx_oracle = &mut ctx.accounts.fx_oracle;
+        fx_oracle.market = ctx.accounts.market.key();
+        fx_oracle.currency = currency;
+        fx_oracle.publisher = publisher;
+        fx_oracle.rate = 0;
+        fx_oracle.last_update_ts = 0;
+        fx_oracle.max_age_sec = max_age_sec;
+        fx_oracle.bump = ctx.bumps.fx_oracle;
+        emit_event!(ctx, FxOracleRegistered { schema_version: EVENT_SCHEMA_VERSION, market: fx_oracle.market, fx_oracle: fx_oracle.key(), currency, publisher });
+        Ok(())
+    }
+
+    // Publish local currency per quote unit (FX_RATE_SCALE); only the registered publisher
+    pub fn publish_fx_rate(ctx: Context<PublishFxRate>, rate: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(rate > 0, CoffeeError::ZeroPrice);
+        let now = Clock::get()?.unix_timestamp;
+        let fx_oracle = &mut ctx.accounts.fx_oracle;
+        fx_oracle.rate = rate;
+        fx_oracle.last_update_ts = now;
+        emit_event!(ctx, FxRatePublished { schema_version: EVENT_SCHEMA_VERSION, fx_oracle: fx_oracle.key(), currency: fx_oracle.currency, rate, ts: now });
+        Ok(())
+    }
+
+
+    // ----- cross-margin accounts -----
+
+    // Create the caller's MarginAccount and its pooled quote vault for this market
+    pub fn init_margin_account(ctx: Context<InitMarginAccount>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let acct = &mut ctx.accounts.margin_account;
+        acct.market = ctx.accounts.market.key();
+        acct.owner = ctx.accounts.owner.key();
+        acct.pool = ctx.accounts.margin_pool.key();
+        acct.long_kg = 0;
+        acct.long_notional = 0;
+        acct.short_kg = 0;
+        acct.short_notional = 0;
+        acct.deal_count = 0;
+        acct.bump = ctx.bumps.margin_account;
+        emit_event!(ctx, MarginAccountOpened { schema_version: EVENT_SCHEMA_VERSION, market: acct.market, owner: acct.owner, margin_account: acct.key() });
+        Ok(())
+    }
+
+    pub fn deposit_margin(ctx: Context<MarginAccountFunds>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.owner_token.to_account_info(),
+                    to: ctx.accounts.margin_pool.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        emit_event!(ctx, MarginAccountFundsMoved {
+            schema_version: EVENT_SCHEMA_VERSION,
+            margin_account: ctx.accounts.margin_account.key(),
+            deposit: true,
+            amount,
+        });
+        Ok(())
+    }
+
+    // Withdraw whatever the pool holds above the initial margin on net exposure (after unrealized PnL)
+    pub fn withdraw_margin(ctx: Context<MarginAccountFunds>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let market = &ctx.accounts.market;
+        let acct = &ctx.accounts.margin_account;
+        let excess = if acct.deal_count == 0 {
+            ctx.accounts.margin_pool.amount as i128
+        } else {
+            let bps = market.initial_margin_bps_at(Clock::get()?.unix_timestamp);
+            acct.excess(ctx.accounts.margin_pool.amount, mark_price(market)?, market.price_exponent, bps).ok_or(CoffeeError::MathOverflow)?
+        };
+        require_gte!(excess, amount as i128, CoffeeError::InsufficientFreeMargin);
+        transfer_from_margin_pool(amount, acct, &ctx.accounts.margin_pool, &ctx.accounts.owner_token, &ctx.accounts.token_program)?;
+        emit_event!(ctx, MarginAccountFundsMoved { schema_version: EVENT_SCHEMA_VERSION, margin_account: acct.key(), deposit: false, amount });
+        Ok(())
+    }
+
+    // Back the owner's side of a live deal with their MarginAccount: the side's initial margin moves
+    // from the deal vault into the pool and the deal's exposure is added to the account
+    pub fn link_deal_margin(ctx: Context<LinkDealMargin>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let acct_key = ctx.accounts.margin_account.key();
+        require_keys_eq!(ctx.accounts.caller.key(), ctx.accounts.margin_account.owner, CoffeeError::Unauthorized);
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        require!(!deal.omnibus, CoffeeError::OmnibusUnsupported);
+        let side = cross_margin_side(deal, &ctx.accounts.margin_account.owner)?;
+        let [farmer_vault, buyer_vault] = deal_margin_vaults(deal.split_vaults, &deal_key, &ctx.accounts.vault_auth.key(), &ctx.accounts.market.quote_mint);
+        let side_vault = if side == math::Side::Short { farmer_vault } else { buyer_vault };
+        require_keys_eq!(ctx.accounts.deal_margin_vault.key(), side_vault, CoffeeError::MarginVaultMismatch);
+        // a MarginAccount is held to market rates, so a side with agreed floors stays isolated
+        require!(!deal.margin_override.binds(side), CoffeeError::DealMarginOverridden);
+        // the vault of a credit-backed buyer holds less than initial_margin_each
+        require!(side == math::Side::Short || deal.credit_used == 0, CoffeeError::CreditBackedSide);
+        // a sponsored farmer side keeps the sponsor's stake in the deal vault
+        require!(side == math::Side::Long || deal.sponsored_margin == 0, CoffeeError::DealSponsored);
+        let linked = match side {
+            math::Side::Short => &mut deal.farmer_margin_account,
+            math::Side::Long => &mut deal.buyer_margin_account,
+        };
+        require!(*linked == Pubkey::default(), CoffeeError::DealCrossMargined);
+        *linked = acct_key;
+
+        let amount = deal.initial_margin_each.min(ctx.accounts.deal_margin_vault.amount);
+        transfer_from_vault_to(
+            amount,
+            &ctx.accounts.vault_auth,
+            &ctx.accounts.deal_margin_vault,
+            &ctx.accounts.margin_pool,
+            &ctx.accounts.token_program,
+            &deal_key,
+        )?;
+        let notional = math::notional(deal.agreed_price_per_kg, deal.quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+        ctx.accounts.margin_account.add_exposure(deal.position(side), deal.quantity_kg, notional)?;
+        emit_event!(ctx, DealMarginLinked {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            margin_account: acct_key,
+            moved: amount,
+        });
+        Ok(())
+    }
+
+    // Fund the deal vault back from the pool (initial margin plus the side's loss at the mark) and drop
+    // the link, so the deal settles like an isolated one. Owner any time while the account stays
+    // healthy; anyone once the deal is settleable or flagged for liquidation.
+    pub fn release_deal_margin(ctx: Context<LinkDealMargin>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let acct_key = ctx.accounts.margin_account.key();
+        let owner = ctx.accounts.margin_account.owner;
+        let deal_key = ctx.accounts.deal.key();
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        let side = cross_margin_side(deal, &owner)?;
+        let [farmer_vault, buyer_vault] = deal_margin_vaults(deal.split_vaults, &deal_key, &ctx.accounts.vault_auth.key(), &market.quote_mint);
+        let side_vault = if side == math::Side::Short { farmer_vault } else { buyer_vault };
+        require_keys_eq!(ctx.accounts.deal_margin_vault.key(), side_vault, CoffeeError::MarginVaultMismatch);
+        let linked = match side {
+            math::Side::Short => &mut deal.farmer_margin_account,
+            math::Side::Long => &mut deal.buyer_margin_account,
+        };
+        require_keys_eq!(*linked, acct_key, CoffeeError::MarginAccountMismatch);
+        *linked = Pubkey::default();
+
+        let now = Clock::get()?.unix_timestamp;
+        let settleable = now >= market.settlement_ts || now >= deal.deadline_ts || deal.liquidated;
+        require!(settleable || ctx.accounts.caller.key() == owner, CoffeeError::Unauthorized);
+
+        let mark = mark_price(market)?;
+        let pnl = deal.slot_pnl(market, mark, deal.quantity_kg, side)?;
+        let loss: u64 = if pnl < 0 { pnl.unsigned_abs().try_into().map_err(|_| CoffeeError::MathOverflow)? } else { 0 };
+        let amount = deal
+            .initial_margin_each
+            .checked_add(loss)
+            .ok_or(CoffeeError::MathOverflow)?
+            .min(ctx.accounts.margin_pool.amount);
+        transfer_from_margin_pool(
+            amount,
+            &ctx.accounts.margin_account,
+            &ctx.accounts.margin_pool,
+            &ctx.accounts.deal_margin_vault,
+            &ctx.accounts.token_program,
+        )?;
+        let notional = math::notional(deal.agreed_price_per_kg, deal.quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+        let acct = &mut ctx.accounts.margin_account;
+        acct.remove_exposure(deal.position(side), deal.quantity_kg, notional);
+
+        // an early release must leave the remaining linked deals covered
+        if !settleable && acct.deal_count > 0 {
+            let pool_after = ctx.accounts.margin_pool.amount.saturating_sub(amount);
+            let bps = market.maintenance_margin_bps_at(now);
+            let excess = acct.excess(pool_after, mark, market.price_exponent, bps).ok_or(CoffeeError::MathOverflow)?;
+            require_gte!(excess, 0, CoffeeError::InsufficientFreeMargin);
+        }
+        emit_event!(ctx, DealMarginReleased {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            margin_account: acct_key,
+            funded: amount,
+        });
+        Ok(())
+    }
+
+
+    // Risk summary for a MarginAccount, recomputed from its linked deals (remaining_accounts, each once,
+    // at most MAX_RISK_DEALS) at the current mark. Written to the account's RiskSnapshot and emitted;
+    // `complete` is false when fewer deals were passed than the account has linked.
+    pub fn compute_portfolio_risk<'info>(ctx: Context<'_, '_, 'info, 'info, ComputePortfolioRisk<'info>>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let acct = &ctx.accounts.margin_account;
+        let acct_key = acct.key();
+        require!(ctx.remaining_accounts.len() <= MAX_RISK_DEALS, CoffeeError::TooManyRiskDeals);
+
+        let now = Clock::get()?.unix_timestamp;
+        let mark = mark_price(market)?;
+        let mut seen: Vec<Pubkey> = Vec::with_capacity(ctx.remaining_accounts.len());
+        let mut exposure = MarginAccount::default();
+        for info in ctx.remaining_accounts.iter() {
+            require!(!seen.contains(info.key), CoffeeError::DuplicateRiskDeal);
+            seen.push(*info.key);
+            let deal: Account<Deal> = Account::try_from(info)?;
+            require_keys_eq!(deal.market, market.key(), CoffeeError::MarginAccountMismatch);
+            let side = if deal.farmer_margin_account == acct_key {
+                math::Side::Short
+            } else if deal.buyer_margin_account == acct_key {
+                math::Side::Long
+            } else {
+                return err!(CoffeeError::MarginAccountMismatch);
+            };
+            let notional = math::notional(deal.agreed_price_per_kg, deal.quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+            exposure.add_exposure(deal.position(side), deal.quantity_kg, notional)?;
+        }
+
+        let collateral = ctx.accounts.margin_pool.amount;
+        let to_i64 = |v: i128| -> Result<i64> { i64::try_from(v).map_err(|_| error!(CoffeeError::MathOverflow)) };
+        let to_u64 = |v: u128| -> Result<u64> { u64::try_from(v).map_err(|_| error!(CoffeeError::MathOverflow)) };
+        let initial_bps = market.initial_margin_bps_at(now);
+        let maint_bps = market.maintenance_margin_bps_at(now);
+
+        let snap = &mut ctx.accounts.risk_snapshot;
+        snap.margin_account = acct_key;
+        snap.owner = acct.owner;
+        snap.ts = now;
+        snap.mark_price = mark;
+        snap.deal_count = exposure.deal_count;
+        snap.complete = exposure.deal_count == acct.deal_count;
+        snap.net_delta_kg = to_i64(exposure.long_kg as i128 - exposure.short_kg as i128)?;
+        snap.gross_kg = exposure.long_kg.checked_add(exposure.short_kg).ok_or(CoffeeError::MathOverflow)?;
+        let exp = market.price_exponent;
+        snap.unrealized_pnl = to_i64(exposure.unrealized_pnl(mark, exp).ok_or(CoffeeError::MathOverflow)?)?;
+        snap.collateral = collateral;
+        snap.initial_required = to_u64(exposure.required_margin(mark, exp, initial_bps).ok_or(CoffeeError::MathOverflow)?)?;
+        snap.maintenance_required = to_u64(exposure.required_margin(mark, exp, maint_bps).ok_or(CoffeeError::MathOverflow)?)?;
+        snap.excess = to_i64(exposure.excess(collateral, mark, exp, maint_bps).ok_or(CoffeeError::MathOverflow)?)?;
+        snap.free_margin = to_i64(exposure.excess(collateral, mark, exp, initial_bps).ok_or(CoffeeError::MathOverflow)?)?;
+        snap.bump = ctx.bumps.risk_snapshot;
+
+        emit_event!(ctx, PortfolioRiskComputed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            margin_account: acct_key,
+            owner: snap.owner,
+            mark_price: mark,
+            deal_count: snap.deal_count,
+            complete: snap.complete,
+            net_delta_kg: snap.net_delta_kg,
+            unrealized_pnl: snap.unrealized_pnl,
+            collateral,
+            maintenance_required: snap.maintenance_required,
+            excess: snap.excess,
+        });
+        Ok(())
+    }
+
+
+    // Create the parametric crop-failure insurance config for a market; disabled until configured
+    pub fn init_crop_insurance(ctx: Context<InitCropInsurance>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        let config = &mut ctx.accounts.crop_insurance;
+        config.market = ctx.accounts.market.key();
+        config.premium_bps = 0;
+        config.payout_bps = 0;
+        config.price_trigger_per_kg = 0;
+        config.weather_feed = Pubkey::default();
+        config.weather_index_trigger = 0;
+        config.total_premiums = 0;
+        config.total_payouts = 0;
+        config.bump = ctx.bumps.crop_insurance;
+        Ok(())
+    }
+
+    // Set premium and payout (bps of deal notional) and the triggers (admin). A zero price trigger or
+    // default weather feed disables that trigger; premium_bps == 0 stops new policies.
+    pub fn configure_crop_insurance(
+        ctx: Context<ManageCropInsurance>,
+        premium_bps: u16,
+        payout_bps: u16,
+        price_trigger_per_kg: u64,
+        weather_feed: Pubkey,
+        weather_index_trigger: u64,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), Role::Admin, ctx.remaining_accounts)?;
+        require!(premium_bps <= MAX_BPS && payout_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        require!(
+            premium_bps == 0 || (payout_bps > 0 && (price_trigger_per_kg > 0 || weather_feed != Pubkey::default())),
+            CoffeeError::InvalidCropInsuranceConfig
+        );
+        if weather_feed != Pubkey::default() {
+            // the feed must belong to this market
+            let feed = ctx.accounts.weather_feed.as_ref().ok_or(CoffeeError::IndexFeedMismatch)?;
+            require_keys_eq!(feed.key(), weather_feed, CoffeeError::IndexFeedMismatch);
+        }
+        let config = &mut ctx.accounts.crop_insurance;
+        config.premium_bps = premium_bps;
+        config.payout_bps = payout_bps;
+        config.price_trigger_per_kg = price_trigger_per_kg;
+        config.weather_feed = weather_feed;
+        config.weather_index_trigger = weather_index_trigger;
+
+        emit_event!(ctx, CropInsuranceConfigured {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: config.market,
+            premium_bps,
+            payout_bps,
+            price_trigger_per_kg,
+            weather_feed,
+            weather_index_trigger,
+        });
+        Ok(())
+    }
+
+
+    // Farmer buys crop-failure cover for a live deal, meant to be bundled with open_deal. Cover
+    // cannot be bought once a trigger has already fired.
+    pub fn buy_crop_insurance(ctx: Context<BuyCropInsurance>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let farmer_signer = farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        let config = &mut ctx.accounts.crop_insurance;
+        let deal = &ctx.accounts.deal;
+        require!(config.premium_bps > 0, CoffeeError::CropInsuranceNotOffered);
+        require!(!deal.settled && !deal.voided && !deal.liquidated, CoffeeError::DealAlreadySettled);
+        require!(deal.farmer_deposited && deal.buyer_deposited, CoffeeError::DealNotLive);
+        let reading = crop_weather_reading(config, ctx.accounts.weather_feed.as_ref())?;
+        require!(!config.triggered(&ctx.accounts.market, reading, 0), CoffeeError::CropTriggerAlreadyHit);
+
+        let notional: u64 = ctx.accounts.market.notional(deal.agreed_price_per_kg, deal.quantity_kg)?
+            .try_into()
+            .map_err(|_| CoffeeError::MathOverflow)?;
+        let premium = bps_of_u64(notional, config.premium_bps)?;
+        let payout = bps_of_u64(notional, config.payout_bps)?;
+        require!(premium > 0 && payout > 0, CoffeeError::ZeroAmount);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.farmer_quote_ata.to_account_info(),
+                    to: ctx.accounts.insurance_treasury.to_account_info(),
+                    authority: farmer_signer,
+                },
+            ),
+            premium,
+        )?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let policy = &mut ctx.accounts.policy;
+        policy.deal = deal.key();
+        policy.farmer = deal.farmer;
+        policy.premium = premium;
+        policy.payout = payout;
+        policy.bought_ts = now;
+        policy.claimed = false;
+        policy.bump = ctx.bumps.policy;
+        config.total_premiums = config.total_premiums.checked_add(premium).ok_or(CoffeeError::MathOverflow)?;
+
+        emit_event!(ctx, CropInsurancePurchased { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, premium, payout });
+        Ok(())
+    }
+
+    // Pay the predefined payout once a trigger has fired: the settlement price at or below the price
+    // trigger, or a weather reading taken after purchase at or above the index trigger. Needs the
+    // verifier's sign-off and the insurance authority to sign the treasury.
+    pub fn file_insurance_claim(ctx: Context<FileInsuranceClaim>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        farmer_or_agent(
+            &ctx.accounts.farmer,
+            ctx.accounts.agent.as_ref(),
+            ctx.accounts.agent_grant.as_ref(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        assert_is_verifier(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.verifier, ctx.remaining_accounts)?;
+        let config = &mut ctx.accounts.crop_insurance;
+        let deal = &mut ctx.accounts.deal;
+        let policy = &mut ctx.accounts.policy;
+        require!(!policy.claimed, CoffeeError::CropClaimAlreadyPaid);
+        // the price trigger reads the settlement price, so it only counts once the deal has settled
+        let market = &ctx.accounts.market;
+        let price_hit = deal.settled && config.price_trigger_hit(market);
+        let reading = crop_weather_reading(config, ctx.accounts.weather_feed.as_ref())?;
+        require!(price_hit || config.weather_trigger_hit(reading, policy.bought_ts), CoffeeError::CropTriggerNotHit);
+        require_gte!(ctx.accounts.insurance_treasury.amount, policy.payout, CoffeeError::InsuranceFundInsufficient);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.insurance_treasury.to_account_info(),
+                    to: ctx.accounts.farmer_receive.to_account_info(),
+                    authority: ctx.accounts.insurance_authority.to_account_info(),
+                },
+            ),
+            policy.payout,
+        )?;
+        policy.claimed = true;
+        config.total_payouts = config.total_payouts.checked_add(policy.payout).ok_or(CoffeeError::MathOverflow)?;
+        deal.insurance_drawn = deal.insurance_drawn.checked_add(policy.payout).ok_or(CoffeeError::MathOverflow)?;
+
+        emit_event!(ctx, CropInsuranceClaimPaid {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            payout: policy.payout,
+            price_trigger: price_hit,
+            weather_index: reading.map_or(0, |feed| feed.value),
+        });
+        Ok(())
+    }
+
+
+    // Create a non-price index feed (rainfall, frost, regional yield) with its publisher (admin)
+    pub fn init_index_feed(ctx: Context<InitIndexFeed>, feed_id: u8, kind: u8, publisher: Pubkey, max_age_sec: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_authority_config(&ctx.accounts.market.authority_config, ctx.remaining_accounts)?;
+        require!(
+            kind >= IndexKind::Rainfall as u8 && kind <= IndexKind::RegionalYield as u8,
+            CoffeeError::InvalidIndexKind
+        );
+        require!(publisher != Pubkey::default(), CoffeeError::Unauthorized);
+        let feed = &mut ctx.accounts.index_feed;
+        feed.market = ctx.accounts.market.key();
+        feed.feed_id = feed_id;
+        feed.kind = kind;
+        feed.publisher = publisher;
+        feed.value = 0;
+        feed.prev_value = 0;
+        feed.nonce = 0;
+        feed.last_update_ts = 0;
+        feed.max_age_sec = max_age_sec;
+        feed.bump = ctx.bumps.index_feed;
+        emit_event!(ctx, IndexFeedInitialized { schema_version: EVENT_SCHEMA_VERSION, market: feed.market, index_feed: feed.key(), feed_id, kind, publisher });
+        Ok(())
+    }
+
+    // Second oracle channel: the feed's publisher posts the latest index reading
+    pub fn publish_index_value(ctx: Context<PublishIndexValue>, value: u64, nonce: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let feed = &mut ctx.accounts.index_feed;
+        require!(nonce > feed.nonce, CoffeeError::ReplayOrStaleNonce);
+        let now = Clock::get()?.unix_timestamp;
+        feed.prev_value = feed.value;
+        feed.value = value;
+        feed.nonce = nonce;
+        feed.last_update_ts = now;
+        emit_event!(ctx, IndexValuePublished { schema_version: EVENT_SCHEMA_VERSION, index_feed: feed.key(), kind: feed.kind, value, ts: now, nonce });
+        Ok(())
+    }
+
+
+    // Farmer authorizes an agent (co-op manager, exporter) to open and manage deals on their behalf
+    // in this market, up to max_notional of newly opened deals until expiry_ts. Re-granting updates
+    // the caps; notional already used keeps counting.
+    pub fn grant_agent(ctx: Context<GrantAgent>, agent: Pubkey, max_notional: u64, expiry_ts: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        require!(agent != Pubkey::default() && agent != ctx.accounts.farmer.key(), CoffeeError::InvalidAgentGrant);
+        require!(max_notional > 0 && expiry_ts > now, CoffeeError::InvalidAgentGrant);
+        let grant = &mut ctx.accounts.agent_grant;
+        if grant.farmer == Pubkey::default() {
+            grant.market = ctx.accounts.market.key();
+            grant.farmer = ctx.accounts.farmer.key();
+            grant.agent = agent;
+            grant.used_notional = 0;
+            grant.bump = ctx.bumps.agent_grant;
+        }
+        grant.max_notional = max_notional;
+        grant.expiry_ts = expiry_ts;
+        emit_event!(ctx, AgentGranted { schema_version: EVENT_SCHEMA_VERSION, market: grant.market, farmer: grant.farmer, agent, max_notional, expiry_ts });
+        Ok(())
+    }
+
+    // Farmer withdraws an agent's power of attorney; the grant account is closed
+    pub fn revoke_agent(ctx: Context<RevokeAgent>) -> Result<()> {
+        let grant = &ctx.accounts.agent_grant;
+        emit_event!(ctx, AgentRevoked { schema_version: EVENT_SCHEMA_VERSION, market: grant.market, farmer: grant.farmer, agent: grant.agent });
+        Ok(())
+    }
+
+
+    // Operator issues a short-lived session key for keeper bots, limited to `scopes` (SessionScope
+    // bits). The operator must hold the role behind every scope, both now and whenever the key is used.
+    pub fn create_session(ctx: Context<CreateSession>, session_key: Pubkey, scopes: u8, expiry_ts: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        require!(scopes != 0 && scopes & !SessionScope::ALL == 0, CoffeeError::InvalidSession);
+        require!(expiry_ts > now && expiry_ts - now <= MAX_SESSION_SEC, CoffeeError::InvalidSession);
+        require!(session_key != ctx.accounts.authority.key(), CoffeeError::InvalidSession);
+        for scope in [SessionScope::PublishPrice, SessionScope::OracleHeartbeat, SessionScope::MarginCall] {
+            if scopes & scope.mask() != 0 {
+                require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.authority.key(), scope.role(), ctx.remaining_accounts)?;
+            }
+        }
+        let session = &mut ctx.accounts.session;
+        session.market = ctx.accounts.market.key();
+        session.authority = ctx.accounts.authority.key();
+        session.session_key = session_key;
+        session.scopes = scopes;
+        session.expiry_ts = expiry_ts;
+        session.bump = ctx.bumps.session;
+        emit_event!(ctx, SessionCreated { schema_version: EVENT_SCHEMA_VERSION, market: session.market, authority: session.authority, session_key, scopes, expiry_ts });
+        Ok(())
+    }
+
+    // Operator revokes a session key before it expires; the account is closed
+    pub fn revoke_session(ctx: Context<RevokeSession>) -> Result<()> {
+        let session = &ctx.accounts.session;
+        emit_event!(ctx, SessionRevoked { schema_version: EVENT_SCHEMA_VERSION, market: session.market, authority: session.authority, session_key: session.session_key });
+        Ok(())
+    }
+
+
+    // Relayed margin top-up: the relayer pays fees and signs, the counterparty only signs the permit
+    // message off-line (cpi_interface::permit_message), verified through an ed25519 instruction
+    // placed right before this one. Funds move through the counterparty's SPL delegation to the
+    // permit authority PDA, approved once while online.
+    pub fn top_up_margin_with_permit(ctx: Context<TopUpMarginWithPermit>, amount: u64, nonce: u64, expiry_ts: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let owner = ctx.accounts.owner.key();
+        let deal = &ctx.accounts.deal;
+        let side = deal.side_of(&owner).ok_or(CoffeeError::InvalidCounterparty)?;
+        require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+        require!(Clock::get()?.unix_timestamp <= expiry_ts, CoffeeError::PermitExpired);
+        check_margin_vaults(&ctx.accounts.market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+
+        let permit_nonce = &mut ctx.accounts.permit_nonce;
+        if permit_nonce.owner == Pubkey::default() {
+            permit_nonce.owner = owner;
+            permit_nonce.bump = ctx.bumps.permit_nonce;
+        }
+        require!(nonce == permit_nonce.next_nonce, CoffeeError::ReplayOrStaleNonce);
+        let message = cpi_interface::permit_message(&deal.key(), &owner, amount, nonce, expiry_ts);
+        verify_ed25519_permit(&ctx.accounts.instructions, &owner, &message)?;
+        permit_nonce.next_nonce = nonce.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+
+        let to = if side == math::Side::Short { &ctx.accounts.farmer_margin_vault } else { &ctx.accounts.buyer_margin_vault };
+        let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, PERMIT_AUTHORITY_SEED, &[ctx.bumps.permit_authority]]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.from_ata.to_account_info(),
+                    to: to.to_account_info(),
+                    authority: ctx.accounts.permit_authority.to_account_info(),
+                },
+                seeds,
+            ),
+            amount,
+        )?;
+
+        emit_event!(ctx, MarginToppedUp {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            who: owner,
+            amount,
+        });
+        emit_event!(ctx, PermitUsed { schema_version: EVENT_SCHEMA_VERSION, owner, relayer: ctx.accounts.relayer.key(), nonce });
+
+        let deal = &mut ctx.accounts.deal;
+        deal.margin_locked = deal.margin_locked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        if deal.omnibus {
+            deal.credit_omnibus(side, amount)?;
+        }
+        deal.credit_farmer_own(side, amount)?;
+        let market = &mut ctx.accounts.market;
+        market.total_margin_locked = market.total_margin_locked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        record_deal_action(&ctx.accounts.deal, ctx.accounts.action_log.as_deref_mut(), DealAction::TopUp, owner, amount)?;
+
+        ctx.accounts.farmer_margin_vault.reload()?;
+        ctx.accounts.buyer_margin_vault.reload()?;
+        let (farmer_vault, buyer_vault) = (ctx.accounts.farmer_margin_vault.amount, ctx.accounts.buyer_margin_vault.amount);
+        if let Some(update) = refresh_health(&ctx.accounts.market, &mut ctx.accounts.deal, farmer_vault, buyer_vault)? {
+            emit_event!(ctx, update);
+        }
+        Ok(())
+    }
+
+
+    // Publish standardized deal terms (market authority or a delegated EXPORTER)
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_deal_template(
+        ctx: Context<CreateDealTemplate>,
+        template_id: u32,
+        quantity_kg: u64,
+        physical_delivery: bool,
+        delivery_start_ts: i64,
+        delivery_end_ts: i64,
+        quality_grade: u8,
+        incoterm: u8,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require_role(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.creator.key(), Role::Exporter, ctx.remaining_accounts)?;
+        require!(quantity_kg > 0 && quantity_kg <= ctx.accounts.market.max_qty_per_deal, CoffeeError::InvalidDealTemplate);
+        require!(delivery_end_ts > delivery_start_ts && delivery_end_ts > Clock::get()?.unix_timestamp, CoffeeError::InvalidDealTemplate);
+        require!(incoterm <= Incoterm::Ddp as u8, CoffeeError::InvalidDealTemplate);
+        let template = &mut ctx.accounts.template;
+        template.market = ctx.accounts.market.key();
+        template.creator = ctx.accounts.creator.key();
+        template.template_id = template_id;
+        template.quantity_kg = quantity_kg;
+        template.physical_delivery = physical_delivery;
+        template.delivery_start_ts = delivery_start_ts;
+        template.delivery_end_ts = delivery_end_ts;
+        template.quality_grade = quality_grade;
+        template.incoterm = incoterm;
+        template.active = true;
+        template.bump = ctx.bumps.template;
+        emit_event!(ctx, DealTemplateCreated {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: template.market,
+            template: template.key(),
+            creator: template.creator,
+            template_id,
+            quantity_kg,
+            physical_delivery,
+            delivery_start_ts,
+            delivery_end_ts,
+            quality_grade,
+            incoterm,
+        });
+        Ok(())
+    }
+
+    // Creator withdraws a template from use; deals already opened from it are unaffected
+    pub fn retire_deal_template(ctx: Context<RetireDealTemplate>) -> Result<()> {
+        let template = &mut ctx.accounts.template;
+        template.active = false;
+        emit_event!(ctx, DealTemplateRetired { schema_version: EVENT_SCHEMA_VERSION, template: template.key() });
+        Ok(())
+    }
+
+    // Pre-trade sample: the buyer escrows a sample fee for the farmer; the farmer has until the window
+    // closes to ship, the buyer to approve
+    pub fn request_sample(ctx: Context<RequestSample>, fee: u64, approval_window_sec: i64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(approval_window_sec > 0, CoffeeError::InvalidSampleWindow);
+        let now = Clock::get()?.unix_timestamp;
+        if fee > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.buyer_from.to_account_info(),
+                        to: ctx.accounts.sample_vault.to_account_info(),
+                        authority: ctx.accounts.buyer.to_account_info(),
+                    },
+                ),
+                fee,
+            )?;
+        }
+        let sample = &mut ctx.accounts.sample;
+        sample.market = ctx.accounts.market.key();
+        sample.farmer = ctx.accounts.farmer.key();
+        sample.buyer = ctx.accounts.buyer.key();
+        sample.fee = fee;
+        sample.requested_ts = now;
+        sample.deadline_ts = now.checked_add(approval_window_sec).ok_or(CoffeeError::MathOverflow)?;
+        sample.shipped_ts = 0;
+        sample.approved = false;
+        sample.bump = ctx.bumps.sample;
+        emit_event!(ctx, SampleRequested {
+            schema_version: EVENT_SCHEMA_VERSION,
+            sample: sample.key(),
+            farmer: sample.farmer,
+            buyer: sample.buyer,
+            fee,
+            deadline_ts: sample.deadline_ts,
+        });
+        Ok(())
+    }
+
+    // Farmer records the sample as shipped; the fee is then theirs even if the buyer lets the window lapse
+    pub fn ship_sample(ctx: Context<ShipSample>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let now = Clock::get()?.unix_timestamp;
+        let sample = &mut ctx.accounts.sample;
+        require!(!sample.approved, CoffeeError::SampleAlreadyApproved);
+        require!(sample.shipped_ts == 0, CoffeeError::SampleAlreadyShipped);
+        require!(!sample.lapsed(now), CoffeeError::SampleWindowLapsed);
+        sample.shipped_ts = now;
+        emit_event!(ctx, SampleShipped { schema_version: EVENT_SCHEMA_VERSION, sample: sample.key(), ts: now });
+        Ok(())
+    }
+
+    // Buyer accepts the sample: the fee goes to the farmer and open_deal may proceed until the window closes
+    pub fn approve_sample(ctx: Context<ApproveSample>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let sample = &ctx.accounts.sample;
+        require!(!sample.approved, CoffeeError::SampleAlreadyApproved);
+        require!(!sample.lapsed(Clock::get()?.unix_timestamp), CoffeeError::SampleWindowLapsed);
+        let fee = ctx.accounts.sample_vault.amount;
+        transfer_from_sample(fee, sample, &ctx.accounts.sample_vault, &ctx.accounts.farmer_receive, &ctx.accounts.token_program)?;
+        ctx.accounts.sample.approved = true;
+        emit_event!(ctx, SampleApproved { schema_version: EVENT_SCHEMA_VERSION, sample: ctx.accounts.sample.key(), fee });
+        Ok(())
+    }
+
+    // Permissionless once the window lapses without a deal: an unapproved fee goes to the farmer if
+    // the sample shipped, back to the buyer otherwise; the escrow closes to the buyer
+    pub fn void_sample(ctx: Context<VoidSample>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let sample = &ctx.accounts.sample;
+        require!(sample.lapsed(Clock::get()?.unix_timestamp), CoffeeError::SampleWindowOpen);
+        let amount = ctx.accounts.sample_vault.amount;
+        let to_farmer = sample.fee_owed_to_farmer();
+        let receive = if to_farmer { &ctx.accounts.farmer_receive } else { &ctx.accounts.buyer_receive };
+        transfer_from_sample(amount, sample, &ctx.accounts.sample_vault, receive, &ctx.accounts.token_program)?;
+        emit_event!(ctx, SampleVoided { schema_version: EVENT_SCHEMA_VERSION, sample: sample.key(), amount, to_farmer });
+        Ok(())
+    }
+
+    // Verifier advances a physical deal's shipment; on prepaid deals each milestone passed releases
+    // its tranche of the purchase escrow to the farmer ahead of delivery
+    pub fn update_shipment_status(ctx: Context<UpdateShipmentStatus>, status: u8) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        assert_is_verifier(&ctx.accounts.market, ctx.accounts.access_control.as_ref(), &ctx.accounts.verifier, ctx.remaining_accounts)?;
+        let next = ShipmentStatus::from_u8(status)?;
+        let deal_key = ctx.accounts.deal.key();
+        let market = &ctx.accounts.market;
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        require!(deal.physical_delivery, CoffeeError::WrongSettlementType);
+        let prev = deal.shipment_status;
+        require!(status > prev, CoffeeError::InvalidShipmentStatus);
+
+        let mut tranche = 0u64;
+        if deal.prepaid {
+            let escrow = ctx.accounts.purchase_escrow.as_ref().ok_or(CoffeeError::EscrowAccountMissing)?;
+            let farmer_receive = ctx.accounts.farmer_receive.as_ref().ok_or(CoffeeError::EscrowAccountMissing)?;
+            let notional: u64 = market.notional(deal.agreed_price_per_kg, deal.quantity_kg)?
+                .try_into()
+                .map_err(|_| CoffeeError::MathOverflow)?;
+            tranche = shipment_tranche(notional, &market.shipment_tranche_bps, prev, status)?.min(escrow.amount);
+            transfer_from_vault_to(tranche, &ctx.accounts.vault_auth, escrow, farmer_receive, &ctx.accounts.token_program, &deal_key)?;
+            deal.escrow_balance = deal.escrow_balance.saturating_sub(tranche);
+            deal.tranches_advanced = deal.tranches_advanced.checked_add(tranche).ok_or(CoffeeError::MathOverflow)?;
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        deal.shipment_status = status;
+        deal.shipment_updated_ts = now;
+        let risk_transferred = ShipmentStatus::risk_transfer(deal.incoterm).map_or(false, |m| prev < m as u8 && next >= m);
+
+        emit_event!(ctx, ShipmentMilestone {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            status,
+            ts: now,
+            tranche_paid: tranche,
+            risk_transferred,
+        });
+        Ok(())
+    }
+
+    // Post a settled deal's outcome as a Wormhole message so an escrow on the buyer's chain can release
+    // stablecoins there. Permissionless (the payer covers the bridge fee and message rent); once per deal
+    pub fn post_settlement_message(ctx: Context<PostSettlementMessage>) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        require!(!market.sandbox, CoffeeError::SandboxUnsupported);
+        require!(market.wormhole_program != Pubkey::default(), CoffeeError::WormholeNotConfigured);
+        let deal = &ctx.accounts.deal;
+        require!(deal.settled, CoffeeError::DealNotSettled);
+        require!(deal.crosschain_chain != 0, CoffeeError::NoCrosschainRecipient);
+        require!(!deal.crosschain_posted, CoffeeError::CrosschainAlreadyPosted);
+
+        let payload = wormhole::settlement_payload(&deal.key(), deal);
+        wormhole::post_settlement_message(&ctx.accounts, &ctx.bumps, payload)?;
+
+        let deal = &mut ctx.accounts.deal;
+        deal.crosschain_posted = true;
+        emit_event!(ctx, CrosschainMessagePosted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            chain: deal.crosschain_chain,
+            recipient: deal.crosschain_recipient,
+            outcome: wormhole::SettlementOutcome::of(deal) as u8,
+            message: ctx.accounts.wormhole_message.key(),
+        });
+        Ok(())
+    }
+
+    // Issue a fee waiver (admin or the market operator). A default beneficiary makes it a promo code
+    // any trader may redeem at open_deal until it expires or runs out of uses
+    pub fn issue_fee_waiver(
+        ctx: Context<IssueFeeWaiver>,
+        waiver_id: u64,
+        beneficiary: Pubkey,
+        discount_bps: u16,
+        expires_ts: i64,
+        max_uses: u32,
+    ) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        let market = &ctx.accounts.market;
+        let issuer = ctx.accounts.issuer.key();
+        // a waiver discounts the whole fee, insurance share included, so the operator is capped below a
+        // full waiver; only an Admin may zero a deal's fees
+        let by_operator = market.operator != Pubkey::default() && issuer == market.operator;
+        if !by_operator {
+            require_role(market, ctx.accounts.access_control.as_ref(), &issuer, Role::Admin, ctx.remaining_accounts)?;
+        }
+        require!(discount_bps > 0 && discount_bps <= MAX_BPS, CoffeeError::InvalidBps);
+        require!(!by_operator || discount_bps <= MAX_OPERATOR_FEE_WAIVER_BPS, CoffeeError::FeeWaiverTooLarge);
+        require!(max_uses > 0 && expires_ts > Clock::get()?.unix_timestamp, CoffeeError::InvalidFeeWaiver);
+
+        ctx.accounts.fee_waiver.set_inner(FeeWaiver {
+            market: market.key(),
+            issuer,
+            waiver_id,
+            beneficiary,
+            discount_bps,
+            expires_ts,
+            max_uses,
+            uses: 0,
+            bump: ctx.bumps.fee_waiver,
+        });
+        emit_event!(ctx, FeeWaiverIssued {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: market.key(),
+            waiver: ctx.accounts.fee_waiver.key(),
+            issuer,
+            beneficiary,
+            discount_bps,
+            expires_ts,
+            max_uses,
+        });
+        Ok(())
+    }
+
+    // Withdraw a waiver and reclaim its rent (issuer); deals that already redeemed it keep their discount
+    pub fn revoke_fee_waiver(ctx: Context<RevokeFeeWaiver>) -> Result<()> {
+        let waiver = &ctx.accounts.fee_waiver;
+        emit_event!(ctx, FeeWaiverRevoked {
+            schema_version: EVENT_SCHEMA_VERSION,
+            market: waiver.market,
+            waiver: waiver.key(),
+            uses: waiver.uses,
+        });
+        Ok(())
+    }
+
+    // Post margin on the farmer side of a live cash deal as a third party (NGO, exporter, bank). The
+    // stake is tracked on the deal and is first-loss: at settlement the farmer side's losses come out
+    // of it before the farmer's own margin. Needs the deal's own buyer vault, so the farmer side's
+    // balance is never mixed with the buyer's. One sponsor per deal
+    pub fn sponsor_margin(ctx: Context<SponsorMargin>, amount: u64) -> Result<()> {
+        version_guard_market(&ctx.accounts.market)?;
+        require!(amount > 0, CoffeeError::ZeroAmount);
+        let sponsor = ctx.accounts.sponsor.key();
+        let deal = &ctx.accounts.deal;
+        require!(!deal.settled && !deal.settling && !deal.liquidated && !deal.stopped, CoffeeError::DealAlreadySettled);
+        require!(deal.farmer_deposited && deal.buyer_deposited, CoffeeError::DealNotLive);
+        require!(!deal.physical_delivery && !deal.prepaid, CoffeeError::WrongSettlementType);
+        require!(!deal.omnibus, CoffeeError::OmnibusUnsupported);
+        require!(deal.farmer_margin_account == Pubkey::default(), CoffeeError::DealCrossMargined);
+        require!(deal.side_of(&sponsor).is_none(), CoffeeError::InvalidCounterparty);
+        require!(deal.sponsor == Pubkey::default() || deal.sponsor == sponsor, CoffeeError::SponsorMismatch);
+        require!(deal.split_vaults, CoffeeError::SponsoredVaultShared);
+        // everything the farmer side holds before the first stake is the farmer's own
+        let farmer_own = if deal.sponsored_margin == 0 { ctx.accounts.farmer_margin_vault.amount } else { deal.farmer_own_margin };
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.sponsor_ata.to_account_info(),
+                    to: ctx.accounts.farmer_margin_vault.to_account_info(),
+                    authority: ctx.accounts.sponsor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let deal = &mut ctx.accounts.deal;
+        deal.sponsor = sponsor;
+        deal.farmer_own_margin = farmer_own;
+        deal.sponsored_margin = deal.sponsored_margin.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        deal.margin_locked = deal.margin_locked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        let market = &mut ctx.accounts.market;
+        market.total_margin_locked = market.total_margin_locked.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        emit_event!(ctx, MarginSponsored {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal.key(),
+            deal_id: deal.deal_id,
+            sponsor,
+            amount,
+            sponsored_margin: deal.sponsored_margin,
+        });
+        record_deal_action(&ctx.accounts.deal, ctx.accounts.action_log.as_deref_mut(), DealAction::TopUp, sponsor, amount)?;
+        Ok(())
+    }
+}
+
+// ------------------------- Accounts & State -------------------------
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(decimals: u8)]
+pub struct InitCftMint<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        mint::decimals = CFT_DECIMALS, // choose alignment with decimals param if desired
+        mint::authority = cft_mint_auth,
+        mint::freeze_authority = cft_mint_auth,
+    )]
+    pub cft_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + CftMintAuth::SIZE,
+        seeds = [SEED_PREFIX, CFT_AUTH_SEED, cft_mint.key().as_ref()],
+        bump
+    )]
+    pub cft_mint_auth: Account<'info, CftMintAuth>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitSandboxMint<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, SANDBOX_SEED],
+        bump
+    )]
+    pub sandbox_auth: Account<'info, VaultAuth>,
+
+    #[account(
+        init,
+        payer = payer,
+        seeds = [SEED_PREFIX, SANDBOX_MINT_SEED],
+        bump,
+        mint::decimals = decimals,
+        mint::authority = sandbox_auth,
+    )]
+    pub sandbox_mint: Account<'info, Mint>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SandboxFaucet<'info> {
+    #[account(seeds = [SEED_PREFIX, SANDBOX_SEED], bump = sandbox_auth.bump)]
+    pub sandbox_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, seeds = [SEED_PREFIX, SANDBOX_MINT_SEED], bump)]
+    pub sandbox_mint: Account<'info, Mint>,
+
+    #[account(mut, token::mint = sandbox_mint)]
+    pub to: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Mint authority PDA; also the supply ledger shared by every market using this CFT mint (a single
+// market's ledger for per-market mints)
+#[account]
+pub struct CftMintAuth {
+    pub bump: u8,
+    pub total_cft_minted: u64,  // cumulative CFT minted by settlement (burns don't reduce it)
+    pub total_kg_verified: u64, // cumulative kg verified across all markets using the mint
+}
+impl CftMintAuth {
+    pub const SIZE: usize = 1 + 8
+        + 8 + 8;
+
+    pub fn record_verified(&mut self, kg: u64) -> Result<()> {
+        self.total_kg_verified = self.total_kg_verified.checked_add(kg).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // CFT is 1 unit per verified kg, so minted supply may never pass verified kilograms
+    pub fn record_mint(&mut self, amount: u64) -> Result<()> {
+        let minted = self.total_cft_minted.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        require_gte!(self.total_kg_verified, minted, CoffeeError::CftSupplyExceedsVerified);
+        self.total_cft_minted = minted;
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CreateMarket<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: multisig or authority PDA ok
+    #[account(mut)]
+    pub verifier: UncheckedAccount<'info>,
+
+    /// CHECK: multisig or oracle PDA ok
+    #[account(mut)]
+    pub oracle_publisher: UncheckedAccount<'info>,
+
+    // shared CFT mint; with market_cft_mint it only keys the market PDA
+    pub cft_mint: Account<'info, Mint>,
+    pub quote_mint: Account<'info, Mint>,
+
+    /// Insurance treasury ATA (must be ATA for quote_mint)
+    #[account(mut, constraint = insurance_treasury.mint == quote_mint.key())]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Market::INIT_SPACE,
+        seeds = [SEED_PREFIX, MARKET_SEED, authority.key().as_ref(), cft_mint.key().as_ref(), quote_mint.key().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+
+    // per-market CFT mode: a mint derived from the market and its own authority / supply ledger
+    #[account(
+        init,
+        payer = authority,
+        mint::decimals = CFT_DECIMALS,
+        mint::authority = market_cft_auth,
+        mint::freeze_authority = market_cft_auth,
+        seeds = [SEED_PREFIX, MARKET_CFT_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub market_cft_mint: Option<Account<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + CftMintAuth::SIZE,
+        seeds = [SEED_PREFIX, CFT_AUTH_SEED, market_cft_mint.key().as_ref()],
+        bump
+    )]
+    pub market_cft_auth: Option<Account<'info, CftMintAuth>>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[account]
+pub struct Market {
+    pub version: u8,
+    pub authority: Pubkey,
+    pub verifier: Pubkey,
+    pub oracle_publisher: Pubkey,
+
+    // pending rotation fields
+    pub pending_oracle: Pubkey,
+    pub pending_oracle_effective_ts: i64,
+
+    pub cft_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub insurance_treasury: Pubkey,
+
+    pub settlement_ts: i64,
+    pub contract_size_kg: u64,
+
+    // margins & fees
+    pub initial_margin_bps: u16,
+    pub maintenance_margin_bps: u16,
+    pub fee_bps: u16,
+    pub farmer_fee_bps: u16,
+    pub buyer_fee_bps: u16,
+    pub insurance_bps: u16,
+    pub default_margin_call_grace_sec: u64,
+
+    // exposure caps
+    pub max_notional_per_deal: u64,
+    pub max_qty_per_deal: u64,
+
+    // oracle / price
+    pub last_price_per_kg: u64,
+    pub prev_price_per_kg: u64,
+    pub last_price_nonce: u64,
+    pub last_oracle_update_ts: i64,
+    pub max_oracle_age_sec: u64,
+
+    // TWAP accumulator (time-weighted)
+    pub twap_acc: u128,     // sum(price * seconds)
+    pub twap_time_acc: u64, // sum(seconds)
+    pub twap_window_sec: u64,
+    pub price_mode: u8,
+
+    // operational
+    pub paused: bool,
+    pub min_transfer_amount: u64,
+
+    // misc
+    pub insurance_treasury_authority: Pubkey, // authority for insurance ATA transfers (hook for prod model)
+    pub program_version: u8,
+
+    // volume fee schedule (ascending min_notional); empty => flat fee_bps
+    pub fee_tier_count: u8,
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+
+    // discount applied when fees are paid (burned) in CFT
+    pub cft_fee_discount_bps: u16,
+
+    // timelocked verifier rotation
+    pub pending_verifier: Pubkey,
+    pub pending_verifier_effective_ts: i64,
+
+    // timelocked parameter update (effective_ts == 0 => none pending)
+    pub pending_params: MarketParams,
+    pub pending_params_effective_ts: i64,
+
+    // governance PDA; when set, admin changes must go through proposals
+    pub governance: Pubkey,
+
+    // how authority / verifier signatures are verified (single key or external multisig)
+    pub authority_config: AuthorityConfig,
+    pub verifier_config: AuthorityConfig,
+
+    // physical default penalty (bps of undelivered notional, farmer -> buyer)
+    pub default_penalty_bps: u16,
+
+    // physical settlement shortfall handling (PaymentPolicy)
+    pub physical_payment_policy: u8,
+    pub debt_claim_wait_sec: i64, // creditor may claim from insurance this long after open_debt
+    // auto-deleveraging: socialized loss per kg of open interest, charged to winning deals
+    pub loss_socialization_index: u128,
+    pub socialized_loss_total: u64,
+    // aggregate exposure of live deals, with optional caps (0 = uncapped)
+    pub total_open_deals: u64,
+    pub total_open_notional_kg: u64,
+    pub total_margin_locked: u64,
+    pub max_open_deals: u64,
+    pub max_open_notional_kg: u64,
+    // perpetual funding (MarketKind::Perpetual)
+    pub market_kind: u8,
+    pub funding_cap_bps: u16,             // max |premium| per day as bps of TWAP
+    pub last_funding_ts: i64,
+    pub cumulative_funding_per_kg: i128,  // paid by longs (negative: paid by shorts), FUNDING_PRECISION
+    pub open_price_qty_sum: u128,         // sum(agreed_price_per_kg * quantity_kg) over open deals
+    pub whole_contracts_only: bool, // open_deal quantity must be a multiple of contract_size_kg
+    pub tick_size: u64, // minimum price increment in the price_convention; 0 = any price
+    // decommissioning
+    pub unclosed_deals: u64,     // Deal accounts opened and not yet closed
+    pub close_after_ts: i64,     // 0 = not scheduled
+    pub deal_seq: u64, // last deal_id issued
+    pub require_fresh_price_for_open: bool, // open_deal needs an oracle update within max_oracle_age_sec
+    pub max_deal_price_deviation_bps: u16, // open_deal price vs oracle mark; 0 = off
+    pub delivery_tree: Pubkey, // concurrent merkle tree for compressed delivery attestations; default = off
+    pub cft_allowlist_required: bool, // CFT is only minted to wallets with a CftAllowlistEntry
+    pub margin_step_count: u8,
+    pub margin_steps: [MarginStep; MAX_MARGIN_STEPS], // initial margin raised as settlement_ts approaches
+    pub realized_var_bps2: u64, // EWMA of squared per-update price returns (bps^2)
+    pub vol_ref_bps: u16, // per-update volatility at which maintenance margin starts scaling; 0 = off
+    pub max_maintenance_margin_bps: u16, // upper bound for volatility-scaled maintenance margin
+    pub insurance_upfront: bool, // collect the insurance share at open_deal instead of at settlement
+    pub total_insurance_premiums: u64, // premiums collected up front over the market's life
+    pub insurance_stake_pool: Pubkey, // third-party first-loss capital; default = none
+    pub risk_officer: Pubkey,              // co-signs open_deal above large_deal_notional
+    pub large_deal_notional: u64,          // maker-checker threshold on deal notional; 0 = off
+    pub reduce_break_fee_bps: u16,         // fee on reduced notional for a one-sided reduce_deal; 0 = both parties must sign
+    pub cft_sale_treasury: Pubkey,         // receives the quote behind CFT minted to cash-settled buyers; default = off
+    pub cft_expiry_ts: i64,                // this harvest's CFT stops minting and becomes redeemable; 0 = never
+    pub cft_floor_price_per_kg: u64,       // quote paid per CFT redeemed after expiry, from the insurance fund
+    pub cft_minted: u64,                   // CFT minted by this market (the mint may be shared)
+    pub cft_redeemed: u64,                 // CFT redeemed against this market's insurance fund
+    pub cft_per_market: bool,              // cft_mint is this market's own PDA mint, not the shared one
+    pub sample_required: bool,             // open_deal needs an approved SampleEscrow for the pair
+    pub shipment_tranche_bps: [u16; SHIPMENT_MILESTONES], // escrow share released to the farmer per milestone (prepaid deals)
+    pub wormhole_program: Pubkey,          // Wormhole core bridge for cross-chain settlement messages; default = off
+    pub cctp_program: Pubkey,              // CCTP TokenMessengerMinter for bridged farmer payouts; default = off
+    pub operator: Pubkey,                  // white-label platform listing this market; default = none
+    pub operator_fee_bps: u16,             // operator's cut of the protocol share of settlement fees
+    pub operator_treasury: Pubkey,         // quote account the operator's cut is paid to
+    pub seed_version: u8,                  // 1 = [v1, market, ..], 2 = migrated to [v2, market, ..]
+    pub migrated_to: Pubkey,               // v2 successor once migrated; the v1 market stays paused
+    pub licensed_liquidators: bool,        // execute_liquidation limited to licensed, bonded liquidators
+    pub liquidator_min_bond: u64,          // quote a licensee's bond vault must hold to liquidate
+    pub liquidator_slash_bps: u16,         // share of the bond forfeited for liquidating a recovered deal
+    pub margin_cure_buffer_bps: u16,       // an open margin call clears above maintenance + this; 0 = calls stay open
+    pub omnibus_vaults: bool,              // new deals hold margin in the two market vaults below, tracked per deal
+    pub omnibus_farmer_vault: Pubkey,
+    pub omnibus_buyer_vault: Pubkey,
+    pub omnibus_open_deals: u32,           // unsettled omnibus deals; an audit covering this many is complete
+    pub ordered_settlement: bool,          // cash deals settle only through the SettlementQueue (liquidations excepted)
+    pub commit_reveal: bool,               // prices arrive via commit_price + reveal_price; publish_price is refused
+    pub min_reveal_delay_sec: u32,         // a reveal lands at least this long after its commit, never in its slot
+    pub price_commit: [u8; 32],            // pending commitment (see cpi_interface::price_commitment); zero = none
+    pub price_commit_ts: i64,
+    pub price_commit_slot: u64,
+    pub max_opens_per_hour: u16, // deals a wallet may open per hour, counted in its TraderStats; 0 = unlimited
+    pub price_exponent: i8, // quote base units per price unit = 10^price_exponent; 0 = prices in quote base units
+    pub price_convention: u8,       // PriceConvention; tick_size and oracle/deal banding apply in it
+    pub price_units_per_cent: u64,  // price units (per-kg price) in one US cent; CentsPerLb only
+    pub sandbox: bool, // quoted in the program's dummy sandbox mint; set at creation, never changes
+    pub settlement_window_sec: u64, // permissionless cash settlement must start this long after a deal falls due; 0 = no limit
+    // final settlement price dispute (fix_settlement_price); window 0 = off, cash deals settle off the feed
+    pub price_dispute_window_sec: u64,
+    pub price_challenge_bond: u64, // quote a counterparty posts to challenge the fixed price
+    pub settlement_price_per_kg: u64, // fixed cash settlement price; 0 = not fixed yet
+    pub settlement_price_fixed_ts: i64,
+    pub settlement_price_challenged: bool, // a PriceChallenge awaits the arbiter's ruling
+    pub settlement_price_ruled: bool, // the arbiter ruled; the price is final
+    pub stop_bounty_bps: u16, // execute_stop pays the executor this share of notional at the mark from the stopped side
+    // > 0: default penalties and stop bounties vest back to the party they were taken from over this
+    // long (MarginVesting) instead of being paid out; 0 = paid out as before
+    pub seized_vesting_sec: i64,
+}
+
+impl Market {
+    // rough size; tune before production
+    pub const INIT_SPACE: usize = 1 + 32*12 + 8*12 + 2*6 + 16 + 8 + 8 + 32
+        + 1 + FeeTier::SIZE * MAX_FEE_TIERS
+        + 2
+        + 32 + 8 + MarketParams::SIZE + 8 + 32
+        + AuthorityConfig::SIZE * 2
+        + 2
+        + 1
+        + 8
+        + 16 + 8
+        + 8*5
+        + 1 + 2 + 8 + 16 + 16
+        + 1
+        + 8
+        + 8 + 8
+        + 8
+        + 1
+        + 2
+        + 32
+        + 1
+        + 1 + MarginStep::SIZE * MAX_MARGIN_STEPS
+        + 8 + 2 + 2
+        + 1 + 8
+        + 32
+        + 32 + 8
+        + 2
+        + 32
+        + 8 + 8 + 8 + 8
+        + 1
+        + 1
+        + 2 * SHIPMENT_MILESTONES
+        + 32
+        + 32
+        + 32 + 2 + 32
+        + 1 + 32
+        + 1 + 8 + 2
+        + 2
+        + 1 + 32*2
+        + 4
+        + 1
+        + 1 + 4 + 32 + 8 + 8
+        + 2
+        + 1
+        + 1 + 8
+        + 1
+        + 8
+        + 8 + 8 + 8 + 8 + 1 + 1
+        + 2
+        + 8;
+
+    pub fn apply_params(&mut self, p: &MarketParams) {
+        self.initial_margin_bps = p.initial_margin_bps;
+        self.maintenance_margin_bps = p.maintenance_margin_bps;
+        self.fee_bps = p.fee_bps;
+        self.farmer_fee_bps = p.farmer_fee_bps;
+        self.buyer_fee_bps = p.buyer_fee_bps;
+        self.insurance_bps = p.insurance_bps;
+        self.max_notional_per_deal = p.max_notional_per_deal;
+        self.max_qty_per_deal = p.max_qty_per_deal;
+        self.max_oracle_age_sec = p.max_oracle_age_sec;
+        self.default_margin_call_grace_sec = p.default_margin_call_grace_sec;
+        self.min_transfer_amount = p.min_transfer_amount;
+    }
+
+    // highest tier whose threshold the volume reaches; falls back to the flat fee
+    pub fn fee_bps_for_volume(&self, rolling_notional: u64) -> u16 {
+        let mut bps = self.fee_bps;
+        for tier in self.fee_tiers.iter().take(self.fee_tier_count as usize) {
+            if rolling_notional >= tier.min_notional {
+                bps = tier.fee_bps;
+            }
+        }
+        bps
+    }
+
+    // initial margin from the schedule step closest to expiry that has been reached; never below the
+    // flat initial_margin_bps. Perpetuals have no expiry and always use the flat rate.
+    pub fn initial_margin_bps_at(&self, now_ts: i64) -> u16 {
+        let mut bps = self.initial_margin_bps;
+        if self.market_kind == MarketKind::Perpetual as u8 {
+            return bps;
+        }
+        let to_expiry = self.settlement_ts.saturating_sub(now_ts).max(0) as u64;
+        for step in self.margin_steps.iter().take(self.margin_step_count as usize) {
+            if to_expiry <= step.secs_to_expiry {
+                bps = bps.max(step.initial_margin_bps);
+            }
+        }
+        bps
+    }
+
+    // maintenance margin scaled by the same factor as the scheduled initial margin
+    // then raised with realized volatility up to max_maintenance_margin_bps; never above initial margin
+    pub fn maintenance_margin_bps_at(&self, now_ts: i64) -> u16 {
+        let initial = self.initial_margin_bps_at(now_ts);
+        let mut maint = self.maintenance_margin_bps as u64;
+        if self.initial_margin_bps > 0 && initial != self.initial_margin_bps {
+            maint = maint * initial as u64 / self.initial_margin_bps as u64;
+        }
+        let maint = maint.min(initial as u64) as u16;
+        math::vol_scaled_bps(maint, self.realized_vol_bps(), self.vol_ref_bps, self.max_maintenance_margin_bps)
+            .unwrap_or(maint)
+            .min(initial)
+    }
+
+    // per-update realized volatility (bps) from the EWMA variance
+    pub fn realized_vol_bps(&self) -> u64 {
+        math::isqrt(self.realized_var_bps2)
+    }
+
+    // Price-unit amounts (price_per_kg * kg) in quote base units, per price_exponent. Every amount that
+    // moves tokens or is compared with a quote-denominated limit goes through these
+    pub fn to_quote_signed(&self, amount: i128) -> Result<i128> {
+        math::to_quote_units_signed(amount, self.price_exponent).ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    pub fn notional(&self, price_per_kg: u64, quantity_kg: u64) -> Result<u128> {
+        math::quote_notional(price_per_kg, quantity_kg, self.price_exponent).ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    pub fn pnl(&self, agreed: u64, mark: u64, qty: u64, side: math::Side) -> Result<i128> {
+        math::quote_pnl(agreed, mark, qty, side, self.price_exponent).ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    pub fn required_margin(&self, price_per_kg: u64, quantity_kg: u64, margin_bps: u16) -> Result<u64> {
+        math::quote_required_margin(price_per_kg, quantity_kg, margin_bps, self.price_exponent)
+            .ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    // maker-checker threshold for open_deal; strictly above the threshold needs the co-signature
+    pub fn requires_risk_cosign(&self, notional: u128) -> bool {
+        self.large_deal_notional > 0 && notional > self.large_deal_notional as u128
+    }
+
+    // past the CFT expiry: no more minting, redemption at the floor price is open
+    pub fn cft_expired(&self, now_ts: i64) -> bool {
+        self.cft_expiry_ts > 0 && now_ts >= self.cft_expiry_ts
+    }
+
+    pub fn record_cft_mint(&mut self, amount: u64) -> Result<()> {
+        self.cft_minted = self.cft_minted.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // redemptions are capped at what this market minted, so a shared mint cannot drain its fund
+    pub fn record_cft_redemption(&mut self, amount: u64) -> Result<()> {
+        let redeemed = self.cft_redeemed.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        require_gte!(self.cft_minted, redeemed, CoffeeError::CftRedemptionCapExceeded);
+        self.cft_redeemed = redeemed;
+        Ok(())
+    }
+
+    // The fixed settlement price can no longer change: ruled on by the arbiter, or unchallenged
+    // through the dispute window
+    pub fn settlement_price_final(&self, now_ts: i64) -> bool {
+        let window = i64::try_from(self.price_dispute_window_sec).unwrap_or(i64::MAX);
+        self.settlement_price_ruled
+            || (self.settlement_price_per_kg > 0
+                && !self.settlement_price_challenged
+                && now_ts >= self.settlement_price_fixed_ts.saturating_add(window))
+    }
+
+    // Under a dispute window, expiry settlement at the fixed price waits until that price is final.
+    // A liquidated deal settles at mark and is never held
+    pub fn settlement_awaits_final_price(&self, deal: &Deal, now_ts: i64) -> bool {
+        self.price_dispute_window_sec > 0
+            && now_ts >= self.settlement_ts
+            && !deal.liquidated
+            && !self.settlement_price_final(now_ts)
+    }
+
+    // Price a physical default cash-settles its undelivered kg at: the fixed settlement price once
+    // settlement_ts has passed and that price is final, else the mark off a fresh feed
+    pub fn default_price(&self, now_ts: i64) -> Result<u64> {
+        if now_ts >= self.settlement_ts && self.settlement_price_final(now_ts) {
+            return Ok(self.settlement_price_per_kg);
+        }
+        require!(self.oracle_is_fresh(now_ts), CoffeeError::OracleStale);
+        mark_price(self)
+    }
+
+    // counterparties may still challenge the fixed price
+    pub fn price_challengeable(&self, now_ts: i64) -> bool {
+        let window = i64::try_from(self.price_dispute_window_sec).unwrap_or(i64::MAX);
+        self.settlement_price_per_kg > 0
+            && !self.settlement_price_challenged
+            && !self.settlement_price_ruled
+            && now_ts < self.settlement_price_fixed_ts.saturating_add(window)
+    }
+
+    // A deal that never started settling and fell due (settlement_ts, or its deadline_ts if earlier)
+    // more than settlement_window_sec ago. Only the arbiter may settle it then
+    pub fn settlement_window_missed(&self, deal: &Deal, now_ts: i64) -> bool {
+        let due_ts = self.settlement_ts.min(deal.deadline_ts);
+        let window = i64::try_from(self.settlement_window_sec).unwrap_or(i64::MAX);
+        self.settlement_window_sec > 0
+            && deal.settlement_step == settlement::SettlementStep::NotStarted as u8
+            && now_ts > due_ts.saturating_add(window)
+    }
+
+    // a price has been published (or heartbeated) within max_oracle_age_sec; age 0 = no limit
+    pub fn oracle_is_fresh(&self, now_ts: i64) -> bool {
+        if self.last_oracle_update_ts == 0 {
+            return false;
+        }
+        self.max_oracle_age_sec == 0
+            || abs_i64_to_u64(now_ts - self.last_oracle_update_ts) <= self.max_oracle_age_sec
+    }
+
+    // margin (bps of notional) an open margin call must regain to clear; None = calls stay open
+    pub fn margin_cure_bps(&self, maintenance_bps: u16) -> Option<u16> {
+        (self.margin_cure_buffer_bps > 0).then(|| maintenance_bps.saturating_add(self.margin_cure_buffer_bps).min(MAX_BPS))
+    }
+
+    pub fn is_on_tick(&self, price_per_kg: u64) -> bool {
+        if self.tick_size == 0 {
+            return true;
+        }
+        if self.price_convention != PriceConvention::CentsPerLb as u8 {
+            return price_per_kg % self.tick_size == 0;
+        }
+        // on a ¢/lb tick, and exactly the per-kg price that quote converts to
+        self.quoted_price(price_per_kg).map_or(false, |quoted| {
+            quoted % self.tick_size == 0 && self.price_from_quoted(quoted).ok() == Some(price_per_kg)
+        })
+    }
+
+    // per-kg price in the market's quoted convention, and back
+    pub fn quoted_price(&self, price_per_kg: u64) -> Result<u64> {
+        if self.price_convention != PriceConvention::CentsPerLb as u8 {
+            return Ok(price_per_kg);
+        }
+        math::price_per_kg_to_cents_lb(price_per_kg, self.price_units_per_cent).ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    pub fn price_from_quoted(&self, quoted: u64) -> Result<u64> {
+        if self.price_convention != PriceConvention::CentsPerLb as u8 {
+            return Ok(quoted);
+        }
+        math::cents_lb_to_price_per_kg(quoted, self.price_units_per_cent).ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    // |price - reference| in bps of reference, measured in the quoted convention
+    pub fn price_band_bps(&self, reference: u64, price: u64) -> Result<u128> {
+        math::deviation_bps(self.quoted_price(reference)?, self.quoted_price(price)?).ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    // number of contracts `quantity_kg` represents, if it is a whole multiple of contract_size_kg
+    pub fn contracts_for_qty(&self, quantity_kg: u64) -> Option<u64> {
+        if self.contract_size_kg == 0 || quantity_kg % self.contract_size_kg != 0 {
+            return None;
+        }
+        Some(quantity_kg / self.contract_size_kg)
+    }
+
+    // count a newly opened deal against the aggregates, enforcing the caps
+    pub fn add_open_interest(&mut self, kg: u64, margin: u64) -> Result<()> {
+        let (deals, notional_kg) = self.open_interest_after(kg)?;
+        self.total_open_deals = deals;
+        self.total_open_notional_kg = notional_kg;
+        self.total_margin_locked = self.total_margin_locked.checked_add(margin).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // Book a deal opened with both margins already paid in from escrow (standing orders, auctions)
+    pub fn record_escrowed_open(&mut self, deal: &Deal) -> Result<()> {
+        self.add_open_interest(deal.quantity_kg, deal.margin_locked)?;
+        self.unclosed_deals = self.unclosed_deals.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        self.deal_seq = deal.deal_id;
+        let price_qty = math::notional(deal.agreed_price_per_kg, deal.quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+        self.open_price_qty_sum = self.open_price_qty_sum.checked_add(price_qty).ok_or(CoffeeError::MathOverflow)?;
+        self.total_insurance_premiums = self.total_insurance_premiums.checked_add(deal.insurance_prepaid).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // (open deals, open kg) with one more deal of `kg`, within the open-interest caps
+    pub fn open_interest_after(&self, kg: u64) -> Result<(u64, u64)> {
+        let deals = self.total_open_deals.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        let notional_kg = self.total_open_notional_kg.checked_add(kg).ok_or(CoffeeError::MathOverflow)?;
+        if self.max_open_deals > 0 {
+            require_gte!(self.max_open_deals, deals, CoffeeError::OpenInterestCapExceeded);
+        }
+        if self.max_open_notional_kg > 0 {
+            require_gte!(self.max_open_notional_kg, notional_kg, CoffeeError::OpenInterestCapExceeded);
+        }
+        Ok((deals, notional_kg))
+    }
+
+    // remove a settled/cancelled deal from the aggregates
+    pub fn release_open_interest(&mut self, deal: &Deal) {
+        self.total_open_deals = self.total_open_deals.saturating_sub(1);
+        self.total_open_notional_kg = self.total_open_notional_kg.saturating_sub(deal.quantity_kg);
+        self.total_margin_locked = self.total_margin_locked.saturating_sub(deal.margin_locked);
+        let notional = (deal.agreed_price_per_kg as u128).saturating_mul(deal.quantity_kg as u128);
+        self.open_price_qty_sum = self.open_price_qty_sum.saturating_sub(notional);
+        if deal.omnibus {
+            self.omnibus_open_deals = self.omnibus_open_deals.saturating_sub(1);
+        }
+    }
+
+    // roll the funding accumulator forward by whole intervals elapsed since last_funding_ts
+    pub fn accrue_funding(&mut self, now: i64) -> Result<()> {
+        let intervals = now.saturating_sub(self.last_funding_ts) / FUNDING_INTERVAL_SEC;
+        if intervals <= 0 {
+            return Ok(());
+        }
+        self.last_funding_ts = self
+            .last_funding_ts
+            .checked_add(intervals * FUNDING_INTERVAL_SEC)
+            .ok_or(CoffeeError::MathOverflow)?;
+        let twap = match math::twap(self.twap_acc, self.twap_time_acc) {
+            Some(t) if self.total_open_notional_kg > 0 => t as i128,
+            _ => return Ok(()),
+        };
+        // premium of the average open deal price over the oracle TWAP, capped
+        let avg_deal_price = (self.open_price_qty_sum / self.total_open_notional_kg as u128) as i128;
+        let cap = twap * self.funding_cap_bps as i128 / 10_000;
+        let premium = (avg_deal_price - twap).clamp(-cap, cap);
+        let per_interval = premium * FUNDING_PRECISION / FUNDING_PERIODS_PER_DAY;
+        self.cumulative_funding_per_kg = self
+            .cumulative_funding_per_kg
+            .checked_add(per_interval.checked_mul(intervals as i128).ok_or(CoffeeError::MathOverflow)?)
+            .ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // spread `loss` over every kg still open; returns the index increment
+    pub fn socialize_loss(&mut self, loss: u64) -> Result<u128> {
+        require!(self.total_open_notional_kg > 0, CoffeeError::NoOpenInterest);
+        let delta = (loss as u128)
+            .checked_mul(ADL_INDEX_PRECISION)
+            .ok_or(CoffeeError::MathOverflow)?
+            / self.total_open_notional_kg as u128;
+        self.loss_socialization_index = self.loss_socialization_index.checked_add(delta).ok_or(CoffeeError::MathOverflow)?;
+        self.socialized_loss_total = self.socialized_loss_total.checked_add(loss).ok_or(CoffeeError::MathOverflow)?;
+        Ok(delta)
+    }
+}
+
+// How a role key proves its signature. For ExternalMultisig the key is the multisig's vault PDA
+// (signing via CPI), and the top-level instruction must come from `program` with a config
+// account whose approval threshold is at least `min_threshold`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorityConfig {
+    SingleKey,
+    ExternalMultisig { program: Pubkey, multisig: Pubkey, min_threshold: u16 },
+}
+impl AuthorityConfig {
+    pub const SIZE: usize = 1 + 32 + 32 + 2;
+}
+
+// One delivered lot: manifest leaf plus its proof against deal.merkle_root
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MerkleLot {
+    pub leaf: [u8; 32],
+    pub proof: Vec<[u8; 32]>, // capped by MAX_PROOF_HASHES
+}
+
+// Risk/fee parameters changeable through the timelocked update path
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct MarketParams {
+    pub initial_margin_bps: u16,
+    pub maintenance_margin_bps: u16,
+    pub fee_bps: u16,
+    pub farmer_fee_bps: u16,
+    pub buyer_fee_bps: u16,
+    pub insurance_bps: u16,
+    pub max_notional_per_deal: u64,
+    pub max_qty_per_deal: u64,
+    pub max_oracle_age_sec: u64,
+    pub default_margin_call_grace_sec: u64,
+    pub min_transfer_amount: u64,
+}
+impl MarketParams {
+    pub const SIZE: usize = 2*6 + 8*5;
+
+    // Hard bounds shared by create_market and propose_params_update. Each one guards settlement: an
+    // over-100% split underflows the protocol cut, so every fee-charging close would revert
+    pub fn validate(&self) -> Result<()> {
+        require_gte!(self.initial_margin_bps, self.maintenance_margin_bps, CoffeeError::BadMarginParams);
+        require!(self.initial_margin_bps <= MAX_BPS, CoffeeError::MarginBpsTooHigh);
+        require!(self.fee_bps <= MAX_FEE_BPS, CoffeeError::FeeBpsTooHigh);
+        let split = self.farmer_fee_bps as u32 + self.buyer_fee_bps as u32 + self.insurance_bps as u32;
+        require!(split <= MAX_BPS as u32, CoffeeError::FeeSplitTooHigh);
+        require!(self.max_notional_per_deal > 0 && self.max_qty_per_deal > 0, CoffeeError::ZeroDealCap);
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeTier {
+    pub min_notional: u64,
+    pub fee_bps: u16,
+}
+impl FeeTier {
+    pub const SIZE: usize = 8 + 2;
+}
+
+// Within `secs_to_expiry` of settlement_ts the initial margin is at least `initial_margin_bps`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct MarginStep {
+    pub secs_to_expiry: u64,
+    pub initial_margin_bps: u16,
+}
+impl MarginStep {
+    pub const SIZE: usize = 8 + 2;
+}
+
+// Market configuration by the authority or a delegated ADMIN
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MarketAdmin<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetOmnibusVaults<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, OMNIBUS_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub omnibus_auth: Account<'info, VaultAuth>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        seeds = [SEED_PREFIX, OMNIBUS_VAULT_SEED, market.key().as_ref(), &[0]],
+        bump,
+        token::mint = quote_mint,
+        token::authority = omnibus_auth,
+    )]
+    pub omnibus_farmer_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        seeds = [SEED_PREFIX, OMNIBUS_VAULT_SEED, market.key().as_ref(), &[1]],
+        bump,
+        token::mint = quote_mint,
+        token::authority = omnibus_auth,
+    )]
+    pub omnibus_buyer_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct AuditOmnibus<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(address = market.omnibus_farmer_vault @ CoffeeError::OmnibusVaultMismatch)]
+    pub omnibus_farmer_vault: Account<'info, TokenAccount>,
+
+    #[account(address = market.omnibus_buyer_vault @ CoffeeError::OmnibusVaultMismatch)]
+    pub omnibus_buyer_vault: Account<'info, TokenAccount>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MigrateDealToOmnibus<'info> {
+    pub farmer: Signer<'info>,
+
+    // receives the deal vault's rent
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = farmer, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // the farmer margin vault; also the buyer's on a deal without split_vaults
+    #[account(mut, associated_token::mint = market.quote_mint, associated_token::authority = vault_auth)]
+    pub vault: Account<'info, TokenAccount>,
+
+    /// CHECK: the buyer_vault PDA; only read when the deal has split_vaults
+    #[account(mut, seeds = [SEED_PREFIX, BUYER_VAULT_SEED, deal.key().as_ref()], bump)]
+    pub buyer_vault: UncheckedAccount<'info>,
+
+    #[account(mut, address = market.omnibus_farmer_vault @ CoffeeError::OmnibusVaultMismatch)]
+    pub omnibus_farmer_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, address = market.omnibus_buyer_vault @ CoffeeError::OmnibusVaultMismatch)]
+    pub omnibus_buyer_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetFeeCurrency<'info> {
+    /// CHECK: must sign, unless `agent` signs under the farmer's `agent_grant`
+    pub farmer: UncheckedAccount<'info>,
+    pub buyer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = farmer, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+
+    // power of attorney: an agent may act for the farmer under a live grant
+    pub agent: Option<Signer<'info>>,
+    #[account(
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent_grant.agent.as_ref()],
+        bump = agent_grant.bump
+    )]
+    pub agent_grant: Option<Account<'info, AgentGrant>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ElectCftPayout<'info> {
+    pub buyer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetCrosschainRecipient<'info> {
+    pub buyer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetPayoutBridge<'info> {
+    pub farmer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = farmer)]
+    pub deal: Account<'info, Deal>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetOperatorTreasury<'info> {
+    #[account(address = market.operator @ CoffeeError::Unauthorized)]
+    pub operator: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(constraint = operator_treasury.mint == market.quote_mint)]
+    pub operator_treasury: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct InitTraderStats<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    /// CHECK: trader whose volume is tracked; any key
+    pub trader: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + TraderStats::SIZE,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), trader.key().as_ref()],
+        bump
+    )]
+    pub trader_stats: Account<'info, TraderStats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetRateLimitExempt<'info> {
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), trader_stats.trader.as_ref()],
+        bump = trader_stats.bump
+    )]
+    pub trader_stats: Account<'info, TraderStats>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(page: u32)]
+pub struct InitParticipantIndexPage<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: wallet whose deals the index lists; any key
+    pub wallet: UncheckedAccount<'info>,
+
+    // page 0, which tracks the last page; required for every later page
+    #[account(mut, seeds = [SEED_PREFIX, PARTICIPANT_INDEX_SEED, wallet.key().as_ref(), &0u32.to_le_bytes()], bump = head.bump)]
+    pub head: Option<Box<Account<'info, ParticipantIndex>>>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + ParticipantIndex::SIZE,
+        seeds = [SEED_PREFIX, PARTICIPANT_INDEX_SEED, wallet.key().as_ref(), &page.to_le_bytes()],
+        bump
+    )]
+    pub index: Box<Account<'info, ParticipantIndex>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitLiquidationQueue<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + LiquidationQueue::SIZE,
+        seeds = [SEED_PREFIX, LIQUIDATION_QUEUE_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub liquidation_queue: Account<'info, LiquidationQueue>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ExecuteLiquidation<'info> {
+    pub settle: SettleCash<'info>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, LIQUIDATION_QUEUE_SEED, settle.market.key().as_ref()],
+        bump = liquidation_queue.bump
+    )]
+    pub liquidation_queue: Account<'info, LiquidationQueue>,
+
+    // required when market.licensed_liquidators; the license must belong to `liquidator`
+    pub liquidator: Option<Signer<'info>>,
+
+    #[account(mut, constraint = liquidator_license.market == settle.market.key() @ CoffeeError::LiquidatorNotLicensed)]
+    pub liquidator_license: Option<Account<'info, LiquidatorLicense>>,
+
+    #[account(mut)]
+    pub bond_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PruneLiquidationQueue<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [SEED_PREFIX, LIQUIDATION_QUEUE_SEED, market.key().as_ref()], bump = liquidation_queue.bump)]
+    pub liquidation_queue: Account<'info, LiquidationQueue>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetSettlementQueue<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + SettlementQueue::SIZE,
+        seeds = [SEED_PREFIX, SETTLEMENT_QUEUE_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub settlement_queue: Box<Account<'info, SettlementQueue>>,
+
+    // crank rebates are paid from here; funded from protocol fee revenue
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = quote_mint,
+        associated_token::authority = settlement_queue,
+    )]
+    pub fee_pot: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct DropSettlementQueueHead<'info> {
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, seeds = [SEED_PREFIX, SETTLEMENT_QUEUE_SEED, market.key().as_ref()], bump = settlement_queue.bump)]
+    pub settlement_queue: Box<Account<'info, SettlementQueue>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct EnqueueSettlement<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, seeds = [SEED_PREFIX, SETTLEMENT_QUEUE_SEED, market.key().as_ref()], bump = settlement_queue.bump)]
+    pub settlement_queue: Box<Account<'info, SettlementQueue>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ProcessSettlementQueue<'info> {
+    pub settle: SettleCash<'info>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, SETTLEMENT_QUEUE_SEED, settle.market.key().as_ref()],
+        bump = settlement_queue.bump
+    )]
+    pub settlement_queue: Box<Account<'info, SettlementQueue>>,
+
+    pub crank: Signer<'info>,
+
+    #[account(mut, constraint = crank_receive.mint == settle.market.quote_mint && crank_receive.owner == crank.key())]
+    pub crank_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, address = settlement_queue.fee_pot)]
+    pub fee_pot: Account<'info, TokenAccount>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(liquidator: Pubkey)]
+pub struct SetLiquidatorLicense<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + LiquidatorLicense::SIZE,
+        seeds = [SEED_PREFIX, LIQUIDATOR_LICENSE_SEED, market.key().as_ref(), liquidator.as_ref()],
+        bump
+    )]
+    pub license: Account<'info, LiquidatorLicense>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PostLiquidatorBond<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [SEED_PREFIX, LIQUIDATOR_LICENSE_SEED, market.key().as_ref(), liquidator.key().as_ref()],
+        bump = license.bump,
+        has_one = market,
+        has_one = liquidator
+    )]
+    pub license: Account<'info, LiquidatorLicense>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = liquidator,
+        associated_token::mint = quote_mint,
+        associated_token::authority = license,
+    )]
+    pub bond_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = liquidator_quote_ata.mint == market.quote_mint)]
+    pub liquidator_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct WithdrawLiquidatorBond<'info> {
+    pub liquidator: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [SEED_PREFIX, LIQUIDATOR_LICENSE_SEED, market.key().as_ref(), liquidator.key().as_ref()],
+        bump = license.bump,
+        has_one = market,
+        has_one = liquidator
+    )]
+    pub license: Account<'info, LiquidatorLicense>,
+
+    #[account(mut, constraint = bond_vault.owner == license.key() && bond_vault.mint == market.quote_mint)]
+    pub bond_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = liquidator_quote_ata.mint == market.quote_mint)]
+    pub liquidator_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// A market's license for one liquidator. The bond is whatever quote sits in the license PDA's ATA
+#[account]
+pub struct LiquidatorLicense {
+    pub market: Pubkey,
+    pub liquidator: Pubkey,
+    pub active: bool,
+    pub liquidations: u32,
+    pub slashed_total: u64,
+    pub bump: u8,
+}
+impl LiquidatorLicense {
+    pub const SIZE: usize = 32 + 32 + 1 + 4 + 8 + 1;
+}
+
+// A counterparty's dispute of the market's fixed settlement price. The bond sits in this PDA's ATA
+#[account]
+pub struct PriceChallenge {
+    pub market: Pubkey,
+    pub challenger: Pubkey,
+    pub proposed_price_per_kg: u64,
+    pub bond: u64,
+    pub bump: u8,
+}
+impl PriceChallenge {
+    pub const SIZE: usize = 32 + 32 + 8 + 8 + 1;
+}
+
+// Deals flagged by mark_to_market, largest maintenance shortfall first (ties in flag order), so keepers
+// poll one account instead of scanning deals. When full, the smallest shortfall is dropped
+#[account]
+pub struct LiquidationQueue {
+    pub market: Pubkey,
+    pub len: u8,
+    pub entries: [LiquidationEntry; MAX_LIQUIDATION_QUEUE],
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LiquidationEntry {
+    pub deal: Pubkey,
+    pub shortfall: u64, // larger side's vault shortfall against maintenance, at the last flag
+    pub flagged_ts: i64, // first flag
+}
+
+impl LiquidationQueue {
+    pub const SIZE: usize = 32 + 1 + (32 + 8 + 8) * MAX_LIQUIDATION_QUEUE + 1;
+
+    pub fn entries(&self) -> &[LiquidationEntry] {
+        &self.entries[..self.len as usize]
+    }
+
+    pub fn head(&self) -> Option<&LiquidationEntry> {
+        self.entries().first()
+    }
+
+    // insert `deal` or update its shortfall, keeping the order; false if it ranks below a full queue
+    pub fn upsert(&mut self, deal: Pubkey, shortfall: u64, now: i64) -> bool {
+        let flagged_ts = self.entries().iter().find(|e| e.deal == deal).map_or(now, |e| e.flagged_ts);
+        self.remove(&deal);
+        let len = self.len as usize;
+        let pos = self.entries().iter().position(|e| e.shortfall < shortfall).unwrap_or(len);
+        if pos >= MAX_LIQUIDATION_QUEUE {
+            return false;
+        }
+        let end = len.min(MAX_LIQUIDATION_QUEUE - 1);
+        self.entries.copy_within(pos..end, pos + 1);
+        self.entries[pos] = LiquidationEntry { deal, shortfall, flagged_ts };
+        self.len = (end + 1) as u8;
+        true
+    }
+
+    pub fn remove(&mut self, deal: &Pubkey) -> bool {
+        let len = self.len as usize;
+        match self.entries().iter().position(|e| e.deal == *deal) {
+            Some(i) => {
+                self.entries.copy_within(i + 1..len, i);
+                self.entries[len - 1] = LiquidationEntry::default();
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Due cash deals in registration order, each settling once the epoch window it registered in has
+// closed. Entries of a later window never precede an earlier one, so the head is always next
+#[account]
+pub struct SettlementQueue {
+    pub market: Pubkey,
+    pub fee_pot: Pubkey,     // quote ATA of this PDA, crank rebates come out of it
+    pub epoch_sec: i64,
+    pub rebate_bps: u16,     // of the fees a processed settlement collects
+    pub processed: u64,
+    pub len: u8,
+    pub entries: [SettlementEntry; MAX_SETTLEMENT_QUEUE],
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SettlementEntry {
+    pub deal: Pubkey,
+    pub epoch: u64,
+    pub closes_ts: i64, // end of the registration window; settles no earlier
+}
+
+impl SettlementQueue {
+    pub const SIZE: usize = 32*2 + 8 + 2 + 8 + 1 + (32 + 8 + 8) * MAX_SETTLEMENT_QUEUE + 1;
+
+    pub fn entries(&self) -> &[SettlementEntry] {
+        &self.entries[..self.len as usize]
+    }
+
+    pub fn head(&self) -> Option<&SettlementEntry> {
+        self.entries().first()
+    }
+
+    // (epoch, close ts) of the window `now` falls in
+    pub fn window(&self, now: i64) -> (u64, i64) {
+        let epoch = now.div_euclid(self.epoch_sec);
+        (epoch.max(0) as u64, epoch.saturating_add(1).saturating_mul(self.epoch_sec))
+    }
+
+    // append `deal`; returns its position
+    pub fn push(&mut self, deal: Pubkey, epoch: u64, closes_ts: i64) -> Result<u8> {
+        require!(self.entries().iter().all(|e| e.deal != deal), CoffeeError::SettlementAlreadyQueued);
+        let len = self.len as usize;
+        require!(len < MAX_SETTLEMENT_QUEUE, CoffeeError::SettlementQueueFull);
+        self.entries[len] = SettlementEntry { deal, epoch, closes_ts };
+        self.len += 1;
+        Ok(len as u8)
+    }
+
+    pub fn pop_front(&mut self) -> Option<SettlementEntry> {
+        let head = *self.head()?;
+        let len = self.len as usize;
+        self.entries.copy_within(1..len, 0);
+        self.entries[len - 1] = SettlementEntry::default();
+        self.len -= 1;
+        Some(head)
+    }
+
+    // empty the queue; returns how many entries it held
+    pub fn clear(&mut self) -> u8 {
+        let dropped = self.len;
+        self.entries = [SettlementEntry::default(); MAX_SETTLEMENT_QUEUE];
+        self.len = 0;
+        dropped
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct TraderStats {
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub rolling_notional: u64, // decayed over TRADER_STATS_WINDOW_SEC
+    pub lifetime_notional: u64,
+    pub last_update_ts: i64,
+    pub bump: u8,
+
+    // reputation
+    pub defaults: u32,
+
+    // open rate limit (Market::max_opens_per_hour)
+    pub opens_window_ts: i64, // start of the current hour window
+    pub opens_in_window: u16,
+    pub rate_limit_exempt: bool, // admin allowlist, e.g. known institutional buyers
+}
+
+impl TraderStats {
+    pub const SIZE: usize = 32*2 + 8*3 + 1 + 4 + 8 + 2 + 1;
+
+    // Linear decay of the rolling bucket (same approximation as the TWAP window), then add.
+    pub fn record_notional(&mut self, notional: u64, now_ts: i64) -> Result<()> {
+        self.rolling_notional = decay_rolling(self.rolling_notional, self.last_update_ts, now_ts, TRADER_STATS_WINDOW_SEC)?
+            .checked_add(notional)
+            .ok_or(CoffeeError::MathOverflow)?;
+        self.lifetime_notional = self.lifetime_notional.checked_add(notional).ok_or(CoffeeError::MathOverflow)?;
+        self.last_update_ts = now_ts;
+        Ok(())
+    }
+
+    // Count `opens` new deals against the hourly limit. Fixed windows: the first open after a window
+    // lapses starts the next one. Exempt traders are still counted, just never refused
+    pub fn record_opens(&mut self, opens: u16, max_per_hour: u16, now_ts: i64) -> Result<()> {
+        if now_ts.saturating_sub(self.opens_window_ts) >= OPEN_RATE_WINDOW_SEC {
+            self.opens_window_ts = now_ts;
+            self.opens_in_window = 0;
+        }
+        self.opens_in_window = self.opens_in_window.saturating_add(opens);
+        require!(
+            self.rate_limit_exempt || max_per_hour == 0 || self.opens_in_window <= max_per_hour,
+            CoffeeError::OpenRateLimited
+        );
+        Ok(())
+    }
+}
+
+// One page of a wallet's deal addresses, across markets, in open order. Pages fill one after another;
+// a pair that reopens at the same deal address is listed again, so readers dedupe
+#[account]
+#[derive(Default)]
+pub struct ParticipantIndex {
+    pub wallet: Pubkey,
+    pub page: u32,
+    pub last_page: u32, // kept current on page 0 only
+    pub len: u8,
+    pub deals: [Pubkey; PARTICIPANT_INDEX_PAGE_LEN],
+    pub bump: u8,
+}
+
+impl ParticipantIndex {
+    pub const SIZE: usize = 32 + 4 + 4 + 1 + 32 * PARTICIPANT_INDEX_PAGE_LEN + 1;
+
+    pub fn deals(&self) -> &[Pubkey] {
+        &self.deals[..self.len as usize]
+    }
+
+    pub fn push(&mut self, deal: Pubkey) -> Result<()> {
+        let len = self.len as usize;
+        require!(len < PARTICIPANT_INDEX_PAGE_LEN, CoffeeError::ParticipantIndexFull);
+        self.deals[len] = deal;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+// Deal actions kept in the ActionLog, in the order they happened
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DealAction {
+    TopUp = 0,
+    MarginCall = 1,
+    Reduce = 2,
+    Settle = 3,
+    Cancel = 4,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ActionEntry {
+    pub action: u8, // DealAction
+    pub actor: Pubkey, // default when the caller is permissionless
+    pub amount: u64, // quote moved, kg reduced or grace seconds, by action
+    pub ts: i64,
+}
+
+impl ActionEntry {
+    pub const SIZE: usize = 1 + 32 + 8 + 8;
+
+    // compressed-tree leaf for the entry at position `seq` of the deal's log
+    pub fn leaf(&self, deal: &Pubkey, seq: u64) -> [u8; 32] {
+        solana_program::keccak::hashv(&[
+            deal.as_ref(),
+            &seq.to_le_bytes(),
+            &[self.action],
+            self.actor.as_ref(),
+            &self.amount.to_le_bytes(),
+            &self.ts.to_le_bytes(),
+        ]).0
+    }
+}
+
+// Append-only per-deal record of its actions, so disputes can be replayed from account state rather
+// than transaction logs. Holds up to max_entries; once full, archive_action_log moves the entries
+// to the market's compressed tree and the archive digest commits to everything moved so far
+#[account]
+#[derive(Default)]
+pub struct ActionLog {
+    pub deal: Pubkey,
+    pub max_entries: u8, // 1..=ACTION_LOG_CAPACITY, chosen at init
+    pub len: u8,
+    pub archived: u64, // entries already moved to the tree; entries[0] is number `archived`
+    pub archive_digest: [u8; 32], // keccak chain over the archived leaves
+    pub entries: [ActionEntry; ACTION_LOG_CAPACITY],
+    pub bump: u8,
+}
+
+impl ActionLog {
+    pub const SIZE: usize = 32 + 1 + 1 + 8 + 32 + ActionEntry::SIZE * ACTION_LOG_CAPACITY + 1;
+
+    pub fn entries(&self) -> &[ActionEntry] {
+        &self.entries[..self.len as usize]
+    }
+
+    pub fn record(&mut self, entry: ActionEntry) -> Result<()> {
+        require!(self.len < self.max_entries, CoffeeError::ActionLogFull);
+        self.entries[self.len as usize] = entry;
+        self.len += 1;
+        Ok(())
+    }
+
+    // Empty the log into tree leaves, oldest first, folding each into the archive digest
+    pub fn archive(&mut self) -> Vec<[u8; 32]> {
+        let leaves: Vec<[u8; 32]> = self
+            .entries()
+            .iter()
+            .zip(self.archived..)
+            .map(|(entry, seq)| entry.leaf(&self.deal, seq))
+            .collect();
+        for leaf in leaves.iter() {
+            self.archive_digest = solana_program::keccak::hashv(&[&self.archive_digest, leaf]).0;
+        }
+        self.archived += leaves.len() as u64;
+        self.entries = [ActionEntry::default(); ACTION_LOG_CAPACITY];
+        self.len = 0;
+        leaves
+    }
+}
+
+// An order resting against the oracle until matched, expired or canceled. Its margin sits in the
+// order PDA's ATA
+#[account]
+#[derive(Default)]
+pub struct StandingOrder {
+    pub market: Pubkey,
+    pub owner: Pubkey, // the farmer of a sell, the buyer of a buy
+    pub order_id: u64, // owner-chosen, part of the seeds
+    pub side: u8,      // OrderSide
+    pub limit_price_per_kg: u64, // sell: lowest price, buy: highest price
+    pub remaining_kg: u64,
+    pub filled_kg: u64,
+    pub expiry_ts: i64,
+    pub deadline_ts: i64, // deadline of the deals it opens; orders only pair on equal deadlines
+    pub bump: u8,
+}
+
+impl StandingOrder {
+    pub const SIZE: usize = 32 + 32 + 8 + 1 + 8 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn is_live(&self, now_ts: i64) -> bool {
+        self.remaining_kg > 0 && now_ts < self.expiry_ts
+    }
+
+    // the price is inside this order's limit
+    pub fn crosses(&self, price_per_kg: u64) -> bool {
+        if self.side == OrderSide::Sell as u8 {
+            price_per_kg >= self.limit_price_per_kg
+        } else {
+            price_per_kg <= self.limit_price_per_kg
+        }
+    }
+}
+
+// A farmer's harvest lot on open ascending auction. The farmer's margin sits in the auction PDA's ATA;
+// finalize_auction opens a deal with the best bidder at their price
+#[account]
+#[derive(Default)]
+pub struct HarvestAuction {
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub auction_id: u64, // farmer-chosen, part of the seeds
+    pub quantity_kg: u64,
+    pub physical_delivery: bool,
+    pub reserve_price_per_kg: u64,
+    pub min_increment_per_kg: u64, // a new best bid beats the last by at least this
+    pub ends_ts: i64,
+    pub deadline_ts: i64, // deadline of the deal it opens
+    pub best_bidder: Pubkey, // default until the first bid
+    pub best_price_per_kg: u64,
+    pub bids: u32,
+    pub finalized: bool,
+    pub deal: Pubkey, // opened by finalize_auction; default if the lot went unsold
+    pub bump: u8,
+}
+
+impl HarvestAuction {
+    pub const SIZE: usize = 32 + 32 + 8 + 8 + 1 + 8 + 8 + 8 + 8 + 32 + 8 + 4 + 1 + 32 + 1;
+
+    // lowest price the next bid may offer
+    pub fn min_next_bid(&self) -> Option<u64> {
+        if self.best_bidder == Pubkey::default() {
+            Some(self.reserve_price_per_kg)
+        } else {
+            self.best_price_per_kg.checked_add(self.min_increment_per_kg)
+        }
+    }
+
+    pub fn accept_bid(&mut self, bidder: Pubkey, price_per_kg: u64, now_ts: i64) -> Result<()> {
+        require!(!self.finalized && now_ts < self.ends_ts, CoffeeError::AuctionClosed);
+        require!(bidder != self.farmer, CoffeeError::InvalidCounterparty);
+        let floor = self.min_next_bid().ok_or(CoffeeError::MathOverflow)?;
+        require_gte!(price_per_kg, floor, CoffeeError::AuctionBidTooLow);
+        self.best_bidder = bidder;
+        self.best_price_per_kg = price_per_kg;
+        self.bids = self.bids.saturating_add(1);
+        Ok(())
+    }
+}
+
+// One bidder's standing bid on a HarvestAuction; its escrow is the bid PDA's ATA
+#[account]
+pub struct AuctionBid {
+    pub auction: Pubkey,
+    pub bidder: Pubkey,
+    pub price_per_kg: u64, // latest bid
+    pub bump: u8,
+}
+
+impl AuctionBid {
+    pub const SIZE: usize = 32 + 32 + 8 + 1;
+}
+
+// A one-shot alert on the market's oracle price, fired by anyone once the condition holds
+#[account]
+#[derive(Default)]
+pub struct PriceTrigger {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub trigger_id: u64, // owner-chosen, part of the seeds
+    pub direction: u8,   // TriggerDirection
+    pub threshold_price_per_kg: u64,
+    pub payload: Pubkey, // echoed in TriggerFired, not interpreted
+    pub created_ts: i64,
+    pub bump: u8,
+}
+
+impl PriceTrigger {
+    pub const SIZE: usize = 32 + 32 + 8 + 1 + 8 + 32 + 8 + 1;
+
+    pub fn is_met(&self, price_per_kg: u64) -> bool {
+        if self.direction == TriggerDirection::Above as u8 {
+            price_per_kg >= self.threshold_price_per_kg
+        } else {
+            price_per_kg <= self.threshold_price_per_kg
+        }
+    }
+}
+
+// Settlement fee discount issued by the market admin or operator (launch promotions, hardship waivers
+// for smallholders). open_deal redeems it once per deal; the discount is fixed on the deal from then on
+#[account]
+#[derive(Default)]
+pub struct FeeWaiver {
+    pub market: Pubkey,
+    pub issuer: Pubkey,
+    pub waiver_id: u64,     // issuer-chosen, part of the seeds; doubles as the promo code
+    pub beneficiary: Pubkey, // farmer or buyer it is reserved for; default = any trader (promo code)
+    pub discount_bps: u16,  // share of the fee waived; MAX_BPS zeroes it
+    pub expires_ts: i64,
+    pub max_uses: u32,      // 1 = single use
+    pub uses: u32,
+    pub bump: u8,
+}
+
+impl FeeWaiver {
+    pub const SIZE: usize = 32 + 32 + 8 + 32 + 2 + 8 + 4 + 4 + 1;
+
+    // the discount a deal between `farmer` and `buyer` would lock in now, without using the waiver
+    pub fn discount_for(&self, farmer: &Pubkey, buyer: &Pubkey, now_ts: i64) -> Result<u16> {
+        require!(now_ts < self.expires_ts, CoffeeError::FeeWaiverExpired);
+        require!(self.uses < self.max_uses, CoffeeError::FeeWaiverUsedUp);
+        require!(
+            self.beneficiary == Pubkey::default() || self.beneficiary == *farmer || self.beneficiary == *buyer,
+            CoffeeError::FeeWaiverNotForTrader
+        );
+        Ok(self.discount_bps)
+    }
+
+    // count one redemption by a deal between `farmer` and `buyer`; returns the discount it locks in
+    pub fn redeem(&mut self, farmer: &Pubkey, buyer: &Pubkey, now_ts: i64) -> Result<u16> {
+        let discount_bps = self.discount_for(farmer, buyer, now_ts)?;
+        self.uses += 1;
+        Ok(discount_bps)
+    }
+}
+
+// Cross-margin account: one pooled quote vault per trader per market backing every linked deal.
+// Linked deals hand their side's initial margin to the pool; the requirement is on net exposure.
+#[account]
+#[derive(Default)]
+pub struct MarginAccount {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub pool: Pubkey, // token account owned by this PDA
+    pub long_kg: u64, // linked deals where owner is the buyer
+    pub long_notional: u128, // sum of agreed_price_per_kg * quantity_kg
+    pub short_kg: u64, // linked deals where owner is the farmer
+    pub short_notional: u128,
+    pub deal_count: u32,
+    pub bump: u8,
+}
+
+impl MarginAccount {
+    pub const SIZE: usize = 32*3 + 8 + 16 + 8 + 16 + 4 + 1;
+
+    pub fn add_exposure(&mut self, side: math::Side, quantity_kg: u64, notional: u128) -> Result<()> {
+        match side {
+            math::Side::Long => {
+                self.long_kg = self.long_kg.checked_add(quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+                self.long_notional = self.long_notional.checked_add(notional).ok_or(CoffeeError::MathOverflow)?;
+            }
+            math::Side::Short => {
+                self.short_kg = self.short_kg.checked_add(quantity_kg).ok_or(CoffeeError::MathOverflow)?;
+                self.short_notional = self.short_notional.checked_add(notional).ok_or(CoffeeError::MathOverflow)?;
+            }
+        }
+        self.deal_count = self.deal_count.checked_add(1).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn remove_exposure(&mut self, side: math::Side, quantity_kg: u64, notional: u128) {
+        match side {
+            math::Side::Long => {
+                self.long_kg = self.long_kg.saturating_sub(quantity_kg);
+                self.long_notional = self.long_notional.saturating_sub(notional);
+            }
+            math::Side::Short => {
+                self.short_kg = self.short_kg.saturating_sub(quantity_kg);
+                self.short_notional = self.short_notional.saturating_sub(notional);
+            }
+        }
+        self.deal_count = self.deal_count.saturating_sub(1);
+    }
+
+    // PnL of all linked deals at the mark: longs gain as the price rises, shorts as it falls.
+    // Exposure is kept in price units; results are in quote base units for the market's price_exponent
+    pub fn unrealized_pnl(&self, mark: u64, price_exponent: i8) -> Option<i128> {
+        let long_value = (mark as u128).checked_mul(self.long_kg as u128)?;
+        let short_value = (mark as u128).checked_mul(self.short_kg as u128)?;
+        let long_pnl = i128::try_from(long_value).ok()?.checked_sub(i128::try_from(self.long_notional).ok()?)?;
+        let short_pnl = i128::try_from(self.short_notional).ok()?.checked_sub(i128::try_from(short_value).ok()?)?;
+        math::to_quote_units_signed(long_pnl.checked_add(short_pnl)?, price_exponent)
+    }
+
+    // margin on the net kilograms only: offsetting long and short deals don't add up
+    pub fn required_margin(&self, mark: u64, price_exponent: i8, margin_bps: u16) -> Option<u128> {
+        let net_kg = self.long_kg.abs_diff(self.short_kg);
+        math::bps_mul(math::quote_notional(mark, net_kg, price_exponent)?, margin_bps)
+    }
+
+    // collateral + unrealized PnL - requirement; negative means under-margined
+    pub fn excess(&self, collateral: u64, mark: u64, price_exponent: i8, margin_bps: u16) -> Option<i128> {
+        if self.deal_count == 0 {
+            return Some(collateral as i128);
+        }
+        let required = i128::try_from(self.required_margin(mark, price_exponent, margin_bps)?).ok()?;
+        (collateral as i128).checked_add(self.unrealized_pnl(mark, price_exponent)?)?.checked_sub(required)
+    }
+}
+
+// Last compute_portfolio_risk result for a MarginAccount (maintenance excess < 0 = liquidatable)
+#[account]
+#[derive(Default)]
+pub struct RiskSnapshot {
+    pub margin_account: Pubkey,
+    pub owner: Pubkey,
+    pub ts: i64,
+    pub mark_price: u64,
+    pub deal_count: u32,
+    pub complete: bool, // every linked deal was included
+    pub net_delta_kg: i64, // long - short
+    pub gross_kg: u64,
+    pub unrealized_pnl: i64,
+    pub collateral: u64, // pool balance
+    pub initial_required: u64,
+    pub maintenance_required: u64,
+    pub excess: i64, // collateral + PnL - maintenance requirement
+    pub free_margin: i64, // collateral + PnL - initial requirement (withdrawable when positive)
+    pub bump: u8,
+}
+
+impl RiskSnapshot {
+    pub const SIZE: usize = 32*2 + 8*2 + 4 + 1 + 8*2 + 8*2 + 8*2 + 8*2 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PublishPrice<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: oracle publisher signer (may be multisig PDA, a delegated ORACLE or a session key)
+    pub oracle_publisher: Signer<'info>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    // keeper session key standing in for the operator (see create_session)
+    #[account(seeds = [SEED_PREFIX, SESSION_SEED, market.key().as_ref(), oracle_publisher.key().as_ref()], bump = session.bump)]
+    pub session: Option<Account<'info, SessionKey>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(agreed_price_per_kg: u64, quantity_kg: u64)]
+pub struct OpenDeal<'info> {
+    /// CHECK: must sign, unless `agent` signs under the farmer's `agent_grant`
+    #[account(mut)]
+    pub farmer: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + Deal::INIT_SPACE,
+        seeds = [SEED_PREFIX, DEAL_SEED, market.key().as_ref(), farmer.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub deal: Account<'info, Deal>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // the deal's own margin vaults, one per side; left out on omnibus markets
+    #[account(
+        init,
+        payer = buyer,
+        associated_token::mint = quote_mint,
+        associated_token::authority = vault_auth,
+    )]
+    pub farmer_margin_vault: Option<Account<'info, TokenAccount>>,
+
+    // the vault_auth ATA is the farmer's, so the buyer's margin sits in a PDA token account
+    #[account(
+        init,
+        payer = buyer,
+        seeds = [SEED_PREFIX, BUYER_VAULT_SEED, deal.key().as_ref()],
+        bump,
+        token::mint = quote_mint,
+        token::authority = vault_auth,
+    )]
+    pub buyer_margin_vault: Option<Account<'info, TokenAccount>>,
+
+    // required when market.omnibus_vaults
+    #[account(mut, address = market.omnibus_farmer_vault @ CoffeeError::OmnibusVaultMismatch)]
+    pub omnibus_farmer_vault: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut, address = market.omnibus_buyer_vault @ CoffeeError::OmnibusVaultMismatch)]
+    pub omnibus_buyer_vault: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut, constraint = farmer_margin_from.mint == quote_mint.key())]
+    pub farmer_margin_from: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_from.mint == quote_mint.key())]
+    pub buyer_margin_from: Account<'info, TokenAccount>,
+
+    // required when the basket holds anything other than the CFT mint
+    #[account(seeds = [SEED_PREFIX, APPROVED_ASSETS_SEED, market.key().as_ref()], bump = approved_assets.bump)]
+    pub approved_assets: Option<Account<'info, ApprovedAssets>>,
+
+    // required when market.insurance_upfront
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Option<Account<'info, TokenAccount>>,
+
+    // required above market.large_deal_notional
+    pub risk_officer: Option<Signer<'info>>,
+
+    // standardized terms (open_deal_from_template)
+    #[account(has_one = market)]
+    pub template: Option<Account<'info, DealTemplate>>,
+
+    // power of attorney: an agent may act for the farmer under a live grant
+    pub agent: Option<Signer<'info>>,
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent_grant.agent.as_ref()],
+        bump = agent_grant.bump
+    )]
+    pub agent_grant: Option<Account<'info, AgentGrant>>,
+
+    // approved sample for this farmer/buyer pair; required when market.sample_required
+    #[account(
+        mut,
+        close = buyer,
+        seeds = [SEED_PREFIX, SAMPLE_SEED, market.key().as_ref(), farmer.key().as_ref(), buyer.key().as_ref()],
+        bump = sample.bump
+    )]
+    pub sample: Option<Account<'info, SampleEscrow>>,
+
+    // the buyer draws on its credit line for part of its margin
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, CREDIT_LINE_SEED, market.key().as_ref(), buyer.key().as_ref()],
+        bump = credit_line.bump
+    )]
+    pub credit_line: Option<Account<'info, CreditLine>>,
+
+    // required when market.max_opens_per_hour > 0
+    #[account(mut, seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), farmer.key().as_ref()], bump = farmer_stats.bump)]
+    pub farmer_stats: Option<Account<'info, TraderStats>>,
+    #[account(mut, seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), buyer.key().as_ref()], bump = buyer_stats.bump)]
+    pub buyer_stats: Option<Account<'info, TraderStats>>,
+
+    #[account(mut, seeds = [SEED_PREFIX, FEE_WAIVER_SEED, market.key().as_ref(), &fee_waiver.waiver_id.to_le_bytes()], bump = fee_waiver.bump)]
+    pub fee_waiver: Option<Account<'info, FeeWaiver>>,
+
+    // each party's latest index page; the new deal is appended when passed
+    #[account(mut, seeds = [SEED_PREFIX, PARTICIPANT_INDEX_SEED, farmer.key().as_ref(), &farmer_index.page.to_le_bytes()], bump = farmer_index.bump)]
+    pub farmer_index: Option<Box<Account<'info, ParticipantIndex>>>,
+    #[account(mut, seeds = [SEED_PREFIX, PARTICIPANT_INDEX_SEED, buyer.key().as_ref(), &buyer_index.page.to_le_bytes()], bump = buyer_index.bump)]
+    pub buyer_index: Option<Box<Account<'info, ParticipantIndex>>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[account]
+pub struct VaultAuth {
+    pub bump: u8,
+}
+impl VaultAuth {
+    pub const SIZE: usize = 1 + 8;
+}
+
+// Margin floors a deal's counterparties agree at open, per side (farmer short, buyer long); 0 = the
+// market rate. They only ever raise requirements: the effective rate is the higher of the two
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MarginOverride {
+    pub farmer_initial_bps: u16,
+    pub farmer_maintenance_bps: u16,
+    pub buyer_initial_bps: u16,
+    pub buyer_maintenance_bps: u16,
+}
+
+impl MarginOverride {
+    pub const SIZE: usize = 2 * 4;
+
+    pub fn initial_bps(&self, side: math::Side, market_bps: u16) -> u16 {
+        match side {
+            math::Side::Short => self.farmer_initial_bps,
+            math::Side::Long => self.buyer_initial_bps,
+        }
+        .max(market_bps)
+    }
+
+    pub fn maintenance_bps(&self, side: math::Side, market_bps: u16) -> u16 {
+        match side {
+            math::Side::Short => self.farmer_maintenance_bps,
+            math::Side::Long => self.buyer_maintenance_bps,
+        }
+        .max(market_bps)
+    }
+
+    // the side has a floor of its own
+    pub fn binds(&self, side: math::Side) -> bool {
+        match side {
+            math::Side::Short => self.farmer_initial_bps > 0 || self.farmer_maintenance_bps > 0,
+            math::Side::Long => self.buyer_initial_bps > 0 || self.buyer_maintenance_bps > 0,
+        }
+    }
+
+    // every effective rate fits in MAX_BPS and no side's maintenance exceeds its initial margin
+    pub fn check(&self, market_initial_bps: u16, market_maintenance_bps: u16) -> Result<()> {
+        for side in [math::Side::Short, math::Side::Long] {
+            let initial = self.initial_bps(side, market_initial_bps);
+            require_gte!(MAX_BPS, initial, CoffeeError::InvalidMarginOverride);
+            require_gte!(initial, self.maintenance_bps(side, market_maintenance_bps), CoffeeError::InvalidMarginOverride);
+        }
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(Default)]
+pub struct Deal {
+    pub version: u8,
+    pub market: Pubkey,
+    pub farmer: Pubkey, // short unless farmer_side is Buy; delivers on physical deals, holds crop cover, agents and sponsors
+    pub buyer: Pubkey,  // the other side: a roaster, exporter hedging inventory or speculator
+    pub agreed_price_per_kg: u64,
+    pub quantity_kg: u64,
+    pub initial_margin_each: u64,
+
+    // settlement & lifecycle
+    pub physical_delivery: bool,
+    pub delivered_kg_total: u64,
+    pub liquidated: bool,
+    pub settled: bool,
+    pub settling: bool, // reentrancy guard
+    pub farmer_deposited: bool,
+    pub buyer_deposited: bool,
+    pub deadline_ts: i64,
+    pub margin_call_ts: i64,
+    pub margin_call_grace_sec: u64,
+
+    // optional referral & fee split
+    pub referrer: Pubkey,
+    pub fee_split_bps: u16,
+    pub fee_currency: u8, // FeeCurrency
+
+    // multi-asset basket (fixed arrays)
+    pub asset_count: u8,
+    pub assets: [Pubkey; MAX_ASSETS],
+    pub asset_qty: [u64; MAX_ASSETS],
+
+    // merkle root for basket proof
+    pub merkle_root: [u8; 32],
+
+    pub voided: bool, // expired/cancelled without the deal going live
+    pub force_majeure_reason: u8, // ForceMajeureReason; 0 = none
+
+    // buyer prepayment mode: purchase price escrowed in a separate vault
+    pub prepaid: bool,
+    pub escrow_balance: u64,
+    pub unpaid_owed: u64, // farmer payouts recorded as owed under PaymentPolicy::RecordDebt
+    pub debt_creditor: Pubkey, // side owed `unpaid_owed`; the other side is the debtor
+    pub debt_opened: bool,     // Debt PDA materialized via open_debt
+    pub loss_index_checkpoint: u128, // market loss_socialization_index at open
+    pub adl_haircut: u64,            // socialized loss withheld from this deal's winnings
+    pub margin_locked: u64, // margin deposited by both sides while live
+    pub funding_checkpoint: i128, // market cumulative_funding_per_kg at last apply_funding
+    pub funding_paid_long: i64,   // net funding paid by the long (negative: received)
+    pub deal_id: u64, // per-market sequence number (Market.deal_seq)
+    pub asset_units_per_kg: [u64; MAX_ASSETS],    // basket conversion snapshotted from ApprovedAssets at open
+    pub asset_units_delivered: [u64; MAX_ASSETS], // basket units minted/transferred to the buyer so far
+    pub merkle_root_version: u32, // bumped on every update_deal_merkle_root
+    pub delivery_leaves: u64,      // attestations appended to market.delivery_tree
+    pub delivery_digest: [u8; 32], // keccak chain over this deal's attestation leaves
+    pub farmer_margin_account: Pubkey, // cross-margin MarginAccount backing the farmer side; default = isolated
+    pub buyer_margin_account: Pubkey,  // same for the buyer side
+    pub insurance_prepaid: u64, // premium paid into insurance at open; settlement then skips the insurance leg
+    pub template: Pubkey, // DealTemplate the terms came from; default = negotiated ad hoc
+    pub quality_grade: u8,
+    pub incoterm: u8, // Incoterm
+    pub delivery_start_ts: i64, // deliveries verified before this are rejected; 0 = no lower bound
+    pub shipment_status: u8,      // ShipmentStatus
+    pub shipment_updated_ts: i64,
+    pub tranches_advanced: u64,   // escrow paid to the farmer at milestones, credited against delivery payouts
+    pub cft_payout: bool, // buyer takes cash-settlement winnings as CFT minted at the settlement price
+    pub crosschain_chain: u16,            // Wormhole chain id of the buyer's off-Solana escrow; 0 = none
+    pub crosschain_recipient: [u8; 32],   // buyer's address on that chain, left-padded to 32 bytes
+    pub crosschain_posted: bool,          // settlement message already posted
+    pub payout_domain: u32,               // CCTP destination domain for the farmer's bridged payout
+    pub payout_recipient: [u8; 32],       // farmer's address on that domain; zero = not bridged
+    pub fx_oracle: Pubkey,                // FxOracle fixing the paired FX forward; default = no hedge
+    pub fx_notional: u64,                 // quote units the farmer sells forward
+    pub fx_locked_rate: u64,              // local currency per quote unit, FX_RATE_SCALE
+    pub fx_settled: bool,                 // FX leg paid out of the margin vaults
+    pub final_price_per_kg: u64,          // price the deal settles at, fixed when settlement starts; 0 before
+    pub realized_pnl_farmer: i64,         // PnL moved between the sides (settlement, reductions, funding, FX), net of ADL haircuts
+    pub realized_pnl_buyer: i64,
+    pub fees_paid: u64,                   // settlement fees and insurance premiums, both sides, in quote terms
+    pub insurance_drawn: u64,             // paid out of the insurance fund on this deal (crop claims)
+    pub settlement_step: u8,              // settlement::SettlementStep reached by a checkpointed settle_cash
+    pub settle_fees: [u64; 4],            // farmer, buyer, protocol, insurance fees charged by the fee step
+    pub settle_pnl: [u64; 3],             // paid to the winner, ADL haircut, shortfall from the PnL step
+    pub seed_version: u8,                 // 1 = [v1, deal, market, farmer, buyer], 2 = [v2, .., deal_id]
+    // weaker vault-backed side's margin over maintenance at the last refresh, bps (10_000 = exactly at
+    // maintenance). Refreshed by mark_to_market, top-ups and funding; u32::MAX until the first refresh
+    pub health_bps: u32,
+    pub health_updated_ts: i64,
+    pub margin_override: MarginOverride,  // per-side margin floors agreed at open; zero = market rate
+    pub credit_used: u64,                 // buyer margin drawn on its CreditLine instead of posted
+    pub omnibus: bool,                    // margin sits in the market's omnibus vaults, not the deal's own
+    pub omnibus_balance: [u64; 2],        // farmer, buyer margin held for this deal in those vaults
+    pub has_action_log: bool,             // an ActionLog exists and must be passed to every logged action
+    // opt-in stop-loss per side (set_stop); 0 = none
+    pub farmer_stop_price_per_kg: u64,    // a short is stopped out at or above, a long at or below
+    pub buyer_stop_price_per_kg: u64,
+    pub stopped: bool,                    // execute_stop fired; cash-settles at the mark like a liquidation
+    pub fee_waiver_bps: u16,              // FeeWaiver discount redeemed at open; applies to every fee on the deal
+
+    // third-party margin on the farmer side (sponsor_margin); first-loss, so it only comes back out of
+    // what the farmer side holds beyond farmer_own_margin
+    pub sponsor: Pubkey,
+    pub sponsored_margin: u64,
+    pub farmer_own_margin: u64, // farmer's own part of its vault on a sponsored deal; settles ahead of the sponsor's stake
+    pub split_vaults: bool,     // buyer margin sits in the buyer_vault PDA; otherwise both sides share the vault_auth ATA
+    pub farmer_side: u8,        // OrderSide the farmer took at open: Sell (short, the default) or Buy (long); the buyer holds the other
+}
+
+impl Deal {
+    pub const INIT_SPACE: usize = 1 + 32*6 + 8*8 + 1*10 + (32*MAX_ASSETS) + (8*MAX_ASSETS) + 40
+        + 1
+        + 1
+        + 1
+        + 1 + 8
+        + 8
+        + 32 + 1
+        + 16 + 8
+        + 8
+        + 16 + 8
+        + 8
+        + (8*MAX_ASSETS) + (8*MAX_ASSETS)
+        + 4
+        + 8 + 32
+        + 32 * 2
+        + 8
+        + 32 + 1 + 1 + 8
+        + 1
+        + 1 + 8 + 8
+        + 2 + 32 + 1
+        + 4 + 32
+        + 32 + 8 + 8 + 1
+        + 8 + 8 + 8 + 8 + 8
+        + 1 + 32 + 24
+        + 1
+        + 4 + 8
+        + MarginOverride::SIZE
+        + 8
+        + 1 + 8*2
+        + 1
+        + 8 + 8 + 1
+        + 2
+        + 32 + 8
+        + 8
+        + 1
+        + 1;
+    // `paid` left the loser's vault and `received` reached the winner (the difference went to insurance)
+    pub fn record_realized_pnl(&mut self, winner: math::Side, paid: u64, received: u64) -> Result<()> {
+        let paid = i64::try_from(paid).map_err(|_| CoffeeError::MathOverflow)?;
+        let received = i64::try_from(received).map_err(|_| CoffeeError::MathOverflow)?;
+        let (won, lost) = match winner {
+            math::Side::Long => (&mut self.realized_pnl_buyer, &mut self.realized_pnl_farmer),
+            math::Side::Short => (&mut self.realized_pnl_farmer, &mut self.realized_pnl_buyer),
+        };
+        *won = won.checked_add(received).ok_or(CoffeeError::MathOverflow)?;
+        *lost = lost.checked_sub(paid).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_fees(&mut self, amount: u64) -> Result<()> {
+        self.fees_paid = self.fees_paid.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // an attached FX forward must be paid out of the vaults before the deal itself settles
+    pub fn fx_hedge_pending(&self) -> bool {
+        self.fx_oracle != Pubkey::default() && !self.fx_settled
+    }
+
+    // a side still draws on a MarginAccount pool; release it before settling
+    pub fn is_cross_margined(&self) -> bool {
+        self.farmer_margin_account != Pubkey::default() || self.buyer_margin_account != Pubkey::default()
+    }
+
+    // a live, plain cash deal on its own vault, which migrate_deal_to_omnibus can move: the same deals
+    // omnibus mode supports, with nothing settling, owed or linked outside the vault
+    pub fn omnibus_migratable(&self) -> bool {
+        !self.omnibus
+            && self.farmer_deposited
+            && self.buyer_deposited
+            && !self.settled
+            && !self.settling
+            && !self.liquidated
+            && !self.stopped
+            && self.settlement_step == settlement::SettlementStep::NotStarted as u8
+            && !self.physical_delivery
+            && !self.prepaid
+            && self.asset_count == 0
+            && self.sponsored_margin == 0
+            && !self.debt_opened
+            && self.fx_oracle == Pubkey::default()
+            && !self.is_cross_margined()
+    }
+
+    // what backs the buyer in margin checks: its vault plus credit drawn instead of posting margin
+    pub fn buyer_collateral(&self, buyer_vault: u64) -> u64 {
+        buyer_vault.saturating_add(self.credit_used)
+    }
+
+    // (farmer, buyer) margin held for the deal: its ledger in omnibus mode, else the vault balances
+    pub fn margin_balances(&self, farmer_vault: u64, buyer_vault: u64) -> (u64, u64) {
+        if self.omnibus {
+            (self.omnibus_balance[0], self.omnibus_balance[1])
+        } else {
+            (farmer_vault, buyer_vault)
+        }
+    }
+
+    // the slot whose stop-loss the mark has crossed, if any; the farmer's is checked first. A short is
+    // stopped out at or above its stop, a long at or below
+    pub fn stop_triggered(&self, mark_price_per_kg: u64) -> Option<math::Side> {
+        [(math::Side::Short, self.farmer_stop_price_per_kg), (math::Side::Long, self.buyer_stop_price_per_kg)]
+            .into_iter()
+            .find(|&(slot, stop)| {
+                stop > 0
+                    && match self.position(slot) {
+                        math::Side::Short => mark_price_per_kg >= stop,
+                        math::Side::Long => mark_price_per_kg <= stop,
+                    }
+            })
+            .map(|(slot, _)| slot)
+    }
+
+    // Margin vaults, omnibus balances, overrides and ledgers are indexed by slot (Short = the farmer's,
+    // Long = the buyer's); `position` is the market side the party in that slot actually holds. It is
+    // its own inverse, so it also gives the slot holding a position
+    pub fn position(&self, slot: math::Side) -> math::Side {
+        if self.farmer_side == OrderSide::Buy as u8 {
+            slot.opposite()
+        } else {
+            slot
+        }
+    }
+
+    // PnL of the party in `slot` over `quantity_kg` at `price_per_kg`, by the position it holds
+    pub fn slot_pnl(&self, market: &Market, price_per_kg: u64, quantity_kg: u64, slot: math::Side) -> Result<i128> {
+        market.pnl(self.agreed_price_per_kg, price_per_kg, quantity_kg, self.position(slot))
+    }
+
+    // the slot `wallet` holds on this deal, if it is a party
+    pub fn side_of(&self, wallet: &Pubkey) -> Option<math::Side> {
+        if *wallet == self.farmer {
+            Some(math::Side::Short)
+        } else if *wallet == self.buyer {
+            Some(math::Side::Long)
+        } else {
+            None
+        }
+    }
+
+    // the wallet in `side`'s slot
+    pub fn party(&self, side: math::Side) -> Pubkey {
+        match side {
+            math::Side::Short => self.farmer,
+            math::Side::Long => self.buyer,
+        }
+    }
+
+    // notional the fee is charged on once the deal's waiver is applied
+    pub fn fee_bearing_notional(&self, notional: u128) -> Result<u128> {
+        fee_bearing_notional(notional, self.fee_waiver_bps)
+    }
+
+    // a farmer top-up on a sponsored deal is the farmer's own margin, not the sponsor's
+    pub fn credit_farmer_own(&mut self, side: math::Side, amount: u64) -> Result<()> {
+        if side == math::Side::Short && self.sponsored_margin > 0 {
+            self.farmer_own_margin = self.farmer_own_margin.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    // book a deposit into the omnibus vault of `side`
+    pub fn credit_omnibus(&mut self, side: math::Side, amount: u64) -> Result<()> {
+        let i = if side == math::Side::Short { 0 } else { 1 };
+        self.omnibus_balance[i] = self.omnibus_balance[i].checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // Health of the weaker vault-backed side against its maintenance due, in bps. Cross-margined sides
+    // are judged on their MarginAccount and left out; u32::MAX when no side is measured or nothing is due
+    pub fn health_bps_for(&self, farmer_vault: u64, farmer_due: u64, buyer_vault: u64, buyer_due: u64) -> u32 {
+        let ratio = |vault: u64, due: u64| match due {
+            0 => u32::MAX,
+            _ => (vault as u128 * MAX_BPS as u128 / due as u128).min(u32::MAX as u128) as u32,
+        };
+        let mut health = u32::MAX;
+        if self.farmer_margin_account == Pubkey::default() {
+            health = health.min(ratio(farmer_vault, farmer_due));
+        }
+        if self.buyer_margin_account == Pubkey::default() {
+            health = health.min(ratio(buyer_vault, buyer_due));
+        }
+        health
+    }
+
+    // cache a fresh health reading; the previous value when it changed
+    pub fn set_health(&mut self, health_bps: u32, now: i64) -> Option<u32> {
+        let previous = self.health_bps;
+        self.health_bps = health_bps;
+        self.health_updated_ts = now;
+        (previous != health_bps).then_some(previous)
+    }
+
+    // cached health was refreshed at or above maintenance after a flag at `flagged_ts`. Cross-margined
+    // deals never count: their health is judged on MarginAccounts the cache does not cover
+    pub fn recovered_since(&self, flagged_ts: i64) -> bool {
+        !self.is_cross_margined() && self.health_updated_ts >= flagged_ts && self.health_bps >= MAX_BPS as u32
+    }
+
+    // push an open margin call's grace out to end no sooner than `now + grace_sec`; the new grace when
+    // it moved
+    pub fn extend_margin_call_grace(&mut self, now: i64, grace_sec: u64) -> Option<u64> {
+        if self.margin_call_ts == 0 {
+            return None;
+        }
+        let grace_end = self.margin_call_ts.saturating_add(self.margin_call_grace_sec as i64);
+        let min_end = now.saturating_add(grace_sec as i64);
+        if grace_end >= min_end {
+            return None;
+        }
+        self.margin_call_grace_sec = abs_i64_to_u64(min_end - self.margin_call_ts);
+        Some(self.margin_call_grace_sec)
+    }
+
+    pub fn mark_settled(&mut self) {
+        self.settled = true;
+        self.settling = false;
+    }
+    pub fn start_settling(&mut self) {
+        self.settling = true;
+    }
+    // accumulate an unpaid settlement amount owed to `creditor`
+    pub fn record_shortfall(&mut self, creditor: Pubkey, amount: u64) -> Result<()> {
+        require!(
+            self.debt_creditor == Pubkey::default() || self.debt_creditor == creditor,
+            CoffeeError::ConflictingDebt
+        );
+        self.debt_creditor = creditor;
+        self.unpaid_owed = self.unpaid_owed.checked_add(amount).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+    // funding owed by the long since the last checkpoint (negative: owed to the long)
+    pub fn funding_owed_long(&self, cumulative_funding_per_kg: i128) -> Result<i128> {
+        let delta = cumulative_funding_per_kg
+            .checked_sub(self.funding_checkpoint)
+            .ok_or(CoffeeError::MathOverflow)?;
+        Ok(delta.checked_mul(self.quantity_kg as i128).ok_or(CoffeeError::MathOverflow)? / FUNDING_PRECISION)
+    }
+    // share of losses socialized since this deal opened (a charge, so rounded up)
+    pub fn adl_haircut_due(&self, loss_index: u128) -> Result<u64> {
+        let owed = loss_index
+            .saturating_sub(self.loss_index_checkpoint)
+            .checked_mul(self.quantity_kg as u128)
+            .ok_or(CoffeeError::MathOverflow)?;
+        let due = math::div_ceil(owed, ADL_INDEX_PRECISION).ok_or(CoffeeError::MathOverflow)?;
+        Ok(due.min(u64::MAX as u128) as u64)
+    }
+    // units of basket asset i owed to the buyer once `delivered_kg` has been delivered (rounded down)
+    pub fn basket_units_due(&self, i: usize, delivered_kg: u64) -> Option<u64> {
+        if self.quantity_kg == 0 {
+            return Some(0);
+        }
+        let units = (delivered_kg as u128)
+            .checked_mul(self.asset_qty[i] as u128)?
+            .checked_mul(self.asset_units_per_kg[i] as u128)?
+            / self.quantity_kg as u128;
+        u64::try_from(units).ok()
+    }
+    pub fn basket_fully_delivered(&self) -> bool {
+        (0..self.asset_count as usize).all(|i| {
+            self.basket_units_due(i, self.delivered_kg_total)
+                .is_some_and(|due| self.asset_units_delivered[i] >= due)
+        })
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct TopUpMargin<'info> {
+    #[account(mut)]
+    pub who: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = from_ata.mint == market.quote_mint)]
+    pub from_ata: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MarginCall<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    // keeper session key standing in for the operator (see create_session)
+    #[account(seeds = [SEED_PREFIX, SESSION_SEED, market.key().as_ref(), authority.key().as_ref()], bump = session.bump)]
+    pub session: Option<Account<'info, SessionKey>>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MtmCheck<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    // required for a side linked to a MarginAccount
+    pub farmer_margin_account: Option<Account<'info, MarginAccount>>,
+    pub farmer_margin_pool: Option<Account<'info, TokenAccount>>,
+    pub buyer_margin_account: Option<Account<'info, MarginAccount>>,
+    pub buyer_margin_pool: Option<Account<'info, TokenAccount>>,
+
+    // when passed, a deal flagged for liquidation is queued with its shortfall
+    #[account(mut, seeds = [SEED_PREFIX, LIQUIDATION_QUEUE_SEED, market.key().as_ref()], bump = liquidation_queue.bump)]
+    pub liquidation_queue: Option<Account<'info, LiquidationQueue>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SettleCash<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = fee_treasury.mint == market.quote_mint)]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    /// CHECK: authority for insurance treasury (placeholder; wire to PDA in prod)
+    pub insurance_treasury_authority: UncheckedAccount<'info>,
+
+    // only required when deal.fee_currency == Cft
+    #[account(mut, address = market.cft_mint)]
+    pub cft_mint: Option<Account<'info, Mint>>,
+
+    #[account(mut, constraint = farmer_cft_ata.mint == market.cft_mint && farmer_cft_ata.owner == deal.farmer)]
+    pub farmer_cft_ata: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut, constraint = buyer_cft_ata.mint == market.cft_mint && buyer_cft_ata.owner == deal.buyer)]
+    pub buyer_cft_ata: Option<Account<'info, TokenAccount>>,
+
+    // only required when deal.cft_payout and the buyer wins
+    #[account(mut, seeds = [SEED_PREFIX, CFT_AUTH_SEED, market.cft_mint.as_ref()], bump = cft_mint_auth.bump)]
+    pub cft_mint_auth: Option<Account<'info, CftMintAuth>>,
+
+    #[account(mut, address = market.cft_sale_treasury, constraint = cft_sale_treasury.mint == market.quote_mint)]
+    pub cft_sale_treasury: Option<Account<'info, TokenAccount>>,
+
+    // required when market.cft_allowlist_required and CFT is minted
+    #[account(seeds = [SEED_PREFIX, CFT_ALLOW_SEED, market.key().as_ref(), deal.buyer.as_ref()], bump = buyer_cft_allowlist.bump)]
+    pub buyer_cft_allowlist: Option<Account<'info, CftAllowlistEntry>>,
+
+    // required when market.operator_fee_bps > 0
+    #[account(mut, address = market.operator_treasury @ CoffeeError::Unauthorized)]
+    pub operator_treasury: Option<Account<'info, TokenAccount>>,
+
+    // required for omnibus deals: signs for the market's omnibus vaults
+    #[account(seeds = [SEED_PREFIX, OMNIBUS_SEED, market.key().as_ref()], bump = omnibus_auth.bump)]
+    pub omnibus_auth: Option<Account<'info, VaultAuth>>,
+
+    // required when deal.sponsored_margin > 0
+    #[account(mut, constraint = sponsor_receive.mint == market.quote_mint && sponsor_receive.owner == deal.sponsor)]
+    pub sponsor_receive: Option<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.farmer.as_ref()],
+        bump = farmer_stats.bump
+    )]
+    pub farmer_stats: Account<'info, TraderStats>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.buyer.as_ref()],
+        bump = buyer_stats.bump
+    )]
+    pub buyer_stats: Account<'info, TraderStats>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct NetSettlePair<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: key only; every deal passed must be with this farmer
+    pub farmer: UncheckedAccount<'info>,
+
+    /// CHECK: key only; every deal passed must be with this buyer
+    pub buyer: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == farmer.key() @ CoffeeError::Unauthorized)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == buyer.key() @ CoffeeError::Unauthorized)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = fee_treasury.mint == market.quote_mint)]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    // required when market.operator_fee_bps > 0
+    #[account(mut, address = market.operator_treasury @ CoffeeError::Unauthorized)]
+    pub operator_treasury: Option<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), farmer.key().as_ref()],
+        bump = farmer_stats.bump
+    )]
+    pub farmer_stats: Account<'info, TraderStats>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), buyer.key().as_ref()],
+        bump = buyer_stats.bump
+    )]
+    pub buyer_stats: Account<'info, TraderStats>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FixSettlementPrice<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ChallengeSettlementPrice<'info> {
+    #[account(mut)]
+    pub challenger: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // a deal of the challenger's in this market
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(
+        init,
+        payer = challenger,
+        space = 8 + PriceChallenge::SIZE,
+        seeds = [SEED_PREFIX, PRICE_CHALLENGE_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub price_challenge: Account<'info, PriceChallenge>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = challenger,
+        associated_token::mint = quote_mint,
+        associated_token::authority = price_challenge,
+    )]
+    pub bond_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = challenger_quote_ata.mint == market.quote_mint)]
+    pub challenger_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RuleSettlementPrice<'info> {
+    pub arbiter: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, PRICE_CHALLENGE_SEED, market.key().as_ref()],
+        bump = price_challenge.bump,
+        has_one = market,
+        has_one = challenger,
+        close = challenger
+    )]
+    pub price_challenge: Account<'info, PriceChallenge>,
+
+    /// CHECK: gets the challenge account's rent back; checked by has_one
+    #[account(mut)]
+    pub challenger: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = bond_vault.owner == price_challenge.key() && bond_vault.mint == market.quote_mint)]
+    pub bond_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = challenger_receive.mint == market.quote_mint && challenger_receive.owner == challenger.key())]
+    pub challenger_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ArbiterSettleCash<'info> {
+    pub arbiter: Signer<'info>,
+
+    pub settle: SettleCash<'info>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, settle.market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetStop<'info> {
+    pub party: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ExecuteStop<'info> {
+    pub executor: Signer<'info>,
+
+    pub settle: SettleCash<'info>,
+
+    #[account(mut, constraint = executor_receive.mint == settle.market.quote_mint)]
+    pub executor_receive: Account<'info, TokenAccount>,
+
+    // required when market.seized_vesting_sec > 0 (init_margin_vesting)
+    #[account(mut, seeds = [SEED_PREFIX, VESTING_SEED, settle.deal.key().as_ref()], bump = vesting.bump)]
+    pub vesting: Option<Account<'info, MarginVesting>>,
+
+    #[account(mut, seeds = [SEED_PREFIX, VESTING_VAULT_SEED, settle.deal.key().as_ref()], bump)]
+    pub vesting_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SettleAndBridge<'info> {
+    // owns the receive account the payout is burned from; pays the CCTP event account rent
+    #[account(
+        mut,
+        address = settle.deal.farmer @ CoffeeError::Unauthorized,
+        constraint = settle.farmer_receive.owner == farmer.key() @ CoffeeError::Unauthorized
+    )]
+    pub farmer: Signer<'info>,
+
+    pub settle: SettleCash<'info>,
+
+    #[account(mut, address = settle.market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    /// CHECK: CCTP TokenMessengerMinter configured on the market
+    #[account(executable, address = settle.market.cctp_program)]
+    pub cctp_token_messenger_minter_program: UncheckedAccount<'info>,
+
+    /// CHECK: CCTP MessageTransmitter program; validated by the TokenMessengerMinter
+    pub cctp_message_transmitter_program: UncheckedAccount<'info>,
+
+    /// CHECK: validated by the TokenMessengerMinter
+    pub cctp_sender_authority: UncheckedAccount<'info>,
+
+    /// CHECK: validated by the TokenMessengerMinter
+    #[account(mut)]
+    pub cctp_message_transmitter: UncheckedAccount<'info>,
+
+    /// CHECK: validated by the TokenMessengerMinter
+    pub cctp_token_messenger: UncheckedAccount<'info>,
+
+    /// CHECK: remote token messenger for the deal's payout domain; validated by the TokenMessengerMinter
+    pub cctp_remote_token_messenger: UncheckedAccount<'info>,
+
+    /// CHECK: validated by the TokenMessengerMinter
+    pub cctp_token_minter: UncheckedAccount<'info>,
+
+    /// CHECK: local token (burn limits) for the quote mint; validated by the TokenMessengerMinter
+    #[account(mut)]
+    pub cctp_local_token: UncheckedAccount<'info>,
+
+    // fresh keypair; the MessageTransmitter stores the outgoing message here
+    #[account(mut)]
+    pub cctp_message_sent_event_data: Signer<'info>,
+
+    /// CHECK: TokenMessengerMinter event authority; validated by the TokenMessengerMinter
+    pub cctp_event_authority: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ReduceDeal<'info> {
+    // farmer or buyer
+    pub initiator: Signer<'info>,
+
+    // the other party; without it the initiator pays the break fee
+    pub counterparty: Option<Signer<'info>>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == deal.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == deal.buyer)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct VerifyAndSettlePhysical<'info> {
+    #[account(mut, has_one = cft_mint, has_one = quote_mint)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    /// CHECK: verifier may be multisig PDA
+    #[account(mut)]
+    pub verifier: Signer<'info>,
+
+    #[account(mut)]
+    pub cft_mint: Account<'info, Mint>,
+
+    #[account(mut, seeds = [SEED_PREFIX, CFT_AUTH_SEED, cft_mint.key().as_ref()], bump)]
+    pub cft_mint_auth: Account<'info, CftMintAuth>,
+
+    #[account(
+        init_if_needed,
+        payer = verifier,
+        associated_token::mint = cft_mint,
+        associated_token::authority = buyer
+    )]
+    pub buyer_cft_ata: Account<'info, TokenAccount>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    // receives rounding dust left in the vaults on completion
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    /// CHECK: only used as ATA authority
+    pub buyer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.farmer.as_ref()],
+        bump = farmer_stats.bump
+    )]
+    pub farmer_stats: Account<'info, TraderStats>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.buyer.as_ref()],
+        bump = buyer_stats.bump
+    )]
+    pub buyer_stats: Account<'info, TraderStats>,
+
+    // required when deal.prepaid
+    #[account(mut, seeds = [SEED_PREFIX, ESCROW_SEED, deal.key().as_ref()], bump)]
+    pub purchase_escrow: Option<Account<'info, TokenAccount>>,
+
+    // required when market.cft_allowlist_required and CFT is minted
+    #[account(seeds = [SEED_PREFIX, CFT_ALLOW_SEED, market.key().as_ref(), buyer.key().as_ref()], bump = buyer_cft_allowlist.bump)]
+    pub buyer_cft_allowlist: Option<Account<'info, CftAllowlistEntry>>,
+
+    // a lot already proven by verify_delivery_proof
+    #[account(mut, has_one = deal)]
+    pub delivery_record: Option<Account<'info, DeliveryRecord>>,
+
+    pub quote_mint: Account<'info, Mint>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(leaf: [u8; 32])]
+pub struct VerifyDeliveryProof<'info> {
+    /// CHECK: verifier may be multisig PDA
+    #[account(mut)]
+    pub verifier: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    // one per (deal, leaf): a lot can't be recorded twice
+    #[account(
+        init,
+        payer = verifier,
+        space = 8 + DeliveryRecord::SIZE,
+        seeds = [SEED_PREFIX, DELIVERY_SEED, deal.key().as_ref(), leaf.as_ref()],
+        bump
+    )]
+    pub delivery_record: Account<'info, DeliveryRecord>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// A manifest leaf proven against the deal's root (at `merkle_root_version`) ahead of its payout
+#[account]
+pub struct DeliveryRecord {
+    pub deal: Pubkey,
+    pub leaf: [u8; 32],
+    pub merkle_root_version: u32,
+    pub verifier: Pubkey,
+    pub verified_ts: i64,
+    pub consumed: bool, // counted by a payout
+    pub bump: u8,
+}
+impl DeliveryRecord {
+    pub const SIZE: usize = 32 + 32 + 4 + 32 + 8 + 1 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CancelDeal<'info> {
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RotateRole<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CloseDeal<'info> {
+    #[account(mut, has_one = market, close = receiver)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    /// CHECK: receiver of rent lamports on close
+    #[account(mut)]
+    pub receiver: UncheckedAccount<'info>,
+
+    // required when the deal drew on the buyer's credit line
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, CREDIT_LINE_SEED, market.key().as_ref(), deal.buyer.as_ref()],
+        bump = credit_line.bump
+    )]
+    pub credit_line: Option<Account<'info, CreditLine>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitStakePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority, has_one = cft_mint, has_one = quote_mint)]
+    pub market: Account<'info, Market>,
+
+    pub cft_mint: Account<'info, Mint>,
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + StakePool::SIZE,
+        seeds = [SEED_PREFIX, STAKE_POOL_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = cft_mint,
+        associated_token::authority = stake_pool,
+    )]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = quote_mint,
+        associated_token::authority = stake_pool,
+    )]
+    pub reward_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct StakePool {
+    pub market: Pubkey,
+    pub stake_vault: Pubkey,  // CFT ATA owned by the pool
+    pub reward_vault: Pubkey, // quote ATA owned by the pool
+    pub total_staked: u64,
+    pub acc_reward_per_share: u128, // scaled by REWARD_PRECISION
+    pub revenue_share_bps: u16,
+    pub total_distributed: u64,
+    pub bump: u8,
+}
+impl StakePool {
+    pub const SIZE: usize = 32*3 + 8 + 16 + 2 + 8 + 1;
+}
+
+#[account]
+pub struct StakePosition {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub amount: u64,
+    pub reward_debt: u128,
+    pub pending_rewards: u64,
+    pub bump: u8,
+}
+
+impl StakePosition {
+    pub const SIZE: usize = 32*2 + 8 + 16 + 8 + 1;
+
+    // amount * acc_reward_per_share at the pool's current index
+    pub fn accrued(&self, pool: &StakePool) -> Result<u128> {
+        (self.amount as u128)
+            .checked_mul(pool.acc_reward_per_share)
+            .ok_or(CoffeeError::MathOverflow.into())
+    }
+
+    // move rewards earned since the last checkpoint into pending_rewards
+    pub fn checkpoint(&mut self, pool: &StakePool) -> Result<()> {
+        let accrued = self.accrued(pool)?;
+        let earned = accrued.saturating_sub(self.reward_debt) / REWARD_PRECISION;
+        self.pending_rewards = self.pending_rewards
+            .checked_add(earned.try_into().map_err(|_| CoffeeError::MathOverflow)?)
+            .ok_or(CoffeeError::MathOverflow)?;
+        self.reward_debt = accrued;
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct StakeCft<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = stake_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + StakePosition::SIZE,
+        seeds = [SEED_PREFIX, STAKE_SEED, stake_pool.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub position: Account<'info, StakePosition>,
+
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_cft_ata.mint == market.cft_mint)]
+    pub owner_cft_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct UnstakeCft<'info> {
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = stake_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, STAKE_SEED, stake_pool.key().as_ref(), owner.key().as_ref()],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, StakePosition>,
+
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_cft_ata.mint == market.cft_mint)]
+    pub owner_cft_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitInsurancePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority, has_one = quote_mint)]
+    pub market: Account<'info, Market>,
+
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + InsuranceStakePool::SIZE,
+        seeds = [SEED_PREFIX, INSURANCE_POOL_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub insurance_pool: Account<'info, InsuranceStakePool>,
+
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = quote_mint,
+        associated_token::authority = insurance_pool,
+    )]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+/// First-loss insurance capital. Stakers hold shares of stake_vault, so premiums raise and
+/// absorbed losses lower the value of every share pro rata.
+#[account]
+pub struct InsuranceStakePool {
+    pub market: Pubkey,
+    pub stake_vault: Pubkey, // quote ATA owned by the pool
+    pub total_shares: u64,
+    pub premium_share_bps: u16,
+    pub cooldown_secs: i64,
+    pub total_premiums: u64,
+    pub total_losses: u64,
+    pub bump: u8,
+    pub unstake_window_secs: i64, // how long a request stays redeemable once its cooldown ends
+}
+
+impl InsuranceStakePool {
+    pub const SIZE: usize = 32*2 + 8 + 2 + 8 + 8 + 8 + 1 + 8;
+
+    // shares minted for `amount` against `assets` already in the vault; 1:1 for the first staker, into
+    // an empty vault (stake_insurance sweeps whatever an unowned vault holds first)
+    pub fn shares_for_deposit(&self, amount: u64, assets: u64) -> Result<u64> {
+        if self.total_shares == 0 {
+            require!(assets == 0, CoffeeError::InsurancePoolNotEmpty);
+            return Ok(amount);
+        }
+        // a fully drained pool with shares outstanding would hand new capital to old stakers
+        require!(assets > 0, CoffeeError::InsurancePoolDepleted);
+        let shares = (amount as u128)
+            .checked_mul(self.total_shares as u128).ok_or(CoffeeError::MathOverflow)?
+            / assets as u128;
+        shares.try_into().map_err(|_| CoffeeError::MathOverflow.into())
+    }
+
+    // quote redeemable for `shares` against `assets` in the vault, rounded down
+    pub fn assets_for_shares(&self, shares: u64, assets: u64) -> Result<u64> {
+        require_gte!(self.total_shares, shares, CoffeeError::InsufficientStake);
+        let amount = (shares as u128)
+            .checked_mul(assets as u128).ok_or(CoffeeError::MathOverflow)?
+            .checked_div(self.total_shares as u128).ok_or(CoffeeError::MathOverflow)?;
+        Ok(amount as u64)
+    }
+
+    // a request that became redeemable at `ready_ts` and was not redeemed within the window
+    pub fn unstake_lapsed(&self, ready_ts: i64, now_ts: i64) -> bool {
+        now_ts >= ready_ts.saturating_add(self.unstake_window_secs)
+    }
+}
+
+#[account]
+pub struct InsuranceStakePosition {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub shares: u64,
+    pub pending_unstake_shares: u64,
+    pub unstake_ready_ts: i64,
+    pub bump: u8,
+    pub pending_unstake_value: u64, // the queued shares' value at request; they redeem at no more
+}
+
+impl InsuranceStakePosition {
+    pub const SIZE: usize = 32*2 + 8 + 8 + 8 + 1 + 8;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct StakeInsurance<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = stake_vault)]
+    pub insurance_pool: Account<'info, InsuranceStakePool>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + InsuranceStakePosition::SIZE,
+        seeds = [SEED_PREFIX, INSURANCE_STAKE_SEED, insurance_pool.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub position: Account<'info, InsuranceStakePosition>,
+
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_quote_ata.mint == market.quote_mint)]
+    pub owner_quote_ata: Account<'info, TokenAccount>,
+
+    // receives what a vault with no shares outstanding holds
+    #[account(mut, address = market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RequestUnstakeInsurance<'info> {
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market, has_one = stake_vault)]
+    pub insurance_pool: Account<'info, InsuranceStakePool>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, INSURANCE_STAKE_SEED, insurance_pool.key().as_ref(), owner.key().as_ref()],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, InsuranceStakePosition>,
+
+    pub stake_vault: Account<'info, TokenAccount>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct UnstakeInsurance<'info> {
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = stake_vault)]
+    pub insurance_pool: Account<'info, InsuranceStakePool>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, INSURANCE_STAKE_SEED, insurance_pool.key().as_ref(), owner.key().as_ref()],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, InsuranceStakePosition>,
+
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_quote_ata.mint == market.quote_mint)]
+    pub owner_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct DistributeInsurancePremiums<'info> {
+    // owner of the insurance treasury token account
+    pub insurance_authority: Signer<'info>,
+
+    #[account(has_one = insurance_treasury)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = stake_vault)]
+    pub insurance_pool: Account<'info, InsuranceStakePool>,
+
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = insurance_treasury.owner == insurance_authority.key() @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct DistributeRevenue<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = reward_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+
+    #[account(mut)]
+    pub reward_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = fee_treasury.mint == market.quote_mint)]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ClaimStakingRewards<'info> {
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market, has_one = reward_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, STAKE_SEED, stake_pool.key().as_ref(), owner.key().as_ref()],
+        bump = position.bump,
+        has_one = owner
+    )]
+    pub position: Account<'info, StakePosition>,
+
+    #[account(mut)]
+    pub reward_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_quote_ata.mint == market.quote_mint)]
+    pub owner_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ActivatePending<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitGovernance<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority, constraint = market.governance == Pubkey::default() @ CoffeeError::GovernanceControlled)]
+    pub market: Account<'info, Market>,
+
+    // CFT or a dedicated governance mint
+    pub vote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Governance::SIZE,
+        seeds = [SEED_PREFIX, GOVERNANCE_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub governance: Account<'info, Governance>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct Governance {
+    pub market: Pubkey,
+    pub vote_mint: Pubkey,
+    pub quorum_votes: u64,
+    pub voting_period_sec: i64,
+    pub timelock_sec: i64, // applied to executed proposals via the market's pending_* fields
+    pub proposal_count: u64,
+    pub bump: u8,
+}
+impl Governance {
+    pub const SIZE: usize = 32*2 + 8*4 + 1;
+}
+
+#[account]
+pub struct Proposal {
+    pub governance: Pubkey,
+    pub proposer: Pubkey,
+    pub id: u64,
+    pub kind: u8, // ProposalKind
+    pub params: MarketParams,
+    pub new_key: Pubkey,
+    pub votes_for: u64,
+    pub votes_against: u64,
+    pub voting_ends_ts: i64,
+    pub executed: bool,
+    pub bump: u8,
+}
+impl Proposal {
+    pub const SIZE: usize = 32*2 + 8 + 1 + MarketParams::SIZE + 32 + 8*3 + 1 + 1;
+}
+
+#[account]
+pub struct VoteRecord {
+    pub proposal: Pubkey,
+    pub voter: Pubkey,
+    pub weight: u64,
+    pub approve: bool,
+}
+impl VoteRecord {
+    pub const SIZE: usize = 32*2 + 8 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CreateProposal<'info> {
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+
+    #[account(mut)]
+    pub governance: Account<'info, Governance>,
+
+    #[account(constraint = proposer_vote_ata.mint == governance.vote_mint && proposer_vote_ata.owner == proposer.key())]
+    pub proposer_vote_ata: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = proposer,
+        space = 8 + Proposal::SIZE,
+        seeds = [SEED_PREFIX, PROPOSAL_SEED, governance.key().as_ref(), &governance.proposal_count.to_le_bytes()],
+        bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CastVote<'info> {
+    #[account(mut)]
+    pub voter: Signer<'info>,
+
+    pub governance: Account<'info, Governance>,
+
+    #[account(mut, has_one = governance)]
+    pub proposal: Account<'info, Proposal>,
+
+    #[account(constraint = voter_vote_ata.mint == governance.vote_mint && voter_vote_ata.owner == voter.key())]
+    pub voter_vote_ata: Account<'info, TokenAccount>,
+
+    // init (not init_if_needed) makes a second vote from the same wallet fail
+    #[account(
+        init,
+        payer = voter,
+        space = 8 + VoteRecord::SIZE,
+        seeds = [SEED_PREFIX, VOTE_SEED, proposal.key().as_ref(), voter.key().as_ref()],
+        bump
+    )]
+    pub vote_record: Account<'info, VoteRecord>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ExecuteProposal<'info> {
+    #[account(mut, constraint = market.governance == governance.key() @ CoffeeError::Unauthorized)]
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub governance: Account<'info, Governance>,
+
+    #[account(mut, has_one = governance)]
+    pub proposal: Account<'info, Proposal>,
+}
+
+#[derive(Accounts)]
+pub struct InitAccessControl<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + AccessControl::SIZE,
+        seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub access_control: Account<'info, AccessControl>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ManageAccess<'info> {
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Account<'info, AccessControl>,
+}
+
+// Per-market role table: member -> bitmask of Role
+#[account]
+pub struct AccessControl {
+    pub market: Pubkey,
+    pub member_count: u8,
+    pub members: [Pubkey; MAX_ACL_MEMBERS],
+    pub role_masks: [u8; MAX_ACL_MEMBERS],
+    pub bump: u8,
+}
+
+impl AccessControl {
+    pub const SIZE: usize = 32 + 1 + 32 * MAX_ACL_MEMBERS + MAX_ACL_MEMBERS + 1;
+
+    fn index_of(&self, member: &Pubkey) -> Option<usize> {
+        self.members[..self.member_count as usize].iter().position(|m| m == member)
+    }
+
+    pub fn has_role(&self, member: &Pubkey, role: Role) -> bool {
+        self.index_of(member).map(|i| self.role_masks[i] & role.mask() != 0).unwrap_or(false)
+    }
+
+    pub fn grant(&mut self, member: Pubkey, role: Role) -> Result<()> {
+        let i = match self.index_of(&member) {
+            Some(i) => i,
+            None => {
+                let i = self.member_count as usize;
+                require!(i < MAX_ACL_MEMBERS, CoffeeError::AccessControlFull);
+                self.members[i] = member;
+                self.role_masks[i] = 0;
+                self.member_count += 1;
+                i
+            }
+        };
+        self.role_masks[i] |= role.mask();
+        Ok(())
+    }
+
+    // drops the member slot (swap-remove) once no roles remain
+    pub fn revoke(&mut self, member: &Pubkey, role: Role) {
+        if let Some(i) = self.index_of(member) {
+            self.role_masks[i] &= !role.mask();
+            if self.role_masks[i] == 0 {
+                let last = self.member_count as usize - 1;
+                self.members[i] = self.members[last];
+                self.role_masks[i] = self.role_masks[last];
+                self.members[last] = Pubkey::default();
+                self.role_masks[last] = 0;
+                self.member_count -= 1;
+            }
+        }
+    }
+}
+
+// Permissionless: refunds may only go to the deal's own parties
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ExpireDeal<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint && farmer_margin_vault.owner == vault_auth.key())]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint && buyer_margin_vault.owner == vault_auth.key())]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == deal.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == deal.buyer)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Permissionless: proceeds may only go to the deal's own parties
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct DefaultPhysicalDeal<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint && farmer_margin_vault.owner == vault_auth.key())]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint && buyer_margin_vault.owner == vault_auth.key())]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == deal.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == deal.buyer)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.farmer.as_ref()],
+        bump = farmer_stats.bump
+    )]
+    pub farmer_stats: Account<'info, TraderStats>,
+
+    // required when deal.prepaid
+    #[account(mut, seeds = [SEED_PREFIX, ESCROW_SEED, deal.key().as_ref()], bump)]
+    pub purchase_escrow: Option<Account<'info, TokenAccount>>,
+
+    // required when market.seized_vesting_sec > 0 (init_margin_vesting)
+    #[account(mut, seeds = [SEED_PREFIX, VESTING_SEED, deal.key().as_ref()], bump = vesting.bump)]
+    pub vesting: Option<Account<'info, MarginVesting>>,
+
+    #[account(mut, seeds = [SEED_PREFIX, VESTING_VAULT_SEED, deal.key().as_ref()], bump)]
+    pub vesting_vault: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SettleForceMajeure<'info> {
+    pub arbiter: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint && farmer_margin_vault.owner == vault_auth.key())]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint && buyer_margin_vault.owner == vault_auth.key())]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == deal.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == deal.buyer)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = fee_treasury.mint == market.quote_mint)]
+    pub fee_treasury: Account<'info, TokenAccount>,
+
+    // required when deal.prepaid
+    #[account(mut, seeds = [SEED_PREFIX, ESCROW_SEED, deal.key().as_ref()], bump)]
+    pub purchase_escrow: Option<Account<'info, TokenAccount>>,
+
+    // required when deal.sponsored_margin > 0
+    #[account(mut, constraint = sponsor_receive.mint == market.quote_mint && sponsor_receive.owner == deal.sponsor)]
+    pub sponsor_receive: Option<Account<'info, TokenAccount>>,
+
+    // optional evidence for the declared reason
+    #[account(has_one = market)]
+    pub index_feed: Option<Account<'info, IndexFeed>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FundPurchaseEscrow<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(has_one = quote_mint)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = buyer,
+        seeds = [SEED_PREFIX, ESCROW_SEED, deal.key().as_ref()],
+        bump,
+        token::mint = quote_mint,
+        token::authority = vault_auth,
+    )]
+    pub purchase_escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_from.mint == market.quote_mint)]
+    pub buyer_from: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+// Margin taken from a party beyond what its counterparty was owed (a default penalty or stop bounty)
+// on a market with seized_vesting_sec set. It vests back to that party linearly from start_ts to
+// end_ts; claim_vested_margin pays out what has vested
+#[account]
+#[derive(Default)]
+pub struct MarginVesting {
+    pub market: Pubkey,
+    pub deal: Pubkey,
+    pub beneficiary: Pubkey, // the party the margin was taken from; unset until funded
+    pub payer: Pubkey,       // paid the rent (init_margin_vesting); closes it with close_margin_vesting
+    pub total: u64,
+    pub claimed: u64,
+    pub start_ts: i64,
+    pub end_ts: i64,
+    pub bump: u8,
+}
+
+impl MarginVesting {
+    pub const SIZE: usize = 32*4 + 8*4 + 1;
+
+    // fund the schedule once, from now over `vesting_sec`
+    pub fn start(&mut self, beneficiary: Pubkey, amount: u64, now_ts: i64, vesting_sec: i64) -> Result<()> {
+        require!(self.start_ts == 0, CoffeeError::VestingAlreadyStarted);
+        self.beneficiary = beneficiary;
+        self.total = amount;
+        self.claimed = 0;
+        self.start_ts = now_ts;
+        self.end_ts = now_ts.checked_add(vesting_sec).ok_or(CoffeeError::MathOverflow)?;
+        Ok(())
+    }
+
+    // vested so far, rounded down; all of it from end_ts
+    pub fn vested(&self, now_ts: i64) -> u64 {
+        if self.start_ts == 0 || now_ts <= self.start_ts {
+            return 0;
+        }
+        if now_ts >= self.end_ts {
+            return self.total;
+        }
+        let elapsed = (now_ts - self.start_ts) as u128;
+        let span = (self.end_ts - self.start_ts) as u128;
+        (self.total as u128 * elapsed / span) as u64
+    }
+
+    pub fn claimable(&self, now_ts: i64) -> u64 {
+        self.vested(now_ts).saturating_sub(self.claimed)
+    }
+}
+
+// Unpaid settlement amount carried past the deal's life
+#[account]
+pub struct Debt {
+    pub market: Pubkey,
+    pub deal: Pubkey,
+    pub debtor: Pubkey,
+    pub creditor: Pubkey,
+    pub amount_owed: u64,
+    pub amount_repaid: u64,
+    pub insurance_paid: u64,
+    pub created_ts: i64,
+    pub claimable_after_ts: i64,
+    pub bump: u8,
+    pub socialized: bool, // loss spread over the market's open interest via ADL
+}
+
+impl Debt {
+    pub const SIZE: usize = 32*4 + 8*5 + 1 + 1;
+
+    pub fn outstanding(&self) -> u64 {
+        self.amount_owed.saturating_sub(self.amount_repaid).saturating_sub(self.insurance_paid)
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(buyer: Pubkey)]
+pub struct SetCreditLine<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + CreditLine::SIZE,
+        seeds = [SEED_PREFIX, CREDIT_LINE_SEED, market.key().as_ref(), buyer.as_ref()],
+        bump
+    )]
+    pub credit_line: Account<'info, CreditLine>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// Unsecured margin the market authority extends to one buyer. Drawn at open_deal, handed back at
+// close_deal; losses beyond the posted margin go through the deal's Debt like any other shortfall
+#[account]
+pub struct CreditLine {
+    pub market: Pubkey,
+    pub buyer: Pubkey,
+    pub limit: u64,               // margin that may be left unposted across open deals
+    pub used: u64,
+    pub margin_discount_bps: u16, // share of a deal's buyer margin drawn from the line
+    pub defaults: u32,            // credit deals that closed with the buyer still owing
+    pub max_defaults: u32,
+    pub active: bool,
+    pub bump: u8,
+}
+
+impl CreditLine {
+    pub const SIZE: usize = 32*2 + 8*2 + 2 + 4*2 + 1 + 1;
+
+    pub fn available(&self) -> u64 {
+        if !self.active || self.defaults > self.max_defaults {
+            return 0;
+        }
+        self.limit.saturating_sub(self.used)
+    }
+
+    // draw the discounted share of `buyer_margin`, capped by what is left on the line
+    pub fn draw(&mut self, buyer_margin: u64) -> Result<u64> {
+        let drawn = bps_of_u64(buyer_margin, self.margin_discount_bps)?.min(self.available());
+        self.used = self.used.checked_add(drawn).ok_or(CoffeeError::MathOverflow)?;
+        Ok(drawn)
+    }
+
+    pub fn release(&mut self, amount: u64, defaulted: bool) {
+        self.used = self.used.saturating_sub(amount);
+        if defaulted {
+            self.defaults = self.defaults.saturating_add(1);
+        }
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitMarginVesting<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(has_one = quote_mint)]
+    pub market: Account<'info, Market>,
+
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + MarginVesting::SIZE,
+        seeds = [SEED_PREFIX, VESTING_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub vesting: Account<'info, MarginVesting>,
+
+    #[account(
+        init,
+        payer = payer,
+        seeds = [SEED_PREFIX, VESTING_VAULT_SEED, deal.key().as_ref()],
+        bump,
+        token::mint = quote_mint,
+        token::authority = vault_auth,
+    )]
+    pub vesting_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ClaimVestedMargin<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub vesting: Account<'info, MarginVesting>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, vesting.deal.as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, seeds = [SEED_PREFIX, VESTING_VAULT_SEED, vesting.deal.as_ref()], bump)]
+    pub vesting_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = beneficiary_receive.mint == market.quote_mint && beneficiary_receive.owner == vesting.beneficiary)]
+    pub beneficiary_receive: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CloseMarginVesting<'info> {
+    // gets the rent of the vesting account and its vault back
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = payer, close = payer)]
+    pub vesting: Account<'info, MarginVesting>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, vesting.deal.as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, seeds = [SEED_PREFIX, VESTING_VAULT_SEED, vesting.deal.as_ref()], bump)]
+    pub vesting_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct OpenDebt<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, constraint = deal.settled @ CoffeeError::DealNotSettled)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Debt::SIZE,
+        seeds = [SEED_PREFIX, DEBT_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub debt: Account<'info, Debt>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RepayDebt<'info> {
+    pub debtor: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = debtor)]
+    pub debt: Account<'info, Debt>,
+
+    #[account(mut, constraint = debtor_from.mint == market.quote_mint)]
+    pub debtor_from: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = creditor_receive.mint == market.quote_mint && creditor_receive.owner == debt.creditor)]
+    pub creditor_receive: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ClaimDebtFromInsurance<'info> {
+    pub creditor: Signer<'info>,
+
+    // owner of the insurance treasury token account
+    pub insurance_authority: Signer<'info>,
+
+    #[account(has_one = insurance_treasury)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = creditor)]
+    pub debt: Account<'info, Debt>,
+
+    #[account(mut, constraint = insurance_treasury.owner == insurance_authority.key() @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = creditor_receive.mint == market.quote_mint && creditor_receive.owner == debt.creditor)]
+    pub creditor_receive: Account<'info, TokenAccount>,
+
+    // required once the market has an insurance stake pool
+    #[account(mut, seeds = [SEED_PREFIX, INSURANCE_POOL_SEED, market.key().as_ref()], bump = insurance_pool.bump)]
+    pub insurance_pool: Option<Account<'info, InsuranceStakePool>>,
+
+    #[account(mut)]
+    pub insurance_stake_vault: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SocializeDebt<'info> {
+    #[account(mut, has_one = insurance_treasury)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub debt: Account<'info, Debt>,
+
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    // required once the market has an insurance stake pool
+    #[account(seeds = [SEED_PREFIX, INSURANCE_POOL_SEED, market.key().as_ref()], bump = insurance_pool.bump)]
+    pub insurance_pool: Option<Account<'info, InsuranceStakePool>>,
+
+    pub insurance_stake_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ApplyFunding<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint && farmer_margin_vault.owner == vault_auth.key())]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint && buyer_margin_vault.owner == vault_auth.key())]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ViewMarket<'info> {
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct ViewDeal<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+}
+
+#[derive(Accounts)]
+pub struct ViewSettlement<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    // fee tier lookup; omitted stats count as zero volume
+    #[account(seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.farmer.as_ref()], bump = farmer_stats.bump)]
+    pub farmer_stats: Option<Account<'info, TraderStats>>,
+
+    #[account(seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), deal.buyer.as_ref()], bump = buyer_stats.bump)]
+    pub buyer_stats: Option<Account<'info, TraderStats>>,
+}
+
+// Return value of view_settlement_preview
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SettlementPreview {
+    pub price: u64,
+    pub pnl_long: i64,
+    pub fee_total: u64,
+    pub farmer_fee: u64, // farmer share + protocol share
+    pub buyer_fee: u64,
+    pub insurance_cut: u64,
+    pub adl_haircut: u64,
+    pub winner_payout: u64,
+}
+
+#[derive(Accounts)]
+pub struct ValidateDealTerms<'info> {
+    /// CHECK: only keys the deal and sample PDAs
+    pub farmer: UncheckedAccount<'info>,
+    /// CHECK: only keys the deal and sample PDAs
+    pub buyer: UncheckedAccount<'info>,
+
+    pub market: Account<'info, Market>,
+
+    /// CHECK: the deal PDA open_deal would create; must still be empty
+    #[account(seeds = [SEED_PREFIX, DEAL_SEED, market.key().as_ref(), farmer.key().as_ref(), buyer.key().as_ref()], bump)]
+    pub deal: UncheckedAccount<'info>,
+
+    // balance checks; omitted accounts are skipped
+    #[account(constraint = farmer_margin_from.mint == market.quote_mint)]
+    pub farmer_margin_from: Option<Account<'info, TokenAccount>>,
+
+    #[account(constraint = buyer_margin_from.mint == market.quote_mint)]
+    pub buyer_margin_from: Option<Account<'info, TokenAccount>>,
+
+    #[account(seeds = [SEED_PREFIX, APPROVED_ASSETS_SEED, market.key().as_ref()], bump = approved_assets.bump)]
+    pub approved_assets: Option<Account<'info, ApprovedAssets>>,
+
+    #[account(has_one = market)]
+    pub template: Option<Account<'info, DealTemplate>>,
+
+    #[account(seeds = [SEED_PREFIX, SAMPLE_SEED, market.key().as_ref(), farmer.key().as_ref(), buyer.key().as_ref()], bump = sample.bump)]
+    pub sample: Option<Account<'info, SampleEscrow>>,
+
+    // the waiver open_deal would redeem; checked, not used
+    #[account(seeds = [SEED_PREFIX, FEE_WAIVER_SEED, market.key().as_ref(), &fee_waiver.waiver_id.to_le_bytes()], bump = fee_waiver.bump)]
+    pub fee_waiver: Option<Account<'info, FeeWaiver>>,
+}
+
+// open_deal checks reported by validate_deal_terms (bits in DealTermsCheck.failed)
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DealCheck {
+    MarketOpen = 0, // not paused or closing
+    OracleFresh = 1,
+    Price = 2, // positive and on tick
+    PriceDeviation = 3,
+    Quantity = 4, // positive, under max_qty_per_deal, whole contracts
+    Basket = 5, // shape and asset allowlist
+    NotionalCap = 6,
+    Sample = 7,
+    Template = 8,
+    DealExists = 9,
+    OpenInterest = 10,
+    FarmerBalance = 11,
+    BuyerBalance = 12,
+    FeeWaiver = 13, // live, with uses left, for this farmer or buyer
+}
+impl DealCheck {
+    pub fn mask(self) -> u32 {
+        1 << (self as u8)
+    }
+}
+
+// Return value of validate_deal_terms
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DealTermsCheck {
+    pub failed: u32, // DealCheck bits; 0 = open_deal would pass
+    pub first_error: u32, // error code of the first failing check, in open_deal's order
+    pub risk_cosign_required: bool, // the risk officer must also sign
+    pub required_margin: u64, // each side
+    pub insurance_premium: u64, // collected up front, split farmer half / buyer the rest
+    pub farmer_shortfall: u64, // missing from farmer_margin_from
+    pub buyer_shortfall: u64,
+}
+impl DealTermsCheck {
+    pub fn record(&mut self, check: DealCheck, result: Result<()>) {
+        if let Err(e) = result {
+            self.failed |= check.mask();
+            if self.first_error == 0 {
+                self.first_error = error_code_of(&e);
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CloseMarket<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = insurance_treasury, close = receiver)]
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    // required when the insurance treasury still holds funds
+    pub insurance_authority: Option<Signer<'info>>,
+
+    #[account(mut, constraint = sweep_destination.mint == market.quote_mint)]
+    pub sweep_destination: Option<Account<'info, TokenAccount>>,
+
+    /// CHECK: receiver of rent lamports on close
+    #[account(mut)]
+    pub receiver: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MigrateDealV2<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(mut, has_one = market, has_one = buyer, close = buyer)]
+    pub old_deal: Account<'info, Deal>,
+
+    #[account(mut, seeds = [SEED_PREFIX, VAULT_AUTH_SEED, old_deal.key().as_ref()], bump = old_vault_auth.bump, close = buyer)]
+    pub old_vault_auth: Account<'info, VaultAuth>,
+
+    // the farmer margin vault; also the buyer's on a deal without split_vaults
+    #[account(mut, associated_token::mint = quote_mint, associated_token::authority = old_vault_auth)]
+    pub old_vault: Account<'info, TokenAccount>,
+
+    /// CHECK: the buyer_vault PDA; only read when the deal has split_vaults
+    #[account(mut, seeds = [SEED_PREFIX, BUYER_VAULT_SEED, old_deal.key().as_ref()], bump)]
+    pub old_buyer_vault: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Deal::INIT_SPACE,
+        seeds = [
+            SEED_PREFIX_V2,
+            DEAL_SEED,
+            market.key().as_ref(),
+            old_deal.farmer.as_ref(),
+            old_deal.buyer.as_ref(),
+            &old_deal.deal_id.to_le_bytes()
+        ],
+        bump
+    )]
+    pub new_deal: Account<'info, Deal>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, VAULT_AUTH_SEED, new_deal.key().as_ref()],
+        bump
+    )]
+    pub new_vault_auth: Account<'info, VaultAuth>,
+
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = quote_mint,
+        associated_token::authority = new_vault_auth,
+    )]
+    pub new_vault: Account<'info, TokenAccount>,
+
+    /// CHECK: created here when the old deal has split_vaults
+    #[account(mut, seeds = [SEED_PREFIX, BUYER_VAULT_SEED, new_deal.key().as_ref()], bump)]
+    pub new_buyer_vault: UncheckedAccount<'info>,
+
+    /// CHECK: the deal's buyer; receives the v1 accounts' rent
+    #[account(mut)]
+    pub buyer: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MigrateMarketV2<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Market::INIT_SPACE,
+        seeds = [SEED_PREFIX_V2, MARKET_SEED, market.authority.as_ref(), market.cft_mint.as_ref(), market.quote_mint.as_ref()],
+        bump
+    )]
+    pub new_market: Account<'info, Market>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ResizeMarket<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // the market's master authority
+    pub authority: Signer<'info>,
+
+    /// CHECK: a Market possibly on an older, shorter layout; discriminator and authority are checked in resize_market
+    #[account(mut, owner = crate::ID)]
+    pub market: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitApprovedAssets<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + ApprovedAssets::SIZE,
+        seeds = [SEED_PREFIX, APPROVED_ASSETS_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub approved_assets: Account<'info, ApprovedAssets>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ManageApprovedAssets<'info> {
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, seeds = [SEED_PREFIX, APPROVED_ASSETS_SEED, market.key().as_ref()], bump = approved_assets.bump)]
+    pub approved_assets: Account<'info, ApprovedAssets>,
+}
+
+// Per-market registry of deliverable basket mints; units_per_kg converts delivered kg to mint base units
+#[account]
+pub struct ApprovedAssets {
+    pub market: Pubkey,
+    pub asset_count: u8,
+    pub mints: [Pubkey; MAX_APPROVED_ASSETS],
+    pub units_per_kg: [u64; MAX_APPROVED_ASSETS],
+    pub bump: u8,
+}
+
+impl ApprovedAssets {
+    pub const SIZE: usize = 32 + 1 + 32 * MAX_APPROVED_ASSETS + 8 * MAX_APPROVED_ASSETS + 1;
+
+    fn index_of(&self, mint: &Pubkey) -> Option<usize> {
+        self.mints[..self.asset_count as usize].iter().position(|m| m == mint)
+    }
+
+    pub fn units_per_kg_for(&self, mint: &Pubkey) -> Option<u64> {
+        self.index_of(mint).map(|i| self.units_per_kg[i])
+    }
+
+    pub fn upsert(&mut self, mint: Pubkey, units_per_kg: u64) -> Result<()> {
+        let i = match self.index_of(&mint) {
+            Some(i) => i,
+            None => {
+                let i = self.asset_count as usize;
+                require!(i < MAX_APPROVED_ASSETS, CoffeeError::ApprovedAssetsFull);
+                self.mints[i] = mint;
+                self.asset_count += 1;
+                i
+            }
+        };
+        self.units_per_kg[i] = units_per_kg;
+        Ok(())
+    }
+
+    // swap-remove, like AccessControl::revoke
+    pub fn remove(&mut self, mint: &Pubkey) {
+        if let Some(i) = self.index_of(mint) {
+            let last = self.asset_count as usize - 1;
+            self.mints[i] = self.mints[last];
+            self.units_per_kg[i] = self.units_per_kg[last];
+            self.mints[last] = Pubkey::default();
+            self.units_per_kg[last] = 0;
+            self.asset_count -= 1;
+        }
+    }
+}
+
+// Basket vault: ATA of the basket mint owned by the deal's vault_auth
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FarmerBasketAsset<'info> {
+    /// CHECK: must sign, unless `agent` signs under the farmer's `agent_grant`
+    #[account(mut)]
+    pub farmer: UncheckedAccount<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market, has_one = farmer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    pub asset_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = farmer,
+        associated_token::mint = asset_mint,
+        associated_token::authority = vault_auth
+    )]
+    pub basket_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_asset_ata.mint == asset_mint.key())]
+    pub farmer_asset_ata: Account<'info, TokenAccount>,
+
+    // power of attorney: an agent may act for the farmer under a live grant
+    pub agent: Option<Signer<'info>>,
+    #[account(
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent_grant.agent.as_ref()],
+        bump = agent_grant.bump
+    )]
+    pub agent_grant: Option<Account<'info, AgentGrant>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+// Permissionless: units only ever go to the deal's buyer
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct DeliverBasketAsset<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    pub asset_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = asset_mint,
+        associated_token::authority = vault_auth
+    )]
+    pub basket_vault: Account<'info, TokenAccount>,
+
+    /// CHECK: only used as ATA authority
+    pub buyer: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = asset_mint,
+        associated_token::authority = buyer
+    )]
+    pub buyer_asset_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct UpdateDealMerkleRoot<'info> {
+    /// CHECK: must sign, unless `agent` signs under the farmer's `agent_grant`
+    pub farmer: UncheckedAccount<'info>,
+    pub buyer: Signer<'info>,
+    /// CHECK: verifier may be multisig PDA
+    pub verifier: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market, has_one = farmer, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+
+    // power of attorney: an agent may act for the farmer under a live grant
+    pub agent: Option<Signer<'info>>,
+    #[account(
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent_grant.agent.as_ref()],
+        bump = agent_grant.bump
+    )]
+    pub agent_grant: Option<Account<'info, AgentGrant>>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitDeliveryTree<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    /// CHECK: pre-allocated tree account; layout is validated by the compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    /// CHECK: PDA signer only (tree authority for the market)
+    #[account(seeds = [SEED_PREFIX, TREE_AUTH_SEED, market.key().as_ref()], bump)]
+    pub tree_authority: UncheckedAccount<'info>,
+
+    pub compression_program: Program<'info, SplAccountCompression>,
+    pub log_wrapper: Program<'info, Noop>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct LogDeliveryAttestations<'info> {
+    /// CHECK: verifier may be multisig PDA
+    pub verifier: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    /// CHECK: must be the market's registered delivery tree
+    #[account(mut, constraint = merkle_tree.key() == market.delivery_tree && market.delivery_tree != Pubkey::default() @ CoffeeError::DeliveryTreeMismatch)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    /// CHECK: PDA signer only (tree authority for the market)
+    #[account(seeds = [SEED_PREFIX, TREE_AUTH_SEED, market.key().as_ref()], bump)]
+    pub tree_authority: UncheckedAccount<'info>,
+
+    pub compression_program: Program<'info, SplAccountCompression>,
+    pub log_wrapper: Program<'info, Noop>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitActionLog<'info> {
+    #[account(mut)]
+    pub party: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(
+        init,
+        payer = party,
+        space = 8 + ActionLog::SIZE,
+        seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub action_log: Box<Account<'info, ActionLog>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ArchiveActionLog<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Box<Account<'info, ActionLog>>,
+
+    /// CHECK: must be the market's registered delivery tree
+    #[account(mut, constraint = merkle_tree.key() == market.delivery_tree && market.delivery_tree != Pubkey::default() @ CoffeeError::DeliveryTreeMismatch)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    /// CHECK: PDA signer only (tree authority for the market)
+    #[account(seeds = [SEED_PREFIX, TREE_AUTH_SEED, market.key().as_ref()], bump)]
+    pub tree_authority: UncheckedAccount<'info>,
+
+    pub compression_program: Program<'info, SplAccountCompression>,
+    pub log_wrapper: Program<'info, Noop>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RedeemCft<'info> {
+    pub holder: Signer<'info>,
+
+    // owner of the insurance treasury token account
+    pub insurance_authority: Signer<'info>,
+
+    #[account(mut, has_one = cft_mint, has_one = insurance_treasury)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub cft_mint: Account<'info, Mint>,
+
+    #[account(mut, constraint = holder_cft_ata.mint == cft_mint.key() && holder_cft_ata.owner == holder.key())]
+    pub holder_cft_ata: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = holder_receive.mint == market.quote_mint && holder_receive.owner == holder.key())]
+    pub holder_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = insurance_treasury.owner == insurance_authority.key() @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FinalizeCftMint<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(has_one = cft_mint)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, constraint = cft_mint.mint_authority == COption::Some(cft_mint_auth.key()) @ CoffeeError::CftMintFinalized)]
+    pub cft_mint: Account<'info, Mint>,
+
+    #[account(seeds = [SEED_PREFIX, CFT_AUTH_SEED, cft_mint.key().as_ref()], bump = cft_mint_auth.bump)]
+    pub cft_mint_auth: Account<'info, CftMintAuth>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CftCompliance<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(has_one = cft_mint)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    pub cft_mint: Account<'info, Mint>,
+
+    #[account(seeds = [SEED_PREFIX, CFT_AUTH_SEED, cft_mint.key().as_ref()], bump = cft_mint_auth.bump)]
+    pub cft_mint_auth: Account<'info, CftMintAuth>,
+
+    #[account(mut, constraint = token_account.mint == cft_mint.key())]
+    pub token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct AddCftRecipient<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + CftAllowlistEntry::SIZE,
+        seeds = [SEED_PREFIX, CFT_ALLOW_SEED, market.key().as_ref(), wallet.as_ref()],
+        bump
+    )]
+    pub entry: Account<'info, CftAllowlistEntry>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RemoveCftRecipient<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market, close = authority)]
+    pub entry: Account<'info, CftAllowlistEntry>,
+}
+
+// Presence of this PDA allowlists `wallet` to receive CFT when market.cft_allowlist_required
+#[account]
+pub struct CftAllowlistEntry {
+    pub market: Pubkey,
+    pub wallet: Pubkey,
+    pub bump: u8,
+}
+impl CftAllowlistEntry {
+    pub const SIZE: usize = 32 + 32 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(currency: [u8; 3])]
+pub struct RegisterFxOracle<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + FxOracle::SIZE,
+        seeds = [SEED_PREFIX, FX_ORACLE_SEED, market.key().as_ref(), currency.as_ref()],
+        bump
+    )]
+    pub fx_oracle: Account<'info, FxOracle>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PublishFxRate<'info> {
+    pub publisher: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = publisher @ CoffeeError::Unauthorized)]
+    pub fx_oracle: Account<'info, FxOracle>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct AttachFxHedge<'info> {
+    pub farmer: Signer<'info>,
+    pub buyer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = farmer, has_one = buyer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(has_one = market)]
+    pub fx_oracle: Account<'info, FxOracle>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SettleFxHedge<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = fx_oracle)]
+    pub deal: Account<'info, Deal>,
+
+    pub fx_oracle: Account<'info, FxOracle>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint)]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == deal.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == deal.buyer)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Rate feed for one local currency against the market's quote, pushed by `publisher`
+#[account]
+pub struct FxOracle {
+    pub market: Pubkey,
+    pub currency: [u8; 3], // ISO 4217, e.g. *b"COP"
+    pub publisher: Pubkey,
+    pub rate: u64,         // local currency per quote unit, FX_RATE_SCALE
+    pub last_update_ts: i64,
+    pub max_age_sec: u64,
+    pub bump: u8,
+}
+impl FxOracle {
+    pub const SIZE: usize = 32 + 3 + 32 + 8 + 8 + 8 + 1;
+
+    pub fn fresh_rate(&self, now_ts: i64) -> Option<u64> {
+        let age = now_ts.checked_sub(self.last_update_ts)?;
+        (self.rate > 0 && age >= 0 && (age as u64) <= self.max_age_sec).then_some(self.rate)
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitMarginAccount<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + MarginAccount::SIZE,
+        seeds = [SEED_PREFIX, MARGIN_ACCOUNT_SEED, market.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(
+        init,
+        payer = owner,
+        token::mint = quote_mint,
+        token::authority = margin_account,
+        seeds = [SEED_PREFIX, MARGIN_POOL_SEED, margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_pool: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MarginAccountFunds<'info> {
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        has_one = market,
+        has_one = owner,
+        seeds = [SEED_PREFIX, MARGIN_ACCOUNT_SEED, market.key().as_ref(), owner.key().as_ref()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(mut, address = margin_account.pool)]
+    pub margin_pool: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_token.mint == market.quote_mint)]
+    pub owner_token: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// link: caller must be the margin account owner; release: see release_deal_margin
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct LinkDealMargin<'info> {
+    pub caller: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // the owner's side vault of the deal; checked against the deal's vault layout
+    #[account(mut, constraint = deal_margin_vault.owner == vault_auth.key() && deal_margin_vault.mint == market.quote_mint)]
+    pub deal_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        has_one = market,
+        seeds = [SEED_PREFIX, MARGIN_ACCOUNT_SEED, market.key().as_ref(), margin_account.owner.as_ref()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(mut, address = margin_account.pool)]
+    pub margin_pool: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Permissionless: anyone may refresh a trader's risk snapshot
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ComputePortfolioRisk<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(address = margin_account.pool)]
+    pub margin_pool: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + RiskSnapshot::SIZE,
+        seeds = [SEED_PREFIX, RISK_SEED, margin_account.key().as_ref()],
+        bump
+    )]
+    pub risk_snapshot: Account<'info, RiskSnapshot>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitCropInsurance<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + CropInsuranceConfig::SIZE,
+        seeds = [SEED_PREFIX, CROP_INSURANCE_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub crop_insurance: Account<'info, CropInsuranceConfig>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ManageCropInsurance<'info> {
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, seeds = [SEED_PREFIX, CROP_INSURANCE_SEED, market.key().as_ref()], bump = crop_insurance.bump)]
+    pub crop_insurance: Account<'info, CropInsuranceConfig>,
+
+    // required when setting a weather feed
+    #[account(has_one = market)]
+    pub weather_feed: Option<Account<'info, IndexFeed>>,
+}
+
+// Parametric crop-failure product: a fixed payout per policy once a price or weather trigger fires
+#[account]
+pub struct CropInsuranceConfig {
+    pub market: Pubkey,
+    pub premium_bps: u16, // of deal notional, paid by the farmer at purchase
+    pub payout_bps: u16,  // of deal notional, paid on a valid claim
+    pub price_trigger_per_kg: u64, // settlement price at or below this fires; 0 = off
+    pub weather_feed: Pubkey,       // IndexFeed read for the weather trigger; default = off
+    pub weather_index_trigger: u64, // reading at or above this fires
+    pub total_premiums: u64,
+    pub total_payouts: u64,
+    pub bump: u8,
+}
+
+impl CropInsuranceConfig {
+    pub const SIZE: usize = 32*2 + 2*2 + 8*4 + 1;
+
+    pub fn price_trigger_hit(&self, market: &Market) -> bool {
+        self.price_trigger_per_kg > 0
+            && market.last_price_per_kg > 0
+            && market.last_price_per_kg <= self.price_trigger_per_kg
+    }
+
+    // weather readings only count when taken after `since` (policy purchase)
+    pub fn weather_trigger_hit(&self, feed: Option<&IndexFeed>, since: i64) -> bool {
+        feed.map_or(false, |feed| feed.last_update_ts > since && feed.value >= self.weather_index_trigger)
+    }
+
+    pub fn triggered(&self, market: &Market, feed: Option<&IndexFeed>, since: i64) -> bool {
+        self.price_trigger_hit(market) || self.weather_trigger_hit(feed, since)
+    }
+}
+
+#[account]
+pub struct CropPolicy {
+    pub deal: Pubkey,
+    pub farmer: Pubkey,
+    pub premium: u64,
+    pub payout: u64,
+    pub bought_ts: i64,
+    pub claimed: bool,
+    pub bump: u8,
+}
+
+impl CropPolicy {
+    pub const SIZE: usize = 32*2 + 8*3 + 1 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct BuyCropInsurance<'info> {
+    // farmer or agent; pays the policy rent
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: must sign, unless `agent` signs under the farmer's `agent_grant`
+    pub farmer: UncheckedAccount<'info>,
+
+    #[account(has_one = insurance_treasury)]
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market, has_one = farmer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, seeds = [SEED_PREFIX, CROP_INSURANCE_SEED, market.key().as_ref()], bump = crop_insurance.bump)]
+    pub crop_insurance: Account<'info, CropInsuranceConfig>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + CropPolicy::SIZE,
+        seeds = [SEED_PREFIX, CROP_POLICY_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub policy: Account<'info, CropPolicy>,
+
+    #[account(mut, constraint = farmer_quote_ata.mint == market.quote_mint)]
+    pub farmer_quote_ata: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    // required when the config has a weather feed
+    pub weather_feed: Option<Account<'info, IndexFeed>>,
+
+    // power of attorney: an agent may act for the farmer under a live grant
+    pub agent: Option<Signer<'info>>,
+    #[account(
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent_grant.agent.as_ref()],
+        bump = agent_grant.bump
+    )]
+    pub agent_grant: Option<Account<'info, AgentGrant>>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FileInsuranceClaim<'info> {
+    /// CHECK: must sign, unless `agent` signs under the farmer's `agent_grant`
+    pub farmer: UncheckedAccount<'info>,
+    /// CHECK: verifier may be multisig PDA
+    pub verifier: Signer<'info>,
+    // owner of the insurance treasury token account
+    pub insurance_authority: Signer<'info>,
+
+    #[account(has_one = insurance_treasury)]
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market, has_one = farmer)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, seeds = [SEED_PREFIX, CROP_INSURANCE_SEED, market.key().as_ref()], bump = crop_insurance.bump)]
+    pub crop_insurance: Account<'info, CropInsuranceConfig>,
+
+    #[account(mut, seeds = [SEED_PREFIX, CROP_POLICY_SEED, deal.key().as_ref()], bump = policy.bump, has_one = deal, has_one = farmer)]
+    pub policy: Account<'info, CropPolicy>,
+
+    #[account(mut, constraint = insurance_treasury.owner == insurance_authority.key() @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == farmer.key())]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    // required when the config has a weather feed
+    pub weather_feed: Option<Account<'info, IndexFeed>>,
+
+    // power of attorney: an agent may act for the farmer under a live grant
+    pub agent: Option<Signer<'info>>,
+    #[account(
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent_grant.agent.as_ref()],
+        bump = agent_grant.bump
+    )]
+    pub agent_grant: Option<Account<'info, AgentGrant>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(feed_id: u8)]
+pub struct InitIndexFeed<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + IndexFeed::SIZE,
+        seeds = [SEED_PREFIX, INDEX_FEED_SEED, market.key().as_ref(), &[feed_id]],
+        bump
+    )]
+    pub index_feed: Account<'info, IndexFeed>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PublishIndexValue<'info> {
+    pub publisher: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [SEED_PREFIX, INDEX_FEED_SEED, market.key().as_ref(), &[index_feed.feed_id]],
+        bump = index_feed.bump,
+        has_one = market,
+        has_one = publisher @ CoffeeError::Unauthorized
+    )]
+    pub index_feed: Account<'info, IndexFeed>,
+}
+
+// Non-price oracle series (IndexKind) for parametric insurance and force-majeure evidence
+#[account]
+pub struct IndexFeed {
+    pub market: Pubkey,
+    pub feed_id: u8,
+    pub kind: u8, // IndexKind
+    pub publisher: Pubkey,
+    pub value: u64,
+    pub prev_value: u64,
+    pub nonce: u64,
+    pub last_update_ts: i64,
+    pub max_age_sec: u64, // 0 = never stale
+    pub bump: u8,
+}
+
+impl IndexFeed {
+    pub const SIZE: usize = 32*2 + 1*2 + 8*5 + 1;
+
+    // latest reading, or an error when nothing was published or it is older than max_age_sec
+    pub fn fresh_value(&self, now: i64) -> Result<u64> {
+        require!(self.last_update_ts > 0, CoffeeError::IndexFeedStale);
+        if self.max_age_sec > 0 {
+            require!(abs_i64_to_u64(now - self.last_update_ts) <= self.max_age_sec, CoffeeError::IndexFeedStale);
+        }
+        Ok(self.value)
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(agent: Pubkey)]
+pub struct GrantAgent<'info> {
+    #[account(mut)]
+    pub farmer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init_if_needed,
+        payer = farmer,
+        space = 8 + AgentGrant::SIZE,
+        seeds = [SEED_PREFIX, AGENT_SEED, market.key().as_ref(), farmer.key().as_ref(), agent.as_ref()],
+        bump
+    )]
+    pub agent_grant: Account<'info, AgentGrant>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RevokeAgent<'info> {
+    #[account(mut)]
+    pub farmer: Signer<'info>,
+
+    #[account(mut, has_one = farmer, close = farmer)]
+    pub agent_grant: Account<'info, AgentGrant>,
+}
+
+// On-chain power of attorney from a farmer to an agent, scoped to one market
+#[account]
+pub struct AgentGrant {
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub agent: Pubkey,
+    pub max_notional: u64,  // cap on notional of deals the agent opens
+    pub used_notional: u64,
+    pub expiry_ts: i64,
+    pub bump: u8,
+}
+
+impl AgentGrant {
+    pub const SIZE: usize = 32*3 + 8*3 + 1;
+
+    pub fn is_active(&self, now: i64) -> bool {
+        now < self.expiry_ts
+    }
+
+    pub fn use_notional(&mut self, notional: u128) -> Result<()> {
+        let used = (self.used_notional as u128).checked_add(notional).ok_or(CoffeeError::MathOverflow)?;
+        require_gte!(self.max_notional as u128, used, CoffeeError::AgentNotionalCapExceeded);
+        self.used_notional = used as u64;
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(session_key: Pubkey)]
+pub struct CreateSession<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + SessionKey::SIZE,
+        seeds = [SEED_PREFIX, SESSION_SEED, market.key().as_ref(), session_key.as_ref()],
+        bump
+    )]
+    pub session: Account<'info, SessionKey>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RevokeSession<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority, close = authority)]
+    pub session: Account<'info, SessionKey>,
+}
+
+// Expiring keeper key acting for `authority` on the scoped crank instructions only
+#[account]
+pub struct SessionKey {
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub session_key: Pubkey,
+    pub scopes: u8, // SessionScope bits
+    pub expiry_ts: i64,
+    pub bump: u8,
+}
+
+impl SessionKey {
+    pub const SIZE: usize = 32*3 + 1 + 8 + 1;
+
+    pub fn allows(&self, signer: &Pubkey, scope: SessionScope, now: i64) -> bool {
+        self.session_key == *signer && self.scopes & scope.mask() != 0 && now < self.expiry_ts
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct TopUpMarginWithPermit<'info> {
+    // pays fees and rent; needs no relation to the deal
+    #[account(mut)]
+    pub relayer: Signer<'info>,
+
+    /// CHECK: deal counterparty; authorizes via the ed25519-verified permit, not a tx signature
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    #[account(
+        init_if_needed,
+        payer = relayer,
+        space = 8 + PermitNonce::SIZE,
+        seeds = [SEED_PREFIX, PERMIT_NONCE_SEED, owner.key().as_ref()],
+        bump
+    )]
+    pub permit_nonce: Account<'info, PermitNonce>,
+
+    /// CHECK: PDA delegate on the owner's token account; signs transfers, holds no data
+    #[account(seeds = [SEED_PREFIX, PERMIT_AUTHORITY_SEED], bump)]
+    pub permit_authority: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = from_ata.mint == market.quote_mint && from_ata.owner == owner.key())]
+    pub from_ata: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_margin_vault.mint == market.quote_mint && (farmer_margin_vault.owner == vault_auth.key() || deal.omnibus))]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_vault.mint == market.quote_mint && (buyer_margin_vault.owner == vault_auth.key() || deal.omnibus))]
+    pub buyer_margin_vault: Account<'info, TokenAccount>,
+
+    /// CHECK: instructions sysvar, read for the ed25519 verification
+    #[account(address = solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+}
+
+// Next permit nonce per signer; permits must be used in order
+#[account]
+pub struct PermitNonce {
+    pub owner: Pubkey,
+    pub next_nonce: u64,
+    pub bump: u8,
+}
+
+impl PermitNonce {
+    pub const SIZE: usize = 32 + 8 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(template_id: u32)]
+pub struct CreateDealTemplate<'info> {
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + DealTemplate::SIZE,
+        seeds = [SEED_PREFIX, TEMPLATE_SEED, market.key().as_ref(), &template_id.to_le_bytes()],
+        bump
+    )]
+    pub template: Account<'info, DealTemplate>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RetireDealTemplate<'info> {
+    pub creator: Signer<'info>,
+
+    #[account(mut, has_one = creator)]
+    pub template: Account<'info, DealTemplate>,
+}
+
+// Standardized contract terms; open_deal_from_template fills in everything but the price
+#[account]
+pub struct DealTemplate {
+    pub market: Pubkey,
+    pub creator: Pubkey,
+    pub template_id: u32,
+    pub quantity_kg: u64,
+    pub physical_delivery: bool,
+    pub delivery_start_ts: i64,
+    pub delivery_end_ts: i64, // becomes the deal's deadline_ts
+    pub quality_grade: u8,    // e.g. SCA score band, agreed off-chain per market
+    pub incoterm: u8,         // Incoterm
+    pub active: bool,
+    pub bump: u8,
+}
+
+impl DealTemplate {
+    pub const SIZE: usize = 32*2 + 4 + 8 + 1 + 8*2 + 1 + 1 + 1 + 1;
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RequestSample<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    /// CHECK: farmer the sample is requested from; any key
+    pub farmer: UncheckedAccount<'info>,
+
+    #[account(has_one = quote_mint)]
+    pub market: Account<'info, Market>,
+
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + SampleEscrow::SIZE,
+        seeds = [SEED_PREFIX, SAMPLE_SEED, market.key().as_ref(), farmer.key().as_ref(), buyer.key().as_ref()],
+        bump
+    )]
+    pub sample: Account<'info, SampleEscrow>,
+
+    // survives the escrow, so a later sample for the same pair reuses it
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = quote_mint,
+        associated_token::authority = sample,
+    )]
+    pub sample_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_from.mint == quote_mint.key() && buyer_from.owner == buyer.key())]
+    pub buyer_from: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ShipSample<'info> {
+    pub farmer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = farmer)]
+    pub sample: Account<'info, SampleEscrow>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ApproveSample<'info> {
+    pub buyer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = buyer)]
+    pub sample: Account<'info, SampleEscrow>,
+
+    #[account(mut, associated_token::mint = market.quote_mint, associated_token::authority = sample)]
+    pub sample_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == sample.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct VoidSample<'info> {
+    /// CHECK: rent refund for the escrow
+    #[account(mut, address = sample.buyer)]
+    pub buyer: UncheckedAccount<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, close = buyer)]
+    pub sample: Account<'info, SampleEscrow>,
+
+    #[account(mut, associated_token::mint = market.quote_mint, associated_token::authority = sample)]
+    pub sample_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == sample.farmer)]
+    pub farmer_receive: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_receive.mint == market.quote_mint && buyer_receive.owner == sample.buyer)]
+    pub buyer_receive: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Pre-trade sample for a farmer/buyer pair; shares the pair key with the Deal PDA it gates
+#[account]
+pub struct SampleEscrow {
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub buyer: Pubkey,
+    pub fee: u64, // escrowed in the sample's quote ATA until approval or void
+    pub requested_ts: i64,
+    pub deadline_ts: i64, // ship, approve and open_deal by this time
+    pub shipped_ts: i64,  // 0 = not shipped
+    pub approved: bool,
+    pub bump: u8,
+}
+impl SampleEscrow {
+    pub const SIZE: usize = 32 + 32 + 32
+        + 8 + 8 + 8 + 8
+        + 1 + 1;
+
+    pub fn lapsed(&self, now_ts: i64) -> bool {
+        now_ts >= self.deadline_ts
+    }
+
+    // a lapsed, unapproved fee compensates the farmer only if the sample went out
+    pub fn fee_owed_to_farmer(&self) -> bool {
+        self.shipped_ts > 0
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct UpdateShipmentStatus<'info> {
+    pub verifier: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    // optional delegated roles (see AccessControl)
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // required on prepaid deals, where milestones release escrow tranches
+    #[account(mut, seeds = [SEED_PREFIX, ESCROW_SEED, deal.key().as_ref()], bump)]
+    pub purchase_escrow: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut, constraint = farmer_receive.mint == market.quote_mint && farmer_receive.owner == deal.farmer)]
+    pub farmer_receive: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PostSettlementMessage<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    /// CHECK: Wormhole core bridge program configured on the market
+    #[account(executable, address = market.wormhole_program)]
+    pub wormhole_program: UncheckedAccount<'info>,
+
+    /// CHECK: bridge config; validated by the core bridge, fee read at a fixed offset
+    #[account(mut, owner = market.wormhole_program)]
+    pub wormhole_bridge: UncheckedAccount<'info>,
+
+    /// CHECK: message account created by the core bridge at this program's PDA
+    #[account(mut, seeds = [SEED_PREFIX, WORMHOLE_MSG_SEED, deal.key().as_ref()], bump)]
+    pub wormhole_message: UncheckedAccount<'info>,
+
+    /// CHECK: program-wide emitter PDA; signs the CPI
+    #[account(seeds = [SEED_PREFIX, EMITTER_SEED], bump)]
+    pub wormhole_emitter: UncheckedAccount<'info>,
+
+    /// CHECK: emitter sequence tracker; validated by the core bridge
+    #[account(mut)]
+    pub wormhole_sequence: UncheckedAccount<'info>,
+
+    /// CHECK: fee collector; validated by the core bridge
+    #[account(mut)]
+    pub wormhole_fee_collector: UncheckedAccount<'info>,
+
+    pub clock: Sysvar<'info, Clock>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct OpenDealsBatch<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    // co-op agent holding an AgentGrant from every member in the batch; pays the farmers' margin
+    pub agent: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    // owner of the staging vault margin passes through on its way to the deal vaults
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, BATCH_AUTH_SEED, market.key().as_ref()],
+        bump
+    )]
+    pub batch_auth: Account<'info, VaultAuth>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = quote_mint,
+        associated_token::authority = batch_auth,
+    )]
+    pub staging_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = agent_margin_from.mint == quote_mint.key())]
+    pub agent_margin_from: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = buyer_margin_from.mint == quote_mint.key())]
+    pub buyer_margin_from: Account<'info, TokenAccount>,
+
+    // required when market.insurance_upfront
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Option<Account<'info, TokenAccount>>,
+
+    // promo or waiver applied to every member deal, one use each
+    #[account(mut, seeds = [SEED_PREFIX, FEE_WAIVER_SEED, market.key().as_ref(), &fee_waiver.waiver_id.to_le_bytes()], bump = fee_waiver.bump)]
+    pub fee_waiver: Option<Account<'info, FeeWaiver>>,
+
+    // required when market.max_opens_per_hour > 0; every deal in the batch counts against the buyer
+    #[account(mut, seeds = [SEED_PREFIX, TRADER_STATS_SEED, market.key().as_ref(), buyer.key().as_ref()], bump = buyer_stats.bump)]
+    pub buyer_stats: Option<Account<'info, TraderStats>>,
+
+    // buyer's latest index page; every member deal is appended when passed
+    #[account(mut, seeds = [SEED_PREFIX, PARTICIPANT_INDEX_SEED, buyer.key().as_ref(), &buyer_index.page.to_le_bytes()], bump = buyer_index.bump)]
+    pub buyer_index: Option<Box<Account<'info, ParticipantIndex>>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(order_id: u64)]
+pub struct PlaceStandingOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + StandingOrder::SIZE,
+        seeds = [SEED_PREFIX, STANDING_ORDER_SEED, market.key().as_ref(), owner.key().as_ref(), &order_id.to_le_bytes()],
+        bump
+    )]
+    pub order: Account<'info, StandingOrder>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = owner,
+        associated_token::mint = quote_mint,
+        associated_token::authority = order,
+    )]
+    pub escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_quote_ata.mint == market.quote_mint)]
+    pub owner_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CancelStandingOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(mut, has_one = owner, close = owner)]
+    pub order: Account<'info, StandingOrder>,
+
+    #[account(mut, constraint = escrow.owner == order.key() @ CoffeeError::Unauthorized)]
+    pub escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = owner_quote_ata.mint == escrow.mint)]
+    pub owner_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(deal_id: u64)]
+pub struct MatchStandingOrders<'info> {
+    #[account(mut)]
+    pub crank: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub farmer_order: Account<'info, StandingOrder>,
+
+    #[account(mut, associated_token::mint = quote_mint, associated_token::authority = farmer_order)]
+    pub farmer_escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, has_one = market)]
+    pub buyer_order: Account<'info, StandingOrder>,
+
+    #[account(mut, associated_token::mint = quote_mint, associated_token::authority = buyer_order)]
+    pub buyer_escrow: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = crank,
+        space = 8 + Deal::INIT_SPACE,
+        seeds = [
+            SEED_PREFIX_V2,
+            DEAL_SEED,
+            market.key().as_ref(),
+            farmer_order.owner.as_ref(),
+            buyer_order.owner.as_ref(),
+            &deal_id.to_le_bytes()
+        ],
+        bump
+    )]
+    pub deal: Box<Account<'info, Deal>>,
+
+    #[account(
+        init,
+        payer = crank,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // the farmer and buyer margin vaults are both this ATA
+    #[account(
+        init,
+        payer = crank,
+        associated_token::mint = quote_mint,
+        associated_token::authority = vault_auth,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    // required when market.insurance_upfront
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(auction_id: u64)]
+pub struct ListHarvestAuction<'info> {
+    #[account(mut)]
+    pub farmer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = farmer,
+        space = 8 + HarvestAuction::SIZE,
+        seeds = [SEED_PREFIX, AUCTION_SEED, market.key().as_ref(), farmer.key().as_ref(), &auction_id.to_le_bytes()],
+        bump
+    )]
+    pub auction: Account<'info, HarvestAuction>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = farmer,
+        associated_token::mint = quote_mint,
+        associated_token::authority = auction,
+    )]
+    pub auction_escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = farmer_quote_ata.mint == market.quote_mint)]
+    pub farmer_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PlaceAuctionBid<'info> {
+    #[account(mut)]
+    pub bidder: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub auction: Account<'info, HarvestAuction>,
+
+    #[account(associated_token::mint = quote_mint, associated_token::authority = auction)]
+    pub auction_escrow: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = bidder,
+        space = 8 + AuctionBid::SIZE,
+        seeds = [SEED_PREFIX, AUCTION_BID_SEED, auction.key().as_ref(), bidder.key().as_ref()],
+        bump
+    )]
+    pub bid: Account<'info, AuctionBid>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = bidder,
+        associated_token::mint = quote_mint,
+        associated_token::authority = bid,
+    )]
+    pub bid_escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = bidder_quote_ata.mint == market.quote_mint)]
+    pub bidder_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct WithdrawAuctionBid<'info> {
+    pub auction: Account<'info, HarvestAuction>,
+
+    #[account(
+        mut,
+        has_one = auction,
+        has_one = bidder,
+        seeds = [SEED_PREFIX, AUCTION_BID_SEED, auction.key().as_ref(), bid.bidder.as_ref()],
+        bump = bid.bump,
+        close = bidder
+    )]
+    pub bid: Account<'info, AuctionBid>,
+
+    /// CHECK: receives the bid's rent; checked by has_one on bid
+    #[account(mut)]
+    pub bidder: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = bid_escrow.owner == bid.key() @ CoffeeError::Unauthorized)]
+    pub bid_escrow: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = bidder_quote_ata.mint == bid_escrow.mint && bidder_quote_ata.owner == bid.bidder @ CoffeeError::Unauthorized)]
+    pub bidder_quote_ata: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(deal_id: u64)]
+pub struct FinalizeAuction<'info> {
+    #[account(mut)]
+    pub crank: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+
+    #[account(mut, has_one = market, has_one = farmer)]
+    pub auction: Box<Account<'info, HarvestAuction>>,
+
+    #[account(mut, associated_token::mint = quote_mint, associated_token::authority = auction)]
+    pub auction_escrow: Box<Account<'info, TokenAccount>>,
+
+    /// CHECK: receives the auction escrow's rent; checked by has_one on auction
+    #[account(mut)]
+    pub farmer: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = farmer_quote_ata.mint == market.quote_mint && farmer_quote_ata.owner == auction.farmer @ CoffeeError::Unauthorized)]
+    pub farmer_quote_ata: Box<Account<'info, TokenAccount>>,
+
+    #[account(seeds = [SEED_PREFIX, AUCTION_BID_SEED, auction.key().as_ref(), auction.best_bidder.as_ref()], bump = winning_bid.bump)]
+    pub winning_bid: Box<Account<'info, AuctionBid>>,
+
+    #[account(mut, associated_token::mint = quote_mint, associated_token::authority = winning_bid)]
+    pub bid_escrow: Box<Account<'info, TokenAccount>>,
+
+    #[account(mut, constraint = buyer_quote_ata.mint == market.quote_mint && buyer_quote_ata.owner == auction.best_bidder @ CoffeeError::Unauthorized)]
+    pub buyer_quote_ata: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = crank,
+        space = 8 + Deal::INIT_SPACE,
+        seeds = [
+            SEED_PREFIX_V2,
+            DEAL_SEED,
+            market.key().as_ref(),
+            auction.farmer.as_ref(),
+            auction.best_bidder.as_ref(),
+            &deal_id.to_le_bytes()
+        ],
+        bump
+    )]
+    pub deal: Box<Account<'info, Deal>>,
+
+    #[account(
+        init,
+        payer = crank,
+        space = 8 + VaultAuth::SIZE,
+        seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()],
+        bump
+    )]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // the farmer and buyer margin vaults are both this ATA
+    #[account(
+        init,
+        payer = crank,
+        associated_token::mint = quote_mint,
+        associated_token::authority = vault_auth,
+    )]
+    pub vault: Box<Account<'info, TokenAccount>>,
+
+    #[account(address = market.quote_mint)]
+    pub quote_mint: Box<Account<'info, Mint>>,
+
+    // required when market.insurance_upfront
+    #[account(mut, constraint = insurance_treasury.key() == market.insurance_treasury @ CoffeeError::Unauthorized)]
+    pub insurance_treasury: Option<Box<Account<'info, TokenAccount>>>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct CloseUnsoldAuction<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = farmer)]
+    pub auction: Account<'info, HarvestAuction>,
+
+    #[account(mut, associated_token::mint = market.quote_mint, associated_token::authority = auction)]
+    pub auction_escrow: Account<'info, TokenAccount>,
+
+    /// CHECK: receives the auction escrow's rent; checked by has_one on auction
+    #[account(mut)]
+    pub farmer: UncheckedAccount<'info>,
+
+    #[account(mut, constraint = farmer_quote_ata.mint == market.quote_mint && farmer_quote_ata.owner == auction.farmer @ CoffeeError::Unauthorized)]
+    pub farmer_quote_ata: Account<'info, TokenAccount>,
+
+    // the best bid's escrow, required to close an auction whose winner no longer fits
+    #[account(seeds = [SEED_PREFIX, AUCTION_BID_SEED, auction.key().as_ref(), auction.best_bidder.as_ref()], bump = winning_bid.bump)]
+    pub winning_bid: Option<Account<'info, AuctionBid>>,
+
+    #[account(associated_token::mint = market.quote_mint, associated_token::authority = winning_bid)]
+    pub bid_escrow: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(trigger_id: u64)]
+pub struct CreatePriceTrigger<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + PriceTrigger::SIZE,
+        seeds = [SEED_PREFIX, PRICE_TRIGGER_SEED, market.key().as_ref(), owner.key().as_ref(), &trigger_id.to_le_bytes()],
+        bump
+    )]
+    pub trigger: Account<'info, PriceTrigger>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FireTrigger<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = owner, close = owner)]
+    pub trigger: Account<'info, PriceTrigger>,
+
+    /// CHECK: receives the trigger's rent; checked by has_one on trigger
+    #[account(mut)]
+    pub owner: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelPriceTrigger<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(mut, has_one = owner, close = owner)]
+    pub trigger: Account<'info, PriceTrigger>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(waiver_id: u64)]
+pub struct IssueFeeWaiver<'info> {
+    #[account(mut)]
+    pub issuer: Signer<'info>,
+
+    pub market: Account<'info, Market>,
+
+    #[account(seeds = [SEED_PREFIX, ACCESS_SEED, market.key().as_ref()], bump = access_control.bump)]
+    pub access_control: Option<Account<'info, AccessControl>>,
+
+    #[account(
+        init,
+        payer = issuer,
+        space = 8 + FeeWaiver::SIZE,
+        seeds = [SEED_PREFIX, FEE_WAIVER_SEED, market.key().as_ref(), &waiver_id.to_le_bytes()],
+        bump
+    )]
+    pub fee_waiver: Account<'info, FeeWaiver>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RevokeFeeWaiver<'info> {
+    #[account(mut)]
+    pub issuer: Signer<'info>,
+
+    #[account(mut, has_one = issuer, close = issuer)]
+    pub fee_waiver: Account<'info, FeeWaiver>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SponsorMargin<'info> {
+    pub sponsor: Signer<'info>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(seeds = [SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref()], bump = vault_auth.bump)]
+    pub vault_auth: Account<'info, VaultAuth>,
+
+    // the deal must have split vaults, so this holds only the farmer side
+    #[account(mut, associated_token::mint = market.quote_mint, associated_token::authority = vault_auth)]
+    pub farmer_margin_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = sponsor_ata.mint == market.quote_mint)]
+    pub sponsor_ata: Account<'info, TokenAccount>,
+
+    // required once the deal has an ActionLog (init_action_log)
+    #[account(mut, seeds = [SEED_PREFIX, ACTION_LOG_SEED, deal.key().as_ref()], bump = action_log.bump)]
+    pub action_log: Option<Box<Account<'info, ActionLog>>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// ------------------------- Helpers -------------------------
+
+fn version_guard_program() -> Result<()> {
+    Ok(())
+}
+
+fn version_guard_market(market: &Account<Market>) -> Result<()> {
+    require!(market.program_version == PROGRAM_VERSION, CoffeeError::VersionMismatch);
+    Ok(())
+}
+
+fn assert_is_verifier(market: &Account<Market>, acl: Option<&Account<AccessControl>>, verifier: &Signer, remaining: &[AccountInfo]) -> Result<()> {
+    require_role(market, acl, &verifier.key(), Role::Verifier, remaining)
+}
+
+// Role check: the configured oracle / verifier keys hold their roles; the master authority holds
+// every role; anyone else needs a grant in the market's AccessControl account.
+fn require_role(
+    market: &Account<Market>,
+    acl: Option<&Account<AccessControl>>,
+    signer: &Pubkey,
+    role: Role,
+    remaining: &[AccountInfo],
+) -> Result<()> {
+    match role {
+        Role::Oracle if *signer == market.oracle_publisher => return Ok(()),
+        Role::Verifier if *signer == market.verifier => {
+            return assert_authority_config(&market.verifier_config, remaining);
+        }
+        _ => {}
+    }
+    if *signer == market.authority {
+        return assert_authority_config(&market.authority_config, remaining);
+    }
+    if let Some(acl) = acl {
+        if acl.has_role(signer, role) {
+            return Ok(());
+        }
+    }
+    err!(CoffeeError::Unauthorized)
+}
+
+// require_role for keeper instructions, also accepting a live session key scoped to the instruction.
+// The session's creator is re-checked so revoking the operator's role also disables its sessions.
+fn require_role_or_session(
+    market: &Account<Market>,
+    acl: Option<&Account<AccessControl>>,
+    signer: &Pubkey,
+    session: Option<&Account<SessionKey>>,
+    scope: SessionScope,
+    remaining: &[AccountInfo],
+) -> Result<()> {
+    let session = match session {
+        Some(session) => session,
+        None => return require_role(market, acl, signer, scope.role(), remaining),
+    };
+    require!(session.allows(signer, scope, Clock::get()?.unix_timestamp), CoffeeError::SessionNotAllowed);
+    require_role(market, acl, &session.authority, scope.role(), remaining)
+}
+
+// Multisig half of an AuthorityConfig; the role key itself is checked by has_one / equality.
+// remaining_accounts for ExternalMultisig: [instructions sysvar, multisig config account].
+fn assert_authority_config(cfg: &AuthorityConfig, remaining: &[AccountInfo]) -> Result<()> {
+    match cfg {
+        AuthorityConfig::SingleKey => Ok(()),
+        AuthorityConfig::ExternalMultisig { program, multisig, min_threshold } => {
+            let ix_sysvar = remaining.first().ok_or(CoffeeError::MultisigAccountsMissing)?;
+            let ms = remaining.get(1).ok_or(CoffeeError::MultisigAccountsMissing)?;
+            require_keys_eq!(ix_sysvar.key(), solana_program::sysvar::instructions::ID, CoffeeError::MultisigAccountsMissing);
+
+            // the transaction-level instruction must be the multisig program executing on our behalf
+            let idx = load_current_index_checked(ix_sysvar)?;
+            let top = load_instruction_at_checked(idx as usize, ix_sysvar)?;
+            require_keys_eq!(top.program_id, *program, CoffeeError::MultisigNotInvoker);
+
+            require_keys_eq!(ms.key(), *multisig, CoffeeError::MultisigAccountsMissing);
+            require_keys_eq!(*ms.owner, *program, CoffeeError::MultisigAccountsMissing);
+            let data = ms.try_borrow_data()?;
+            require!(data.len() >= SQUADS_THRESHOLD_OFFSET + 2, CoffeeError::MultisigAccountsMissing);
+            let threshold = u16::from_le_bytes([data[SQUADS_THRESHOLD_OFFSET], data[SQUADS_THRESHOLD_OFFSET + 1]]);
+            require!(threshold >= *min_threshold, CoffeeError::MultisigThresholdTooLow);
+            Ok(())
+        }
+    }
+}
+// Append to the deal's ActionLog; a deal that has one can't act without it
+fn record_deal_action(deal: &Deal, log: Option<&mut Account<ActionLog>>, action: DealAction, actor: Pubkey, amount: u64) -> Result<()> {
+    match log {
+        Some(log) => log.record(ActionEntry { action: action as u8, actor, amount, ts: Clock::get()?.unix_timestamp }),
+        None => {
+            require!(!deal.has_action_log, CoffeeError::ActionLogRequired);
+            Ok(())
+        }
+    }
+}
+
+fn assert_is_counterparty(deal: &Account<Deal>, signer: &Signer) -> Result<()> {
+    require!(deal.side_of(&signer.key()).is_some(), CoffeeError::InvalidCounterparty);
+    Ok(())
+}
+
+/// The instruction right before this one must be a native ed25519 verification of `message`
+/// signed by `signer`, with all data inline in that instruction.
+fn verify_ed25519_permit(ix_sysvar: &AccountInfo, signer: &Pubkey, message: &[u8]) -> Result<()> {
+    let idx = load_current_index_checked(ix_sysvar)?;
+    require!(idx > 0, CoffeeError::PermitSignatureMissing);
+    let ix = load_instruction_at_checked(idx as usize - 1, ix_sysvar)?;
+    require_keys_eq!(ix.program_id, solana_program::ed25519_program::ID, CoffeeError::PermitSignatureMissing);
+    let (pubkey, signed) = ed25519_signed_message(&ix.data)?;
+    require_keys_eq!(pubkey, *signer, CoffeeError::PermitSignatureInvalid);
+    require!(signed == message, CoffeeError::PermitSignatureInvalid);
+    Ok(())
+}
+
+// Ed25519 program data: count u8, padding u8, then 7 u16 offsets per signature
+// (sig, sig ix, pubkey, pubkey ix, msg, msg len, msg ix). Only one self-contained signature is accepted.
+fn ed25519_signed_message(data: &[u8]) -> Result<(Pubkey, &[u8])> {
+    const HEADER: usize = 2 + 14;
+    require!(data.len() >= HEADER && data[0] == 1, CoffeeError::PermitSignatureInvalid);
+    let u16_at = |i: usize| u16::from_le_bytes([data[2 + 2 * i], data[3 + 2 * i]]);
+    // instruction indexes must point at this same instruction (u16::MAX)
+    for i in [1, 3, 6] {
+        require!(u16_at(i) == u16::MAX, CoffeeError::PermitSignatureInvalid);
+    }
+    let pk = u16_at(2) as usize;
+    let (msg, len) = (u16_at(4) as usize, u16_at(5) as usize);
+    let pubkey = data.get(pk..pk + 32).ok_or(CoffeeError::PermitSignatureInvalid)?;
+    let signed = data.get(msg..msg + len).ok_or(CoffeeError::PermitSignatureInvalid)?;
+    Ok((Pubkey::try_from(pubkey).map_err(|_| CoffeeError::PermitSignatureInvalid)?, signed))
+}
+
+// safe multiplication by bps returning u128
+fn bps_mul_u128(x: u128, bps: u16) -> Result<u128> {
+    math::bps_mul(x, bps).ok_or(CoffeeError::MathOverflow.into())
+}
+
+fn bps_of_u64(x: u64, bps: u16) -> Result<u64> {
+    math::bps_of(x, bps).ok_or(CoffeeError::MathOverflow.into())
+}
+
+fn fee_split(notional: u128, fee_bps: u16, market: &Market) -> Result<math::FeeSplit> {
+    math::fee_split(notional, fee_bps, market.farmer_fee_bps, market.buyer_fee_bps, market.insurance_bps)
+        .ok_or(CoffeeError::MathOverflow.into())
+}
+
+// notional the fee is charged on once a FeeWaiver discount is applied
+fn fee_bearing_notional(notional: u128, fee_waiver_bps: u16) -> Result<u128> {
+    let kept = MAX_BPS.saturating_sub(fee_waiver_bps) as u128;
+    Ok(notional.checked_mul(kept).ok_or(CoffeeError::MathOverflow)? / MAX_BPS as u128)
+}
+
+// Insurance premium collected up front at open (0 unless insurance_upfront), after any fee waiver.
+// Every open path and validate_deal_terms size it here so they charge the same amount
+fn upfront_premium(market: &Market, notional: u128, fee_waiver_bps: u16) -> Result<u64> {
+    if !market.insurance_upfront {
+        return Ok(0);
+    }
+    Ok(fee_split(fee_bearing_notional(notional, fee_waiver_bps)?, market.fee_bps, market)?.insurance_cut)
+}
+
+/// Transfer amount from vault (PDA authoritiy) to `to_ata` using signer PDA
+fn transfer_from_vault_to<'a>(
+    amount: u64,
+    vault_auth: &Account<'a, VaultAuth>,
+    from_vault: &Account<'a, TokenAccount>,
+    to_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+    deal_key: &Pubkey,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let bump = vault_auth.bump;
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, VAULT_AUTH_SEED, deal_key.as_ref(), &[bump]]];
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: from_vault.to_account_info(),
+                to: to_ata.to_account_info(),
+                authority: vault_auth.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of one of the market's omnibus vaults using the omnibus PDA as signer
+fn transfer_from_omnibus<'a>(
+    amount: u64,
+    omnibus_auth: &Account<'a, VaultAuth>,
+    from_vault: &Account<'a, TokenAccount>,
+    to_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+    market_key: &Pubkey,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, OMNIBUS_SEED, market_key.as_ref(), &[omnibus_auth.bump]]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: from_vault.to_account_info(),
+                to: to_ata.to_account_info(),
+                authority: omnibus_auth.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+// The margin vaults passed for a deal must be the ones it holds margin in: the market's omnibus vaults,
+// which an omnibus deal's ledger is kept against, or the vault_auth ATA for the farmer and, on a
+// split-vault deal, the buyer_vault PDA for the buyer
+fn check_margin_vaults(
+    market: &Market,
+    deal: &Account<Deal>,
+    vault_auth: &Pubkey,
+    farmer_vault: &Account<TokenAccount>,
+    buyer_vault: &Account<TokenAccount>,
+) -> Result<()> {
+    if deal.omnibus {
+        require!(
+            farmer_vault.key() == market.omnibus_farmer_vault && buyer_vault.key() == market.omnibus_buyer_vault,
+            CoffeeError::OmnibusVaultMismatch
+        );
+        return Ok(());
+    }
+    let [farmer, buyer] = deal_margin_vaults(deal.split_vaults, &deal.key(), vault_auth, &market.quote_mint);
+    require!(farmer_vault.key() == farmer && buyer_vault.key() == buyer, CoffeeError::MarginVaultMismatch);
+    Ok(())
+}
+
+// A deal's own margin vaults, [farmer, buyer]
+fn deal_margin_vaults(split_vaults: bool, deal: &Pubkey, vault_auth: &Pubkey, quote_mint: &Pubkey) -> [Pubkey; 2] {
+    let farmer = anchor_spl::associated_token::get_associated_token_address(vault_auth, quote_mint);
+    let buyer = if split_vaults { pda::buyer_margin_vault(deal).0 } else { farmer };
+    [farmer, buyer]
+}
+
+// Per-side ledger totals over `ledgers` ([farmer, buyer] each) and vault minus ledger; a positive
+// discrepancy is unaccounted surplus in the vault, a negative one a ledger the vault can't cover
+fn omnibus_audit(ledgers: &[[u64; 2]], vault: [u64; 2]) -> Result<([u64; 2], [i128; 2])> {
+    let mut ledger = [0u64; 2];
+    for balances in ledgers {
+        for (total, balance) in ledger.iter_mut().zip(balances) {
+            *total = total.checked_add(*balance).ok_or(CoffeeError::MathOverflow)?;
+        }
+    }
+    let discrepancy = [vault[0] as i128 - ledger[0] as i128, vault[1] as i128 - ledger[1] as i128];
+    Ok((ledger, discrepancy))
+}
+
+// With ordered settlement on, cash deals settle through process_settlement_queue; liquidations keep
+// their own queue
+fn check_settlement_order(market: &Market, deal: &Deal) -> Result<()> {
+    require!(!market.ordered_settlement || deal.liquidated || deal.stopped, CoffeeError::SettlementQueueRequired);
+    Ok(())
+}
+
+/// Transfer out of the settlement queue's fee pot using the queue PDA as signer
+fn transfer_from_settlement_queue<'a>(
+    amount: u64,
+    queue: &Account<'a, SettlementQueue>,
+    fee_pot: &Account<'a, TokenAccount>,
+    to_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+    market_key: &Pubkey,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, SETTLEMENT_QUEUE_SEED, market_key.as_ref(), &[queue.bump]]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: fee_pot.to_account_info(),
+                to: to_ata.to_account_info(),
+                authority: queue.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+// Quote fee -> CFT units at the settlement price (1 CFT base unit per kg), less the CFT discount
+fn quote_to_cft_fee(quote_fee: u64, price_per_kg: u64, price_exponent: i8, discount_bps: u16) -> Result<u64> {
+    require!(price_per_kg > 0, CoffeeError::ZeroPrice);
+    let discounted = quote_fee
+        .checked_sub(bps_of_u64(quote_fee, discount_bps)?)
+        .ok_or(CoffeeError::MathOverflow)?;
+    // fee / (price * 10^exponent), scaling whichever side keeps it exact
+    let (fee, price) = if price_exponent >= 0 {
+        (Some(discounted as u128), math::to_quote_units(price_per_kg as u128, price_exponent))
+    } else {
+        (math::to_quote_units(discounted as u128, price_exponent.saturating_neg()), Some(price_per_kg as u128))
+    };
+    let (fee, price) = fee.zip(price).ok_or(CoffeeError::MathOverflow)?;
+    // fee, so rounded up
+    Ok(math::div_ceil(fee, price).ok_or(CoffeeError::MathOverflow)? as u64)
+}
+
+/// Burn CFT from a trader ATA where vault_auth was approved as delegate
+fn burn_cft_as_delegate<'a>(
+    amount: u64,
+    vault_auth: &Account<'a, VaultAuth>,
+    cft_mint: &Account<'a, Mint>,
+    from_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+    deal_key: &Pubkey,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    require!(
+        from_ata.delegate == COption::Some(vault_auth.key()) && from_ata.delegated_amount >= amount,
+        CoffeeError::CftFeeNotApproved
+    );
+    let bump = vault_auth.bump;
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, VAULT_AUTH_SEED, deal_key.as_ref(), &[bump]]];
+
+    token::burn(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Burn {
+                mint: cft_mint.to_account_info(),
+                from: from_ata.to_account_info(),
+                authority: vault_auth.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+// Merkle verification (binary, keccak-based). Returns Result<bool, _> for easy use.
+fn verify_merkle_proof(mut leaf: [u8; 32], proof: &Vec<[u8; 32]>, root: [u8; 32]) -> Result<bool> {
+    for p in proof.iter() {
+        // deterministic ordering by bytes
+        let combined = if leaf <= *p {
+            [&leaf[..], &p[..]].concat()
+        } else {
+            [&p[..], &leaf[..]].concat()
+        };
+        leaf = solana_program::keccak::hash(&combined).0;
+    }
+    Ok(leaf == root)
+}
+
+// Helper: absolute i64 to u64 (safe)
+fn abs_i64_to_u64(v: i64) -> u64 {
+    if v >= 0 { v as u64 } else { (-v) as u64 }
+}
+
+// Rolling-window decay: the bucket loses window-proportional value for the time elapsed since last_ts.
+fn decay_rolling(value: u64, last_ts: i64, now_ts: i64, window_sec: u64) -> Result<u64> {
+    if last_ts == 0 || now_ts <= last_ts || window_sec == 0 {
+        return Ok(value);
+    }
+    let elapsed = ((now_ts - last_ts) as u64).min(window_sec);
+    let kept = (value as u128)
+        .checked_mul((window_sec - elapsed) as u128).ok_or(CoffeeError::MathOverflow)?
+        .checked_div(window_sec as u128).ok_or(CoffeeError::MathOverflow)?;
+    Ok(kept as u64)
+}
+
+// Replay/nonce protection and staleness shared by publish_price and oracle_heartbeat:
+// a feed older than max_oracle_age_sec rejects both until the admin raises the age limit
+fn check_oracle_update(market: &Market, nonce: u64, now_ts: i64) -> Result<()> {
+    require!(nonce > market.last_price_nonce, CoffeeError::ReplayOrStaleNonce);
+    if market.last_oracle_update_ts > 0 && market.max_oracle_age_sec > 0 {
+        let age_u64 = abs_i64_to_u64(now_ts - market.last_oracle_update_ts);
+        require!(age_u64 <= market.max_oracle_age_sec, CoffeeError::OracleStale);
+    }
+    Ok(())
+}
+
+// Validate and record a new oracle price at `now_ts`: nonce, staleness, tick and band checks, then the
+// TWAP, realized volatility and last/prev price
+fn apply_price(market: &mut Market, price_per_kg: u64, nonce: u64, now_ts: i64) -> Result<()> {
+    check_oracle_update(market, nonce, now_ts)?;
+    require!(price_per_kg > 0, CoffeeError::ZeroPrice);
+    require!(market.is_on_tick(price_per_kg), CoffeeError::PriceNotOnTick);
+
+    // price-band check against previous price (if present), in the quoted convention
+    if market.prev_price_per_kg > 0 {
+        is_price_band_ok(market.quoted_price(market.prev_price_per_kg)?, market.quoted_price(price_per_kg)?, 2_500 /* 25% demo cap */)?;
+    }
+
+    // Update TWAP (time-weighted)
+    update_twap(market, now_ts)?;
+
+    // rolling realized volatility from the return since the last published price
+    if market.last_price_per_kg > 0 {
+        let return_bps = math::deviation_bps(market.last_price_per_kg, price_per_kg).ok_or(CoffeeError::MathOverflow)?;
+        let return_bps: u64 = return_bps.try_into().map_err(|_| CoffeeError::MathOverflow)?;
+        market.realized_var_bps2 = math::ewma_variance(market.realized_var_bps2, return_bps, VOL_EWMA_SPAN)
+            .ok_or(CoffeeError::MathOverflow)?;
+    }
+
+    market.prev_price_per_kg = market.last_price_per_kg;
+    market.last_price_per_kg = price_per_kg;
+    market.last_oracle_update_ts = now_ts;
+    market.last_price_nonce = nonce;
+    Ok(())
+}
+
+// a pending commit may be revealed from the next slot on, once min_reveal_delay_sec has passed
+fn check_price_reveal(market: &Market, now_ts: i64, slot: u64) -> Result<()> {
+    require!(market.price_commit != [0u8; 32], CoffeeError::NoPriceCommit);
+    require!(slot > market.price_commit_slot, CoffeeError::RevealTooEarly);
+    require!(
+        now_ts >= market.price_commit_ts.saturating_add(market.min_reveal_delay_sec as i64),
+        CoffeeError::RevealTooEarly
+    );
+    Ok(())
+}
+
+// TWAP update: incorporate previous price over elapsed time into twap_acc / twap_time_acc.
+// This is a simple sliding-window approximation.
+fn update_twap(market: &mut Market, now_ts: i64) -> Result<()> {
+    // if no previous price/time, just set last_oracle_update_ts (no accumulation)
+    if market.last_oracle_update_ts == 0 {
+        market.last_oracle_update_ts = now_ts;
+        return Ok(());
+    }
+
+    let dt_i64 = now_ts.checked_sub(market.last_oracle_update_ts).ok_or(CoffeeError::MathOverflow)?;
+    if dt_i64 <= 0 {
+        market.last_oracle_update_ts = now_ts;
+        return Ok(());
+    }
+    let dt_u64 = dt_i64 as u64;
+    let add = dt_u64.min(market.twap_window_sec);
+
+    // add last_price contribution for elapsed seconds
+    let add_val = (market.last_price_per_kg as u128)
+        .checked_mul(add as u128)
+        .ok_or(CoffeeError::MathOverflow)?;
+    market.twap_acc = market.twap_acc.checked_add(add_val).ok_or(CoffeeError::MathOverflow)?;
+    market.twap_time_acc = market.twap_time_acc.checked_add(add).ok_or(CoffeeError::MathOverflow)?;
+
+    // if we've exceeded window, scale-down (approximate sliding window)
+    if market.twap_time_acc > market.twap_window_sec {
+        market.twap_acc = market.twap_acc
+            .checked_mul(market.twap_window_sec as u128).ok_or(CoffeeError::MathOverflow)?
+            .checked_div(market.twap_time_acc as u128).ok_or(CoffeeError::MathOverflow)?;
+        market.twap_time_acc = market.twap_window_sec;
+    }
+
+    market.last_oracle_update_ts = now_ts;
+    Ok(())
+}
+
+// Mark / settlement price by market.price_mode (last price or TWAP); errors if unavailable
+fn mark_price(market: &Market) -> Result<u64> {
+    let price = match market.price_mode {
+        0 => market.last_price_per_kg,
+        1 => math::twap(market.twap_acc, market.twap_time_acc).ok_or(CoffeeError::ZeroPrice)?,
+        _ => market.last_price_per_kg,
+    };
+    require!(price > 0, CoffeeError::ZeroPrice);
+    Ok(price)
+}
+
+// Re-cache a deal's health from its vault balances at the current mark and maintenance schedule (the
+// test mark_to_market applies); the HealthUpdated to emit when it moved. Skipped while the market has
+// no mark yet, so top-ups never fail on it
+fn refresh_health(market: &Market, deal: &mut Account<Deal>, farmer_vault: u64, buyer_vault: u64) -> Result<Option<HealthUpdated>> {
+    let Ok(price) = mark_price(market) else { return Ok(None) };
+    let (farmer_vault, buyer_vault) = deal.margin_balances(farmer_vault, buyer_vault);
+    let now = Clock::get()?.unix_timestamp;
+    let notional = market.notional(price, deal.quantity_kg)?;
+    let maint_bps = market.maintenance_margin_bps_at(now);
+    let due = |side: math::Side| -> Result<u64> {
+        let bps = deal.margin_override.maintenance_bps(side, maint_bps);
+        Ok(bps_mul_u128(notional, bps)?.min(u64::MAX as u128) as u64)
+    };
+    let buyer_collateral = deal.buyer_collateral(buyer_vault);
+    let health_bps = deal.health_bps_for(farmer_vault, due(math::Side::Short)?, buyer_collateral, due(math::Side::Long)?);
+    Ok(deal.set_health(health_bps, now).map(|previous_bps| HealthUpdated {
+        schema_version: EVENT_SCHEMA_VERSION,
+        deal: deal.key(),
+        deal_id: deal.deal_id,
+        health_bps,
+        previous_bps,
+    }))
+}
+
+// Simple price band check helper (returns Err on violation)
+fn is_price_band_ok(prev: u64, next: u64, max_delta_bps: u128) -> Result<()> {
+    if prev == 0 { return Ok(()); }
+    let prev_u = prev as u128;
+    let next_u = next as u128;
+    let delta = if next_u >= prev_u { next_u - prev_u } else { prev_u - next_u };
+    let delta_bps = delta.checked_mul(10_000).ok_or(CoffeeError::MathOverflow)?.checked_div(prev_u).ok_or(CoffeeError::MathOverflow)?;
+    require_gte!(max_delta_bps, delta_bps, CoffeeError::OraclePriceBandExceeded);
+    Ok(())
+}
+
+/// Create a program-owned PDA account of `space` bytes, rent paid by `payer`; it must not exist yet
+fn create_pda_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    space: usize,
+    seeds: &[&[u8]],
+    system_program: &Program<'info, System>,
+    program_id: &Pubkey,
+) -> Result<()> {
+    require!(account.data_is_empty() && account.lamports() == 0, CoffeeError::BatchAccountsInvalid);
+    anchor_lang::system_program::create_account(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            anchor_lang::system_program::CreateAccount { from: payer.clone(), to: account.clone() },
+            &[seeds],
+        ),
+        Rent::get()?.minimum_balance(space),
+        space as u64,
+        program_id,
+    )
+}
+
+// The buyer_vault PDA of a split-vault deal; batch, standing order and auction deals keep both sides
+// in the vault_auth ATA and have none
+fn own_buyer_vault<'info>(deal: &Deal, info: &AccountInfo<'info>) -> Result<Option<Account<'info, TokenAccount>>> {
+    if !deal.split_vaults {
+        return Ok(None);
+    }
+    Ok(Some(Account::try_from(info)?))
+}
+
+/// Serialize an account (discriminator first) into a freshly created PDA
+fn write_account<T: AccountSerialize>(info: &AccountInfo, value: &T) -> Result<()> {
+    let mut data = info.try_borrow_mut_data()?;
+    let mut writer: &mut [u8] = &mut data;
+    value.try_serialize(&mut writer)
+}
+
+// Whether a finished harvest auction can still open its deal; if not, it closes unsold
+fn auction_can_open(market: &Market) -> bool {
+    market.close_after_ts == 0 && market.migrated_to == Pubkey::default()
+}
+
+// Whether a finished auction's best bid can still open at the clearing price: the notional within the
+// deal limits and both escrows covering the open cost, which the admin may have raised since the bids
+fn auction_winner_fits(market: &Market, auction: &HarvestAuction, auction_escrow: u64, bid_escrow: u64) -> Result<bool> {
+    let (price, quantity_kg) = (auction.best_price_per_kg, auction.quantity_kg);
+    let notional = market.notional(price, quantity_kg)?;
+    if notional > market.max_notional_per_deal as u128 || market.requires_risk_cosign(notional) {
+        return Ok(false);
+    }
+    let (margin, premium) = escrowed_open_cost(market, price, quantity_kg, market.initial_margin_bps_at(auction.ends_ts))?;
+    let from_farmer = premium / 2;
+    Ok(auction_escrow >= margin + from_farmer && bid_escrow >= margin + (premium - from_farmer))
+}
+
+// Each side's initial margin, and the whole upfront insurance premium, of a deal paid in from escrow
+fn escrowed_open_cost(market: &Market, price_per_kg: u64, quantity_kg: u64, initial_margin_bps: u16) -> Result<(u64, u64)> {
+    let margin = market.required_margin(price_per_kg, quantity_kg, initial_margin_bps)?;
+    // no waiver: the escrow was sized when the order or bid was placed, and the deal keeps fee_waiver_bps 0
+    let premium = upfront_premium(market, market.notional(price_per_kg, quantity_kg)?, 0)?;
+    Ok((margin, premium))
+}
+
+// A v2 deal whose margins (and premium) were already paid in from escrow, as standing order matches
+// and auctions open them; the caller books it with Market::record_escrowed_open
+#[allow(clippy::too_many_arguments)]
+fn escrowed_deal(
+    market: &Account<Market>,
+    farmer: Pubkey,
+    buyer: Pubkey,
+    deal_id: u64,
+    price_per_kg: u64,
+    quantity_kg: u64,
+    margin_each: u64,
+    premium: u64,
+    deadline_ts: i64,
+) -> Result<Deal> {
+    Ok(Deal {
+        version: PROGRAM_VERSION,
+        market: market.key(),
+        farmer,
+        buyer,
+        agreed_price_per_kg: price_per_kg,
+        quantity_kg,
+        initial_margin_each: margin_each,
+        farmer_deposited: true,
+        buyer_deposited: true,
+        deadline_ts,
+        fee_currency: FeeCurrency::Quote as u8,
+        loss_index_checkpoint: market.loss_socialization_index,
+        margin_locked: margin_each.checked_mul(2).ok_or(CoffeeError::MathOverflow)?,
+        funding_checkpoint: market.cumulative_funding_per_kg,
+        deal_id,
+        merkle_root: EMPTY_MERKLE_ROOT,
+        insurance_prepaid: premium,
+        fees_paid: premium,
+        incoterm: Incoterm::Unspecified as u8,
+        shipment_status: ShipmentStatus::NotShipped as u8,
+        settlement_step: settlement::SettlementStep::NotStarted as u8,
+        seed_version: 2,
+        health_bps: u32::MAX,
+        ..Deal::default()
+    })
+}
+
+// kg a sell and a buy standing order fill against each other at `price_per_kg`: different owners, both
+// live, the same deal deadline, and the price inside both limits
+fn plan_standing_match(sell: &StandingOrder, buy: &StandingOrder, price_per_kg: u64, now_ts: i64) -> Result<u64> {
+    require!(
+        sell.side == OrderSide::Sell as u8 && buy.side == OrderSide::Buy as u8 && sell.owner != buy.owner,
+        CoffeeError::StandingOrderMismatch
+    );
+    require!(sell.deadline_ts == buy.deadline_ts, CoffeeError::StandingOrderMismatch);
+    require!(sell.is_live(now_ts) && buy.is_live(now_ts), CoffeeError::StandingOrderExpired);
+    require!(sell.crosses(price_per_kg) && buy.crosses(price_per_kg), CoffeeError::StandingOrderNotCrossed);
+    Ok(sell.remaining_kg.min(buy.remaining_kg))
+}
+
+/// Transfer out of a standing order's margin escrow using the order PDA as signer
+fn transfer_from_standing_order<'a>(
+    amount: u64,
+    order: &Account<'a, StandingOrder>,
+    escrow: &Account<'a, TokenAccount>,
+    to: &AccountInfo<'a>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[
+        SEED_PREFIX,
+        STANDING_ORDER_SEED,
+        order.market.as_ref(),
+        order.owner.as_ref(),
+        &order.order_id.to_le_bytes(),
+        &[order.bump],
+    ]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: escrow.to_account_info(),
+                to: to.clone(),
+                authority: order.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of a harvest auction's farmer escrow using the auction PDA as signer
+fn transfer_from_auction<'a>(
+    amount: u64,
+    auction: &Account<'a, HarvestAuction>,
+    escrow: &Account<'a, TokenAccount>,
+    to: &AccountInfo<'a>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[
+        SEED_PREFIX,
+        AUCTION_SEED,
+        auction.market.as_ref(),
+        auction.farmer.as_ref(),
+        &auction.auction_id.to_le_bytes(),
+        &[auction.bump],
+    ]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: escrow.to_account_info(),
+                to: to.clone(),
+                authority: auction.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+// Hand the rest of a harvest auction's farmer escrow back to the farmer and close it; returns the refund
+fn close_auction_escrow<'a>(
+    auction: &Account<'a, HarvestAuction>,
+    escrow: &Account<'a, TokenAccount>,
+    farmer_quote_ata: &Account<'a, TokenAccount>,
+    farmer: &AccountInfo<'a>,
+    token_program: &Program<'a, Token>,
+) -> Result<u64> {
+    let refund = escrow.amount;
+    transfer_from_auction(refund, auction, escrow, &farmer_quote_ata.to_account_info(), token_program)?;
+    let seeds: &[&[&[u8]]] = &[&[
+        SEED_PREFIX,
+        AUCTION_SEED,
+        auction.market.as_ref(),
+        auction.farmer.as_ref(),
+        &auction.auction_id.to_le_bytes(),
+        &[auction.bump],
+    ]];
+    token::close_account(CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        CloseAccount {
+            account: escrow.to_account_info(),
+            destination: farmer.clone(),
+            authority: auction.to_account_info(),
+        },
+        seeds,
+    ))?;
+    Ok(refund)
+}
+
+/// Transfer out of an auction bid's escrow using the bid PDA as signer
+fn transfer_from_auction_bid<'a>(
+    amount: u64,
+    bid: &Account<'a, AuctionBid>,
+    escrow: &Account<'a, TokenAccount>,
+    to: &AccountInfo<'a>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, AUCTION_BID_SEED, bid.auction.as_ref(), bid.bidder.as_ref(), &[bid.bump]]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: escrow.to_account_info(),
+                to: to.clone(),
+                authority: bid.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of the market's batch staging vault using the batch_auth PDA as signer
+fn transfer_from_batch_staging<'a>(
+    amount: u64,
+    batch_auth: &Account<'a, VaultAuth>,
+    staging_vault: &Account<'a, TokenAccount>,
+    to: &AccountInfo<'a>,
+    token_program: &Program<'a, Token>,
+    market_key: &Pubkey,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, BATCH_AUTH_SEED, market_key.as_ref(), &[batch_auth.bump]]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: staging_vault.to_account_info(),
+                to: to.clone(),
+                authority: batch_auth.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of a price challenge's bond vault using the challenge PDA as signer
+fn transfer_from_price_challenge<'a>(
+    amount: u64,
+    challenge: &Account<'a, PriceChallenge>,
+    bond_vault: &Account<'a, TokenAccount>,
+    to: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, PRICE_CHALLENGE_SEED, challenge.market.as_ref(), &[challenge.bump]]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: bond_vault.to_account_info(),
+                to: to.to_account_info(),
+                authority: challenge.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of a liquidator's bond vault using the license PDA as signer
+fn transfer_from_liquidator_bond<'a>(
+    amount: u64,
+    license: &Account<'a, LiquidatorLicense>,
+    bond_vault: &Account<'a, TokenAccount>,
+    to: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[
+        SEED_PREFIX,
+        LIQUIDATOR_LICENSE_SEED,
+        license.market.as_ref(),
+        license.liquidator.as_ref(),
+        &[license.bump],
+    ]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: bond_vault.to_account_info(),
+                to: to.to_account_info(),
+                authority: license.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of a stake-pool-owned vault using the pool PDA as signer
+fn transfer_from_pool_vault<'a>(
+    amount: u64,
+    pool: &Account<'a, StakePool>,
+    from_vault: &Account<'a, TokenAccount>,
+    to_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let bump = pool.bump;
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, STAKE_POOL_SEED, pool.market.as_ref(), &[bump]]];
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: from_vault.to_account_info(),
+                to: to_ata.to_account_info(),
+                authority: pool.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Transfer out of the insurance stake vault using the pool PDA as signer
+fn transfer_from_insurance_pool<'a>(
+    amount: u64,
+    pool: &Account<'a, InsuranceStakePool>,
+    from_vault: &Account<'a, TokenAccount>,
+    to_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let bump = pool.bump;
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, INSURANCE_POOL_SEED, pool.market.as_ref(), &[bump]]];
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: from_vault.to_account_info(),
+                to: to_ata.to_account_info(),
+                authority: pool.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+/// Resolve the market's insurance stake pool from optional accounts: None when the market has
+/// no pool, an error when it has one and the caller left it (or its vault) out.
+fn insurance_pool_accounts<'a, 'b>(
+    market: &Market,
+    pool: Option<&'b Account<'a, InsuranceStakePool>>,
+    vault: Option<&'b Account<'a, TokenAccount>>,
+) -> Result<Option<(&'b Account<'a, InsuranceStakePool>, &'b Account<'a, TokenAccount>)>> {
+    if market.insurance_stake_pool == Pubkey::default() {
+        return Ok(None);
+    }
+    let pool = pool.ok_or(CoffeeError::InsurancePoolMissing)?;
+    let vault = vault.ok_or(CoffeeError::InsurancePoolMissing)?;
+    require_keys_eq!(pool.key(), market.insurance_stake_pool, CoffeeError::InsurancePoolMissing);
+    require_keys_eq!(vault.key(), pool.stake_vault, CoffeeError::InsurancePoolMissing);
+    Ok(Some((pool, vault)))
+}
+
+// Return whatever purchase escrow is left to the buyer (prepaid deals closing out)
+fn refund_purchase_escrow<'a>(
+    deal: &mut Deal,
+    escrow: Option<&mut Account<'a, TokenAccount>>,
+    vault_auth: &Account<'a, VaultAuth>,
+    buyer_receive: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+    deal_key: &Pubkey,
+) -> Result<u64> {
+    let escrow = escrow.ok_or(CoffeeError::EscrowAccountMissing)?;
+    escrow.reload()?;
+    let amt = escrow.amount;
+    transfer_from_vault_to(amt, vault_auth, escrow, buyer_receive, token_program, deal_key)?;
+    deal.escrow_balance = 0;
+    Ok(amt)
+}
+
+// Basket must split the deal quantity across distinct deliverable mints: the market's CFT
+// (minted 1 unit per kg) or an ApprovedAssets entry. Returns each asset's units_per_kg.
+fn validate_basket(
+    market: &Market,
+    approved: Option<&Account<ApprovedAssets>>,
+    assets: &[Pubkey],
+    asset_qty: &[u64],
+    quantity_kg: u64,
+) -> Result<[u64; MAX_ASSETS]> {
+    let mut units_per_kg = [0u64; MAX_ASSETS];
+    if assets.is_empty() {
+        return Ok(units_per_kg);
+    }
+    let mut total_kg: u64 = 0;
+    for (i, mint) in assets.iter().enumerate() {
+        require!(asset_qty[i] > 0 && !assets[..i].contains(mint), CoffeeError::InvalidAssetBasket);
+        total_kg = total_kg.checked_add(asset_qty[i]).ok_or(CoffeeError::MathOverflow)?;
+        units_per_kg[i] = if *mint == market.cft_mint {
+            1
+        } else {
+            approved
+                .ok_or(CoffeeError::ApprovedAssetsMissing)?
+                .units_per_kg_for(mint)
+                .ok_or(CoffeeError::AssetNotApproved)?
+        };
+    }
+    require!(total_kg == quantity_kg, CoffeeError::InvalidAssetBasket);
+    Ok(units_per_kg)
+}
+
+// Index of a non-CFT basket asset matching `mint` (CFT is minted at settlement, never escrowed)
+fn basket_asset_index(deal: &Deal, market: &Market, asset_index: u8, mint: &Pubkey) -> Result<usize> {
+    let i = asset_index as usize;
+    require!(i < deal.asset_count as usize, CoffeeError::InvalidAssetBasket);
+    require!(deal.assets[i] == *mint && *mint != market.cft_mint, CoffeeError::InvalidAssetBasket);
+    Ok(i)
+}
+
+// Shared body of verify_and_settle_physical(_batch): lot proofs, CFT mint, farmer payout, residuals
+fn settle_physical_delivery(ctx: Context<VerifyAndSettlePhysical>, delivered_kg: u64, lots: &[MerkleLot]) -> Result<()> {
+    version_guard_market(&ctx.accounts.market)?;
+    let market = &ctx.accounts.market;
+    require!(!market.paused, CoffeeError::MarketPaused);
+
+    // cap proofs: each lot to MAX_PROOF_HASHES, the whole batch to MAX_BATCH_PROOF_HASHES
+    require!(lots.len() <= MAX_BATCH_LOTS, CoffeeError::ProofTooLarge);
+    let mut total_hashes = 0usize;
+    for lot in lots {
+        require!(lot.proof.len() <= MAX_PROOF_HASHES, CoffeeError::ProofTooLarge);
+        total_hashes += lot.proof.len();
+    }
+    require!(total_hashes <= MAX_BATCH_PROOF_HASHES, CoffeeError::ProofTooLarge);
+
+    let deal_key = ctx.accounts.deal.key();
+    let deal = &mut ctx.accounts.deal;
+    require!(!deal.settled, CoffeeError::DealAlreadySettled);
+    check_margin_vaults(market, deal, &ctx.accounts.vault_auth.key(), &ctx.accounts.farmer_margin_vault, &ctx.accounts.buyer_margin_vault)?;
+    require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+    require!(!deal.fx_hedge_pending(), CoffeeError::FxHedgeNotSettled);
+    require!(delivered_kg > 0, CoffeeError::ZeroQty);
+    require!(Clock::get()?.unix_timestamp >= deal.delivery_start_ts, CoffeeError::DeliveryWindowNotOpen);
+
+    // ensure verifier
+    assert_is_verifier(market, ctx.accounts.access_control.as_ref(), &ctx.accounts.verifier, ctx.remaining_accounts)?;
+
+    // verify merkle if used: every lot in the batch, no lot counted twice
+    if deal.merkle_root != EMPTY_MERKLE_ROOT {
+        // a DeliveryRecord from verify_delivery_proof stands in for one lot, once, under the same root
+        let recorded_leaf = match ctx.accounts.delivery_record.as_mut() {
+            Some(record) => {
+                require!(record.merkle_root_version == deal.merkle_root_version, CoffeeError::DeliveryRecordStale);
+                require!(!record.consumed, CoffeeError::DeliveryRecordConsumed);
+                record.consumed = true;
+                Some(record.leaf)
+            }
+            None => None,
+        };
+        require!(!lots.is_empty() || recorded_leaf.is_some(), CoffeeError::MerkleProofMissing);
+        for (i, lot) in lots.iter().enumerate() {
+            require!(
+                lots[..i].iter().all(|l| l.leaf != lot.leaf) && recorded_leaf != Some(lot.leaf),
+                CoffeeError::DuplicateLot
+            );
+            let ok = verify_merkle_proof(lot.leaf, &lot.proof, deal.merkle_root)?;
+            require!(ok, CoffeeError::MerkleProofInvalid);
+        }
+    }
+
+    // partial delivery logic
+    let new_total = deal.delivered_kg_total.checked_add(delivered_kg).ok_or(CoffeeError::MathOverflow)?;
+    require!(new_total <= deal.quantity_kg, CoffeeError::OverDelivery);
+
+    // reentrancy guard
+    deal.start_settling();
+
+    // verified kilograms back the CFT supply across every market on this mint
+    ctx.accounts.cft_mint_auth.record_verified(delivered_kg)?;
+
+    // bind cft key before signer seeds
+    let cft_key = ctx.accounts.cft_mint.key();
+    let cft_bump = ctx.accounts.cft_mint_auth.bump;
+    let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, CFT_AUTH_SEED, cft_key.as_ref(), &[cft_bump]]];
+
+    // mint the CFT share of this delivery if CFT is in the basket; other basket assets are
+    // released from the basket vault by deliver_basket_asset
+    let mut cft_minted = 0;
+    for i in 0..(deal.asset_count as usize) {
+        if deal.assets[i] == market.cft_mint {
+            let mint_amt = deal
+                .basket_units_due(i, new_total)
+                .ok_or(CoffeeError::MathOverflow)?
+                .saturating_sub(deal.asset_units_delivered[i]);
+            require!(
+                !market.cft_allowlist_required || mint_amt == 0 || ctx.accounts.buyer_cft_allowlist.is_some(),
+                CoffeeError::RecipientNotAllowlisted
+            );
+            require!(mint_amt == 0 || !market.cft_expired(Clock::get()?.unix_timestamp), CoffeeError::CftExpired);
+            ctx.accounts.cft_mint_auth.record_mint(mint_amt)?;
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    MintTo {
+                        mint: ctx.accounts.cft_mint.to_account_info(),
+                        to: ctx.accounts.buyer_cft_ata.to_account_info(),
+                        authority: ctx.accounts.cft_mint_auth.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                mint_amt,
+            )?;
+            deal.asset_units_delivered[i] = deal.asset_units_delivered[i]
+                .checked_add(mint_amt)
+                .ok_or(CoffeeError::MathOverflow)?;
+            cft_minted = mint_amt;
+            break;
+        }
+    }
+
+    // payout to farmer at the strategy price (agreed_price_per_kg) * delivered_kg
+    let strategy = SettlementStrategy::physical_for(new_total, deal.quantity_kg);
+    let pay: u64 = market.notional(strategy.price(market, deal)?, delivered_kg)?
+        .try_into()
+        .map_err(|_| CoffeeError::MathOverflow)?;
+    // shipment tranches already advanced out of the escrow count towards this payout
+    let advanced = deal.tranches_advanced.min(pay);
+    deal.tranches_advanced -= advanced;
+    let legs = settlement::VaultLegs::new(
+        &ctx.accounts.vault_auth,
+        &ctx.accounts.farmer_margin_vault,
+        &ctx.accounts.buyer_margin_vault,
+        &ctx.accounts.token_program,
+        deal_key,
+    );
+    // prepaid deals release purchase funds from escrow instead of the buyer's margin
+    let escrow = if deal.prepaid {
+        Some(ctx.accounts.purchase_escrow.as_ref().ok_or(CoffeeError::EscrowAccountMissing)?)
+    } else {
+        None
+    };
+    let pay_amt = (pay - advanced).min(escrow.map_or(legs.available(math::Side::Long), |e| e.amount));
+    let shortfall = pay - advanced - pay_amt;
+    if shortfall > 0 {
+        match market.physical_payment_policy {
+            p if p == PaymentPolicy::RequireFull as u8 => return err!(CoffeeError::PayoutShortfall),
+            p if p == PaymentPolicy::RecordDebt as u8 => {
+                let farmer = deal.farmer;
+                deal.record_shortfall(farmer, shortfall)?;
+            }
+            _ => {}
+        }
+        emit_event!(ctx, DeliveryShortfall { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, owed: pay, paid: pay_amt + advanced, shortfall, policy: market.physical_payment_policy });
+    }
+    match escrow {
+        Some(escrow) => {
+            transfer_from_vault_to(
+                pay_amt,
+                &ctx.accounts.vault_auth,
+                escrow,
+                &ctx.accounts.farmer_receive,
+                &ctx.accounts.token_program,
+                &deal_key,
+            )?;
+            deal.escrow_balance = deal.escrow_balance.saturating_sub(pay_amt);
+        }
+        None => legs.pay(math::Side::Long, pay_amt, &ctx.accounts.farmer_receive)?,
+    }
+
+    // delivered notional counts towards both traders' volume tiers
+    let now = Clock::get()?.unix_timestamp;
+    ctx.accounts.farmer_stats.record_notional(pay, now)?;
+    ctx.accounts.buyer_stats.record_notional(pay, now)?;
+
+    // update delivered total
+    deal.delivered_kg_total = new_total;
+
+    // return residuals on completion; else leave funds until full delivery or deadline
+    let mut residuals = settlement::Residuals::default();
+    if strategy.is_final() {
+        residuals = legs.release_residuals(
+            market.min_transfer_amount,
+            &ctx.accounts.farmer_receive,
+            &ctx.accounts.buyer_receive,
+            &ctx.accounts.insurance_treasury,
+        )?;
+        legs.check_drained()?;
+        for (vault, amount) in residuals.swept {
+            if amount > 0 {
+                emit_event!(ctx, DustSwept { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, vault, amount });
+            }
+        }
+        if deal.prepaid {
+            refund_purchase_escrow(
+                deal,
+                ctx.accounts.purchase_escrow.as_mut(),
+                &ctx.accounts.vault_auth,
+                &ctx.accounts.buyer_receive,
+                &ctx.accounts.token_program,
+                &deal_key,
+            )?;
+        }
+        deal.final_price_per_kg = deal.agreed_price_per_kg;
+        deal.mark_settled();
+    } else {
+        legs.check_solvency()?;
+    }
+
+    emit_event!(ctx, SettledPhysical {
+        schema_version: EVENT_SCHEMA_VERSION,
+        deal: deal.key(),
+        deal_id: deal.deal_id,
+        market: market.key(),
+        delivered_kg,
+        total_delivered: deal.delivered_kg_total,
+        payout_owed: pay,
+        payout_paid: pay_amt + advanced, // includes shipment tranches advanced earlier
+        shortfall,
+        from_escrow: deal.prepaid,
+        farmer_residual: residuals.farmer,
+        buyer_residual: residuals.buyer,
+        dust_swept: residuals.dust,
+    });
+
+    ctx.accounts.market.record_cft_mint(cft_minted)?;
+    if ctx.accounts.deal.settled {
+        ctx.accounts.market.release_open_interest(&ctx.accounts.deal);
+    }
+    Ok(())
+}
+
+/// Anchor's events-CPI encoding: the event is the data of an instruction to this program, signed
+/// by the event authority PDA. Anchor's generated dispatch accepts and ignores it.
+#[cfg(feature = "event-cpi")]
+fn emit_self_cpi<E: anchor_lang::Event>(event: &E, event_authority: &AccountInfo, bump: u8) -> Result<()> {
+    let data: Vec<u8> = anchor_lang::event::EVENT_IX_TAG_LE.iter().copied().chain(event.data()).collect();
+    let ix = solana_program::instruction::Instruction::new_with_bytes(
+        crate::ID,
+        &data,
+        vec![solana_program::instruction::AccountMeta::new_readonly(*event_authority.key, true)],
+    );
+    solana_program::program::invoke_signed(
+        &ix,
+        &[event_authority.clone()],
+        &[&[anchor_lang::event::EVENT_AUTHORITY_SEED, &[bump]]],
+    )?;
+    Ok(())
+}
+
+// Which side of the deal a MarginAccount owner is on
+fn cross_margin_side(deal: &Deal, owner: &Pubkey) -> Result<math::Side> {
+    Ok(deal.side_of(owner).ok_or(CoffeeError::Unauthorized)?)
+}
+
+// Maintenance check for a cross-margined side: the linked account and its pool must be supplied
+fn cross_margin_ok(
+    linked: Pubkey,
+    account: Option<&Account<MarginAccount>>,
+    pool: Option<&Account<TokenAccount>>,
+    mark: u64,
+    price_exponent: i8,
+    maint_bps: u16,
+) -> Result<bool> {
+    let account = account.ok_or(CoffeeError::MarginAccountMissing)?;
+    let pool = pool.ok_or(CoffeeError::MarginAccountMissing)?;
+    require_keys_eq!(account.key(), linked, CoffeeError::MarginAccountMismatch);
+    require_keys_eq!(pool.key(), account.pool, CoffeeError::MarginAccountMismatch);
+    let excess = account.excess(pool.amount, mark, price_exponent, maint_bps).ok_or(CoffeeError::MathOverflow)?;
+    Ok(excess >= 0)
+}
+
+// Pool transfers are signed by the MarginAccount PDA
+fn transfer_from_margin_pool<'a>(
+    amount: u64,
+    margin_account: &Account<'a, MarginAccount>,
+    pool: &Account<'a, TokenAccount>,
+    to: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: &[&[&[u8]]] = &[&[
+        SEED_PREFIX,
+        MARGIN_ACCOUNT_SEED,
+        margin_account.market.as_ref(),
+        margin_account.owner.as_ref(),
+        &[margin_account.bump],
+    ]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: pool.to_account_info(),
+                to: to.to_account_info(),
+                authority: margin_account.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )
+}
+
+/// Weather feed backing a crop insurance config: None when the config has no weather trigger,
+/// an error when it has one and the caller passed the wrong feed or none.
+fn crop_weather_reading<'a, 'b>(
+    config: &CropInsuranceConfig,
+    feed: Option<&'b Account<'a, IndexFeed>>,
+) -> Result<Option<&'b IndexFeed>> {
+    if config.weather_feed == Pubkey::default() {
+        return Ok(None);
+    }
+    let feed = feed.ok_or(CoffeeError::IndexFeedMismatch)?;
+    require_keys_eq!(feed.key(), config.weather_feed, CoffeeError::IndexFeedMismatch);
+    Ok(Some(feed))
+}
+
+/// Signer for a farmer-side action: the farmer, or an agent holding a live AgentGrant from that
+/// farmer. Token transfers use the returned account as authority, so an agent moving the farmer's
+/// funds needs an SPL delegation on the source account.
+fn farmer_or_agent<'info>(
+    farmer: &UncheckedAccount<'info>,
+    agent: Option<&Signer<'info>>,
+    grant: Option<&Account<'info, AgentGrant>>,
+    now: i64,
+) -> Result<AccountInfo<'info>> {
+    if farmer.is_signer {
+        return Ok(farmer.to_account_info());
+    }
+    let agent = agent.ok_or(CoffeeError::FarmerSignatureRequired)?;
+    let grant = grant.ok_or(CoffeeError::FarmerSignatureRequired)?;
+    require_keys_eq!(grant.farmer, farmer.key(), CoffeeError::AgentNotAuthorized);
+    require_keys_eq!(grant.agent, agent.key(), CoffeeError::AgentNotAuthorized);
+    require!(grant.is_active(now), CoffeeError::AgentGrantExpired);
+    Ok(agent.to_account_info())
+}
+
+/// Pay out of a sample's fee escrow, signed by the SampleEscrow PDA
+fn transfer_from_sample<'a>(
+    amount: u64,
+    sample: &Account<'a, SampleEscrow>,
+    from_vault: &Account<'a, TokenAccount>,
+    to_ata: &Account<'a, TokenAccount>,
+    token_program: &Program<'a, Token>,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let bump = sample.bump;
+    let seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, SAMPLE_SEED, sample.market.as_ref(), sample.farmer.as_ref(), sample.buyer.as_ref(), &[bump]]];
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: from_vault.to_account_info(),
+                to: to_ata.to_account_info(),
+                authority: sample.to_account_info(),
+            },
+            seeds,
+        ),
+        amount,
+    )?;
+    Ok(())
+}
+
+// Escrow released when a shipment moves from `prev` to `next`: the tranches of every milestone passed
+fn shipment_tranche(notional: u64, tranche_bps: &[u16; SHIPMENT_MILESTONES], prev: u8, next: u8) -> Result<u64> {
+    let bps: u32 = tranche_bps
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| (*i as u8) + 1 > prev && (*i as u8) < next)
+        .map(|(_, &b)| b as u32)
+        .sum();
+    let amount = (notional as u128).checked_mul(bps as u128).ok_or(CoffeeError::MathOverflow)? / 10_000;
+    amount.try_into().map_err(|_| CoffeeError::MathOverflow.into())
+}
+
+// `require!` as a value, for checks that are collected rather than returned
+fn check_that(condition: bool, error: CoffeeError) -> Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(error.into())
+    }
+}
+
+fn error_code_of(e: &Error) -> u32 {
+    match e {
+        Error::AnchorError(e) => e.error_code_number,
+        Error::ProgramError(e) => u64::from(e.program_error.clone()) as u32,
+    }
+}
+
+// Market.authority follows the discriminator and the version byte
+const MARKET_AUTHORITY_OFFSET: usize = 8 + 1;
+
+// account length resize_market grows a `len`-byte Market to: the current layout, at most one
+// runtime realloc step away
+fn market_resize_target(len: usize) -> usize {
+    let full = 8 + Market::INIT_SPACE;
+    if len >= full {
+        len
+    } else {
+        full.min(len + solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE)
+    }
+}
+
+// ------------------------- Events -------------------------
+#[event]
+pub struct CftMintInitialized {
+    pub schema_version: u8,
+    pub cft_mint: Pubkey,
+    pub authority: Pubkey,
+    pub decimals: u8,
+}
+
+#[event]
+pub struct SandboxMintInitialized {
+    pub schema_version: u8,
+    pub sandbox_mint: Pubkey,
+    pub decimals: u8,
+}
+
+#[event]
+pub struct SandboxFaucetMinted {
+    pub schema_version: u8,
+    pub to: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct MarketCreated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub cft_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub settlement_ts: i64,
+}
+
+#[event]
+pub struct PricePublished {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub price_per_kg: u64,
+    pub publisher: Pubkey,
+    pub ts: i64,
+    pub nonce: u64,
+}
+
+#[event]
+pub struct PriceCommitted {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub publisher: Pubkey,
+    pub commitment: [u8; 32],
+    pub ts: i64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PriceRevealed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub commitment: [u8; 32],
+    pub commit_ts: i64,
+    pub ts: i64,
+}
+
+#[event]
+pub struct CommitRevealUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub required: bool,
+    pub min_delay_sec: u32,
+}
+
+#[event]
+pub struct OpenRateLimitUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub max_opens_per_hour: u16,
+}
+
+#[event]
+pub struct SettlementWindowUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub settlement_window_sec: u64,
+}
+
+#[event]
+pub struct PriceDisputePolicyUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub window_sec: u64,
+    pub challenge_bond: u64,
+}
+
+#[event]
+pub struct SettlementPriceFixed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub price_per_kg: u64,
+    pub dispute_ends_ts: i64,
+}
+
+#[event]
+pub struct SettlementPriceChallenged {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub challenger: Pubkey,
+    pub fixed_price_per_kg: u64,
+    pub proposed_price_per_kg: u64,
+    pub bond: u64,
+}
+
+#[event]
+pub struct SettlementPriceRuled {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub arbiter: Pubkey,
+    pub challenger: Pubkey,
+    pub upheld: bool,
+    pub price_per_kg: u64, // the final price
+    pub bond: u64,         // returned if upheld, else paid to insurance
+}
+
+#[event]
+pub struct ArbiterSettlementStarted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub arbiter: Pubkey,
+    pub window_missed: bool,
+}
+
+#[event]
+pub struct RateLimitExemptionSet {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub exempt: bool,
+}
+
+#[event]
+pub struct ParticipantIndexPageCreated {
+    pub schema_version: u8,
+    pub wallet: Pubkey,
+    pub page: u32,
+}
+
+#[event]
+pub struct StandingOrderPlaced {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub side: u8,
+    pub limit_price_per_kg: u64,
+    pub quantity_kg: u64,
+    pub expiry_ts: i64,
+    pub deadline_ts: i64,
+    pub margin: u64,
+}
+
+#[event]
+pub struct StandingOrderCanceled {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub order: Pubkey,
+    pub owner: Pubkey,
+    pub unfilled_kg: u64,
+    pub refund: u64,
+}
+
+#[event]
+pub struct StandingOrdersMatched {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub farmer_order: Pubkey,
+    pub buyer_order: Pubkey,
+    pub price_per_kg: u64,
+    pub quantity_kg: u64,
+    pub margin_each: u64,
+}
+
+#[event]
+pub struct HarvestAuctionListed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub auction: Pubkey,
+    pub farmer: Pubkey,
+    pub quantity_kg: u64,
+    pub physical_delivery: bool,
+    pub reserve_price_per_kg: u64,
+    pub ends_ts: i64,
+    pub deadline_ts: i64,
+    pub farmer_margin: u64,
+}
+
+#[event]
+pub struct AuctionBidPlaced {
+    pub schema_version: u8,
+    pub auction: Pubkey,
+    pub bidder: Pubkey,
+    pub price_per_kg: u64,
+    pub escrowed: u64,
+}
+
+#[event]
+pub struct AuctionBidRefunded {
+    pub schema_version: u8,
+    pub auction: Pubkey,
+    pub bidder: Pubkey,
+    pub refund: u64,
+}
+
+#[event]
+pub struct HarvestAuctionFinalized {
+    pub schema_version: u8,
+    pub auction: Pubkey,
+    pub deal: Pubkey,  // default when closed unsold
+    pub buyer: Pubkey, // default when closed unsold
+    pub clearing_price_per_kg: u64,
+    pub quantity_kg: u64,
+    pub bids: u32,
+    pub farmer_refund: u64,
+}
+
+#[event]
+pub struct PriceTriggerCreated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub trigger: Pubkey,
+    pub owner: Pubkey,
+    pub direction: u8,
+    pub threshold_price_per_kg: u64,
+    pub payload: Pubkey,
+}
+
+#[event]
+pub struct TriggerFired {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub trigger: Pubkey,
+    pub owner: Pubkey,
+    pub direction: u8,
+    pub threshold_price_per_kg: u64,
+    pub price_per_kg: u64, // oracle price it fired at
+    pub oracle_ts: i64,
+    pub payload: Pubkey,
+}
+
+#[event]
+pub struct PriceExponentSet {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub price_exponent: i8,
+}
+
+#[event]
+pub struct PriceConventionSet {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub convention: u8,
+    pub price_units_per_cent: u64,
+    pub tick_size: u64,
+}
+
+#[event]
+pub struct DealOpened {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub buyer: Pubkey,
+    pub agreed_price_per_kg: u64,
+    pub quantity_kg: u64,
+    pub farmer_side: u8, // OrderSide
+}
+
+#[event]
+pub struct MarginToppedUp {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub who: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct MarginCalled {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub ts: i64,
+    pub grace_sec: u64,
+}
+
+// mark_to_market found the oracle stale and pushed out an open margin call's grace
+#[event]
+pub struct MarginCallGraceExtended {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub grace_sec: u64,
+    pub last_oracle_update_ts: i64,
+}
+
+// both sides are back above the market's cure level; the deal's margin call is cleared
+#[event]
+pub struct MarginCallCured {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub ts: i64,
+}
+
+#[event]
+pub struct LiquidationFlagged {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub ts: i64,
+}
+
+#[event]
+pub struct DealsBatchOpened {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub buyer: Pubkey,
+    pub agent: Pubkey,
+    pub deals: u8,
+    pub agreed_price_per_kg: u64,
+    pub total_quantity_kg: u64,
+    pub farmer_margin: u64, // pulled from the agent, up-front premium included
+    pub buyer_margin: u64,
+}
+
+#[event]
+pub struct SettledCash {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub price: u64,
+    pub pnl_long: i64,
+    pub fee_currency: u8,  // FeeCurrency; CFT fees are reported as quote equivalents
+    pub farmer_fee: u64,   // includes protocol_fee
+    pub buyer_fee: u64,
+    pub protocol_fee: u64,
+    pub insurance_fee: u64,
+    pub pnl_paid: u64,     // to the winner, net of adl_haircut
+    pub adl_haircut: u64,  // to insurance
+    pub pnl_shortfall: u64, // recorded as debt
+    pub farmer_residual: u64,
+    pub buyer_residual: u64,
+    pub dust_swept: u64,
+}
+
+// net_settle_pair outcome across the pair's deals; per-deal haircuts and shortfalls are emitted as
+// AdlHaircutApplied / SettlementShortfall
+#[event]
+pub struct PairNetSettled {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub buyer: Pubkey,
+    pub deals: u8,
+    pub price: u64,
+    pub net_pnl_long: i64,
+    pub gross_pnl: u64,    // sum of the deals' PnL before netting
+    pub farmer_fee: u64,   // includes protocol_fee and operator_fee
+    pub buyer_fee: u64,
+    pub protocol_fee: u64,
+    pub operator_fee: u64,
+    pub insurance_fee: u64,
+    pub pnl_paid: u64,
+    pub adl_haircut: u64,
+    pub pnl_shortfall: u64,
+    pub farmer_residual: u64,
+    pub buyer_residual: u64,
+    pub dust_swept: u64,
+    pub transfers: u16,    // token transfers out of the pooled vaults
+}
+
+#[event]
+pub struct SettledPhysical {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub delivered_kg: u64,
+    pub total_delivered: u64,
+    pub payout_owed: u64, // agreed_price_per_kg * delivered_kg
+    pub payout_paid: u64,
+    pub shortfall: u64,
+    pub from_escrow: bool,
+    // set on the completing delivery
+    pub farmer_residual: u64,
+    pub buyer_residual: u64,
+    pub dust_swept: u64,
+}
+
+#[event]
+pub struct DealCanceled {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+}
+
+#[event]
+pub struct RoleRotationProposed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub role: Vec<u8>,
+    pub pending: Pubkey,
+    pub effective_ts: i64,
+}
+
+#[event]
+pub struct RoleRotationActivated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub role: Vec<u8>,
+    pub activated: Pubkey,
+}
+
+#[event]
+pub struct FeeTiersUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub tier_count: u8,
+}
+
+#[event]
+pub struct CftFeeDiscountUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub discount_bps: u16,
+}
+
+#[event]
+pub struct FeeCurrencySet {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub fee_currency: u8,
+}
+
+#[event]
+pub struct FeeBurnedCft {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub farmer_cft: u64,
+    pub buyer_cft: u64,
+    pub quote_equivalent: u64,
+}
+
+#[event]
+pub struct StakePoolInitialized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub stake_pool: Pubkey,
+    pub revenue_share_bps: u16,
+}
+
+#[event]
+pub struct InsurancePoolInitialized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub insurance_pool: Pubkey,
+    pub premium_share_bps: u16,
+    pub cooldown_secs: i64,
+    pub unstake_window_secs: i64,
+}
+
+#[event]
+pub struct InsuranceStaked {
+    pub schema_version: u8,
+    pub insurance_pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub shares: u64,
+    pub total_shares: u64,
+}
+
+#[event]
+pub struct InsuranceUnstakeRequested {
+    pub schema_version: u8,
+    pub insurance_pool: Pubkey,
+    pub owner: Pubkey,
+    pub shares: u64,
+    pub ready_ts: i64,
+}
+
+#[event]
+pub struct InsuranceUnstaked {
+    pub schema_version: u8,
+    pub insurance_pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub shares: u64,
+    pub total_shares: u64,
+}
+
+#[event]
+pub struct InsurancePremiumsDistributed {
+    pub schema_version: u8,
+    pub insurance_pool: Pubkey,
+    pub amount: u64,
+    pub total_shares: u64,
+}
+
+#[event]
+pub struct InsuranceLossAbsorbed {
+    pub schema_version: u8,
+    pub insurance_pool: Pubkey,
+    pub debt: Pubkey,
+    pub amount: u64,
+    pub total_shares: u64,
+}
+
+#[event]
+pub struct CftStaked {
+    pub schema_version: u8,
+    pub stake_pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct CftUnstaked {
+    pub schema_version: u8,
+    pub stake_pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct RevenueDistributed {
+    pub schema_version: u8,
+    pub stake_pool: Pubkey,
+    pub amount: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct StakingRewardsClaimed {
+    pub schema_version: u8,
+    pub stake_pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ParamsUpdateProposed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub effective_ts: i64,
+}
+
+#[event]
+pub struct ParamsUpdateActivated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub ts: i64,
+}
+
+#[event]
+pub struct GovernanceInitialized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub governance: Pubkey,
+    pub vote_mint: Pubkey,
+    pub quorum_votes: u64,
+}
+
+#[event]
+pub struct ProposalCreated {
+    pub schema_version: u8,
+    pub governance: Pubkey,
+    pub proposal: Pubkey,
+    pub id: u64,
+    pub kind: u8,
+    pub voting_ends_ts: i64,
+}
+
+#[event]
+pub struct VoteCast {
+    pub schema_version: u8,
+    pub proposal: Pubkey,
+    pub voter: Pubkey,
+    pub weight: u64,
+    pub approve: bool,
+}
+
+#[event]
+pub struct ProposalExecuted {
+    pub schema_version: u8,
+    pub proposal: Pubkey,
+    pub id: u64,
+    pub effective_ts: i64,
+}
+
+#[event]
+pub struct AuthorityConfigUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub role: u8,
+    pub multisig: bool,
+}
+
+#[event]
+pub struct RoleGranted {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub member: Pubkey,
+    pub role: u8,
+}
+
+#[event]
+pub struct RoleRevoked {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub member: Pubkey,
+    pub role: u8,
+}
+
+#[event]
+pub struct MarketPauseSet {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub paused: bool,
+    pub by: Pubkey,
+}
+
+#[event]
+pub struct DealExpired {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub farmer_refund: u64,
+    pub buyer_refund: u64,
+    pub ts: i64,
+}
+
+#[event]
+pub struct DefaultPenaltyUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub penalty_bps: u16,
+}
+
+#[event]
+pub struct PhysicalDealDefaulted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub undelivered_kg: u64,
+    pub price: u64,
+    pub pnl_long: i64,
+    pub pnl_paid: u64,
+    pub penalty: u64,
+    pub farmer_defaults: u32,
+}
+
+#[event]
+pub struct SettledForceMajeure {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub arbiter: Pubkey,
+    pub reason: u8,
+    pub farmer_fee: u64,
+    pub buyer_fee: u64,
+    pub farmer_refund: u64,
+    pub buyer_refund: u64,
+    pub evidence_feed: Pubkey, // IndexFeed read at settlement; default = none
+    pub evidence_value: u64,
+}
+
+#[event]
+pub struct PurchaseEscrowFunded {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub buyer: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct PaymentPolicyUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub policy: u8,
+}
+
+#[event]
+pub struct DeliveryShortfall {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub owed: u64,
+    pub paid: u64,
+    pub shortfall: u64,
+    pub policy: u8,
+}
+
+#[event]
+pub struct SettlementShortfall {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub creditor: Pubkey,
+    pub shortfall: u64,
+}
+
+#[event]
+pub struct DebtOpened {
+    pub schema_version: u8,
+    pub debt: Pubkey,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub debtor: Pubkey,
+    pub creditor: Pubkey,
+    pub amount: u64,
+    pub claimable_after_ts: i64,
+}
+
+#[event]
+pub struct DebtRepaid {
+    pub schema_version: u8,
+    pub debt: Pubkey,
+    pub amount: u64,
+    pub outstanding: u64,
+}
+
+#[event]
+pub struct DebtClaimedFromInsurance {
+    pub schema_version: u8,
+    pub debt: Pubkey,
+    pub amount: u64,
+    pub outstanding: u64,
+}
+
+#[event]
+pub struct LossSocialized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub debt: Pubkey,
+    pub amount: u64,
+    pub index_delta: u128,
+    pub loss_socialization_index: u128,
+    pub total_open_notional_kg: u64,
+}
+
+#[event]
+pub struct AdlHaircutApplied {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub winner: Pubkey,
+    pub haircut: u64,
+}
+
+#[event]
+pub struct OpenInterestCapsUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub max_open_deals: u64,
+    pub max_open_notional_kg: u64,
+}
+
+#[event]
+pub struct MarketMadePerpetual {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub funding_cap_bps: u16,
+}
+
+// A deal's cached health factor moved (Deal.health_bps); below 10_000 a side is under maintenance
+#[event]
+pub struct HealthUpdated {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub health_bps: u32,
+    pub previous_bps: u32,
+}
+
+#[event]
+pub struct FundingApplied {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub cumulative_funding_per_kg: i128,
+    pub owed_long: i64,
+    pub paid: i64, // positive: buyer -> farmer
+}
+
+#[event]
+pub struct DustSwept {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub vault: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ContractSizingUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub contract_size_kg: u64,
+    pub whole_contracts_only: bool,
+}
+
+#[event]
+pub struct TickSizeUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub tick_size: u64,
+}
+
+#[event]
+pub struct MarketCloseScheduled {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub close_after_ts: i64,
+}
+
+#[event]
+pub struct MarketClosed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub insurance_swept: u64,
+    pub receiver: Pubkey,
+}
+
+#[event]
+pub struct OracleHeartbeat {
+    pub schema_version: u8,
+    pub market: Pubkey,
     pub price_per_kg: u64,
     pub publisher: Pubkey,
     pub ts: i64,
     pub nonce: u64,
 }
 
-#[event]
-pub struct DealOpened {
-    pub deal: Pubkey,
-    pub market: Pubkey,
-    pub farmer: Pubkey,
-    pub buyer: Pubkey,
-    pub agreed_price_per_kg: u64,
-    pub quantity_kg: u64,
-}
+#[event]
+pub struct FreshPriceRequirementUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub required: bool,
+}
+
+#[event]
+pub struct DealPriceDeviationUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub max_deviation_bps: u16,
+}
+
+#[event]
+pub struct ApprovedAssetUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub units_per_kg: u64, // 0 = removed
+}
+
+#[event]
+pub struct BasketAssetDeposited {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct BasketAssetDelivered {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub total_delivered: u64,
+}
+
+#[event]
+pub struct BasketAssetWithdrawn {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct DealMerkleRootUpdated {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub old_root: [u8; 32],
+    pub new_root: [u8; 32],
+    pub version: u32,
+}
+
+#[event]
+pub struct DeliveryTreeInitialized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub tree: Pubkey,
+    pub max_depth: u32,
+    pub max_buffer_size: u32,
+}
+
+#[event]
+pub struct DeliveriesLogged {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub tree: Pubkey,
+    pub first_index: u64, // per-deal attestation index of the first leaf in this call
+    pub count: u8,
+    pub digest: [u8; 32],
+}
+
+#[event]
+pub struct CftAccountFrozen {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub token_account: Pubkey,
+    pub frozen: bool, // false = thawed
+    pub by: Pubkey,
+}
+
+#[event]
+pub struct CftMintFinalized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub cft_mint: Pubkey,
+    pub mint_authority: Pubkey, // the governance PDA, or default once minting is disabled
+    pub supply: u64,
+    pub cft_minted: u64,
+}
+
+#[event]
+pub struct CftAllowlistRequirementUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub required: bool,
+}
+
+#[event]
+pub struct CftRecipientAllowlisted {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub wallet: Pubkey,
+    pub allowed: bool,
+}
+
+#[event]
+pub struct MarginScheduleUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub step_count: u8,
+}
+
+#[event]
+pub struct VolMarginScalingUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub vol_ref_bps: u16,
+    pub max_maintenance_margin_bps: u16,
+}
+
+#[event]
+pub struct MarginCureBufferUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub buffer_bps: u16,
+}
+
+#[event]
+pub struct MarginAccountOpened {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub margin_account: Pubkey,
+}
+
+#[event]
+pub struct MarginAccountFundsMoved {
+    pub schema_version: u8,
+    pub margin_account: Pubkey,
+    pub deposit: bool, // false = withdrawal
+    pub amount: u64,
+}
+
+#[event]
+pub struct DealMarginLinked {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub margin_account: Pubkey,
+    pub moved: u64, // initial margin moved from the deal vault into the pool
+}
+
+#[event]
+pub struct DealMarginReleased {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub margin_account: Pubkey,
+    pub funded: u64, // moved from the pool back into the deal vault
+}
+
+#[event]
+pub struct PortfolioRiskComputed {
+    pub schema_version: u8,
+    pub margin_account: Pubkey,
+    pub owner: Pubkey,
+    pub mark_price: u64,
+    pub deal_count: u32,
+    pub complete: bool,
+    pub net_delta_kg: i64,
+    pub unrealized_pnl: i64,
+    pub collateral: u64,
+    pub maintenance_required: u64,
+    pub excess: i64,
+}
+
+#[event]
+pub struct InsuranceUpfrontUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub upfront: bool,
+}
+
+#[event]
+pub struct InsurancePremiumCollected {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub premium: u64,
+}
+
+#[event]
+pub struct CropInsuranceConfigured {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub premium_bps: u16,
+    pub payout_bps: u16,
+    pub price_trigger_per_kg: u64,
+    pub weather_feed: Pubkey,
+    pub weather_index_trigger: u64,
+}
+
+#[event]
+pub struct CropInsurancePurchased {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub premium: u64,
+    pub payout: u64,
+}
+
+#[event]
+pub struct CropInsuranceClaimPaid {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub payout: u64,
+    pub price_trigger: bool, // false: the weather trigger fired
+    pub weather_index: u64,
+}
+
+#[event]
+pub struct IndexFeedInitialized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub index_feed: Pubkey,
+    pub feed_id: u8,
+    pub kind: u8,
+    pub publisher: Pubkey,
+}
+
+#[event]
+pub struct IndexValuePublished {
+    pub schema_version: u8,
+    pub index_feed: Pubkey,
+    pub kind: u8,
+    pub value: u64,
+    pub ts: i64,
+    pub nonce: u64,
+}
+
+#[event]
+pub struct AgentGranted {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub agent: Pubkey,
+    pub max_notional: u64,
+    pub expiry_ts: i64,
+}
+
+#[event]
+pub struct AgentRevoked {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub agent: Pubkey,
+}
+
+#[event]
+pub struct SessionCreated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub session_key: Pubkey,
+    pub scopes: u8,
+    pub expiry_ts: i64,
+}
+
+#[event]
+pub struct SessionRevoked {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub session_key: Pubkey,
+}
+
+#[event]
+pub struct PermitUsed {
+    pub schema_version: u8,
+    pub owner: Pubkey,
+    pub relayer: Pubkey,
+    pub nonce: u64,
+}
+
+#[event]
+pub struct LargeDealCosignUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub risk_officer: Pubkey,
+    pub large_deal_notional: u64,
+}
+
+#[event]
+pub struct DealTemplateCreated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub template: Pubkey,
+    pub creator: Pubkey,
+    pub template_id: u32,
+    pub quantity_kg: u64,
+    pub physical_delivery: bool,
+    pub delivery_start_ts: i64,
+    pub delivery_end_ts: i64,
+    pub quality_grade: u8,
+    pub incoterm: u8,
+}
+
+#[event]
+pub struct DealTemplateRetired {
+    pub schema_version: u8,
+    pub template: Pubkey,
+}
+
+#[event]
+pub struct ReduceBreakFeeUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub break_fee_bps: u16,
+}
+
+#[event]
+pub struct StopBountyUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub bounty_bps: u16,
+}
+
+#[event]
+pub struct StopSet {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub party: Pubkey,
+    pub stop_price_per_kg: u64, // 0 = cleared
+}
+
+#[event]
+pub struct StopExecuted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub party: Pubkey, // side stopped out
+    pub stop_price_per_kg: u64,
+    pub mark_price_per_kg: u64,
+    pub executor: Pubkey,
+    pub bounty: u64,
+}
+
+#[event]
+pub struct DealReduced {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub initiator: Pubkey,
+    pub price: u64,
+    pub reduced_kg: u64,
+    pub remaining_kg: u64,
+    pub pnl_long: i64,
+    pub break_fee: u64, // 0 when both parties signed
+    pub farmer_released: u64,
+    pub buyer_released: u64,
+}
+
+#[event]
+pub struct CftSaleTreasuryUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub treasury: Pubkey,
+}
+
+#[event]
+pub struct CftPayoutElected {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub enabled: bool,
+}
+
+#[event]
+pub struct CftPayoutMinted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub buyer: Pubkey,
+    pub price: u64,
+    pub quote_converted: u64, // moved to market.cft_sale_treasury
+    pub cft_minted: u64,
+}
+
+#[event]
+pub struct CftExpiryUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub expiry_ts: i64,
+    pub floor_price_per_kg: u64,
+}
+
+#[event]
+pub struct CftRedeemed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub holder: Pubkey,
+    pub amount: u64,
+    pub payout: u64,
+}
+
+#[event]
+pub struct SampleRequirementUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub required: bool,
+}
+
+#[event]
+pub struct SampleRequested {
+    pub schema_version: u8,
+    pub sample: Pubkey,
+    pub farmer: Pubkey,
+    pub buyer: Pubkey,
+    pub fee: u64,
+    pub deadline_ts: i64,
+}
+
+#[event]
+pub struct SampleShipped {
+    pub schema_version: u8,
+    pub sample: Pubkey,
+    pub ts: i64,
+}
+
+#[event]
+pub struct SampleApproved {
+    pub schema_version: u8,
+    pub sample: Pubkey,
+    pub fee: u64,
+}
+
+#[event]
+pub struct SampleVoided {
+    pub schema_version: u8,
+    pub sample: Pubkey,
+    pub amount: u64,
+    pub to_farmer: bool,
+}
+
+#[event]
+pub struct ShipmentTranchesUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub tranche_bps: [u16; SHIPMENT_MILESTONES],
+}
+
+#[event]
+pub struct ShipmentMilestone {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub status: u8, // ShipmentStatus
+    pub ts: i64,
+    pub tranche_paid: u64,
+    pub risk_transferred: bool, // this update passed the deal's Incoterm risk-transfer point
+}
+
+#[event]
+pub struct WormholeProgramUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub wormhole_program: Pubkey,
+}
+
+#[event]
+pub struct CrosschainRecipientSet {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub chain: u16,
+    pub recipient: [u8; 32],
+}
+
+#[event]
+pub struct CrosschainMessagePosted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub chain: u16,
+    pub recipient: [u8; 32],
+    pub outcome: u8, // wormhole::SettlementOutcome
+    pub message: Pubkey,
+}
+
+#[event]
+pub struct CctpProgramUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub cctp_program: Pubkey,
+}
+
+#[event]
+pub struct PayoutBridgeSet {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub domain: u32,
+    pub recipient: [u8; 32],
+}
+
+#[event]
+pub struct PayoutBridged {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub farmer: Pubkey,
+    pub amount: u64,
+    pub domain: u32,
+    pub recipient: [u8; 32],
+}
+
+#[event]
+pub struct FxOracleRegistered {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub fx_oracle: Pubkey,
+    pub currency: [u8; 3],
+    pub publisher: Pubkey,
+}
+
+#[event]
+pub struct FxRatePublished {
+    pub schema_version: u8,
+    pub fx_oracle: Pubkey,
+    pub currency: [u8; 3],
+    pub rate: u64,
+    pub ts: i64,
+}
+
+#[event]
+pub struct FxHedgeAttached {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub fx_oracle: Pubkey,
+    pub currency: [u8; 3],
+    pub notional: u64,
+    pub locked_rate: u64,
+}
+
+#[event]
+pub struct FxHedgeSettled {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub spot_rate: u64,
+    pub pnl_farmer: i64, // quote units, + = paid to the farmer
+    pub paid: u64,
+    pub shortfall: u64, // loser's vault ran dry
+}
+
+#[event]
+pub struct DealClosed {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub farmer: Pubkey,
+    pub buyer: Pubkey,
+    pub quantity_kg: u64,
+    pub delivered_kg: u64,
+    pub agreed_price_per_kg: u64,
+    pub final_price_per_kg: u64,
+    pub realized_pnl_farmer: i64,
+    pub realized_pnl_buyer: i64,
+    pub fees_paid: u64,
+    pub insurance_drawn: u64,
+    pub unpaid_owed: u64, // carried into a Debt account
+}
+
+#[event]
+pub struct SettlementCheckpointed {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub step: u8, // settlement::SettlementStep completed so far
+    pub price: u64,
+}
+
+#[event]
+pub struct DeliveryProofVerified {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub leaf: [u8; 32],
+    pub delivery_record: Pubkey,
+    pub merkle_root_version: u32,
+}
+
+#[event]
+pub struct OperatorUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub operator: Pubkey,
+    pub operator_fee_bps: u16,
+    pub operator_treasury: Pubkey,
+}
+
+#[event]
+pub struct OperatorFeePaid {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub market: Pubkey,
+    pub operator: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct DealMigrated {
+    pub schema_version: u8,
+    pub deal: Pubkey, // new address
+    pub deal_id: u64,
+    pub old_deal: Pubkey,
+    pub seed_version: u8,
+}
+
+#[event]
+pub struct MarketMigrated {
+    pub schema_version: u8,
+    pub market: Pubkey, // v1 address
+    pub new_market: Pubkey,
+    pub seed_version: u8,
+}
+
+#[event]
+pub struct MarketResized {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub old_len: u32,
+    pub new_len: u32,
+}
+
+#[event]
+pub struct LiquidationQueued {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub shortfall: u64,
+    pub queue_len: u8,
+}
+
+#[event]
+pub struct LiquidationExecuted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub shortfall: u64,
+    pub queue_len: u8,
+}
+
+#[event]
+pub struct LiquidationQueuePruned {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub dropped: u8,
+    pub queue_len: u8,
+}
+
+#[event]
+pub struct LiquidatorPolicyUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub licensed: bool,
+    pub min_bond: u64,
+    pub slash_bps: u16,
+}
+
+#[event]
+pub struct LiquidatorLicenseUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub liquidator: Pubkey,
+    pub active: bool,
+}
+
+#[event]
+pub struct LiquidatorBondPosted {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub liquidator: Pubkey,
+    pub amount: u64,
+    pub bond: u64,
+}
+
+#[event]
+pub struct LiquidatorBondWithdrawn {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub liquidator: Pubkey,
+    pub amount: u64,
+    pub bond: u64,
+}
+
+// A licensee liquidated a deal whose cached health (`health_bps`) had recovered since the flag
+#[event]
+pub struct LiquidatorSlashed {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub liquidator: Pubkey,
+    pub deal: Pubkey,
+    pub amount: u64,
+    pub health_bps: u32,
+}
+
+// Counterparties opened a deal with margin floors above the market's
+#[event]
+pub struct MarginOverrideAgreed {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub margin_override: MarginOverride,
+}
+
+#[event]
+pub struct CreditLineUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub buyer: Pubkey,
+    pub limit: u64,
+    pub margin_discount_bps: u16,
+    pub max_defaults: u32,
+    pub active: bool,
+}
+
+#[event]
+pub struct CreditLineDrawn {
+    pub schema_version: u8,
+    pub credit_line: Pubkey,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub amount: u64,
+    pub used: u64,
+}
+
+#[event]
+pub struct CreditLineReleased {
+    pub schema_version: u8,
+    pub credit_line: Pubkey,
+    pub deal: Pubkey,
+    pub amount: u64,
+    pub used: u64,
+    pub defaulted: bool,
+}
+
+#[event]
+pub struct OmnibusVaultsSet {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub enabled: bool,
+    pub farmer_vault: Pubkey,
+    pub buyer_vault: Pubkey,
+}
+
+#[event]
+pub struct OmnibusAudited {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub deals: u16,
+    pub complete: bool,           // covered every unsettled omnibus deal of the market
+    pub ledger: [u64; 2],         // [farmer, buyer] summed deal balances
+    pub vault: [u64; 2],          // [farmer, buyer] omnibus vault balances
+    pub discrepancy: [i128; 2],   // vault - ledger
+    pub balanced: bool,
+}
+
+#[event]
+pub struct DealMovedToOmnibus {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub farmer_balance: u64, // booked to omnibus_balance
+    pub buyer_balance: u64,
+}
+
+#[event]
+pub struct SettlementQueueUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub enabled: bool,
+    pub epoch_sec: i64,
+    pub rebate_bps: u16,
+    pub fee_pot: Pubkey,
+    pub dropped: u8, // entries emptied by turning the queue off
+}
+
+#[event]
+pub struct SettlementQueueHeadDropped {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub deal: Pubkey,
+    pub epoch: u64,
+    pub queue_len: u8,
+}
+
+#[event]
+pub struct SettlementEnqueued {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub epoch: u64,
+    pub closes_ts: i64,
+    pub position: u8,
+}
+
+#[event]
+pub struct SettlementProcessed {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub epoch: u64,
+    pub crank: Pubkey,
+    pub settled: bool, // false when the head was only dequeued
+    pub rebate: u64,
+    pub queue_len: u8,
+}
+
+#[event]
+pub struct ActionLogInitialized {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub max_entries: u8,
+}
+
+#[event]
+pub struct ActionLogArchived {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub tree: Pubkey,
+    pub first_seq: u64,
+    pub count: u8,
+    pub digest: [u8; 32],
+}
+
+#[event]
+pub struct FeeWaiverIssued {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub waiver: Pubkey,
+    pub issuer: Pubkey,
+    pub beneficiary: Pubkey,
+    pub discount_bps: u16,
+    pub expires_ts: i64,
+    pub max_uses: u32,
+}
+
+#[event]
+pub struct FeeWaiverRedeemed {
+    pub schema_version: u8,
+    pub waiver: Pubkey,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub discount_bps: u16,
+    pub uses_left: u32,
+}
+
+#[event]
+pub struct FeeWaiverRevoked {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub waiver: Pubkey,
+    pub uses: u32,
+}
+
+#[event]
+pub struct MarginSponsored {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub sponsor: Pubkey,
+    pub amount: u64,
+    pub sponsored_margin: u64, // the sponsor's stake after this deposit
+}
+
+#[event]
+pub struct SponsorMarginReturned {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub deal_id: u64,
+    pub sponsor: Pubkey,
+    pub returned: u64,
+    pub loss: u64, // stake absorbed by the farmer side's losses and fees
+}
+
+#[event]
+pub struct SeizedVestingUpdated {
+    pub schema_version: u8,
+    pub market: Pubkey,
+    pub vesting_sec: i64,
+}
+
+#[event]
+pub struct MarginVestingStarted {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u64,
+    pub end_ts: i64,
+}
+
+#[event]
+pub struct VestedMarginClaimed {
+    pub schema_version: u8,
+    pub deal: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u64,
+    pub remaining: u64,
+}
+
+#[event]
+pub struct InsurancePoolSwept {
+    pub schema_version: u8,
+    pub insurance_pool: Pubkey,
+    pub amount: u64, // to the insurance treasury
+}
+
+// ------------------------- Errors -------------------------
+#[error_code]
+pub enum CoffeeError {
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Zero price")]
+    ZeroPrice,
+    #[msg("Zero quantity")]
+    ZeroQty,
+    #[msg("Zero amount")]
+    ZeroAmount,
+    #[msg("Initial margin must be >= maintenance margin")]
+    BadMarginParams,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Market paused")]
+    MarketPaused,
+    #[msg("Not yet settlement time")]
+    NotYetSettleTime,
+    #[msg("Wrong settlement type")]
+    WrongSettlementType,
+    #[msg("Deal already settled")]
+    DealAlreadySettled,
+    #[msg("Deal not settled")]
+    DealNotSettled,
+    #[msg("Delivered kg exceeds deal quantity")]
+    OverDelivery,
+    #[msg("Mint decimals mismatch")]
+    MintDecimalsMismatch,
+    #[msg("Invalid counterparty")]
+    InvalidCounterparty,
+    #[msg("Invalid asset basket")]
+    InvalidAssetBasket,
+    #[msg("Too many assets in basket")]
+    TooManyAssets,
+    #[msg("Merkle proof missing")]
+    MerkleProofMissing,
+    #[msg("Merkle proof invalid")]
+    MerkleProofInvalid,
+    #[msg("Cannot cancel after both deposited")]
+    CannotCancelAfterBothDeposited,
+    #[msg("Deadline passed")]
+    DeadlinePassed,
+    #[msg("Oracle stale")]
+    OracleStale,
+    #[msg("Oracle price band exceeded")]
+    OraclePriceBandExceeded,
+    #[msg("Replay or stale nonce")]
+    ReplayOrStaleNonce,
+    #[msg("Proof too large")]
+    ProofTooLarge,
+    #[msg("Deal qty exceeds limit")]
+    DealQtyExceedsLimit,
+    #[msg("Deal notional exceeds limit")]
+    DealNotionalExceedsLimit,
+    #[msg("Version mismatch")]
+    VersionMismatch,
+    #[msg("Account not rent exempt")]
+    AccountNotRentExempt,
+    #[msg("Invalid TWAP window")]
+    InvalidTwapWindow,
+    #[msg("Rotation not yet effective")]
+    RotationNotEffectiveYet,
+    #[msg("No pending rotation")]
+    NoPendingRotation,
+    #[msg("Invalid fee tiers")]
+    InvalidFeeTiers,
+    #[msg("Too many fee tiers")]
+    TooManyFeeTiers,
+    #[msg("Invalid bps value")]
+    InvalidBps,
+    #[msg("Invalid fee currency")]
+    InvalidFeeCurrency,
+    #[msg("CFT fee accounts missing")]
+    CftFeeAccountsMissing,
+    #[msg("CFT fee burn not approved to vault authority")]
+    CftFeeNotApproved,
+    #[msg("Insufficient stake")]
+    InsufficientStake,
+    #[msg("No stakers in pool")]
+    NoStakers,
+    #[msg("Market is governance controlled")]
+    GovernanceControlled,
+    #[msg("No pending parameter update")]
+    NoPendingParams,
+    #[msg("Invalid governance config")]
+    InvalidGovernanceConfig,
+    #[msg("Invalid proposal")]
+    InvalidProposal,
+    #[msg("No voting power")]
+    NoVotingPower,
+    #[msg("Voting closed")]
+    VotingClosed,
+    #[msg("Voting still open")]
+    VotingStillOpen,
+    #[msg("Proposal not passed")]
+    ProposalNotPassed,
+    #[msg("Proposal already executed")]
+    ProposalAlreadyExecuted,
+    #[msg("Invalid authority config")]
+    InvalidAuthorityConfig,
+    #[msg("Multisig accounts missing or mismatched")]
+    MultisigAccountsMissing,
+    #[msg("Instruction not invoked by the configured multisig program")]
+    MultisigNotInvoker,
+    #[msg("Multisig threshold below configured minimum")]
+    MultisigThresholdTooLow,
+    #[msg("Invalid role")]
+    InvalidRole,
+    #[msg("Access control table full")]
+    AccessControlFull,
+    #[msg("Deal is live (both margins deposited)")]
+    DealIsLive,
+    #[msg("Deadline not reached")]
+    DeadlineNotReached,
+    #[msg("Invalid reason code")]
+    InvalidReasonCode,
+    #[msg("Purchase escrow account missing")]
+    EscrowAccountMissing,
+    #[msg("Purchase escrow already funded or delivery started")]
+    EscrowAlreadyFunded,
+    #[msg("Invalid payment policy")]
+    InvalidPaymentPolicy,
+    #[msg("Payout vault cannot cover the delivery")]
+    PayoutShortfall,
+    #[msg("Conflicting debt creditor on deal")]
+    ConflictingDebt,
+    #[msg("Shortfall must be opened as a Debt before closing")]
+    DebtNotRecorded,
+    #[msg("No outstanding debt")]
+    NoDebt,
+    #[msg("Debt already opened")]
+    DebtAlreadyOpened,
+    #[msg("Debt claim waiting period not elapsed")]
+    DebtClaimNotYetAllowed,
+    #[msg("Invalid debt config")]
+    InvalidDebtConfig,
+    #[msg("No open interest to socialize loss over")]
+    NoOpenInterest,
+    #[msg("Debt already socialized")]
+    DebtAlreadySocialized,
+    #[msg("Insurance treasury can cover the debt")]
+    InsuranceCanCover,
+    #[msg("Market open interest cap exceeded")]
+    OpenInterestCapExceeded,
+    #[msg("Market is not perpetual")]
+    NotPerpetual,
+    #[msg("Market has open deals")]
+    MarketHasOpenDeals,
+    #[msg("Apply pending funding before settling")]
+    FundingNotApplied,
+    #[msg("Quantity is not a whole number of contracts")]
+    NotWholeContracts,
+    #[msg("Price is not a multiple of the market tick size")]
+    PriceNotOnTick,
+    #[msg("Market is being decommissioned")]
+    MarketClosing,
+    #[msg("Market close not scheduled")]
+    MarketCloseNotScheduled,
+    #[msg("Timelock not elapsed")]
+    TimelockNotElapsed,
+    #[msg("Insurance sweep accounts missing")]
+    SweepAccountsMissing,
+    #[msg("Agreed price deviates too far from the oracle price")]
+    DealPriceDeviationExceeded,
+    #[msg("ApprovedAssets account required for non-CFT basket assets")]
+    ApprovedAssetsMissing,
+    #[msg("Basket asset mint is not approved for this market")]
+    AssetNotApproved,
+    #[msg("Approved asset registry is full")]
+    ApprovedAssetsFull,
+    #[msg("Basket vault holds less than the units owed")]
+    BasketUnderfunded,
+    #[msg("Basket assets owed to the buyer have not been delivered")]
+    BasketNotDelivered,
+    #[msg("New merkle root equals the current one")]
+    MerkleRootUnchanged,
+    #[msg("Lot appears twice in the batch")]
+    DuplicateLot,
+    #[msg("Market already has a delivery tree")]
+    DeliveryTreeAlreadySet,
+    #[msg("Delivery tree does not match the market")]
+    DeliveryTreeMismatch,
+    #[msg("Attestation batch is empty or too large")]
+    AttestationBatchSize,
+    #[msg("CFT recipient is not allowlisted")]
+    RecipientNotAllowlisted,
+    #[msg("CFT supply would exceed verified delivered kilograms")]
+    CftSupplyExceedsVerified,
+    #[msg("Settlement accounting invariant violated")]
+    InvariantViolation,
+    #[msg("Margin schedule must have matching lengths, descending times and non-decreasing margins")]
+    InvalidMarginSchedule,
+    #[msg("Too many margin schedule steps")]
+    TooManyMarginSteps,
+    #[msg("Volatility scaling needs a reference vol and a cap between maintenance and initial margin")]
+    InvalidVolScaling,
+    #[msg("Deal side is backed by a MarginAccount; release it first")]
+    DealCrossMargined,
+    #[msg("Linked MarginAccount or its pool not supplied")]
+    MarginAccountMissing,
+    #[msg("MarginAccount or pool does not match the deal link")]
+    MarginAccountMismatch,
+    #[msg("Withdrawal would leave the MarginAccount under-margined")]
+    InsufficientFreeMargin,
+    #[msg("Too many deals for one risk computation")]
+    TooManyRiskDeals,
+    #[msg("Deal passed twice to the risk computation")]
+    DuplicateRiskDeal,
+    #[msg("Insurance treasury account required for an up-front premium")]
+    InsuranceTreasuryMissing,
+    #[msg("Insurance stake pool accounts missing or mismatched")]
+    InsurancePoolMissing,
+    #[msg("Insurance stake pool is depleted")]
+    InsurancePoolDepleted,
+    #[msg("No unstake requested")]
+    NoPendingUnstake,
+    #[msg("Unstake cooldown has not elapsed")]
+    UnstakeCooldownActive,
+    #[msg("Invalid unstake cooldown")]
+    InvalidUnstakeCooldown,
+    #[msg("Invalid crop insurance config")]
+    InvalidCropInsuranceConfig,
+    #[msg("Crop insurance is not offered on this market")]
+    CropInsuranceNotOffered,
+    #[msg("Deal is not live")]
+    DealNotLive,
+    #[msg("Crop insurance trigger has already fired")]
+    CropTriggerAlreadyHit,
+    #[msg("Crop insurance trigger has not fired")]
+    CropTriggerNotHit,
+    #[msg("Crop insurance claim already paid")]
+    CropClaimAlreadyPaid,
+    #[msg("Insurance fund cannot cover the payout")]
+    InsuranceFundInsufficient,
+    #[msg("Invalid index kind")]
+    InvalidIndexKind,
+    #[msg("Index feed missing or not the configured feed")]
+    IndexFeedMismatch,
+    #[msg("Index feed has no fresh reading")]
+    IndexFeedStale,
+    #[msg("Farmer signature or agent grant required")]
+    FarmerSignatureRequired,
+    #[msg("Signer is not the farmer's authorized agent")]
+    AgentNotAuthorized,
+    #[msg("Agent grant has expired")]
+    AgentGrantExpired,
+    #[msg("Agent notional cap exceeded")]
+    AgentNotionalCapExceeded,
+    #[msg("Invalid agent grant")]
+    InvalidAgentGrant,
+    #[msg("Invalid session key parameters")]
+    InvalidSession,
+    #[msg("Session key expired or not scoped to this instruction")]
+    SessionNotAllowed,
+    #[msg("Permit has expired")]
+    PermitExpired,
+    #[msg("Ed25519 permit verification instruction missing")]
+    PermitSignatureMissing,
+    #[msg("Permit signature does not match the signer or message")]
+    PermitSignatureInvalid,
+    #[msg("Deal above the large-deal threshold needs the risk officer's signature")]
+    RiskOfficerSignatureRequired,
+    #[msg("Large-deal threshold needs a risk officer")]
+    InvalidRiskCosignConfig,
+    #[msg("Invalid deal template")]
+    InvalidDealTemplate,
+    #[msg("Deal template account missing")]
+    TemplateMissing,
+    #[msg("Deal template is retired")]
+    TemplateInactive,
+    #[msg("Deal terms do not match the template")]
+    TemplateTermsMismatch,
+    #[msg("Delivery window has not opened")]
+    DeliveryWindowNotOpen,
+    #[msg("Reduction must leave a positive, whole-contract quantity open")]
+    InvalidReduceQuantity,
+    #[msg("Reducing without the counterparty's signature is disabled on this market")]
+    ReduceNeedsCounterparty,
+    #[msg("Losing side's margin cannot cover the PnL on the reduced quantity")]
+    ReduceUndercollateralized,
+    #[msg("CFT payouts are not offered on this market")]
+    CftPayoutNotOffered,
+    #[msg("Missing CFT mint authority, sale treasury or buyer CFT account for a CFT payout")]
+    CftPayoutAccountsMissing,
+    #[msg("CFT expiry must be in the future with a non-zero floor price")]
+    InvalidCftExpiry,
+    #[msg("This market's CFT has expired")]
+    CftExpired,
+    #[msg("This market's CFT has not expired yet")]
+    CftNotExpired,
+    #[msg("Redemption exceeds the CFT this market minted")]
+    CftRedemptionCapExceeded,
+    #[msg("Per-market CFT mode needs both the market CFT mint and its authority")]
+    MarketCftAccountsMissing,
+    #[msg("Sample approval window must be positive")]
+    InvalidSampleWindow,
+    #[msg("Sample already marked shipped")]
+    SampleAlreadyShipped,
+    #[msg("Sample already approved")]
+    SampleAlreadyApproved,
+    #[msg("Sample window has lapsed")]
+    SampleWindowLapsed,
+    #[msg("Sample window is still open")]
+    SampleWindowOpen,
+    #[msg("Deal needs an approved sample for this farmer and buyer")]
+    SampleApprovalRequired,
+    #[msg("Shipment status unknown or not ahead of the current one")]
+    InvalidShipmentStatus,
+    #[msg("Shipment tranches exceed 100% of the escrow")]
+    InvalidShipmentTranches,
+    #[msg("No Wormhole bridge is configured for this market")]
+    WormholeNotConfigured,
+    #[msg("Wormhole bridge account is malformed")]
+    WormholeAccountInvalid,
+    #[msg("Deal has no cross-chain recipient")]
+    NoCrosschainRecipient,
+    #[msg("Settlement message already posted for this deal")]
+    CrosschainAlreadyPosted,
+    #[msg("CCTP bridging is not configured for this market")]
+    CctpNotConfigured,
+    #[msg("Deal has no payout bridge destination")]
+    NoPayoutBridge,
+    #[msg("FX oracle needs a positive max age")]
+    InvalidFxOracle,
+    #[msg("FX rate is stale or unpublished")]
+    FxRateStale,
+    #[msg("Deal already has an FX hedge")]
+    FxHedgeAlreadyAttached,
+    #[msg("Deal has no FX hedge")]
+    NoFxHedge,
+    #[msg("Settle the deal's FX hedge first")]
+    FxHedgeNotSettled,
+    #[msg("max_steps must be at least 1")]
+    InvalidSettlementSteps,
+    #[msg("Deal is part-way through a checkpointed settlement")]
+    SettlementInProgress,
+    #[msg("Deal has no delivery manifest root")]
+    NoDeliveryManifest,
+    #[msg("Delivery record was verified against an older manifest root")]
+    DeliveryRecordStale,
+    #[msg("Delivery record already counted by a payout")]
+    DeliveryRecordConsumed,
+    #[msg("A deal between these parties already exists on this market")]
+    DealAlreadyExists,
+    #[msg("Funding account holds less than the margin and premium due")]
+    InsufficientMarginBalance,
+    #[msg("Operator fee needs an operator and an operator treasury")]
+    OperatorNotConfigured,
+    #[msg("Operator treasury account missing")]
+    OperatorTreasuryMissing,
+    #[msg("Deal has a settlement, escrow, debt or cross-margin link in progress and cannot migrate")]
+    DealNotMigratable,
+    #[msg("Market has migrated to a new address")]
+    MarketMigrated,
+    #[msg("Not a Market account")]
+    InvalidMarketAccount,
+    #[msg("Liquidation queue is empty")]
+    LiquidationQueueEmpty,
+    #[msg("Deal is not at the head of the liquidation queue")]
+    NotLiquidationQueueHead,
+    #[msg("Liquidation needs an active liquidator license on this market")]
+    LiquidatorNotLicensed,
+    #[msg("Liquidator bond is below the market minimum")]
+    LiquidatorBondTooLow,
+    #[msg("Margin override above 100% or with maintenance over initial margin")]
+    InvalidMarginOverride,
+    #[msg("This side of the deal has agreed margin floors and cannot be cross-margined")]
+    DealMarginOverridden,
+    #[msg("Deal drew on a credit line; pass the buyer's CreditLine")]
+    CreditLineMissing,
+    #[msg("The buyer's margin is partly credit-backed and cannot be cross-margined")]
+    CreditBackedSide,
+    #[msg("net_settle_pair takes [deal, vault_auth, farmer vault, buyer vault] per deal")]
+    NetSettleAccountsInvalid,
+    #[msg("Too many deals for one net settlement")]
+    TooManyNetSettleDeals,
+    #[msg("Deal passed twice to the net settlement")]
+    DuplicateNetSettleDeal,
+    #[msg("Deal is not between this pair or cannot be net-settled")]
+    NetSettleDealIneligible,
+    #[msg("open_deals_batch takes [agent_grant, deal, vault_auth, margin vault] per quantity, each member once")]
+    BatchAccountsInvalid,
+    #[msg("Too many deals for one batch open")]
+    TooManyBatchDeals,
+    #[msg("Not supported for deals held in omnibus vaults")]
+    OmnibusUnsupported,
+    #[msg("Margin vaults or omnibus accounts do not match the deal's vault mode")]
+    OmnibusVaultMismatch,
+    #[msg("Market has no omnibus vaults")]
+    OmnibusDisabled,
+    #[msg("Farmer balance exceeds the deal vault")]
+    OmnibusSplitExceedsVault,
+    #[msg("Audit deals must be distinct omnibus deals of this market")]
+    OmnibusAuditDealInvalid,
+    #[msg("Cash settlement on this market goes through the settlement queue")]
+    SettlementQueueRequired,
+    #[msg("Ordered settlement is not enabled on this market")]
+    SettlementQueueDisabled,
+    #[msg("Settlement epoch length must be positive")]
+    InvalidSettlementEpoch,
+    #[msg("Deal is already in the settlement queue")]
+    SettlementAlreadyQueued,
+    #[msg("Settlement queue is full")]
+    SettlementQueueFull,
+    #[msg("Settlement queue is empty")]
+    SettlementQueueEmpty,
+    #[msg("Deal is not at the head of the settlement queue")]
+    NotSettlementQueueHead,
+    #[msg("The deal's settlement window has not closed yet")]
+    SettlementWindowOpen,
+    #[msg("Prices on this market are published by commit and reveal")]
+    PriceCommitRequired,
+    #[msg("No price commitment pending")]
+    NoPriceCommit,
+    #[msg("Revealed price does not match the commitment")]
+    PriceCommitMismatch,
+    #[msg("Reveal must come in a later slot and after the minimum delay")]
+    RevealTooEarly,
+    #[msg("Trader has opened too many deals this hour")]
+    OpenRateLimited,
+    #[msg("TraderStats required while open rate limits are on")]
+    TraderStatsRequired,
+    #[msg("Participant index pages are created in order, page 0 first")]
+    ParticipantIndexPageInvalid,
+    #[msg("Participant index page is full; create the next page")]
+    ParticipantIndexFull,
+    #[msg("Price exponent out of range")]
+    InvalidPriceExponent,
+    #[msg("Unknown price convention, or CentsPerLb without price units per cent")]
+    InvalidPriceConvention,
+    #[msg("Not available on sandbox markets")]
+    SandboxUnsupported,
+    #[msg("Sandbox faucet amount above SANDBOX_FAUCET_MAX")]
+    SandboxFaucetCapExceeded,
+    #[msg("ActionLog max entries must be between 1 and ACTION_LOG_CAPACITY")]
+    InvalidActionLogSize,
+    #[msg("ActionLog is full; archive it first")]
+    ActionLogFull,
+    #[msg("Nothing to archive in the ActionLog")]
+    ActionLogEmpty,
+    #[msg("This deal keeps an ActionLog; pass it")]
+    ActionLogRequired,
+    #[msg("Settlement window has passed; only the arbiter can settle this deal")]
+    SettlementWindowMissed,
+    #[msg("Settlement price disputes are off for this market")]
+    PriceDisputeDisabled,
+    #[msg("Settlement price already fixed")]
+    SettlementPriceAlreadyFixed,
+    #[msg("Settlement price is not open to challenge")]
+    PriceChallengeClosed,
+    #[msg("Settlement price is not final yet")]
+    SettlementPriceNotFinal,
+    #[msg("Order side must be 0 (sell) or 1 (buy)")]
+    InvalidOrderSide,
+    #[msg("Order expiry must be in the future and no later than the deal deadline")]
+    InvalidOrderExpiry,
+    #[msg("Standing orders can't be paired")]
+    StandingOrderMismatch,
+    #[msg("Standing order expired or fully filled")]
+    StandingOrderExpired,
+    #[msg("Oracle price is outside a standing order's limit")]
+    StandingOrderNotCrossed,
+    #[msg("Standing order escrow can't cover the margin")]
+    StandingOrderEscrowShort,
+    #[msg("deal_id must be the market's next deal sequence number")]
+    StaleDealId,
+    #[msg("Auction must end in the future and before the deal deadline")]
+    InvalidAuctionSchedule,
+    #[msg("Auction is closed to bids")]
+    AuctionClosed,
+    #[msg("Auction is still open")]
+    AuctionStillOpen,
+    #[msg("Bid is below the reserve or the minimum increment over the best bid")]
+    AuctionBidTooLow,
+    #[msg("Leading bid can't be withdrawn before the auction is finalized")]
+    AuctionBidLeading,
+    #[msg("Auction escrow can't cover the margin at this price")]
+    AuctionEscrowShort,
+    #[msg("Auction has no bids")]
+    AuctionUnsold,
+    #[msg("Auction has a winning bid; use finalize_auction")]
+    AuctionHasWinner,
+    #[msg("Trigger direction must be 0 (above) or 1 (below)")]
+    InvalidTriggerDirection,
+    #[msg("Oracle price hasn't crossed the trigger threshold")]
+    TriggerNotMet,
+    #[msg("Stop must be above the agreed price for the farmer, below it for the buyer")]
+    InvalidStopPrice,
+    #[msg("Mark hasn't crossed either side's stop")]
+    StopNotTriggered,
+    #[msg("Deal is due; it settles normally instead of by stop")]
+    StopExpired,
+    #[msg("Only a market's own CFT mint can be finalized")]
+    CftMintShared,
+    #[msg("CFT mint can only be finalized once the market is closing with no unclosed deals")]
+    CftMintNotRetired,
+    #[msg("CFT mint authority already handed off")]
+    CftMintFinalized,
+    #[msg("Fee exceeds MAX_FEE_BPS")]
+    FeeBpsTooHigh,
+    #[msg("Farmer, buyer and insurance fee shares add up to more than 100%")]
+    FeeSplitTooHigh,
+    #[msg("Margin exceeds 100% of notional")]
+    MarginBpsTooHigh,
+    #[msg("Settlement time must be in the future")]
+    SettlementInPast,
+    #[msg("Per-deal notional and quantity caps must be nonzero")]
+    ZeroDealCap,
+    #[msg("Fee waiver needs at least one use and a future expiry")]
+    InvalidFeeWaiver,
+    #[msg("Fee waiver has expired")]
+    FeeWaiverExpired,
+    #[msg("Fee waiver has no uses left")]
+    FeeWaiverUsedUp,
+    #[msg("Fee waiver is reserved for another trader")]
+    FeeWaiverNotForTrader,
+    #[msg("Operator fee waivers are capped at MAX_OPERATOR_FEE_WAIVER_BPS")]
+    FeeWaiverTooLarge,
+    #[msg("Deal already has a different margin sponsor")]
+    SponsorMismatch,
+    #[msg("sponsor_receive is required to settle a sponsored deal")]
+    SponsorAccountMissing,
+    #[msg("Not available on a deal with sponsored margin")]
+    DealSponsored,
+    #[msg("Sponsored margin needs the deal's farmer and buyer vaults to be separate")]
+    SponsoredVaultShared,
+    #[msg("Margin vault is not the one this deal holds that side's margin in")]
+    MarginVaultMismatch,
+    #[msg("A physical delivery deal needs the farmer on the sell side")]
+    PhysicalFarmerMustSell,
+    #[msg("Vesting period must not be negative")]
+    InvalidVestingPeriod,
+    #[msg("The market does not vest seized margin")]
+    VestingNotEnabled,
+    #[msg("This market vests seized margin: pass the deal's MarginVesting and vesting vault (init_margin_vesting)")]
+    VestingAccountsRequired,
+    #[msg("The deal's MarginVesting is already funded")]
+    VestingAlreadyStarted,
+    #[msg("Nothing has vested since the last claim")]
+    NothingVested,
+    #[msg("Vesting still holds unclaimed margin")]
+    VestingNotComplete,
+    #[msg("Unstake window must be positive")]
+    InvalidUnstakeWindow,
+    #[msg("The unstake request lapsed unredeemed; request again")]
+    UnstakeRequestLapsed,
+    #[msg("An insurance pool without shares must be swept before staking")]
+    InsurancePoolNotEmpty,
+}
+
+// ------------------------- CPI interface -------------------------
+// Typed entry points for programs that CPI into coffee_futures (e.g. lending protocols holding deals
+// as collateral). Build with `features = ["cpi"]`; the account structs are the ones Anchor generates
+// under `crate::cpi::accounts`, and the discriminators below are pinned by a unit test.
+#[cfg(any(feature = "cpi", test))]
+pub mod cpi_interface {
+    use super::*;
+
+    pub const OPEN_DEAL_DISCRIMINATOR: [u8; 8] = [9, 105, 52, 78, 105, 158, 187, 9];
+    pub const TOP_UP_MARGIN_DISCRIMINATOR: [u8; 8] = [245, 191, 155, 247, 252, 205, 122, 4];
+    pub const SETTLE_CASH_DISCRIMINATOR: [u8; 8] = [115, 90, 129, 70, 51, 125, 236, 188];
+
+    #[cfg(feature = "cpi")]
+    pub use crate::cpi::accounts::{OpenDeal, SettleCash, TopUpMargin};
+
+    // open_deal arguments bundled so callers don't depend on positional order
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+    pub struct OpenDealArgs {
+        pub agreed_price_per_kg: u64,
+        pub quantity_kg: u64,
+        pub physical_delivery: bool,
+        pub deadline_ts: i64,
+        pub assets: Vec<Pubkey>,
+        pub asset_qty: Vec<u64>,
+        pub merkle_root: Option<[u8; 32]>,
+        pub referrer: Option<Pubkey>,
+        pub fee_split_bps: Option<u16>,
+        pub margin_override: Option<MarginOverride>,
+        pub farmer_side: u8, // OrderSide; the default (0) is Sell
+    }
+
+    // kept for existing integrators; see `crate::pda` for the rest
+    pub fn deal_address(market: &Pubkey, farmer: &Pubkey, buyer: &Pubkey) -> (Pubkey, u8) {
+        crate::pda::deal(market, farmer, buyer)
+    }
+
+    pub fn vault_auth_address(deal: &Pubkey) -> (Pubkey, u8) {
+        crate::pda::vault_auth(deal)
+    }
+
+    pub fn trader_stats_address(market: &Pubkey, trader: &Pubkey) -> (Pubkey, u8) {
+        crate::pda::trader_stats(market, trader)
+    }
+
+    // delegate to approve on a token account before relaying permits for it
+    pub fn permit_authority_address() -> (Pubkey, u8) {
+        crate::pda::permit_authority()
+    }
+
+    // bytes a counterparty signs off-line for top_up_margin_with_permit
+    pub fn permit_message(deal: &Pubkey, owner: &Pubkey, amount: u64, nonce: u64, expiry_ts: i64) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(PERMIT_DOMAIN.len() + 32 * 3 + 8 * 3);
+        msg.extend_from_slice(PERMIT_DOMAIN);
+        msg.extend_from_slice(crate::ID.as_ref());
+        msg.extend_from_slice(deal.as_ref());
+        msg.extend_from_slice(owner.as_ref());
+        msg.extend_from_slice(&amount.to_le_bytes());
+        msg.extend_from_slice(&nonce.to_le_bytes());
+        msg.extend_from_slice(&expiry_ts.to_le_bytes());
+        msg
+    }
+
+    // what an oracle passes to commit_price ahead of reveal_price(price_per_kg, salt, nonce)
+    pub fn price_commitment(market: &Pubkey, price_per_kg: u64, nonce: u64, salt: &[u8; 32]) -> [u8; 32] {
+        solana_program::keccak::hashv(&[market.as_ref(), &price_per_kg.to_le_bytes(), &nonce.to_le_bytes(), salt]).0
+    }
+
+    // with `event-cpi`, every emitting instruction also takes this PDA and the program itself
+    #[cfg(feature = "event-cpi")]
+    pub fn event_authority_address() -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[anchor_lang::event::EVENT_AUTHORITY_SEED], &crate::ID)
+    }
+
+    #[cfg(feature = "cpi")]
+    pub fn open_deal<'info>(
+        ctx: CpiContext<'_, '_, '_, 'info, OpenDeal<'info>>,
+        args: OpenDealArgs,
+    ) -> Result<()> {
+        crate::cpi::open_deal(
+            ctx,
+            args.agreed_price_per_kg,
+            args.quantity_kg,
+            args.physical_delivery,
+            args.deadline_ts,
+            args.assets,
+            args.asset_qty,
+            args.merkle_root,
+            args.referrer,
+            args.fee_split_bps,
+            args.margin_override,
+            args.farmer_side,
+        )
+    }
+
+    #[cfg(feature = "cpi")]
+    pub fn top_up_margin<'info>(
+        ctx: CpiContext<'_, '_, '_, 'info, TopUpMargin<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        crate::cpi::top_up_margin(ctx, amount)
+    }
+
+    #[cfg(feature = "cpi")]
+    pub fn settle_cash<'info>(ctx: CpiContext<'_, '_, '_, 'info, SettleCash<'info>>) -> Result<()> {
+        crate::cpi::settle_cash(ctx)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use anchor_lang::Discriminator;
+
+        #[test]
+        fn test_discriminators_are_stable() {
+            assert_eq!(&crate::instruction::OpenDeal::DISCRIMINATOR[..], &OPEN_DEAL_DISCRIMINATOR[..]);
+            assert_eq!(&crate::instruction::TopUpMargin::DISCRIMINATOR[..], &TOP_UP_MARGIN_DISCRIMINATOR[..]);
+            assert_eq!(&crate::instruction::SettleCash::DISCRIMINATOR[..], &SETTLE_CASH_DISCRIMINATOR[..]);
+        }
+    }
+}
+
+// ------------------------- Unit tests -------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_band_ok() {
+        // small change ok
+        assert!(is_price_band_ok(1000, 1100, 2000).is_ok()); // 10% delta, max 20%
+        // big change triggers error
+        assert!(is_price_band_ok(1000, 2000, 500).is_err()); // 100% change vs 5% cap
+    }
+
+    fn test_market() -> Market {
+        Market {
+            version: 1,
+            authority: Pubkey::default(),
+            verifier: Pubkey::default(),
+            oracle_publisher: Pubkey::default(),
+            pending_oracle: Pubkey::default(),
+            pending_oracle_effective_ts: 0,
+            cft_mint: Pubkey::default(),
+            quote_mint: Pubkey::default(),
+            insurance_treasury: Pubkey::default(),
+            settlement_ts: 0,
+            contract_size_kg: 0,
+            initial_margin_bps: 0,
+            maintenance_margin_bps: 0,
+            fee_bps: 0,
+            farmer_fee_bps: 0,
+            buyer_fee_bps: 0,
+            insurance_bps: 0,
+            default_margin_call_grace_sec: 0,
+            max_notional_per_deal: 0,
+            max_qty_per_deal: 0,
+            last_price_per_kg: 100,
+            prev_price_per_kg: 0,
+            last_price_nonce: 0,
+            last_oracle_update_ts: 0,
+            max_oracle_age_sec: 3600,
+            twap_acc: 0,
+            twap_time_acc: 0,
+            twap_window_sec: 60,
+            price_mode: PriceMode::TWAP as u8,
+            paused: false,
+            min_transfer_amount: 0,
+            insurance_treasury_authority: Pubkey::default(),
+            program_version: PROGRAM_VERSION,
+            fee_tier_count: 0,
+            fee_tiers: [FeeTier::default(); MAX_FEE_TIERS],
+            cft_fee_discount_bps: 0,
+            pending_verifier: Pubkey::default(),
+            pending_verifier_effective_ts: 0,
+            pending_params: MarketParams::default(),
+            pending_params_effective_ts: 0,
+            governance: Pubkey::default(),
+            authority_config: AuthorityConfig::SingleKey,
+            verifier_config: AuthorityConfig::SingleKey,
+            default_penalty_bps: 0,
+            physical_payment_policy: PaymentPolicy::CapAtBalance as u8,
+            debt_claim_wait_sec: 0,
+            total_open_notional_kg: 0,
+            loss_socialization_index: 0,
+            socialized_loss_total: 0,
+            total_open_deals: 0,
+            total_margin_locked: 0,
+            max_open_deals: 0,
+            max_open_notional_kg: 0,
+            market_kind: MarketKind::Dated as u8,
+            funding_cap_bps: 0,
+            last_funding_ts: 0,
+            cumulative_funding_per_kg: 0,
+            open_price_qty_sum: 0,
+            whole_contracts_only: false,
+            tick_size: 0,
+            unclosed_deals: 0,
+            close_after_ts: 0,
+            deal_seq: 0,
+            require_fresh_price_for_open: false,
+            max_deal_price_deviation_bps: 0,
+            delivery_tree: Pubkey::default(),
+            cft_allowlist_required: false,
+            margin_step_count: 0,
+            margin_steps: [MarginStep::default(); MAX_MARGIN_STEPS],
+            realized_var_bps2: 0,
+            vol_ref_bps: 0,
+            max_maintenance_margin_bps: 0,
+            insurance_upfront: false,
+            total_insurance_premiums: 0,
+            insurance_stake_pool: Pubkey::default(),
+            risk_officer: Pubkey::default(),
+            large_deal_notional: 0,
+            reduce_break_fee_bps: 0,
+            cft_sale_treasury: Pubkey::default(),
+            cft_expiry_ts: 0,
+            cft_floor_price_per_kg: 0,
+            cft_minted: 0,
+            cft_redeemed: 0,
+            cft_per_market: false,
+            sample_required: false,
+            shipment_tranche_bps: [0u16; SHIPMENT_MILESTONES],
+            wormhole_program: Pubkey::default(),
+            cctp_program: Pubkey::default(),
+            operator: Pubkey::default(),
+            operator_fee_bps: 0,
+            operator_treasury: Pubkey::default(),
+            seed_version: 1,
+            migrated_to: Pubkey::default(),
+            licensed_liquidators: false,
+            liquidator_min_bond: 0,
+            liquidator_slash_bps: 0,
+            margin_cure_buffer_bps: 0,
+            omnibus_vaults: false,
+            omnibus_farmer_vault: Pubkey::default(),
+            omnibus_buyer_vault: Pubkey::default(),
+            omnibus_open_deals: 0,
+            ordered_settlement: false,
+            commit_reveal: false,
+            min_reveal_delay_sec: 0,
+            price_commit: [0; 32],
+            price_commit_ts: 0,
+            price_commit_slot: 0,
+            max_opens_per_hour: 0,
+            price_exponent: 0,
+            price_convention: 0,
+            price_units_per_cent: 0,
+            sandbox: false,
+            settlement_window_sec: 0,
+            price_dispute_window_sec: 0,
+            price_challenge_bond: 0,
+            settlement_price_per_kg: 0,
+            settlement_price_fixed_ts: 0,
+            settlement_price_challenged: false,
+            settlement_price_ruled: false,
+            stop_bounty_bps: 0,
+            seized_vesting_sec: 0,
+        }
+    }
+
+    #[test]
+    fn test_update_twap_accumulates() {
+        let mut m = test_market();
+
+        // first publish: last_oracle_update_ts is 0 -> sets it only
+        let now = 1_700_000_000i64;
+        assert!(update_twap(&mut m, now).is_ok());
+        assert_eq!(m.twap_acc, 0);
+        assert_eq!(m.twap_time_acc, 0);
+        // set last_price and simulate later publish with dt
+        m.last_price_per_kg = 200;
+        let later = now + 10;
+        assert!(update_twap(&mut m, later).is_ok());
+        assert!(m.twap_acc > 0);
+        assert_eq!(m.twap_time_acc, 10u64);
+    }
+
+    #[test]
+    fn test_fee_tier_lookup_and_decay() {
+        let mut m = test_market();
+        m.fee_bps = 50;
+        assert_eq!(m.fee_bps_for_volume(u64::MAX), 50); // no tiers -> flat fee
+        m.fee_tiers[0] = FeeTier { min_notional: 1_000, fee_bps: 40 };
+        m.fee_tiers[1] = FeeTier { min_notional: 10_000, fee_bps: 25 };
+        m.fee_tier_count = 2;
+        assert_eq!(m.fee_bps_for_volume(999), 50);
+        assert_eq!(m.fee_bps_for_volume(1_000), 40);
+        assert_eq!(m.fee_bps_for_volume(50_000), 25);
+
+        let w = TRADER_STATS_WINDOW_SEC;
+        assert_eq!(decay_rolling(1_000, 0, 100, w).unwrap(), 1_000); // first record: no decay
+        assert_eq!(decay_rolling(1_000, 100, 100 + (w / 2) as i64, w).unwrap(), 500);
+        assert_eq!(decay_rolling(1_000, 100, 100 + (2 * w) as i64, w).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_quote_to_cft_fee_discount() {
+        // 1_500 quote fee at 1_500/kg -> 1 CFT; with a 20% discount the fee still rounds up to 1
+        assert_eq!(quote_to_cft_fee(1_500, 1_500, 0, 0).unwrap(), 1);
+        assert_eq!(quote_to_cft_fee(1_500, 1_500, 0, 2_000).unwrap(), 1);
+        assert_eq!(quote_to_cft_fee(0, 1_500, 0, 2_000).unwrap(), 0);
+        assert_eq!(quote_to_cft_fee(30_000, 1_500, 0, 1_000).unwrap(), 18);
+        assert!(quote_to_cft_fee(1, 0, 0, 0).is_err());
+        // prices with three more decimals than the quote mint: 1_500_000 -> 1_500 quote units per kg
+        assert_eq!(quote_to_cft_fee(30_000, 1_500_000, -3, 1_000).unwrap(), 18);
+    }
+
+    #[test]
+    fn test_rent_is_exempt_behavior() {
+        // Rent::default() exists and is_exempt must return false for 0 lamports and true for huge lamports
+        let rent = Rent::default();
+        assert!(!rent.is_exempt(0, 10));
+        assert!(rent.is_exempt(u64::MAX / 4, 10));
+    }
+
+    #[test]
+    fn test_stake_rewards_pro_rata() {
+        let mut pool = StakePool {
+            market: Pubkey::default(),
+            stake_vault: Pubkey::default(),
+            reward_vault: Pubkey::default(),
+            total_staked: 300,
+            acc_reward_per_share: 0,
+            revenue_share_bps: 5_000,
+            total_distributed: 0,
+            bump: 0,
+        };
+        let mut a = StakePosition { owner: Pubkey::default(), pool: Pubkey::default(), amount: 100, reward_debt: 0, pending_rewards: 0, bump: 0 };
+        let mut b = StakePosition { amount: 200, ..a.clone() };
+
+        // 600 distributed across 300 staked -> 2 per CFT
+        pool.acc_reward_per_share += 600 * REWARD_PRECISION / 300;
+        a.checkpoint(&pool).unwrap();
+        b.checkpoint(&pool).unwrap();
+        assert_eq!(a.pending_rewards, 200);
+        assert_eq!(b.pending_rewards, 400);
+
+        // second checkpoint without new revenue earns nothing
+        a.checkpoint(&pool).unwrap();
+        assert_eq!(a.pending_rewards, 200);
+    }
+
+    #[test]
+    fn test_market_params_validate() {
+        let mut p = MarketParams {
+            initial_margin_bps: 1_000,
+            maintenance_margin_bps: 500,
+            fee_bps: 50,
+            max_notional_per_deal: 1_000_000,
+            max_qty_per_deal: 1_000,
+            ..Default::default()
+        };
+        assert!(p.validate().is_ok());
+        p.maintenance_margin_bps = 1_500;
+        assert!(p.validate().is_err());
+        p.maintenance_margin_bps = 500;
+        p.farmer_fee_bps = 6_000;
+        p.buyer_fee_bps = 5_000;
+        assert_eq!(p.validate().unwrap_err(), CoffeeError::FeeSplitTooHigh.into());
+    }
+
+    #[test]
+    fn test_market_params_hard_bounds() {
+        let ok = MarketParams {
+            initial_margin_bps: 1_000,
+            maintenance_margin_bps: 500,
+            fee_bps: MAX_FEE_BPS,
+            farmer_fee_bps: 5_000,
+            buyer_fee_bps: 4_000,
+            insurance_bps: 1_000,
+            max_notional_per_deal: 1_000_000,
+            max_qty_per_deal: 1_000,
+            ..Default::default()
+        };
+        assert!(ok.validate().is_ok());
+
+        let cases: [(MarketParams, CoffeeError); 4] = [
+            (MarketParams { fee_bps: MAX_FEE_BPS + 1, ..ok }, CoffeeError::FeeBpsTooHigh),
+            (MarketParams { initial_margin_bps: MAX_BPS + 1, ..ok }, CoffeeError::MarginBpsTooHigh),
+            (MarketParams { max_notional_per_deal: 0, ..ok }, CoffeeError::ZeroDealCap),
+            (MarketParams { max_qty_per_deal: 0, ..ok }, CoffeeError::ZeroDealCap),
+        ];
+        for (p, err) in cases {
+            assert_eq!(p.validate().unwrap_err(), err.into());
+        }
+    }
+
+    #[test]
+    fn test_access_control_grant_revoke() {
+        let mut acl = AccessControl {
+            market: Pubkey::default(),
+            member_count: 0,
+            members: [Pubkey::default(); MAX_ACL_MEMBERS],
+            role_masks: [0u8; MAX_ACL_MEMBERS],
+            bump: 0,
+        };
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        acl.grant(a, Role::Pauser).unwrap();
+        acl.grant(a, Role::Oracle).unwrap();
+        acl.grant(b, Role::Arbiter).unwrap();
+        assert!(acl.has_role(&a, Role::Pauser) && acl.has_role(&a, Role::Oracle));
+        assert!(!acl.has_role(&a, Role::Admin));
+        assert_eq!(acl.member_count, 2);
+
+        acl.revoke(&a, Role::Pauser);
+        assert!(!acl.has_role(&a, Role::Pauser) && acl.has_role(&a, Role::Oracle));
+        acl.revoke(&a, Role::Oracle);
+        assert_eq!(acl.member_count, 1);
+        assert!(acl.has_role(&b, Role::Arbiter));
+    }
+
+    #[test]
+    fn test_socialized_loss_pro_rata() {
+        let mut m = test_market();
+        m.add_open_interest(100, 0).unwrap();
+        m.add_open_interest(200, 0).unwrap();
+        let d = |qty: u64, checkpoint: u128| Deal {
+            quantity_kg: qty,
+            loss_index_checkpoint: checkpoint,
+            ..Default::default()
+        };
+        let early = d(100, 0);
+        let other = d(200, 0);
+        m.socialize_loss(900).unwrap();
+        let late = d(50, m.loss_socialization_index);
+        assert_eq!(early.adl_haircut_due(m.loss_socialization_index).unwrap(), 300);
+        assert_eq!(other.adl_haircut_due(m.loss_socialization_index).unwrap(), 600);
+        assert_eq!(late.adl_haircut_due(m.loss_socialization_index).unwrap(), 0);
+        m.release_open_interest(&early);
+        assert_eq!(m.total_open_notional_kg, 200);
+    }
+
+    #[test]
+    fn test_open_interest_caps() {
+        let mut m = test_market();
+        m.max_open_deals = 2;
+        m.max_open_notional_kg = 150;
+        m.add_open_interest(100, 40).unwrap();
+        assert!(m.add_open_interest(60, 10).is_err());
+        m.add_open_interest(50, 10).unwrap();
+        match m.add_open_interest(0, 0).unwrap_err() {
+            // the error log carries the cap and the value that broke it
+            Error::AnchorError(e) => assert_eq!(e.compared_values, Some(anchor_lang::error::ComparedValues::Values(("2".into(), "3".into())))),
+            e => panic!("unexpected error {e:?}"),
+        }
+        assert_eq!((m.total_open_deals, m.total_open_notional_kg, m.total_margin_locked), (2, 150, 50));
+        let d = Deal { quantity_kg: 100, margin_locked: 40, ..Default::default() };
+        m.release_open_interest(&d);
+        assert_eq!((m.total_open_deals, m.total_open_notional_kg, m.total_margin_locked), (1, 50, 10));
+    }
+
+    #[test]
+    fn test_funding_accrual_longs_pay_premium() {
+        let mut m = test_market();
+        m.market_kind = MarketKind::Perpetual as u8;
+        m.funding_cap_bps = 1_000; // 10%
+        m.twap_acc = 1_000 * 60;
+        m.twap_time_acc = 60;
+        m.add_open_interest(10, 0).unwrap();
+        m.open_price_qty_sum = 1_048 * 10; // deals 4.8% above TWAP
+        m.last_funding_ts = 0;
+
+        // less than an interval: nothing accrues
+        m.accrue_funding(FUNDING_INTERVAL_SEC - 1).unwrap();
+        assert_eq!(m.cumulative_funding_per_kg, 0);
+
+        m.accrue_funding(2 * FUNDING_INTERVAL_SEC + 5).unwrap();
+        assert_eq!(m.last_funding_ts, 2 * FUNDING_INTERVAL_SEC);
+        assert_eq!(m.cumulative_funding_per_kg, 2 * 48 * FUNDING_PRECISION / 24);
+
+        let deal = Deal { quantity_kg: 10, ..Default::default() };
+        assert_eq!(deal.funding_owed_long(m.cumulative_funding_per_kg).unwrap(), 40);
+
+        // premium is capped
+        m.open_price_qty_sum = 2_000 * 10;
+        m.accrue_funding(3 * FUNDING_INTERVAL_SEC).unwrap();
+        assert_eq!(m.cumulative_funding_per_kg, (2 * 48 + 100) * FUNDING_PRECISION / 24);
+    }
+
+    #[test]
+    fn test_contracts_for_qty() {
+        let mut m = test_market();
+        assert_eq!(m.contracts_for_qty(10), None); // contract size unset
+        m.contract_size_kg = 60; // one 60 kg bag
+        assert_eq!(m.contracts_for_qty(300), Some(5));
+        assert_eq!(m.contracts_for_qty(301), None);
+        assert_eq!(m.contracts_for_qty(0), Some(0));
+    }
+
+    #[test]
+    fn test_tick_size() {
+        let mut m = test_market();
+        assert!(m.is_on_tick(1_501));
+        m.tick_size = 5;
+        assert!(m.is_on_tick(1_500));
+        assert!(!m.is_on_tick(1_501));
+    }
+
+    #[test]
+    fn test_oracle_freshness() {
+        let mut m = test_market();
+        let now = 1_700_000_000i64;
+        // never published
+        assert!(!m.oracle_is_fresh(now));
+        m.last_oracle_update_ts = now - 3_600;
+        assert!(m.oracle_is_fresh(now));
+        assert!(!m.oracle_is_fresh(now + 1));
+        // no age limit: any published price counts
+        m.max_oracle_age_sec = 0;
+        assert!(m.oracle_is_fresh(now + 86_400));
+    }
+
+    #[test]
+    fn test_approved_assets_registry() {
+        let mut reg = ApprovedAssets {
+            market: Pubkey::default(),
+            asset_count: 0,
+            mints: [Pubkey::default(); MAX_APPROVED_ASSETS],
+            units_per_kg: [0u64; MAX_APPROVED_ASSETS],
+            bump: 0,
+        };
+        let receipt = Pubkey::new_unique();
+        let green = Pubkey::new_unique();
+        reg.upsert(receipt, 1).unwrap();
+        reg.upsert(green, 1_000_000).unwrap();
+        reg.upsert(receipt, 10).unwrap();
+        assert_eq!(reg.asset_count, 2);
+        assert_eq!(reg.units_per_kg_for(&receipt), Some(10));
+        reg.remove(&receipt);
+        assert_eq!(reg.units_per_kg_for(&receipt), None);
+        assert_eq!(reg.units_per_kg_for(&green), Some(1_000_000));
+    }
+
+    #[test]
+    fn test_basket_units_due_pro_rata() {
+        // 100 kg split 60 kg CFT (1/kg) + 40 kg of a 6-decimal receipt token
+        let mut d = Deal { quantity_kg: 100, asset_count: 2, ..Default::default() };
+        d.asset_qty[0] = 60;
+        d.asset_qty[1] = 40;
+        d.asset_units_per_kg = [1, 1_000_000, 0, 0];
+        assert_eq!(d.basket_units_due(0, 50), Some(30));
+        assert_eq!(d.basket_units_due(1, 50), Some(20_000_000));
+        // partial deliveries settle on cumulative totals, so rounding never leaks
+        assert_eq!(d.basket_units_due(0, 33), Some(19));
+        assert_eq!(d.basket_units_due(0, 100), Some(60));
+
+        d.delivered_kg_total = 50;
+        d.asset_units_delivered = [30, 0, 0, 0];
+        assert!(!d.basket_fully_delivered());
+        d.asset_units_delivered[1] = 20_000_000;
+        assert!(d.basket_fully_delivered());
+    }
+
+    #[test]
+    fn test_cft_supply_backed_by_verified_kg() {
+        let mut auth = CftMintAuth { bump: 0, total_cft_minted: 0, total_kg_verified: 0 };
+        assert!(auth.record_mint(1).is_err());
+        auth.record_verified(100).unwrap();
+        auth.record_mint(60).unwrap();
+        auth.record_mint(40).unwrap();
+        assert!(auth.record_mint(1).is_err());
+        assert_eq!((auth.total_cft_minted, auth.total_kg_verified), (100, 100));
+    }
+
+    #[test]
+    fn test_margin_schedule_by_time_to_expiry() {
+        let mut m = test_market();
+        m.settlement_ts = 100_000;
+        m.initial_margin_bps = 1_000;
+        m.maintenance_margin_bps = 500;
+        assert_eq!(m.initial_margin_bps_at(0), 1_000); // no schedule -> flat margin
+        m.margin_steps[0] = MarginStep { secs_to_expiry: 30 * 86_400, initial_margin_bps: 1_500 };
+        m.margin_steps[1] = MarginStep { secs_to_expiry: 86_400, initial_margin_bps: 2_500 };
+        m.margin_step_count = 2;
+        assert_eq!(m.initial_margin_bps_at(100_000 - 30 * 86_400 - 1), 1_000);
+        assert_eq!(m.initial_margin_bps_at(100_000 - 30 * 86_400), 1_500);
+        assert_eq!(m.maintenance_margin_bps_at(100_000 - 30 * 86_400), 750);
+        assert_eq!(m.initial_margin_bps_at(100_000 - 3_600), 2_500);
+        assert_eq!(m.maintenance_margin_bps_at(100_000 - 3_600), 1_250);
+        assert_eq!(m.initial_margin_bps_at(200_000), 2_500); // past expiry stays at the last step
+        m.market_kind = MarketKind::Perpetual as u8;
+        assert_eq!(m.initial_margin_bps_at(100_000 - 3_600), 1_000);
+    }
+
+    #[test]
+    fn test_vol_scaled_maintenance_margin() {
+        let mut m = test_market();
+        m.initial_margin_bps = 1_000;
+        m.maintenance_margin_bps = 500;
+        m.realized_var_bps2 = 400 * 400;
+        assert_eq!(m.realized_vol_bps(), 400);
+        assert_eq!(m.maintenance_margin_bps_at(0), 500); // scaling off
+        m.vol_ref_bps = 200;
+        m.max_maintenance_margin_bps = 800;
+        assert_eq!(m.maintenance_margin_bps_at(0), 800); // 2x reference vol, capped
+        m.realized_var_bps2 = 250 * 250;
+        assert_eq!(m.maintenance_margin_bps_at(0), 625);
+        m.realized_var_bps2 = 100 * 100;
+        assert_eq!(m.maintenance_margin_bps_at(0), 500);
+    }
+
+    #[test]
+    fn test_margin_account_net_exposure() {
+        let mut acct = MarginAccount::default();
+        assert_eq!(acct.excess(500, 0, 0, 1_000), Some(500)); // nothing linked: the whole pool is free
+        // long 100 kg @ 1_500 and short 80 kg @ 1_600: only 20 kg net long is margined
+        acct.add_exposure(math::Side::Long, 100, 150_000).unwrap();
+        acct.add_exposure(math::Side::Short, 80, 128_000).unwrap();
+        assert_eq!(acct.deal_count, 2);
+        assert_eq!(acct.required_margin(1_500, 0, 1_000), Some(3_000));
+        // at 1_500: long flat, short +8_000
+        assert_eq!(acct.unrealized_pnl(1_500, 0), Some(8_000));
+        assert_eq!(acct.excess(1_000, 1_500, 0, 1_000), Some(6_000));
+        // at 1_700: long +20_000, short -8_000, requirement 3_400
+        assert_eq!(acct.excess(1_000, 1_700, 0, 1_000), Some(1_000 + 12_000 - 3_400));
+        // a quote mint with one more decimal than the prices scales every amount by 10
+        assert_eq!(acct.excess(10_000, 1_700, 1, 1_000), Some(10 * (1_000 + 12_000 - 3_400)));
+        acct.remove_exposure(math::Side::Short, 80, 128_000);
+        assert_eq!(acct.required_margin(1_500, 0, 1_000), Some(15_000));
+        assert_eq!(acct.deal_count, 1);
+    }
+
+    #[test]
+    fn test_insurance_pool_shares_absorb_losses() {
+        let mut pool = InsuranceStakePool {
+            market: Pubkey::default(),
+            stake_vault: Pubkey::default(),
+            total_shares: 0,
+            premium_share_bps: 5_000,
+            cooldown_secs: 86_400,
+            total_premiums: 0,
+            total_losses: 0,
+            bump: 0,
+            unstake_window_secs: 3_600,
+        };
+        // first staker mints 1:1 into an empty vault; an unowned balance has to be swept first
+        assert!(pool.shares_for_deposit(1_000, 5).is_err());
+        assert_eq!(pool.shares_for_deposit(1_000, 0).unwrap(), 1_000);
+        pool.total_shares = 1_000;
+        // premiums lift the vault to 1_500: a new 300 deposit buys 200 shares
+        assert_eq!(pool.shares_for_deposit(300, 1_500).unwrap(), 200);
+        pool.total_shares = 1_200;
+        // a 900 loss leaves 900 for 1_200 shares
+        assert_eq!(pool.assets_for_shares(1_000, 900).unwrap(), 750);
+        assert_eq!(pool.assets_for_shares(200, 900).unwrap(), 150);
+        assert!(pool.assets_for_shares(1_201, 900).is_err());
+        // fully drained with shares outstanding: deposits are refused
+        assert!(pool.shares_for_deposit(100, 0).is_err());
+        // a request ready at 1_000 is redeemable until the window closes
+        assert!(!pool.unstake_lapsed(1_000, 4_599));
+        assert!(pool.unstake_lapsed(1_000, 4_600));
+    }
+
+    #[test]
+    fn test_crop_insurance_triggers() {
+        let mut market = test_market();
+        let config = CropInsuranceConfig {
+            market: Pubkey::default(),
+            premium_bps: 200,
+            payout_bps: 5_000,
+            price_trigger_per_kg: 1_000,
+            weather_feed: Pubkey::new_unique(),
+            weather_index_trigger: 80,
+            total_premiums: 0,
+            total_payouts: 0,
+            bump: 0,
+        };
+        market.last_price_per_kg = 1_200;
+        assert!(!config.triggered(&market, None, 100));
+        market.last_price_per_kg = 1_000;
+        assert!(config.price_trigger_hit(&market));
+
+        market.last_price_per_kg = 1_200;
+        let mut feed = IndexFeed {
+            market: Pubkey::default(),
+            feed_id: 0,
+            kind: IndexKind::Rainfall as u8,
+            publisher: Pubkey::default(),
+            value: 90,
+            prev_value: 0,
+            nonce: 1,
+            last_update_ts: 100,
+            max_age_sec: 3_600,
+            bump: 0,
+        };
+        // readings at or before purchase do not count
+        assert!(!config.weather_trigger_hit(Some(&feed), 100));
+        assert!(config.weather_trigger_hit(Some(&feed), 99));
+        feed.value = 79;
+        assert!(!config.weather_trigger_hit(Some(&feed), 99));
+    }
+
+    #[test]
+    fn test_index_feed_freshness() {
+        let mut feed = IndexFeed {
+            market: Pubkey::default(),
+            feed_id: 1,
+            kind: IndexKind::Frost as u8,
+            publisher: Pubkey::default(),
+            value: 0,
+            prev_value: 0,
+            nonce: 0,
+            last_update_ts: 0,
+            max_age_sec: 600,
+            bump: 0,
+        };
+        assert!(feed.fresh_value(1_000).is_err()); // never published
+        feed.value = 12;
+        feed.last_update_ts = 1_000;
+        assert_eq!(feed.fresh_value(1_600).unwrap(), 12);
+        assert!(feed.fresh_value(1_601).is_err());
+        feed.max_age_sec = 0;
+        assert_eq!(feed.fresh_value(1_000_000).unwrap(), 12);
+    }
+
+    #[test]
+    fn test_agent_grant_caps() {
+        let mut grant = AgentGrant {
+            market: Pubkey::default(),
+            farmer: Pubkey::new_unique(),
+            agent: Pubkey::new_unique(),
+            max_notional: 1_000_000,
+            used_notional: 0,
+            expiry_ts: 5_000,
+            bump: 0,
+        };
+        assert!(grant.is_active(4_999));
+        assert!(!grant.is_active(5_000));
+        grant.use_notional(600_000).unwrap();
+        assert!(grant.use_notional(400_001).is_err());
+        assert_eq!(grant.used_notional, 600_000);
+        grant.use_notional(400_000).unwrap();
+        assert_eq!(grant.used_notional, 1_000_000);
+    }
+
+    #[test]
+    fn test_session_key_scopes() {
+        let key = Pubkey::new_unique();
+        let session = SessionKey {
+            market: Pubkey::default(),
+            authority: Pubkey::new_unique(),
+            session_key: key,
+            scopes: SessionScope::OracleHeartbeat.mask() | SessionScope::MarginCall.mask(),
+            expiry_ts: 1_000,
+            bump: 0,
+        };
+        assert!(session.allows(&key, SessionScope::OracleHeartbeat, 999));
+        assert!(session.allows(&key, SessionScope::MarginCall, 0));
+        assert!(!session.allows(&key, SessionScope::PublishPrice, 999));
+        assert!(!session.allows(&key, SessionScope::MarginCall, 1_000));
+        assert!(!session.allows(&Pubkey::new_unique(), SessionScope::MarginCall, 0));
+        assert!(SessionScope::PublishPrice.role() == Role::Oracle);
+        assert!(SessionScope::MarginCall.role() == Role::Liquidator);
+    }
+
+    #[test]
+    fn test_ed25519_permit_parsing() {
+        let owner = Pubkey::new_unique();
+        let message = cpi_interface::permit_message(&Pubkey::new_unique(), &owner, 500, 0, 1_000);
+        // layout produced by the ed25519 program's new_ed25519_instruction: header, pubkey, sig, msg
+        let (pk_off, sig_off) = (16u16, 48u16);
+        let msg_off = sig_off + 64;
+        let mut data = vec![1u8, 0];
+        for v in [sig_off, u16::MAX, pk_off, u16::MAX, msg_off, message.len() as u16, u16::MAX] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data.extend_from_slice(owner.as_ref());
+        data.extend_from_slice(&[0u8; 64]);
+        data.extend_from_slice(&message);
+
+        let (pubkey, signed) = ed25519_signed_message(&data).unwrap();
+        assert_eq!(pubkey, owner);
+        assert_eq!(signed, &message[..]);
+
+        // message pulled from another instruction is rejected
+        let mut other = data.clone();
+        other[14..16].copy_from_slice(&0u16.to_le_bytes());
+        assert!(ed25519_signed_message(&other).is_err());
+        // two signatures are rejected
+        let mut two = data.clone();
+        two[0] = 2;
+        assert!(ed25519_signed_message(&two).is_err());
+        // truncated data is rejected
+        assert!(ed25519_signed_message(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_large_deal_requires_cosign() {
+        let mut m = test_market();
+        assert!(!m.requires_risk_cosign(u128::MAX)); // off by default
+        m.large_deal_notional = 1_000_000;
+        assert!(!m.requires_risk_cosign(1_000_000));
+        assert!(m.requires_risk_cosign(1_000_001));
+    }
+
+    #[test]
+    fn test_cft_expiry_and_redemption_cap() {
+        let mut m = test_market();
+        assert!(!m.cft_expired(1_000)); // no expiry configured
+        m.cft_expiry_ts = 500;
+        assert!(!m.cft_expired(499));
+        assert!(m.cft_expired(500));
+
+        m.record_cft_mint(100).unwrap();
+        m.record_cft_redemption(60).unwrap();
+        assert!(m.record_cft_redemption(41).is_err());
+        m.record_cft_redemption(40).unwrap();
+        assert_eq!((m.cft_minted, m.cft_redeemed), (100, 100));
+    }
+
+    #[test]
+    fn test_sample_window_and_fee_owner() {
+        let mut s = SampleEscrow {
+            market: Pubkey::default(),
+            farmer: Pubkey::new_unique(),
+            buyer: Pubkey::new_unique(),
+            fee: 50,
+            requested_ts: 100,
+            deadline_ts: 200,
+            shipped_ts: 0,
+            approved: false,
+            bump: 0,
+        };
+        assert!(!s.lapsed(199));
+        assert!(s.lapsed(200));
+        assert!(!s.fee_owed_to_farmer()); // never shipped: refunded to the buyer
+        s.shipped_ts = 150;
+        assert!(s.fee_owed_to_farmer());
+    }
+
+    #[test]
+    fn test_shipment_tranches_and_risk_transfer() {
+        let bps = [1_000, 2_000, 0, 3_000, 0];
+        // NotShipped -> AtMill releases the AtMill tranche only
+        assert_eq!(shipment_tranche(10_000, &bps, 0, 1).unwrap(), 1_000);
+        // skipping ahead AtMill -> Arrived releases AtPort, OnVessel and Arrived
+        assert_eq!(shipment_tranche(10_000, &bps, 1, 4).unwrap(), 5_000);
+        assert_eq!(shipment_tranche(10_000, &bps, 4, 5).unwrap(), 0);
+
+        assert_eq!(ShipmentStatus::risk_transfer(Incoterm::Fob as u8), Some(ShipmentStatus::OnVessel));
+        assert_eq!(ShipmentStatus::risk_transfer(Incoterm::Ddp as u8), Some(ShipmentStatus::CustomsCleared));
+        assert_eq!(ShipmentStatus::risk_transfer(Incoterm::Unspecified as u8), None);
+        assert!(ShipmentStatus::from_u8(6).is_err());
+    }
+
+    #[test]
+    fn test_fx_oracle_fresh_rate() {
+        let mut oracle = FxOracle {
+            market: Pubkey::new_unique(),
+            currency: *b"COP",
+            publisher: Pubkey::new_unique(),
+            rate: 0,
+            last_update_ts: 1_000,
+            max_age_sec: 60,
+            bump: 0,
+        };
+        assert_eq!(oracle.fresh_rate(1_010), None); // never published
+        oracle.rate = 4_000 * FX_RATE_SCALE;
+        assert_eq!(oracle.fresh_rate(1_060), Some(4_000 * FX_RATE_SCALE));
+        assert_eq!(oracle.fresh_rate(1_061), None);
+        assert_eq!(oracle.fresh_rate(999), None);
+
+        let mut deal = Deal::default();
+        assert!(!deal.fx_hedge_pending());
+        deal.fx_oracle = Pubkey::new_unique();
+        assert!(deal.fx_hedge_pending());
+        deal.fx_settled = true;
+        assert!(!deal.fx_hedge_pending());
+    }
+
+    #[test]
+    fn test_deal_realized_pnl() {
+        let mut deal = Deal::default();
+        // buyer wins 1_000 out of the farmer's vault, 50 of it haircut to insurance
+        deal.record_realized_pnl(math::Side::Long, 1_000, 950).unwrap();
+        assert_eq!((deal.realized_pnl_farmer, deal.realized_pnl_buyer), (-1_000, 950));
+        deal.record_realized_pnl(math::Side::Short, 300, 300).unwrap();
+        assert_eq!((deal.realized_pnl_farmer, deal.realized_pnl_buyer), (-700, 650));
+        assert!(deal.record_realized_pnl(math::Side::Long, u64::MAX, 0).is_err());
+
+        deal.record_fees(25).unwrap();
+        deal.record_fees(5).unwrap();
+        assert_eq!(deal.fees_paid, 30);
+    }
+
+    #[test]
+    fn test_deal_terms_check_records_first_error() {
+        let mut check = DealTermsCheck::default();
+        check.record(DealCheck::MarketOpen, Ok(()));
+        check.record(DealCheck::Price, check_that(false, CoffeeError::ZeroPrice));
+        check.record(DealCheck::Quantity, check_that(false, CoffeeError::ZeroQty));
+        assert_eq!(check.failed, DealCheck::Price.mask() | DealCheck::Quantity.mask());
+        assert_eq!(check.first_error, u32::from(CoffeeError::ZeroPrice));
+        assert!(check_that(true, CoffeeError::ZeroQty).is_ok());
+    }
+
+    #[test]
+    fn test_market_resize_target() {
+        let full = 8 + Market::INIT_SPACE;
+        assert_eq!(market_resize_target(full), full);
+        assert_eq!(market_resize_target(full - 100), full);
+        assert_eq!(market_resize_target(full + 8), full + 8); // never shrinks
+        let step = solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+        if full > 8 + step {
+            assert_eq!(market_resize_target(8), 8 + step);
+        }
+    }
+
+    #[test]
+    fn test_liquidation_queue_orders_by_shortfall() {
+        let mut q = LiquidationQueue {
+            market: Pubkey::new_unique(),
+            len: 0,
+            entries: [LiquidationEntry::default(); MAX_LIQUIDATION_QUEUE],
+            bump: 0,
+        };
+        let (a, b, c) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        assert!(q.upsert(a, 100, 1));
+        assert!(q.upsert(b, 300, 2));
+        assert!(q.upsert(c, 100, 3)); // tie goes behind a
+        assert_eq!(q.entries().iter().map(|e| e.deal).collect::<Vec<_>>(), vec![b, a, c]);
+        // re-flagging updates the shortfall but keeps the first flag time
+        assert!(q.upsert(c, 500, 9));
+        assert_eq!(*q.head().unwrap(), LiquidationEntry { deal: c, shortfall: 500, flagged_ts: 3 });
+        assert!(q.remove(&b));
+        assert!(!q.remove(&b));
+        assert_eq!(q.len, 2);
+
+        // a full queue drops its smallest entry, or refuses one smaller still
+        for i in 0..MAX_LIQUIDATION_QUEUE as u64 {
+            q.upsert(Pubkey::new_unique(), 1_000 + i, 10);
+        }
+        assert_eq!(q.len as usize, MAX_LIQUIDATION_QUEUE);
+        assert!(!q.upsert(Pubkey::new_unique(), 1, 11));
+        assert!(q.entries().iter().all(|e| e.shortfall >= 1_000));
+    }
+
+    #[test]
+    fn test_deal_health_tracks_weaker_vault() {
+        let mut deal = Deal::default();
+        // 1_000 due: 1_500 / 900 held -> the buyer's 0.9x decides
+        assert_eq!(deal.health_bps_for(1_500, 1_000, 900, 1_000), 9_000);
+        assert_eq!(deal.health_bps_for(1_500, 0, 900, 0), u32::MAX);
+        // a farmer held to a 2x floor is the weaker side
+        assert_eq!(deal.health_bps_for(1_500, 2_000, 900, 1_000), 7_500);
+        // a cross-margined buyer is judged elsewhere
+        deal.buyer_margin_account = Pubkey::new_unique();
+        assert_eq!(deal.health_bps_for(1_500, 1_000, 900, 1_000), 15_000);
+        deal.farmer_margin_account = Pubkey::new_unique();
+        assert_eq!(deal.health_bps_for(1_500, 1_000, 900, 1_000), u32::MAX);
+
+        assert_eq!(deal.set_health(12_000, 10), Some(0));
+        assert_eq!(deal.set_health(12_000, 20), None);
+        assert_eq!(deal.health_updated_ts, 20);
+    }
+
+    #[test]
+    fn test_recovered_since_flag() {
+        let mut deal = Deal::default();
+        deal.set_health(8_000, 100);
+        assert!(!deal.recovered_since(100));
+        // a top-up after the flag lifts it back over maintenance
+        deal.set_health(10_500, 160);
+        assert!(deal.recovered_since(100));
+        // a reading from before the flag says nothing about it
+        assert!(!deal.recovered_since(200));
+        deal.buyer_margin_account = Pubkey::new_unique();
+        assert!(!deal.recovered_since(100));
+    }
+
+    #[test]
+    fn test_margin_call_grace_extends_on_outage() {
+        let mut deal = Deal::default();
+        // no open margin call: nothing to extend
+        assert_eq!(deal.extend_margin_call_grace(1_000, 600), None);
+        deal.margin_call_ts = 1_000;
+        deal.margin_call_grace_sec = 300;
+        // grace would end at 1_300; a stale check at 1_200 pushes it to 1_800
+        assert_eq!(deal.extend_margin_call_grace(1_200, 600), Some(800));
+        assert_eq!(deal.margin_call_grace_sec, 800);
+        // already long enough
+        assert_eq!(deal.extend_margin_call_grace(1_100, 600), None);
+    }
+
+    #[test]
+    fn test_margin_cure_level() {
+        let mut m = test_market();
+        assert_eq!(m.margin_cure_bps(500), None);
+        m.margin_cure_buffer_bps = 150;
+        assert_eq!(m.margin_cure_bps(500), Some(650));
+        m.margin_cure_buffer_bps = MAX_BPS;
+        assert_eq!(m.margin_cure_bps(500), Some(MAX_BPS));
+    }
+
+    #[test]
+    fn test_margin_override_only_raises() {
+        let ov = MarginOverride { farmer_initial_bps: 2_000, farmer_maintenance_bps: 1_200, ..Default::default() };
+        assert_eq!(ov.initial_bps(math::Side::Short, 1_000), 2_000);
+        assert_eq!(ov.maintenance_bps(math::Side::Short, 500), 1_200);
+        // below the market rate the market wins; the buyer keeps market terms
+        assert_eq!(ov.initial_bps(math::Side::Short, 2_500), 2_500);
+        assert_eq!(ov.initial_bps(math::Side::Long, 1_000), 1_000);
+        assert!(ov.binds(math::Side::Short) && !ov.binds(math::Side::Long));
+        assert!(ov.check(1_000, 500).is_ok());
+        // maintenance may not outrun the side's initial margin
+        let bad = MarginOverride { buyer_maintenance_bps: 1_500, ..Default::default() };
+        assert!(bad.check(1_000, 500).is_err());
+        assert!(MarginOverride { farmer_initial_bps: MAX_BPS + 1, ..Default::default() }.check(1_000, 500).is_err());
+    }
+
+    #[test]
+    fn test_credit_line_draw_and_release() {
+        let mut line = CreditLine {
+            market: Pubkey::new_unique(),
+            buyer: Pubkey::new_unique(),
+            limit: 1_000,
+            used: 0,
+            margin_discount_bps: 5_000,
+            defaults: 0,
+            max_defaults: 0,
+            active: true,
+            bump: 0,
+        };
+        // half of each deal's margin, until the limit runs out
+        assert_eq!(line.draw(1_200).unwrap(), 600);
+        assert_eq!(line.draw(1_200).unwrap(), 400);
+        assert_eq!(line.draw(1_200).unwrap(), 0);
+        line.release(600, false);
+        assert_eq!(line.available(), 600);
+        // one default over the allowance freezes the line
+        line.release(400, true);
+        assert_eq!(line.used, 0);
+        assert_eq!(line.available(), 0);
+        line.max_defaults = 1;
+        assert_eq!(line.available(), 1_000);
+
+        let deal = Deal { credit_used: 300, ..Default::default() };
+        assert_eq!(deal.buyer_collateral(700), 1_000);
+    }
+
+    #[test]
+    fn test_omnibus_ledger_balances() {
+        let mut deal = Deal { credit_used: 100, ..Default::default() };
+        // isolated deals are measured on their vaults
+        assert_eq!(deal.margin_balances(700, 300), (700, 300));
+        deal.omnibus = true;
+        deal.omnibus_balance = [500, 200];
+        // omnibus deals ignore the shared vault balances
+        assert_eq!(deal.margin_balances(1_000_000, 1_000_000), (500, 200));
+        deal.credit_omnibus(math::Side::Long, 50).unwrap();
+        deal.credit_omnibus(math::Side::Short, 25).unwrap();
+        assert_eq!(deal.omnibus_balance, [525, 250]);
+        assert_eq!(deal.buyer_collateral(deal.margin_balances(0, 0).1), 350);
+        deal.omnibus_balance[0] = u64::MAX;
+        assert!(deal.credit_omnibus(math::Side::Short, 1).is_err());
+    }
+
+    #[test]
+    fn test_omnibus_audit_discrepancy() {
+        let ledgers = [[500, 200], [300, 0], [0, 150]];
+        assert_eq!(omnibus_audit(&ledgers, [800, 350]).unwrap(), ([800, 350], [0, 0]));
+        // a stray deposit shows as surplus, an over-credited ledger as a deficit
+        assert_eq!(omnibus_audit(&ledgers, [810, 340]).unwrap().1, [10, -10]);
+        assert_eq!(omnibus_audit(&[], [5, 0]).unwrap(), ([0, 0], [5, 0]));
+        assert!(omnibus_audit(&[[u64::MAX, 0], [1, 0]], [0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_settlement_queue_fifo_windows() {
+        let mut q = SettlementQueue {
+            market: Pubkey::new_unique(),
+            fee_pot: Pubkey::new_unique(),
+            epoch_sec: 600,
+            rebate_bps: 1_000,
+            processed: 0,
+            len: 0,
+            entries: [SettlementEntry::default(); MAX_SETTLEMENT_QUEUE],
+            bump: 0,
+        };
+        // a registration closes at the end of its window
+        assert_eq!(q.window(1_199), (1, 1_200));
+        assert_eq!(q.window(1_200), (2, 1_800));
+
+        let (a, b, c) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(q.push(a, 1, 1_200).unwrap(), 0);
+        assert_eq!(q.push(b, 1, 1_200).unwrap(), 1);
+        assert!(q.push(a, 2, 1_800).is_err());
+        assert_eq!(q.push(c, 2, 1_800).unwrap(), 2);
+        // first in, first out
+        assert_eq!(q.pop_front().unwrap().deal, a);
+        assert_eq!(*q.head().unwrap(), SettlementEntry { deal: b, epoch: 1, closes_ts: 1_200 });
+        assert_eq!(q.entries().iter().map(|e| e.deal).collect::<Vec<_>>(), vec![b, c]);
+        q.pop_front();
+        q.pop_front();
+        assert!(q.pop_front().is_none());
+
+        for _ in 0..MAX_SETTLEMENT_QUEUE {
+            q.push(Pubkey::new_unique(), 3, 2_400).unwrap();
+        }
+        assert!(q.push(Pubkey::new_unique(), 3, 2_400).is_err());
+        assert_eq!(q.clear() as usize, MAX_SETTLEMENT_QUEUE);
+        assert!(q.head().is_none());
+        q.push(a, 4, 3_000).unwrap();
+    }
+
+    #[test]
+    fn test_price_commit_reveal_timing() {
+        let mut m = test_market();
+        let market = Pubkey::new_unique();
+        let salt = [7u8; 32];
+        m.price_commit = cpi_interface::price_commitment(&market, 1_500, 4, &salt);
+        // the commitment binds price, nonce, salt and market
+        assert_ne!(m.price_commit, cpi_interface::price_commitment(&market, 1_501, 4, &salt));
+        assert_ne!(m.price_commit, cpi_interface::price_commitment(&market, 1_500, 5, &salt));
+        assert_ne!(m.price_commit, cpi_interface::price_commitment(&market, 1_500, 4, &[8u8; 32]));
+        assert_ne!(m.price_commit, cpi_interface::price_commitment(&Pubkey::new_unique(), 1_500, 4, &salt));
+
+        m.price_commit_ts = 1_000;
+        m.price_commit_slot = 50;
+        m.min_reveal_delay_sec = 30;
+        assert!(check_price_reveal(&m, 1_100, 50).is_err()); // same slot
+        assert!(check_price_reveal(&m, 1_029, 51).is_err()); // before the delay
+        assert!(check_price_reveal(&m, 1_030, 51).is_ok());
+        m.price_commit = [0; 32];
+        assert!(check_price_reveal(&m, 1_030, 51).is_err()); // nothing pending
+    }
+
+    #[test]
+    fn test_open_rate_limit_window() {
+        let mut stats = TraderStats::default();
+        stats.record_opens(2, 3, 1_000).unwrap();
+        stats.record_opens(1, 3, 1_500).unwrap();
+        assert!(stats.record_opens(1, 3, 1_600).is_err()); // 4th open inside the hour
+        assert_eq!(stats.opens_window_ts, 1_000);
+
+        // the window lapses an hour after it started, not after the last open
+        stats.opens_in_window = 3;
+        stats.record_opens(1, 3, 1_000 + OPEN_RATE_WINDOW_SEC).unwrap();
+        assert_eq!((stats.opens_window_ts, stats.opens_in_window), (1_000 + OPEN_RATE_WINDOW_SEC, 1));
+
+        // allowlisted wallets are counted but never refused
+        stats.rate_limit_exempt = true;
+        stats.record_opens(10, 3, 1_000 + OPEN_RATE_WINDOW_SEC).unwrap();
+        assert_eq!(stats.opens_in_window, 11);
+    }
+
+    #[test]
+    fn test_participant_index_page_fills() {
+        let mut index = ParticipantIndex::default();
+        let deals: Vec<Pubkey> = (0..PARTICIPANT_INDEX_PAGE_LEN).map(|_| Pubkey::new_unique()).collect();
+        for deal in &deals {
+            index.push(*deal).unwrap();
+        }
+        assert_eq!(index.deals(), &deals[..]);
+        assert!(index.push(Pubkey::new_unique()).is_err());
+        assert_eq!(index.len as usize, PARTICIPANT_INDEX_PAGE_LEN);
+    }
+
+    #[test]
+    fn test_price_exponent_scales_quote_amounts() {
+        let mut m = test_market();
+        // USD/kg with 6 decimals against a 6 dp mint: prices are already quote base units
+        assert_eq!(m.notional(2_500_000, 100).unwrap(), 250_000_000);
+        // same prices against a 9 dp mint, then a 2 dp one
+        m.price_exponent = 3;
+        assert_eq!(m.notional(2_500_000, 100).unwrap(), 250_000_000_000);
+        assert_eq!(m.required_margin(2_500_000, 100, 1_000).unwrap(), 25_000_000_000);
+        m.price_exponent = -4;
+        assert_eq!(m.notional(2_500_000, 100).unwrap(), 25_000);
+        // PnL truncates toward zero on both sides, so the loser pays what the winner gets
+        let long = m.pnl(2_500_000, 2_500_150, 1, math::Side::Long).unwrap();
+        let short = m.pnl(2_500_000, 2_500_150, 1, math::Side::Short).unwrap();
+        assert_eq!((long, short), (0, 0));
+        assert_eq!(m.pnl(2_500_000, 2_600_000, 10, math::Side::Short).unwrap(), -100);
+        assert!(math::to_quote_units(u128::MAX, 1).is_none());
+    }
+
+    #[test]
+    fn test_cents_per_lb_convention() {
+        let mut m = test_market();
+        m.price_convention = PriceConvention::CentsPerLb as u8;
+        m.price_units_per_cent = 10_000; // USD/kg with 6 decimals
+        m.tick_size = 5; // 0.05 ¢/lb
+        // 215.50 ¢/lb = 4.750962 USD/kg, and back
+        let per_kg = m.price_from_quoted(21_550).unwrap();
+        assert_eq!(per_kg, 4_750_962);
+        assert_eq!(m.quoted_price(per_kg).unwrap(), 21_550);
+        assert!(m.is_on_tick(per_kg));
+        assert!(!m.is_on_tick(per_kg + 1)); // still 215.50 ¢/lb, but not its per-kg price
+        assert!(!m.is_on_tick(m.price_from_quoted(21_551).unwrap())); // between ticks
+        // banding is measured on the ¢/lb quotes: 215.50 -> 226.28 is 500 bps
+        let up = m.price_from_quoted(22_628).unwrap();
+        assert_eq!(m.price_band_bps(per_kg, up).unwrap(), 500);
 
-#[event]
-pub struct MarginToppedUp {
-    pub deal: Pubkey,
-    pub who: Pubkey,
-    pub amount: u64,
-}
+        m.price_convention = PriceConvention::PerKg as u8;
+        assert_eq!(m.quoted_price(per_kg).unwrap(), per_kg);
+    }
 
-#[event]
-pub struct MarginCalled {
-    pub deal: Pubkey,
-    pub ts: i64,
-    pub grace_sec: u64,
-}
+    #[test]
+    fn test_action_log_archive() {
+        let deal = Pubkey::new_unique();
+        let mut log = ActionLog { deal, max_entries: 2, ..Default::default() };
+        let entry = |action: DealAction, amount: u64| ActionEntry { action: action as u8, actor: Pubkey::default(), amount, ts: 100 };
+        log.record(entry(DealAction::TopUp, 50)).unwrap();
+        log.record(entry(DealAction::MarginCall, 3_600)).unwrap();
+        assert!(log.record(entry(DealAction::Settle, 0)).is_err()); // full until archived
 
-#[event]
-pub struct LiquidationFlagged {
-    pub deal: Pubkey,
-    pub ts: i64,
-}
+        let leaves = log.archive();
+        assert_eq!(leaves, vec![entry(DealAction::TopUp, 50).leaf(&deal, 0), entry(DealAction::MarginCall, 3_600).leaf(&deal, 1)]);
+        assert_eq!((log.len, log.archived), (0, 2));
+        let digest = leaves.iter().fold([0u8; 32], |d, l| solana_program::keccak::hashv(&[&d, l]).0);
+        assert_eq!(log.archive_digest, digest);
 
-#[event]
-pub struct SettledCash {
-    pub deal: Pubkey,
-    pub market: Pubkey,
-    pub price: u64,
-}
+        // numbering carries on after an archive
+        log.record(entry(DealAction::Settle, 0)).unwrap();
+        assert_eq!(log.archive(), vec![entry(DealAction::Settle, 0).leaf(&deal, 2)]);
+    }
 
-#[event]
-pub struct SettledPhysical {
-    pub deal: Pubkey,
-    pub market: Pubkey,
-    pub delivered_kg: u64,
-    pub total_delivered: u64,
-}
+    #[test]
+    fn test_settlement_window_missed() {
+        let mut m = test_market();
+        m.settlement_ts = 1_000;
+        let mut d = Deal { deadline_ts: 2_000, ..Default::default() };
+        assert!(!m.settlement_window_missed(&d, 1_000_000)); // no window: open-ended
 
-#[event]
-pub struct DealCanceled {
-    pub deal: Pubkey,
-    pub market: Pubkey,
-}
+        m.settlement_window_sec = 500;
+        assert!(!m.settlement_window_missed(&d, 1_500));
+        assert!(m.settlement_window_missed(&d, 1_501));
+        // an earlier deal deadline starts the window
+        d.deadline_ts = 800;
+        assert!(m.settlement_window_missed(&d, 1_301));
+        // a settlement already under way may always finish
+        d.settlement_step = settlement::SettlementStep::Started as u8;
+        assert!(!m.settlement_window_missed(&d, 1_000_000));
+    }
 
-#[event]
-pub struct RoleRotationProposed {
-    pub market: Pubkey,
-    pub role: Vec<u8>,
-    pub pending: Pubkey,
-    pub effective_ts: i64,
-}
+    #[test]
+    fn test_settlement_price_dispute_window() {
+        let mut m = test_market();
+        m.price_dispute_window_sec = 100;
+        assert!(!m.settlement_price_final(1_000) && !m.price_challengeable(1_000)); // not fixed yet
 
-#[event]
-pub struct RoleRotationActivated {
-    pub market: Pubkey,
-    pub role: Vec<u8>,
-    pub activated: Pubkey,
-}
+        m.settlement_price_per_kg = 2_000;
+        m.settlement_price_fixed_ts = 1_000;
+        assert!(m.price_challengeable(1_099) && !m.settlement_price_final(1_099));
+        assert!(!m.price_challengeable(1_100) && m.settlement_price_final(1_100));
 
-// ------------------------- Errors -------------------------
-#[error_code]
-pub enum CoffeeError {
-    #[msg("Math overflow")]
-    MathOverflow,
-    #[msg("Zero price")]
-    ZeroPrice,
-    #[msg("Zero quantity")]
-    ZeroQty,
-    #[msg("Zero amount")]
-    ZeroAmount,
-    #[msg("Initial margin must be >= maintenance margin")]
-    BadMarginParams,
-    #[msg("Unauthorized")]
-    Unauthorized,
-    #[msg("Market paused")]
-    MarketPaused,
-    #[msg("Not yet settlement time")]
-    NotYetSettleTime,
-    #[msg("Wrong settlement type")]
-    WrongSettlementType,
-    #[msg("Deal already settled")]
-    DealAlreadySettled,
-    #[msg("Deal not settled")]
-    DealNotSettled,
-    #[msg("Delivered kg exceeds deal quantity")]
-    OverDelivery,
-    #[msg("Mint decimals mismatch")]
-    MintDecimalsMismatch,
-    #[msg("Invalid counterparty")]
-    InvalidCounterparty,
-    #[msg("Invalid asset basket")]
-    InvalidAssetBasket,
-    #[msg("Too many assets in basket")]
-    TooManyAssets,
-    #[msg("Merkle proof missing")]
-    MerkleProofMissing,
-    #[msg("Merkle proof invalid")]
-    MerkleProofInvalid,
-    #[msg("Cannot cancel after both deposited")]
-    CannotCancelAfterBothDeposited,
-    #[msg("Deadline passed")]
-    DeadlinePassed,
-    #[msg("Oracle stale")]
-    OracleStale,
-    #[msg("Oracle price band exceeded")]
-    OraclePriceBandExceeded,
-    #[msg("Replay or stale nonce")]
-    ReplayOrStaleNonce,
-    #[msg("Proof too large")]
-    ProofTooLarge,
-    #[msg("Deal qty exceeds limit")]
-    DealQtyExceedsLimit,
-    #[msg("Deal notional exceeds limit")]
-    DealNotionalExceedsLimit,
-    #[msg("Version mismatch")]
-    VersionMismatch,
-    #[msg("Account not rent exempt")]
-    AccountNotRentExempt,
-    #[msg("Invalid TWAP window")]
-    InvalidTwapWindow,
-    #[msg("Rotation not yet effective")]
-    RotationNotEffectiveYet,
-    #[msg("No pending rotation")]
-    NoPendingRotation,
-}
+        // a challenge holds the price open past the window until the ruling
+        m.settlement_price_challenged = true;
+        assert!(!m.price_challengeable(1_050) && !m.settlement_price_final(5_000));
+        m.settlement_price_challenged = false;
+        m.settlement_price_ruled = true;
+        assert!(m.settlement_price_final(1_050));
 
-// ------------------------- Unit tests -------------------------
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // expiry settlement is held until then, liquidations are not
+        let mut d = Deal::default();
+        m.settlement_ts = 1_000;
+        m.settlement_price_ruled = false;
+        m.settlement_price_challenged = true;
+        assert!(m.settlement_awaits_final_price(&d, 1_050));
+        assert!(!m.settlement_awaits_final_price(&d, 999));
+        d.liquidated = true;
+        assert!(!m.settlement_awaits_final_price(&d, 1_050));
+    }
 
     #[test]
-    fn test_price_band_ok() {
-        // small change ok
-        assert!(is_price_band_ok(1000, 1100, 2000).is_ok()); // 10% delta, max 20%
-        // big change triggers error
-        assert!(is_price_band_ok(1000, 2000, 500).is_err()); // 100% change vs 5% cap
+    fn test_standing_orders_match_inside_both_limits() {
+        let order = |side: OrderSide, limit: u64, kg: u64| StandingOrder {
+            owner: Pubkey::new_unique(),
+            side: side as u8,
+            limit_price_per_kg: limit,
+            remaining_kg: kg,
+            expiry_ts: 1_000,
+            deadline_ts: 5_000,
+            ..Default::default()
+        };
+        let sell = order(OrderSide::Sell, 2_000, 300);
+        let mut buy = order(OrderSide::Buy, 2_100, 200);
+        assert_eq!(plan_standing_match(&sell, &buy, 2_050, 900).unwrap(), 200); // smaller side fills
+        assert!(plan_standing_match(&sell, &buy, 1_999, 900).is_err()); // under the sell limit
+        assert!(plan_standing_match(&sell, &buy, 2_101, 900).is_err()); // over the buy limit
+        assert!(plan_standing_match(&sell, &buy, 2_050, 1_000).is_err()); // expired
+        assert!(plan_standing_match(&buy, &sell, 2_050, 900).is_err()); // sides swapped
+        buy.deadline_ts = 6_000;
+        assert!(plan_standing_match(&sell, &buy, 2_050, 900).is_err()); // different deal deadlines
     }
 
     #[test]
-    fn test_update_twap_accumulates() {
-        let mut m = Market {
-            version: 1,
-            authority: Pubkey::default(),
-            verifier: Pubkey::default(),
-            oracle_publisher: Pubkey::default(),
-            pending_oracle: Pubkey::default(),
-            pending_oracle_effective_ts: 0,
-            cft_mint: Pubkey::default(),
-            quote_mint: Pubkey::default(),
-            insurance_treasury: Pubkey::default(),
-            settlement_ts: 0,
-            contract_size_kg: 0,
-            initial_margin_bps: 0,
-            maintenance_margin_bps: 0,
-            fee_bps: 0,
-            farmer_fee_bps: 0,
-            buyer_fee_bps: 0,
-            insurance_bps: 0,
-            default_margin_call_grace_sec: 0,
-            max_notional_per_deal: 0,
-            max_qty_per_deal: 0,
-            last_price_per_kg: 100,
-            prev_price_per_kg: 0,
-            last_price_nonce: 0,
-            last_oracle_update_ts: 0,
-            max_oracle_age_sec: 3600,
-            twap_acc: 0,
-            twap_time_acc: 0,
-            twap_window_sec: 60,
-            price_mode: PriceMode::TWAP as u8,
-            paused: false,
-            min_transfer_amount: 0,
-            insurance_treasury_authority: Pubkey::default(),
-            program_version: PROGRAM_VERSION,
+    fn test_harvest_auction_ascending_bids() {
+        let farmer = Pubkey::new_unique();
+        let mut auction = HarvestAuction {
+            farmer,
+            reserve_price_per_kg: 2_000,
+            min_increment_per_kg: 50,
+            ends_ts: 1_000,
+            ..Default::default()
         };
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert!(auction.accept_bid(a, 1_950, 100).is_err()); // under the reserve
+        auction.accept_bid(a, 2_000, 100).unwrap();
+        assert_eq!(auction.min_next_bid(), Some(2_050));
+        assert!(auction.accept_bid(b, 2_049, 200).is_err()); // under the increment
+        auction.accept_bid(b, 2_100, 200).unwrap();
+        assert_eq!((auction.best_bidder, auction.best_price_per_kg, auction.bids), (b, 2_100, 2));
+        assert!(auction.accept_bid(farmer, 2_500, 300).is_err()); // farmer can't bid up their own lot
+        assert!(auction.accept_bid(a, 2_500, 1_000).is_err()); // closed at ends_ts
 
-        // first publish: last_oracle_update_ts is 0 -> sets it only
-        let now = 1_700_000_000i64;
-        assert!(update_twap(&mut m, now).is_ok());
-        assert_eq!(m.twap_acc, 0);
-        assert_eq!(m.twap_time_acc, 0);
-        // set last_price and simulate later publish with dt
-        m.last_price_per_kg = 200;
-        let later = now + 10;
-        assert!(update_twap(&mut m, later).is_ok());
-        assert!(m.twap_acc > 0);
-        assert_eq!(m.twap_time_acc, 10u64);
+        // escrows sized at 10% margin stop fitting once the admin raises it
+        auction.quantity_kg = 100;
+        let mut market = Market { initial_margin_bps: 1_000, max_notional_per_deal: 1_000_000, ..test_market() };
+        assert!(auction_winner_fits(&market, &auction, 21_000, 21_000).unwrap());
+        market.initial_margin_bps = 1_500;
+        assert!(!auction_winner_fits(&market, &auction, 21_000, 21_000).unwrap());
+        market.initial_margin_bps = 1_000;
+        market.max_notional_per_deal = 200_000; // 2_100 * 100 over the limit
+        assert!(!auction_winner_fits(&market, &auction, 21_000, 21_000).unwrap());
     }
 
     #[test]
-    fn test_rent_is_exempt_behavior() {
-        // Rent::default() exists and is_exempt must return false for 0 lamports and true for huge lamports
-        let rent = Rent::default();
-        assert!(!rent.is_exempt(0, 10));
-        assert!(rent.is_exempt(u64::MAX / 4, 10));
+    fn test_price_trigger_directions() {
+        let mut trigger = PriceTrigger { direction: TriggerDirection::Below as u8, threshold_price_per_kg: 1_800, ..Default::default() };
+        assert!(trigger.is_met(1_800) && trigger.is_met(1_500));
+        assert!(!trigger.is_met(1_801));
+        trigger.direction = TriggerDirection::Above as u8;
+        assert!(trigger.is_met(1_800) && trigger.is_met(2_500));
+        assert!(!trigger.is_met(1_799));
+        assert!(TriggerDirection::from_u8(2).is_err());
+    }
+
+    #[test]
+    fn test_stop_triggered_by_side() {
+        let mut deal = Deal { agreed_price_per_kg: 2_000, ..Deal::default() };
+        assert_eq!(deal.stop_triggered(5_000), None); // no stops set
+        deal.farmer_stop_price_per_kg = 2_400;
+        deal.buyer_stop_price_per_kg = 1_700;
+        assert_eq!(deal.stop_triggered(2_399), None);
+        assert_eq!(deal.stop_triggered(2_400), Some(math::Side::Short));
+        assert_eq!(deal.stop_triggered(1_701), None);
+        assert_eq!(deal.stop_triggered(1_700), Some(math::Side::Long));
+
+        // a long farmer's stop sits below the agreed price, the short buyer's above
+        deal.farmer_side = OrderSide::Buy as u8;
+        deal.farmer_stop_price_per_kg = 1_700;
+        deal.buyer_stop_price_per_kg = 2_400;
+        assert_eq!(deal.stop_triggered(1_700), Some(math::Side::Short));
+        assert_eq!(deal.stop_triggered(2_400), Some(math::Side::Long));
+        assert_eq!(deal.stop_triggered(2_000), None);
+    }
+
+    #[test]
+    fn test_omnibus_migration_eligibility() {
+        let live = Deal { farmer_deposited: true, buyer_deposited: true, ..Deal::default() };
+        assert!(live.omnibus_migratable());
+        assert!(!Deal { farmer_deposited: false, ..live.clone() }.omnibus_migratable()); // not funded yet
+        assert!(!Deal { omnibus: true, ..live.clone() }.omnibus_migratable());
+        assert!(!Deal { physical_delivery: true, ..live.clone() }.omnibus_migratable());
+        assert!(!Deal { fx_oracle: Pubkey::new_unique(), ..live.clone() }.omnibus_migratable()); // hedged
+        assert!(!Deal { buyer_margin_account: Pubkey::new_unique(), ..live.clone() }.omnibus_migratable());
+        assert!(!Deal { settlement_step: settlement::SettlementStep::Started as u8, ..live }.omnibus_migratable());
+    }
+
+    #[test]
+    fn test_fee_waiver_redeem_and_discount() {
+        let (farmer, buyer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut promo = FeeWaiver { discount_bps: 5_000, expires_ts: 1_000, max_uses: 2, ..Default::default() };
+        assert_eq!(promo.redeem(&farmer, &buyer, 10).unwrap(), 5_000);
+        assert_eq!(promo.redeem(&buyer, &farmer, 20).unwrap(), 5_000);
+        assert_eq!(promo.redeem(&farmer, &buyer, 30).unwrap_err(), CoffeeError::FeeWaiverUsedUp.into());
+
+        let mut hardship = FeeWaiver { beneficiary: farmer, discount_bps: MAX_BPS, expires_ts: 1_000, max_uses: 1, ..Default::default() };
+        assert_eq!(hardship.redeem(&Pubkey::new_unique(), &buyer, 10).unwrap_err(), CoffeeError::FeeWaiverNotForTrader.into());
+        assert_eq!(hardship.redeem(&farmer, &buyer, 1_000).unwrap_err(), CoffeeError::FeeWaiverExpired.into());
+        assert_eq!(hardship.discount_for(&farmer, &buyer, 999).unwrap(), MAX_BPS);
+        assert_eq!(hardship.uses, 0); // checking does not use it
+        assert_eq!(hardship.redeem(&farmer, &buyer, 999).unwrap(), MAX_BPS);
+        assert_eq!(hardship.discount_for(&farmer, &buyer, 999).unwrap_err(), CoffeeError::FeeWaiverUsedUp.into());
+
+        let mut deal = Deal::default();
+        assert_eq!(deal.fee_bearing_notional(1_000_001).unwrap(), 1_000_001);
+        deal.fee_waiver_bps = 2_500;
+        assert_eq!(deal.fee_bearing_notional(1_000_001).unwrap(), 750_000);
+        deal.fee_waiver_bps = MAX_BPS;
+        assert_eq!(deal.fee_bearing_notional(1_000_001).unwrap(), 0);
+
+        // the up-front premium every open path charges follows the same waiver
+        let mut m = test_market();
+        assert_eq!(upfront_premium(&m, 1_000_000, 2_500).unwrap(), 0); // not collected up front
+        m.insurance_upfront = true;
+        m.fee_bps = 100;
+        m.insurance_bps = 2_000;
+        let full = fee_split(1_000_000, m.fee_bps, &m).unwrap().insurance_cut;
+        assert!(full > 0);
+        assert_eq!(upfront_premium(&m, 1_000_000, 0).unwrap(), full);
+        assert_eq!(upfront_premium(&m, 1_000_000, 2_500).unwrap(), fee_split(750_000, m.fee_bps, &m).unwrap().insurance_cut);
+        assert_eq!(upfront_premium(&m, 1_000_000, MAX_BPS).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_deal_sides_by_wallet() {
+        let (farmer, buyer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let deal = Deal { farmer, buyer, agreed_price_per_kg: 1_500, ..Default::default() };
+        assert_eq!(deal.side_of(&farmer), Some(math::Side::Short));
+        assert_eq!(deal.side_of(&buyer), Some(math::Side::Long));
+        assert_eq!(deal.side_of(&Pubkey::new_unique()), None);
+        for side in [math::Side::Short, math::Side::Long] {
+            assert_eq!(deal.side_of(&deal.party(side)), Some(side));
+            assert_ne!(deal.party(side.opposite()), deal.party(side));
+            assert_eq!(deal.position(side), side); // the farmer sells by default
+        }
+
+        // a farmer going long keeps its slot (vaults, agents, sponsor) but holds the long position
+        let m = test_market();
+        let long_farmer = Deal { farmer_side: OrderSide::Buy as u8, ..deal.clone() };
+        assert_eq!(long_farmer.position(math::Side::Short), math::Side::Long);
+        assert_eq!(long_farmer.position(math::Side::Long), math::Side::Short);
+        assert!(long_farmer.slot_pnl(&m, 1_800, 10, math::Side::Short).unwrap() > 0);
+        assert_eq!(
+            long_farmer.slot_pnl(&m, 1_800, 10, math::Side::Long).unwrap(),
+            deal.slot_pnl(&m, 1_800, 10, math::Side::Short).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_farmer_own_margin_on_sponsored_deal() {
+        let mut deal = Deal::default();
+        deal.credit_farmer_own(math::Side::Short, 500).unwrap();
+        assert_eq!(deal.farmer_own_margin, 0); // unsponsored: nothing to keep apart
+
+        deal.sponsored_margin = 5_000;
+        deal.farmer_own_margin = 4_000;
+        deal.credit_farmer_own(math::Side::Long, 700).unwrap();
+        deal.credit_farmer_own(math::Side::Short, 1_000).unwrap();
+        assert_eq!(deal.farmer_own_margin, 5_000);
+        // the farmer's top-up settles ahead of the stake: a 3_000 loss on 10_000 comes out of the sponsor
+        assert_eq!(settlement::plan_sponsor_return(deal.sponsored_margin, deal.farmer_own_margin, 7_000), (2_000, 3_000));
+    }
+
+    #[test]
+    fn test_deal_margin_vaults_follow_layout() {
+        let (deal, mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let vault_auth = pda::vault_auth(&deal).0;
+        let ata = anchor_spl::associated_token::get_associated_token_address(&vault_auth, &mint);
+        assert_eq!(deal_margin_vaults(true, &deal, &vault_auth, &mint), [ata, pda::buyer_margin_vault(&deal).0]);
+        // without split vaults the farmer ATA is the only vault, so it can't stand in for a buyer vault PDA
+        assert_eq!(deal_margin_vaults(false, &deal, &vault_auth, &mint), [ata, ata]);
+    }
+
+    #[test]
+    fn test_default_price_fresh_mark_or_final_price() {
+        let mut m = test_market();
+        m.price_mode = PriceMode::LastPrice as u8;
+        m.settlement_ts = 1_000;
+        m.price_dispute_window_sec = 100;
+        // no feed yet, and a stale one, are refused
+        assert!(m.default_price(500).is_err());
+        m.last_oracle_update_ts = 500;
+        assert!(m.default_price(500 + 3_601).is_err());
+        assert_eq!(m.default_price(600).unwrap(), 100);
+
+        // past settlement_ts a fixed price is used once final; until then the mark must still be fresh
+        m.settlement_price_per_kg = 2_000;
+        m.settlement_price_fixed_ts = 1_000;
+        assert_eq!(m.default_price(1_050).unwrap(), 100);
+        assert_eq!(m.default_price(5_000).unwrap(), 2_000);
+    }
+
+    #[test]
+    fn test_margin_vesting_is_linear() {
+        let mut v = MarginVesting::default();
+        assert_eq!(v.claimable(1_000), 0); // not funded
+        v.start(Pubkey::new_unique(), 1_000, 1_000, 100).unwrap();
+        assert!(v.start(Pubkey::new_unique(), 1, 1_000, 100).is_err());
+        assert_eq!(v.vested(1_000), 0);
+        assert_eq!(v.vested(1_033), 330);
+        v.claimed = 330;
+        assert_eq!(v.claimable(1_050), 170);
+        assert_eq!(v.claimable(1_100), 670);
+        assert_eq!(v.vested(5_000), 1_000);
+
+        // a zero-length schedule would not reach start(); from end_ts everything has vested
+        let mut instant = MarginVesting::default();
+        instant.start(Pubkey::new_unique(), 7, 10, 1).unwrap();
+        assert_eq!(instant.claimable(11), 7);
+    }
+}
+
+// ------------------------- Property tests -------------------------
+// Randomized checks of the settlement arithmetic (proptest dev-dependency).
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Balance-tracking model of the settle_cash quote-fee waterfall: fees, insurance, PnL, residuals.
+    struct CashSettlement {
+        fees: u64,
+        insurance: u64,
+        buyer_out: u64,
+        farmer_out: u64,
+        shortfall: u64,
+    }
+
+    fn simulate_cash_settlement(farmer_vault: u64, buyer_vault: u64, pnl_long: i128, split: math::FeeSplit) -> CashSettlement {
+        let (mut farmer, mut buyer) = (farmer_vault, buyer_vault);
+        let farmer_fee = split.farmer_cut.saturating_add(split.protocol_cut).min(farmer);
+        farmer -= farmer_fee;
+        let buyer_fee = split.buyer_cut.min(buyer);
+        buyer -= buyer_fee;
+        let ins_buyer = split.insurance_cut.min(buyer);
+        buyer -= ins_buyer;
+        let ins_farmer = (split.insurance_cut - ins_buyer).min(farmer);
+        farmer -= ins_farmer;
+
+        let (mut buyer_out, mut farmer_out, mut shortfall) = (0u64, 0u64, 0u64);
+        let owed = pnl_long.unsigned_abs() as u64;
+        if pnl_long > 0 {
+            let pay = owed.min(farmer);
+            farmer -= pay;
+            buyer_out += pay;
+            shortfall = owed - pay;
+        } else if pnl_long < 0 {
+            let pay = owed.min(buyer);
+            buyer -= pay;
+            farmer_out += pay;
+            shortfall = owed - pay;
+        }
+        CashSettlement {
+            fees: farmer_fee + buyer_fee,
+            insurance: ins_buyer + ins_farmer,
+            buyer_out: buyer_out + buyer,
+            farmer_out: farmer_out + farmer,
+            shortfall,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_pnl_is_antisymmetric(agreed: u64, mark: u64, qty: u64) {
+            let long = math::pnl(agreed, mark, qty, math::Side::Long).unwrap();
+            let short = math::pnl(agreed, mark, qty, math::Side::Short).unwrap();
+            prop_assert_eq!(long, -short);
+        }
+
+        #[test]
+        fn prop_pnl_never_overflows_for_u64_inputs(agreed: u64, mark: u64, qty: u64) {
+            // |mark - agreed| < 2^64 and qty < 2^64, so the product fits in i128
+            prop_assert!(math::pnl(agreed, mark, qty, math::Side::Long).is_some());
+        }
+
+        #[test]
+        fn prop_bps_mul_bounded_and_monotonic(x in 0u128..=(u64::MAX as u128) * 1_000_000, a in 0u16..=10_000, b in 0u16..=10_000) {
+            let ra = bps_mul_u128(x, a).unwrap();
+            let rb = bps_mul_u128(x, b).unwrap();
+            prop_assert!(ra <= x);
+            if a <= b {
+                prop_assert!(ra <= rb);
+            }
+            // rounds down: never more than one unit below the exact value
+            prop_assert!(ra * 10_000 <= x * a as u128 && x * a as u128 - ra * 10_000 < 10_000);
+        }
+
+        #[test]
+        fn prop_bps_mul_overflow_is_an_error(x in (u128::MAX / 2)..=u128::MAX, bps in 2u16..=10_000) {
+            prop_assert!(bps_mul_u128(x, bps).is_err());
+        }
+
+        #[test]
+        fn prop_twap_stays_within_published_prices(
+            window in 1u64..=3_600,
+            steps in proptest::collection::vec((1u64..=1_000_000, 1i64..=7_200), 1..32),
+        ) {
+            let mut m = test_market();
+            m.twap_window_sec = window;
+            m.twap_acc = 0;
+            m.twap_time_acc = 0;
+            m.last_oracle_update_ts = 1;
+            let mut now = 1i64;
+            let (mut lo, mut hi) = (u64::MAX, 0u64);
+            for (price, dt) in steps {
+                m.last_price_per_kg = price;
+                lo = lo.min(price);
+                hi = hi.max(price);
+                now += dt;
+                update_twap(&mut m, now).unwrap();
+                prop_assert!(m.twap_time_acc <= window);
+                prop_assert_eq!(m.last_oracle_update_ts, now);
+                let twap = mark_price(&Market { price_mode: 1, ..m.clone() }).unwrap();
+                // window rescaling and the final division each truncate by less than one unit
+                prop_assert!(twap >= lo.saturating_sub(2) && twap <= hi, "twap {} outside [{}, {}]", twap, lo, hi);
+            }
+        }
+
+        #[test]
+        fn prop_fee_split_parts_sum_to_total(
+            notional in 0u128..=(u64::MAX as u128),
+            fee_bps in 0u16..=10_000,
+            farmer_bps in 0u16..=5_000,
+            buyer_bps in 0u16..=5_000,
+        ) {
+            let insurance_bps = 10_000 - farmer_bps - buyer_bps;
+            let s = math::fee_split(notional, fee_bps, farmer_bps, buyer_bps, insurance_bps).unwrap();
+            prop_assert_eq!(s.farmer_cut + s.buyer_cut + s.insurance_cut + s.protocol_cut, s.fee_total);
+            prop_assert!(s.fee_total as u128 <= notional);
+        }
+
+        #[test]
+        fn prop_cash_settlement_conserves_vaults(
+            farmer_vault in 0u64..=1_000_000_000_000,
+            buyer_vault in 0u64..=1_000_000_000_000,
+            agreed in 1u64..=1_000_000,
+            mark in 1u64..=1_000_000,
+            qty in 1u64..=1_000_000,
+            fee_bps in 0u16..=1_000,
+        ) {
+            let notional = math::notional(agreed, qty).unwrap();
+            let split = math::fee_split(notional, fee_bps, 2_500, 2_500, 1_000).unwrap();
+            let pnl_long = math::pnl(agreed, mark, qty, math::Side::Long).unwrap();
+            let out = simulate_cash_settlement(farmer_vault, buyer_vault, pnl_long, split);
+            // fees + payouts + residuals == initial vault balances
+            prop_assert_eq!(
+                out.fees as u128 + out.insurance as u128 + out.buyer_out as u128 + out.farmer_out as u128,
+                farmer_vault as u128 + buyer_vault as u128
+            );
+            prop_assert!(out.shortfall <= pnl_long.unsigned_abs() as u64);
+        }
     }
 }
@@ -0,0 +1,261 @@
+// Pure settlement arithmetic shared by the program and off-chain clients.
+// Rounding policy: amounts a trader pays (fees, haircuts) round up, amounts a trader receives
+// round down; whatever the truncation leaves in a vault is swept to insurance as dust.
+// Only `core` is used here (no anchor_lang types, no allocation), so keepers and front-ends can
+// depend on it with `features = ["client"]` and round exactly the way the program does.
+// Every function returns None on overflow; the program maps that to CoffeeError::MathOverflow.
+
+pub const BPS_DENOMINATOR: u128 = 10_000;
+
+// International pound: 1 lb = 0.45359237 kg exactly
+pub const LB_IN_KG_NUM: u128 = 45_359_237;
+pub const LB_IN_KG_DEN: u128 = 100_000_000;
+// cents-per-pound quotes carry two decimals: 21_550 = 215.50 ¢/lb (KC futures tick in 0.05 ¢)
+pub const CENTS_LB_SCALE: u128 = 100;
+
+// A market position. The program also indexes a deal's two slots by it (Short = the farmer's,
+// Long = the buyer's); Deal::position maps a slot to the position its party holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Long,
+    Short,
+}
+
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Long => Side::Short,
+            Side::Short => Side::Long,
+        }
+    }
+}
+
+// x * bps / 10_000, rounded down
+pub fn bps_mul(x: u128, bps: u16) -> Option<u128> {
+    x.checked_mul(bps as u128)?.checked_div(BPS_DENOMINATOR)
+}
+
+pub fn bps_of(x: u64, bps: u16) -> Option<u64> {
+    u64::try_from(bps_mul(x as u128, bps)?).ok()
+}
+
+// x * bps / 10_000, rounded up (charges)
+pub fn bps_mul_up(x: u128, bps: u16) -> Option<u128> {
+    div_ceil(x.checked_mul(bps as u128)?, BPS_DENOMINATOR)
+}
+
+pub fn div_ceil(n: u128, d: u128) -> Option<u128> {
+    if d == 0 {
+        return None;
+    }
+    Some(n / d + (n % d != 0) as u128)
+}
+
+// n / d rounded half up (price conversions, where neither side is paying)
+pub fn div_round(n: u128, d: u128) -> Option<u128> {
+    if d == 0 {
+        return None;
+    }
+    n.checked_add(d / 2).map(|n| n / d)
+}
+
+pub fn notional(price_per_kg: u64, quantity_kg: u64) -> Option<u128> {
+    (price_per_kg as u128).checked_mul(quantity_kg as u128)
+}
+
+// Long PnL: (mark - agreed) * qty; Short PnL is negative of long
+pub fn pnl(agreed: u64, mark: u64, qty: u64, side: Side) -> Option<i128> {
+    let agreed = agreed as i128;
+    let mark = mark as i128;
+    let diff = match side {
+        Side::Long => mark.checked_sub(agreed)?,
+        Side::Short => agreed.checked_sub(mark)?,
+    };
+    diff.checked_mul(qty as i128)
+}
+
+// Margin for a position of this size at `margin_bps` (initial or maintenance)
+pub fn required_margin(price_per_kg: u64, quantity_kg: u64, margin_bps: u16) -> Option<u64> {
+    u64::try_from(bps_mul(notional(price_per_kg, quantity_kg)?, margin_bps)?).ok()
+}
+
+// Price units (price_per_kg * kg) -> quote base units: x * 10^price_exponent, rounded down when the
+// exponent is negative. Exponent 0 means prices are already quoted in quote base units per kg
+pub fn to_quote_units(x: u128, price_exponent: i8) -> Option<u128> {
+    let scale = 10u128.checked_pow(price_exponent.unsigned_abs() as u32)?;
+    if price_exponent >= 0 {
+        x.checked_mul(scale)
+    } else {
+        Some(x / scale)
+    }
+}
+
+// Signed variant for PnL; truncates toward zero so payer and receiver agree on the amount
+pub fn to_quote_units_signed(x: i128, price_exponent: i8) -> Option<i128> {
+    let scale = 10i128.checked_pow(price_exponent.unsigned_abs() as u32)?;
+    if price_exponent >= 0 {
+        x.checked_mul(scale)
+    } else {
+        Some(x / scale)
+    }
+}
+
+// notional, pnl and required_margin in quote base units for a market's price_exponent
+pub fn quote_notional(price_per_kg: u64, quantity_kg: u64, price_exponent: i8) -> Option<u128> {
+    to_quote_units(notional(price_per_kg, quantity_kg)?, price_exponent)
+}
+
+pub fn quote_pnl(agreed: u64, mark: u64, qty: u64, side: Side, price_exponent: i8) -> Option<i128> {
+    to_quote_units_signed(pnl(agreed, mark, qty, side)?, price_exponent)
+}
+
+pub fn quote_required_margin(price_per_kg: u64, quantity_kg: u64, margin_bps: u16, price_exponent: i8) -> Option<u64> {
+    u64::try_from(bps_mul(quote_notional(price_per_kg, quantity_kg, price_exponent)?, margin_bps)?).ok()
+}
+
+// ¢/lb quote (see CENTS_LB_SCALE) -> price per kg in price units, one US cent being
+// `price_units_per_cent` of them. Rounded half up; price_per_kg_to_cents_lb inverts it exactly as long
+// as a price unit is finer than a hundredth of a cent per lb
+pub fn cents_lb_to_price_per_kg(cents_lb: u64, price_units_per_cent: u64) -> Option<u64> {
+    let n = (cents_lb as u128).checked_mul(price_units_per_cent as u128)?.checked_mul(LB_IN_KG_DEN)?;
+    u64::try_from(div_round(n, CENTS_LB_SCALE * LB_IN_KG_NUM)?).ok()
+}
+
+pub fn price_per_kg_to_cents_lb(price_per_kg: u64, price_units_per_cent: u64) -> Option<u64> {
+    let n = (price_per_kg as u128).checked_mul(CENTS_LB_SCALE * LB_IN_KG_NUM)?;
+    u64::try_from(div_round(n, (price_units_per_cent as u128).checked_mul(LB_IN_KG_DEN)?)?).ok()
+}
+
+// |price - reference| in bps of reference, rounded down; None if reference is 0
+pub fn deviation_bps(reference: u64, price: u64) -> Option<u128> {
+    if reference == 0 {
+        return None;
+    }
+    let delta = (reference as u128).abs_diff(price as u128);
+    delta.checked_mul(BPS_DENOMINATOR)?.checked_div(reference as u128)
+}
+
+// Time-weighted average from the market accumulators; None until some time has accrued
+pub fn twap(twap_acc: u128, twap_time_acc: u64) -> Option<u64> {
+    if twap_time_acc == 0 {
+        return None;
+    }
+    u64::try_from(twap_acc / twap_time_acc as u128).ok()
+}
+
+// Exponentially weighted variance of per-update returns, in bps^2: var + (r^2 - var) / span
+pub fn ewma_variance(var_bps2: u64, return_bps: u64, span: u64) -> Option<u64> {
+    if span == 0 {
+        return None;
+    }
+    let sample = (return_bps as u128).checked_mul(return_bps as u128)?;
+    let var = var_bps2 as u128;
+    let next = if sample >= var { var + (sample - var) / span as u128 } else { var - (var - sample) / span as u128 };
+    u64::try_from(next).ok()
+}
+
+// Floor square root (Newton's method)
+pub fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+// Maintenance margin scaled by vol / ref_vol once volatility exceeds the reference, capped at max_bps;
+// calm markets keep the base rate
+pub fn vol_scaled_bps(base_bps: u16, vol_bps: u64, ref_vol_bps: u16, max_bps: u16) -> Option<u16> {
+    if ref_vol_bps == 0 || vol_bps <= ref_vol_bps as u64 {
+        return Some(base_bps);
+    }
+    let scaled = (base_bps as u128).checked_mul(vol_bps as u128)? / ref_vol_bps as u128;
+    Some(scaled.min(max_bps.max(base_bps) as u128) as u16)
+}
+
+// Settlement fee on notional (rounded up), split the way settle_cash routes it; the shares round
+// down and the protocol takes the remainder, so the parts always sum to fee_total
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeSplit {
+    pub fee_total: u64,
+    pub farmer_cut: u64,
+    pub buyer_cut: u64,
+    pub insurance_cut: u64,
+    pub protocol_cut: u64, // remainder after the three shares
+}
+
+pub fn fee_split(notional: u128, fee_bps: u16, farmer_bps: u16, buyer_bps: u16, insurance_bps: u16) -> Option<FeeSplit> {
+    let fee_total = u64::try_from(bps_mul_up(notional, fee_bps)?).ok()?;
+    let farmer_cut = bps_of(fee_total, farmer_bps)?;
+    let buyer_cut = bps_of(fee_total, buyer_bps)?;
+    let insurance_cut = bps_of(fee_total, insurance_bps)?;
+    let protocol_cut = fee_total
+        .checked_sub(farmer_cut)?
+        .checked_sub(buyer_cut)?
+        .checked_sub(insurance_cut)?;
+    Some(FeeSplit { fee_total, farmer_cut, buyer_cut, insurance_cut, protocol_cut })
+}
+
+// Farmer-side PnL in quote units on an FX forward selling `notional` quote at `locked_rate` (local
+// currency per quote unit), fixed at `spot_rate`: notional * (locked - spot) / spot, truncated toward
+// zero so the winner never receives the rounding
+pub fn fx_forward_pnl(notional: u64, locked_rate: u64, spot_rate: u64) -> Option<i128> {
+    if spot_rate == 0 {
+        return None;
+    }
+    let diff = (locked_rate as i128).checked_sub(spot_rate as i128)?;
+    diff.checked_mul(notional as i128)?.checked_div(spot_rate as i128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pnl_and_fee_split() {
+        assert_eq!(pnl(1_500, 1_800, 10, Side::Long), Some(3_000));
+        assert_eq!(pnl(1_500, 1_800, 10, Side::Short), Some(-3_000));
+        assert_eq!(required_margin(1_500, 10, 1_000), Some(1_500));
+        assert_eq!(twap(0, 0), None);
+        assert_eq!(twap(1_500 * 60, 60), Some(1_500));
+        assert_eq!(deviation_bps(1_500, 1_650), Some(1_000));
+        assert_eq!(deviation_bps(1_500, 1_350), Some(1_000));
+        assert_eq!(deviation_bps(0, 1_500), None);
+        assert_eq!(ewma_variance(0, 100, 10), Some(1_000));
+        assert_eq!(ewma_variance(1_000, 0, 10), Some(900));
+        assert_eq!(isqrt(0), 0);
+        assert_eq!(isqrt(1_000_000), 1_000);
+        assert_eq!(isqrt(999_999), 999);
+        assert_eq!(vol_scaled_bps(500, 100, 200, 1_000), Some(500)); // below reference
+        assert_eq!(vol_scaled_bps(500, 300, 200, 1_000), Some(750));
+        assert_eq!(vol_scaled_bps(500, 1_000, 200, 1_000), Some(1_000)); // capped
+        assert_eq!(vol_scaled_bps(500, 1_000, 0, 1_000), Some(500)); // scaling off
+
+        let split = fee_split(1_000_000, 50, 2_500, 2_500, 1_000).unwrap();
+        assert_eq!(split.fee_total, 5_000);
+        assert_eq!((split.farmer_cut, split.buyer_cut, split.insurance_cut, split.protocol_cut), (1_250, 1_250, 500, 2_000));
+        // fees round up: 1 unit of notional at 50 bps still pays 1
+        assert_eq!(fee_split(1, 50, 2_500, 2_500, 1_000).unwrap().fee_total, 1);
+        assert_eq!(bps_mul_up(10_001, 1), Some(2));
+        assert_eq!(bps_mul(10_001, 1), Some(1));
+        // shares above 100% of the fee don't fit
+        assert_eq!(fee_split(1_000_000, 50, 6_000, 6_000, 0), None);
+    }
+
+    #[test]
+    fn test_fx_forward_pnl() {
+        // farmer locked 4_000 local per quote; the quote weakened to 3_200: the farmer is owed a quarter
+        assert_eq!(fx_forward_pnl(10_000, 4_000, 3_200), Some(2_500));
+        // the quote strengthened to 5_000: the farmer pays a fifth
+        assert_eq!(fx_forward_pnl(10_000, 4_000, 5_000), Some(-2_000));
+        assert_eq!(fx_forward_pnl(10_000, 4_000, 4_000), Some(0));
+        assert_eq!(fx_forward_pnl(10, 4_000, 3_000), Some(3)); // 3.33 truncated
+        assert_eq!(fx_forward_pnl(10, 3_000, 4_000), Some(-2)); // -2.5 truncated
+        assert_eq!(fx_forward_pnl(10_000, 4_000, 0), None);
+    }
+}
@@ -0,0 +1,330 @@
+// PDA derivation for clients.
+// Each helper builds the same seeds as the account constraints in lib.rs, from the exported seed
+// constants, and returns (address, bump) for this program id. Keepers and front-ends should derive
+// through here (or the IDL constants) rather than hand-writing seed bytes.
+use super::*;
+
+fn find(seeds: &[&[u8]]) -> (Pubkey, u8) {
+    Pubkey::find_program_address(seeds, &crate::ID)
+}
+
+// ---- market scope
+
+pub fn market(authority: &Pubkey, cft_mint: &Pubkey, quote_mint: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, MARKET_SEED, authority.as_ref(), cft_mint.as_ref(), quote_mint.as_ref()])
+}
+
+// where migrate_market_v2 re-creates a market (Market.seed_version 2); `authority` is the current one
+pub fn market_v2(authority: &Pubkey, cft_mint: &Pubkey, quote_mint: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX_V2, MARKET_SEED, authority.as_ref(), cft_mint.as_ref(), quote_mint.as_ref()])
+}
+
+pub fn access_control(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, ACCESS_SEED, market.as_ref()])
+}
+
+pub fn approved_assets(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, APPROVED_ASSETS_SEED, market.as_ref()])
+}
+
+// the farmer's margin escrow is this PDA's ATA
+pub fn harvest_auction(market: &Pubkey, farmer: &Pubkey, auction_id: u64) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, AUCTION_SEED, market.as_ref(), farmer.as_ref(), &auction_id.to_le_bytes()])
+}
+
+// a bidder's escrow is this PDA's ATA
+pub fn auction_bid(auction: &Pubkey, bidder: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, AUCTION_BID_SEED, auction.as_ref(), bidder.as_ref()])
+}
+
+// margin escrow is this PDA's ATA
+pub fn standing_order(market: &Pubkey, owner: &Pubkey, order_id: u64) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, STANDING_ORDER_SEED, market.as_ref(), owner.as_ref(), &order_id.to_le_bytes()])
+}
+
+// holds the challenger's bond in its ATA
+pub fn price_challenge(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, PRICE_CHALLENGE_SEED, market.as_ref()])
+}
+
+pub fn price_trigger(market: &Pubkey, owner: &Pubkey, trigger_id: u64) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, PRICE_TRIGGER_SEED, market.as_ref(), owner.as_ref(), &trigger_id.to_le_bytes()])
+}
+
+pub fn fee_waiver(market: &Pubkey, waiver_id: u64) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, FEE_WAIVER_SEED, market.as_ref(), &waiver_id.to_le_bytes()])
+}
+
+pub fn market_cft_mint(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, MARKET_CFT_SEED, market.as_ref()])
+}
+
+// mint authority of a CFT mint (shared or per-market)
+pub fn cft_mint_auth(cft_mint: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, CFT_AUTH_SEED, cft_mint.as_ref()])
+}
+
+pub fn cft_allowlist(market: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, CFT_ALLOW_SEED, market.as_ref(), wallet.as_ref()])
+}
+
+pub fn trader_stats(market: &Pubkey, trader: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, TRADER_STATS_SEED, market.as_ref(), trader.as_ref()])
+}
+
+pub fn credit_line(market: &Pubkey, buyer: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, CREDIT_LINE_SEED, market.as_ref(), buyer.as_ref()])
+}
+
+pub fn agent_grant(market: &Pubkey, farmer: &Pubkey, agent: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, AGENT_SEED, market.as_ref(), farmer.as_ref(), agent.as_ref()])
+}
+
+pub fn session(market: &Pubkey, session_key: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, SESSION_SEED, market.as_ref(), session_key.as_ref()])
+}
+
+pub fn template(market: &Pubkey, template_id: u32) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, TEMPLATE_SEED, market.as_ref(), &template_id.to_le_bytes()])
+}
+
+pub fn index_feed(market: &Pubkey, feed_id: u8) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, INDEX_FEED_SEED, market.as_ref(), &[feed_id]])
+}
+
+pub fn fx_oracle(market: &Pubkey, currency: &[u8; 3]) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, FX_ORACLE_SEED, market.as_ref(), currency.as_ref()])
+}
+
+pub fn tree_authority(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, TREE_AUTH_SEED, market.as_ref()])
+}
+
+pub fn governance(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, GOVERNANCE_SEED, market.as_ref()])
+}
+
+pub fn proposal(governance: &Pubkey, index: u64) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, PROPOSAL_SEED, governance.as_ref(), &index.to_le_bytes()])
+}
+
+pub fn vote(proposal: &Pubkey, voter: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, VOTE_SEED, proposal.as_ref(), voter.as_ref()])
+}
+
+pub fn crop_insurance(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, CROP_INSURANCE_SEED, market.as_ref()])
+}
+
+pub fn liquidation_queue(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, LIQUIDATION_QUEUE_SEED, market.as_ref()])
+}
+
+// owner of the staging vault (its quote ATA) open_deals_batch passes margin through
+pub fn batch_auth(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, BATCH_AUTH_SEED, market.as_ref()])
+}
+
+// signer for the market's two omnibus margin vaults
+pub fn omnibus_auth(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, OMNIBUS_SEED, market.as_ref()])
+}
+
+pub fn omnibus_vault(market: &Pubkey, side: math::Side) -> (Pubkey, u8) {
+    let side = match side {
+        math::Side::Short => 0u8, // farmer
+        math::Side::Long => 1u8,  // buyer
+    };
+    find(&[SEED_PREFIX, OMNIBUS_VAULT_SEED, market.as_ref(), &[side]])
+}
+
+// the crank rebate fee pot is this PDA's quote ATA
+pub fn settlement_queue(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, SETTLEMENT_QUEUE_SEED, market.as_ref()])
+}
+
+// the bond vault is this PDA's quote ATA
+pub fn liquidator_license(market: &Pubkey, liquidator: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, LIQUIDATOR_LICENSE_SEED, market.as_ref(), liquidator.as_ref()])
+}
+
+// ---- pools
+
+pub fn stake_pool(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, STAKE_POOL_SEED, market.as_ref()])
+}
+
+pub fn stake_position(stake_pool: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, STAKE_SEED, stake_pool.as_ref(), owner.as_ref()])
+}
+
+pub fn insurance_pool(market: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, INSURANCE_POOL_SEED, market.as_ref()])
+}
+
+pub fn insurance_stake(insurance_pool: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, INSURANCE_STAKE_SEED, insurance_pool.as_ref(), owner.as_ref()])
+}
+
+pub fn margin_account(market: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, MARGIN_ACCOUNT_SEED, market.as_ref(), owner.as_ref()])
+}
+
+pub fn margin_pool(margin_account: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, MARGIN_POOL_SEED, margin_account.as_ref()])
+}
+
+pub fn risk_snapshot(margin_account: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, RISK_SEED, margin_account.as_ref()])
+}
+
+// ---- deal scope
+
+pub fn deal(market: &Pubkey, farmer: &Pubkey, buyer: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, DEAL_SEED, market.as_ref(), farmer.as_ref(), buyer.as_ref()])
+}
+
+// where migrate_deal_v2 re-homes a deal (Deal.seed_version 2)
+pub fn deal_v2(market: &Pubkey, farmer: &Pubkey, buyer: &Pubkey, deal_id: u64) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX_V2, DEAL_SEED, market.as_ref(), farmer.as_ref(), buyer.as_ref(), &deal_id.to_le_bytes()])
+}
+
+// owner of the deal's margin vaults and purchase escrow; the farmer vault is its ATA
+pub fn vault_auth(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, VAULT_AUTH_SEED, deal.as_ref()])
+}
+
+// the buyer's margin vault on deals from open_deal; batch, standing order and auction deals have none
+pub fn buyer_margin_vault(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, BUYER_VAULT_SEED, deal.as_ref()])
+}
+
+pub fn sample(market: &Pubkey, farmer: &Pubkey, buyer: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, SAMPLE_SEED, market.as_ref(), farmer.as_ref(), buyer.as_ref()])
+}
+
+pub fn purchase_escrow(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, ESCROW_SEED, deal.as_ref()])
+}
+
+// a deal's MarginVesting and the vault (owned by vault_auth) holding what vests
+pub fn margin_vesting(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, VESTING_SEED, deal.as_ref()])
+}
+
+pub fn vesting_vault(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, VESTING_VAULT_SEED, deal.as_ref()])
+}
+
+pub fn debt(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, DEBT_SEED, deal.as_ref()])
+}
+
+pub fn crop_policy(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, CROP_POLICY_SEED, deal.as_ref()])
+}
+
+pub fn delivery_record(deal: &Pubkey, leaf: &[u8; 32]) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, DELIVERY_SEED, deal.as_ref(), leaf.as_ref()])
+}
+
+pub fn wormhole_message(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, WORMHOLE_MSG_SEED, deal.as_ref()])
+}
+
+pub fn action_log(deal: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, ACTION_LOG_SEED, deal.as_ref()])
+}
+
+// ---- program scope
+
+pub fn wormhole_emitter() -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, EMITTER_SEED])
+}
+
+// delegate to approve on a token account before relaying permits for it
+pub fn permit_authority() -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, PERMIT_AUTHORITY_SEED])
+}
+
+// dummy quote mint; markets quoted in it are sandboxes
+pub fn sandbox_mint() -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, SANDBOX_MINT_SEED])
+}
+
+pub fn sandbox_auth() -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, SANDBOX_SEED])
+}
+
+pub fn permit_nonce(owner: &Pubkey) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, PERMIT_NONCE_SEED, owner.as_ref()])
+}
+
+// page 0 holds `last_page`; fetch it first, then the rest in one getMultipleAccounts
+pub fn participant_index(wallet: &Pubkey, page: u32) -> (Pubkey, u8) {
+    find(&[SEED_PREFIX, PARTICIPANT_INDEX_SEED, wallet.as_ref(), &page.to_le_bytes()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the exported constants must keep producing the addresses existing accounts live at
+    #[test]
+    fn test_seeds_match_deployed_layout() {
+        let (market, farmer, buyer) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let expected = Pubkey::find_program_address(&[b"v1", b"deal", market.as_ref(), farmer.as_ref(), buyer.as_ref()], &crate::ID);
+        assert_eq!(deal(&market, &farmer, &buyer), expected);
+        let (deal_key, _) = expected;
+        assert_eq!(vault_auth(&deal_key), Pubkey::find_program_address(&[b"v1", b"vault_auth", deal_key.as_ref()], &crate::ID));
+        assert_eq!(
+            buyer_margin_vault(&deal_key),
+            Pubkey::find_program_address(&[b"v1", b"buyer_vault", deal_key.as_ref()], &crate::ID)
+        );
+        assert_eq!(action_log(&deal_key), Pubkey::find_program_address(&[b"v1", b"action_log", deal_key.as_ref()], &crate::ID));
+        assert_eq!(
+            template(&market, 7),
+            Pubkey::find_program_address(&[b"v1", b"template", market.as_ref(), &7u32.to_le_bytes()], &crate::ID)
+        );
+        assert_ne!(sample(&market, &farmer, &buyer).0, deal_key);
+        assert_eq!(batch_auth(&market), Pubkey::find_program_address(&[b"v1", b"batch_auth", market.as_ref()], &crate::ID));
+        assert_eq!(price_challenge(&market), Pubkey::find_program_address(&[b"v1", b"price_challenge", market.as_ref()], &crate::ID));
+        assert_eq!(
+            price_trigger(&market, &buyer, 9),
+            Pubkey::find_program_address(&[b"v1", b"price_trigger", market.as_ref(), buyer.as_ref(), &9u64.to_le_bytes()], &crate::ID)
+        );
+        assert_eq!(
+            fee_waiver(&market, 11),
+            Pubkey::find_program_address(&[b"v1", b"fee_waiver", market.as_ref(), &11u64.to_le_bytes()], &crate::ID)
+        );
+        assert_eq!(
+            standing_order(&market, &farmer, 3),
+            Pubkey::find_program_address(&[b"v1", b"standing_order", market.as_ref(), farmer.as_ref(), &3u64.to_le_bytes()], &crate::ID)
+        );
+        let (auction, _) = harvest_auction(&market, &farmer, 4);
+        assert_eq!(
+            auction,
+            Pubkey::find_program_address(&[b"v1", b"auction", market.as_ref(), farmer.as_ref(), &4u64.to_le_bytes()], &crate::ID).0
+        );
+        assert_eq!(auction_bid(&auction, &buyer), Pubkey::find_program_address(&[b"v1", b"auction_bid", auction.as_ref(), buyer.as_ref()], &crate::ID));
+        assert_eq!(omnibus_auth(&market), Pubkey::find_program_address(&[b"v1", b"omnibus", market.as_ref()], &crate::ID));
+        assert_eq!(
+            omnibus_vault(&market, math::Side::Long),
+            Pubkey::find_program_address(&[b"v1", b"omnibus_vault", market.as_ref(), &[1]], &crate::ID)
+        );
+        assert_eq!(
+            participant_index(&farmer, 2),
+            Pubkey::find_program_address(&[b"v1", b"participant_index", farmer.as_ref(), &2u32.to_le_bytes()], &crate::ID)
+        );
+        assert_eq!(sandbox_mint(), Pubkey::find_program_address(&[b"v1", b"sandbox_mint"], &crate::ID));
+    }
+
+    #[test]
+    fn test_v2_deal_addresses_are_per_deal_id() {
+        let (market, farmer, buyer) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (v1, _) = deal(&market, &farmer, &buyer);
+        let (first, _) = deal_v2(&market, &farmer, &buyer, 1);
+        let (second, _) = deal_v2(&market, &farmer, &buyer, 2);
+        assert!(first != v1 && second != v1 && first != second);
+    }
+}
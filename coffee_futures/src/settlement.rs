@@ -0,0 +1,1340 @@
+// Settlement engine shared by the settlement instructions.
+// Each instruction selects a SettlementStrategy for the call; the strategy fixes the price the deal
+// settles at and which legs run. The `plan_*` functions are pure arithmetic (unit-tested below); the
+// VaultLegs helpers move tokens out of the deal's margin vaults, signed by vault_auth.
+use super::*;
+use std::cell::Cell;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettlementStrategy {
+    CashLast,        // cash PnL at the last oracle price
+    CashTwap,        // cash PnL at the TWAP
+    PhysicalFull,    // delivery completing the deal quantity
+    PhysicalPartial, // delivery leaving kilograms outstanding
+    ForceMajeure,    // unwind at the agreed price, no PnL
+    Liquidation,     // cash PnL at the mark after a lapsed margin call
+}
+
+// Progress of a checkpointed cash settlement, stored in Deal::settlement_step
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettlementStep {
+    NotStarted = 0,
+    Started = 1,       // price fixed, deal locked
+    FeesCollected = 2, // fee and insurance legs paid
+    PnlPaid = 3,       // winner paid, shortfall recorded
+    Done = 4,          // residuals released, deal settled
+}
+
+impl SettlementStrategy {
+    // cash settlement for this deal: liquidated and stopped deals settle as Liquidation, others by
+    // market.price_mode
+    pub fn cash_for(market: &Market, deal: &Deal) -> Self {
+        if deal.liquidated || deal.stopped {
+            SettlementStrategy::Liquidation
+        } else {
+            Self::by_price_mode(market)
+        }
+    }
+
+    pub fn by_price_mode(market: &Market) -> Self {
+        if market.price_mode == PriceMode::TWAP as u8 {
+            SettlementStrategy::CashTwap
+        } else {
+            SettlementStrategy::CashLast
+        }
+    }
+
+    pub fn physical_for(delivered_kg_total: u64, quantity_kg: u64) -> Self {
+        if delivered_kg_total >= quantity_kg {
+            SettlementStrategy::PhysicalFull
+        } else {
+            SettlementStrategy::PhysicalPartial
+        }
+    }
+
+    // strategies that pay mark-to-market PnL between the two vaults
+    pub fn settles_pnl(self) -> bool {
+        matches!(self, SettlementStrategy::CashLast | SettlementStrategy::CashTwap | SettlementStrategy::Liquidation)
+    }
+
+    // strategies that end the deal and hand residual margin back
+    pub fn is_final(self) -> bool {
+        self != SettlementStrategy::PhysicalPartial
+    }
+
+    pub fn price(self, market: &Market, deal: &Deal) -> Result<u64> {
+        match self {
+            SettlementStrategy::PhysicalFull
+            | SettlementStrategy::PhysicalPartial
+            | SettlementStrategy::ForceMajeure => {
+                require!(deal.agreed_price_per_kg > 0, CoffeeError::ZeroPrice);
+                Ok(deal.agreed_price_per_kg)
+            }
+            _ => self.market_price(market),
+        }
+    }
+
+    // price the market sets for cash strategies: the fixed settlement price once there is one
+    // (fix_settlement_price), else the feed
+    pub fn market_price(self, market: &Market) -> Result<u64> {
+        let price = match self {
+            SettlementStrategy::CashLast | SettlementStrategy::CashTwap if market.settlement_price_per_kg > 0 => {
+                market.settlement_price_per_kg
+            }
+            SettlementStrategy::CashLast => market.last_price_per_kg,
+            SettlementStrategy::CashTwap => {
+                math::twap(market.twap_acc, market.twap_time_acc).ok_or(CoffeeError::ZeroPrice)?
+            }
+            SettlementStrategy::Liquidation => mark_price(market)?,
+            _ => return err!(CoffeeError::WrongSettlementType),
+        };
+        require!(price > 0, CoffeeError::ZeroPrice);
+        Ok(price)
+    }
+}
+
+// Cash PnL leg: the loser's vault pays the winner up to its balance; the ADL haircut comes out of that
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PnlPlan {
+    pub winner: Option<math::Side>, // None when flat
+    pub owed: u64,
+    pub pay: u64,     // taken from the loser's vault
+    pub haircut: u64, // part of `pay` routed to insurance
+    pub shortfall: u64,
+}
+
+impl PnlPlan {
+    pub fn paid_to_winner(&self) -> u64 {
+        self.pay - self.haircut
+    }
+}
+
+pub fn plan_pnl(pnl_long: i128, loser_balance: u64, haircut_due: u64) -> Result<PnlPlan> {
+    let winner = match pnl_long {
+        p if p > 0 => math::Side::Long,
+        p if p < 0 => math::Side::Short,
+        _ => return Ok(PnlPlan::default()),
+    };
+    let owed = u64::try_from(pnl_long.unsigned_abs()).map_err(|_| CoffeeError::MathOverflow)?;
+    let pay = owed.min(loser_balance);
+    let haircut = haircut_due.min(pay);
+    Ok(PnlPlan { winner: Some(winner), owed, pay, haircut, shortfall: owed - pay })
+}
+
+// Insurance cut comes from the buyer's vault first, then the farmer's; returns (from_buyer, from_farmer)
+pub fn plan_insurance(insurance_cut: u64, buyer_balance: u64, farmer_balance: u64) -> (u64, u64) {
+    let from_buyer = insurance_cut.min(buyer_balance);
+    let from_farmer = (insurance_cut - from_buyer).min(farmer_balance);
+    (from_buyer, from_farmer)
+}
+
+// The sponsor's stake is first-loss: the farmer side's `farmer_balance` covers the farmer's own
+// margin first, and the sponsor takes back only what is left above it, up to its stake. Losses and
+// fees on the farmer side eat the stake before the farmer's own margin. Returns (returned, lost)
+pub fn plan_sponsor_return(sponsored: u64, farmer_own: u64, farmer_balance: u64) -> (u64, u64) {
+    let returned = sponsored.min(farmer_balance.saturating_sub(farmer_own));
+    (returned, sponsored - returned)
+}
+
+// Whole `unit`s of `amount`, capped at `max_units`; returns (units, units * unit)
+pub fn plan_units(amount: u64, unit: u64, max_units: u64) -> Result<(u64, u64)> {
+    require!(unit > 0, CoffeeError::ZeroPrice);
+    let units = (amount / unit).min(max_units);
+    Ok((units, units * unit))
+}
+
+// Amounts handed back when a deal's vaults are emptied
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Residuals {
+    pub farmer: u64,
+    pub buyer: u64,
+    pub dust: u64, // swept to insurance
+    pub swept: [(Pubkey, u64); 2], // (vault, amount) per side, for DustSwept
+}
+
+// The deal's two margin vaults and what it takes to pay out of them. Balances are snapshotted at
+// construction and every payment is tracked, because `Account::amount` goes stale after each CPI.
+// When both vaults are the same token account their balance is shared: a deal without split_vaults,
+// whose vaults check_margin_vaults pins to the one vault_auth ATA. An omnibus deal pays out of
+// the market's omnibus vaults, signed by the omnibus PDA, and only up to its ledger balances.
+pub struct VaultLegs<'a, 'info> {
+    pub vault_auth: &'a Account<'info, VaultAuth>,
+    pub farmer_vault: &'a Account<'info, TokenAccount>,
+    pub buyer_vault: &'a Account<'info, TokenAccount>,
+    pub token_program: &'a Program<'info, Token>,
+    pub deal_key: Pubkey,
+    omnibus_market: Option<Pubkey>, // set when vault_auth is the market's omnibus PDA
+    split: bool,             // a shared vault booked per side by split_shared
+    start: [u64; 2],         // [farmer, buyer] balances at construction
+    paid_out: [Cell<u64>; 2], // [farmer, buyer] transferred out since
+}
+
+fn side_index(side: math::Side) -> usize {
+    match side {
+        math::Side::Short => 0, // farmer slot
+        math::Side::Long => 1,  // buyer slot
+    }
+}
+
+impl<'a, 'info> VaultLegs<'a, 'info> {
+    pub fn new(
+        vault_auth: &'a Account<'info, VaultAuth>,
+        farmer_vault: &'a Account<'info, TokenAccount>,
+        buyer_vault: &'a Account<'info, TokenAccount>,
+        token_program: &'a Program<'info, Token>,
+        deal_key: Pubkey,
+    ) -> Self {
+        VaultLegs {
+            vault_auth,
+            farmer_vault,
+            buyer_vault,
+            token_program,
+            deal_key,
+            omnibus_market: None,
+            split: false,
+            start: [farmer_vault.amount, buyer_vault.amount],
+            paid_out: [Cell::new(0), Cell::new(0)],
+        }
+    }
+
+    // legs over the market's omnibus vaults, bounded by the deal's ledger `balances` [farmer, buyer]
+    pub fn omnibus(
+        omnibus_auth: &'a Account<'info, VaultAuth>,
+        farmer_vault: &'a Account<'info, TokenAccount>,
+        buyer_vault: &'a Account<'info, TokenAccount>,
+        token_program: &'a Program<'info, Token>,
+        deal_key: Pubkey,
+        market_key: Pubkey,
+        balances: [u64; 2],
+    ) -> Self {
+        VaultLegs {
+            vault_auth: omnibus_auth,
+            farmer_vault,
+            buyer_vault,
+            token_program,
+            deal_key,
+            omnibus_market: Some(market_key),
+            split: false,
+            start: balances,
+            paid_out: [Cell::new(0), Cell::new(0)],
+        }
+    }
+
+    // what is left per side, [farmer, buyer]; an omnibus deal's new ledger balances
+    pub fn balances(&self) -> [u64; 2] {
+        [self.available(math::Side::Short), self.available(math::Side::Long)]
+    }
+
+    fn shared(&self) -> bool {
+        self.omnibus_market.is_none() && !self.split && self.farmer_vault.key() == self.buyer_vault.key()
+    }
+
+    // Book `farmer_share` of a shared vault to the farmer side and the rest to the buyer, so each side
+    // only pays out of its own part from here on. Call before any payment; separate vaults are untouched
+    pub fn split_shared(mut self, farmer_share: u64) -> Self {
+        if self.shared() {
+            let farmer = farmer_share.min(self.start[0]);
+            self.start = [farmer, self.start[0] - farmer];
+            self.split = true;
+        }
+        self
+    }
+
+    fn total_paid_out(&self) -> u64 {
+        self.paid_out[0].get().saturating_add(self.paid_out[1].get())
+    }
+
+    // Long = buyer, Short = farmer
+    pub fn vault(&self, side: math::Side) -> &'a Account<'info, TokenAccount> {
+        match side {
+            math::Side::Long => self.buyer_vault,
+            math::Side::Short => self.farmer_vault,
+        }
+    }
+
+    // what is left in a side's vault after the payments made so far
+    pub fn available(&self, side: math::Side) -> u64 {
+        if self.shared() {
+            self.start[0].saturating_sub(self.total_paid_out())
+        } else {
+            let i = side_index(side);
+            self.start[i].saturating_sub(self.paid_out[i].get())
+        }
+    }
+
+    pub fn pay(&self, from: math::Side, amount: u64, to: &Account<'info, TokenAccount>) -> Result<()> {
+        check_invariant(amount <= self.available(from), "vault overdrawn")?;
+        match self.omnibus_market {
+            Some(market_key) => transfer_from_omnibus(amount, self.vault_auth, self.vault(from), to, self.token_program, &market_key)?,
+            None => transfer_from_vault_to(amount, self.vault_auth, self.vault(from), to, self.token_program, &self.deal_key)?,
+        }
+        let i = side_index(from);
+        self.paid_out[i].set(self.paid_out[i].get().saturating_add(amount));
+        Ok(())
+    }
+
+    // quote-currency fees: farmer share plus the protocol remainder from the farmer, buyer share from the buyer
+    // returns (from_farmer, from_buyer, to_operator); the operator's cut comes out of the protocol
+    // share the farmer vault pays, so a short vault shorts the operator before the fee treasury
+    pub fn collect_quote_fees(
+        &self,
+        fees: &math::FeeSplit,
+        fee_treasury: &Account<'info, TokenAccount>,
+        operator: Option<(&Account<'info, TokenAccount>, u64)>,
+    ) -> Result<(u64, u64, u64)> {
+        let farmer_fee = fees.farmer_cut.min(self.available(math::Side::Short));
+        let proto_plus_farmer = farmer_fee.saturating_add(fees.protocol_cut);
+        let farmer_fee_paid = proto_plus_farmer.min(self.available(math::Side::Short));
+        let operator_paid = match operator {
+            Some((treasury, cut)) => {
+                let paid = cut.min(farmer_fee_paid - farmer_fee);
+                self.pay(math::Side::Short, paid, treasury)?;
+                paid
+            }
+            None => 0,
+        };
+        self.pay(math::Side::Short, farmer_fee_paid - operator_paid, fee_treasury)?;
+        let buyer_fee_paid = fees.buyer_cut.min(self.available(math::Side::Long));
+        self.pay(math::Side::Long, buyer_fee_paid, fee_treasury)?;
+        Ok((farmer_fee_paid, buyer_fee_paid, operator_paid))
+    }
+
+    // returns (from_buyer, from_farmer)
+    pub fn collect_insurance(&self, insurance_cut: u64, insurance: &Account<'info, TokenAccount>) -> Result<(u64, u64)> {
+        let from_buyer = insurance_cut.min(self.available(math::Side::Long));
+        self.pay(math::Side::Long, from_buyer, insurance)?;
+        let (_, from_farmer) = plan_insurance(insurance_cut, from_buyer, self.available(math::Side::Short));
+        self.pay(math::Side::Short, from_farmer, insurance)?;
+        Ok((from_buyer, from_farmer))
+    }
+
+    // plan the PnL leg against the loser's vault and pay it: winner gets pay - haircut, insurance the haircut
+    pub fn pay_pnl(
+        &self,
+        pnl_long: i128,
+        haircut_due: u64,
+        farmer_receive: &Account<'info, TokenAccount>,
+        buyer_receive: &Account<'info, TokenAccount>,
+        insurance: &Account<'info, TokenAccount>,
+    ) -> Result<PnlPlan> {
+        let loser_balance = match pnl_long {
+            p if p > 0 => self.available(math::Side::Short),
+            p if p < 0 => self.available(math::Side::Long),
+            _ => 0,
+        };
+        let plan = plan_pnl(pnl_long, loser_balance, haircut_due)?;
+        if let Some(winner) = plan.winner {
+            let (loser, receive) = match winner {
+                math::Side::Long => (math::Side::Short, buyer_receive),
+                math::Side::Short => (math::Side::Long, farmer_receive),
+            };
+            self.pay(loser, plan.paid_to_winner(), receive)?;
+            self.pay(loser, plan.haircut, insurance)?;
+        }
+        Ok(plan)
+    }
+
+    // empty both vaults back to their owners; balances at or below min_transfer are swept as dust
+    pub fn release_residuals(
+        &self,
+        min_transfer: u64,
+        farmer_receive: &Account<'info, TokenAccount>,
+        buyer_receive: &Account<'info, TokenAccount>,
+        insurance: &Account<'info, TokenAccount>,
+    ) -> Result<Residuals> {
+        let (farmer, farmer_dust) = self.release_side(math::Side::Short, min_transfer, farmer_receive, insurance)?;
+        let (buyer, buyer_dust) = self.release_side(math::Side::Long, min_transfer, buyer_receive, insurance)?;
+        Ok(Residuals {
+            farmer,
+            buyer,
+            dust: farmer_dust.saturating_add(buyer_dust),
+            swept: [(self.farmer_vault.key(), farmer_dust), (self.buyer_vault.key(), buyer_dust)],
+        })
+    }
+
+    // A margin sponsor's first-loss stake back out of the farmer vault, from whatever it holds beyond
+    // the farmer's own margin; the farmer's residual follows. A shared vault can't tell the farmer
+    // side from the buyer's, so it is refused. Returns (returned, lost)
+    pub fn return_sponsor_margin(
+        &self,
+        sponsored: u64,
+        farmer_own: u64,
+        receive: Option<&Account<'info, TokenAccount>>,
+    ) -> Result<(u64, u64)> {
+        if sponsored == 0 {
+            return Ok((0, 0));
+        }
+        require!(!self.shared(), CoffeeError::SponsoredVaultShared);
+        let receive = receive.ok_or(CoffeeError::SponsorAccountMissing)?;
+        let (returned, lost) = plan_sponsor_return(sponsored, farmer_own, self.available(math::Side::Short));
+        self.pay(math::Side::Short, returned, receive)?;
+        Ok((returned, lost))
+    }
+
+    // returns (returned, swept)
+    fn release_side(
+        &self,
+        side: math::Side,
+        min_transfer: u64,
+        receive: &Account<'info, TokenAccount>,
+        insurance: &Account<'info, TokenAccount>,
+    ) -> Result<(u64, u64)> {
+        let amount = self.available(side);
+        if amount > min_transfer {
+            self.pay(side, amount, receive)?;
+            return Ok((amount, 0));
+        }
+        if amount > 0 {
+            self.pay(side, amount, insurance)?;
+        }
+        Ok((0, amount))
+    }
+
+    // pay `amount` out of `from`: whole multiples of `unit` (at most `max_units`) to `units_to`, the
+    // rest to `rest_to`; returns the number of units
+    pub fn pay_in_units(
+        &self,
+        from: math::Side,
+        amount: u64,
+        unit: u64,
+        max_units: u64,
+        units_to: &Account<'info, TokenAccount>,
+        rest_to: &Account<'info, TokenAccount>,
+    ) -> Result<u64> {
+        let (units, converted) = plan_units(amount, unit, max_units)?;
+        self.pay(from, converted, units_to)?;
+        self.pay(from, amount - converted, rest_to)?;
+        Ok(units)
+    }
+
+    // outgoing transfers never exceed what the vaults held
+    pub fn check_solvency(&self) -> Result<()> {
+        let held = if self.shared() { self.start[0] } else { self.start[0].saturating_add(self.start[1]) };
+        check_invariant(self.total_paid_out() <= held, "outgoing transfers exceed vault balances")
+    }
+
+    // a settled deal leaves nothing behind in its margin vaults
+    pub fn check_drained(&self) -> Result<()> {
+        self.check_solvency()?;
+        check_invariant(
+            self.available(math::Side::Short) == 0 && self.available(math::Side::Long) == 0,
+            "settled deal left a vault balance",
+        )
+    }
+}
+
+// The margin vaults of several deals between the same farmer and buyer, paid out of as one pot per
+// side. A payment drains the side's vaults in order, so each destination costs one transfer per vault
+// it reaches rather than one per deal and leg. Balances are snapshotted like VaultLegs; a deal whose
+// two vaults are the same token account shares that balance between the sides.
+pub struct PairVaults<'a, 'info> {
+    vaults: Vec<(&'a Account<'info, VaultAuth>, &'a Account<'info, TokenAccount>, Pubkey)>, // (vault_auth, vault, deal)
+    left: Vec<u64>,
+    sides: [Vec<usize>; 2], // [farmer, buyer] indexes into `vaults`
+    token_program: &'a Program<'info, Token>,
+    pub transfers: u16,
+}
+
+impl<'a, 'info> PairVaults<'a, 'info> {
+    pub fn new(token_program: &'a Program<'info, Token>) -> Self {
+        PairVaults { vaults: Vec::new(), left: Vec::new(), sides: [Vec::new(), Vec::new()], token_program, transfers: 0 }
+    }
+
+    pub fn add(
+        &mut self,
+        vault_auth: &'a Account<'info, VaultAuth>,
+        farmer_vault: &'a Account<'info, TokenAccount>,
+        buyer_vault: &'a Account<'info, TokenAccount>,
+        deal_key: Pubkey,
+    ) {
+        for (side, vault) in [(math::Side::Short, farmer_vault), (math::Side::Long, buyer_vault)] {
+            let i = match self.vaults.iter().position(|(_, v, _)| v.key() == vault.key()) {
+                Some(i) => i,
+                None => {
+                    self.vaults.push((vault_auth, vault, deal_key));
+                    self.left.push(vault.amount);
+                    self.vaults.len() - 1
+                }
+            };
+            self.sides[side_index(side)].push(i);
+        }
+    }
+
+    pub fn available(&self, side: math::Side) -> u64 {
+        self.sides[side_index(side)].iter().fold(0u64, |acc, &i| acc.saturating_add(self.left[i]))
+    }
+
+    pub fn pay(&mut self, from: math::Side, amount: u64, to: &Account<'info, TokenAccount>) -> Result<()> {
+        check_invariant(amount <= self.available(from), "vault overdrawn")?;
+        let mut rest = amount;
+        for &i in self.sides[side_index(from)].iter() {
+            let take = rest.min(self.left[i]);
+            if take == 0 {
+                continue;
+            }
+            let (vault_auth, vault, deal_key) = self.vaults[i];
+            transfer_from_vault_to(take, vault_auth, vault, to, self.token_program, &deal_key)?;
+            self.left[i] -= take;
+            self.transfers = self.transfers.saturating_add(1);
+            rest -= take;
+        }
+        Ok(())
+    }
+
+    // like VaultLegs::release_side: returns (returned, swept)
+    pub fn release_side(
+        &mut self,
+        side: math::Side,
+        min_transfer: u64,
+        receive: &Account<'info, TokenAccount>,
+        insurance: &Account<'info, TokenAccount>,
+    ) -> Result<(u64, u64)> {
+        let amount = self.available(side);
+        if amount > min_transfer {
+            self.pay(side, amount, receive)?;
+            return Ok((amount, 0));
+        }
+        self.pay(side, amount, insurance)?;
+        Ok((0, amount))
+    }
+
+    pub fn check_drained(&self) -> Result<()> {
+        check_invariant(self.left.iter().all(|&l| l == 0), "settled deal left a vault balance")
+    }
+}
+
+// Accounting invariant: logged and asserted in debug builds; with the `strict-invariants` feature a
+// violation fails the instruction instead (InvariantViolation) so the transaction rolls back.
+pub fn check_invariant(ok: bool, what: &str) -> Result<()> {
+    if ok {
+        return Ok(());
+    }
+    msg!("invariant violated: {}", what);
+    if cfg!(feature = "strict-invariants") {
+        return err!(CoffeeError::InvariantViolation);
+    }
+    if cfg!(debug_assertions) {
+        panic!("invariant violated: {}", what);
+    }
+    Ok(())
+}
+
+// Cash settlement of a whole deal (CashLast / CashTwap / Liquidation): fees, insurance, PnL, residuals
+// Cash settlement runs as checkpointed steps (SettlementStep) so a deal whose transfers don't fit one
+// transaction's compute can be finished over several. The price is fixed when settlement starts and
+// each completed leg is recorded on the deal; a call runs at most `max_steps` legs and a later call
+// resumes where it stopped. The deal stays `settling` in between, which blocks everything else on it.
+pub fn settle_cash_deal(ctx: Context<SettleCash>, strategy: SettlementStrategy, max_steps: u8) -> Result<()> {
+    let accounts = ctx.accounts;
+    require!(strategy.settles_pnl(), CoffeeError::WrongSettlementType);
+    require!(max_steps > 0, CoffeeError::InvalidSettlementSteps);
+    let market = &accounts.market;
+    let deal_key = accounts.deal.key();
+    let deal = &mut accounts.deal;
+
+    require!(!deal.settled, CoffeeError::DealAlreadySettled);
+    require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+    let now = Clock::get()?.unix_timestamp;
+
+    if deal.settlement_step == SettlementStep::NotStarted as u8 {
+        // allow settlement if market settled time reached OR if post-deadline auto cash fallback
+        // a deal flagged for liquidation or stopped out settles at once, but not off a stale feed
+        let due = now >= market.settlement_ts || now >= deal.deadline_ts;
+        require!(due || deal.liquidated || deal.stopped, CoffeeError::NotYetSettleTime);
+        require!(due || market.oracle_is_fresh(now), CoffeeError::OracleStale);
+        require!(!market.settlement_awaits_final_price(deal, now), CoffeeError::SettlementPriceNotFinal);
+
+        if market.market_kind == MarketKind::Perpetual as u8 {
+            require!(deal.funding_checkpoint == market.cumulative_funding_per_kg, CoffeeError::FundingNotApplied);
+        }
+        require!(!deal.fx_hedge_pending(), CoffeeError::FxHedgeNotSettled);
+
+        // Reentrancy guard
+        deal.start_settling();
+        deal.final_price_per_kg = strategy.price(market, deal)?;
+        deal.settlement_step = SettlementStep::Started as u8;
+    }
+    let price = deal.final_price_per_kg;
+
+    // PnL of the buyer slot, by the position it holds (long unless the farmer bought)
+    let pnl_long = deal.slot_pnl(market, price, deal.quantity_kg, math::Side::Long)?;
+
+    check_margin_vaults(market, deal, &accounts.vault_auth.key(), &accounts.farmer_margin_vault, &accounts.buyer_margin_vault)?;
+    let legs = if deal.omnibus {
+        let omnibus_auth = accounts.omnibus_auth.as_ref().ok_or(CoffeeError::OmnibusVaultMismatch)?;
+        VaultLegs::omnibus(
+            omnibus_auth,
+            &accounts.farmer_margin_vault,
+            &accounts.buyer_margin_vault,
+            &accounts.token_program,
+            deal_key,
+            market.key(),
+            deal.omnibus_balance,
+        )
+    } else {
+        VaultLegs::new(
+            &accounts.vault_auth,
+            &accounts.farmer_margin_vault,
+            &accounts.buyer_margin_vault,
+            &accounts.token_program,
+            deal_key,
+        )
+    };
+    let mut steps = max_steps;
+    let mut cft_units = 0;
+
+    // fee leg
+    if deal.settlement_step == SettlementStep::Started as u8 && steps > 0 {
+        // fee on notional; volume tier keyed off the larger of the two traders' rolling notional
+        let notional = market.notional(deal.agreed_price_per_kg, deal.quantity_kg)?;
+        let tier_volume = accounts.farmer_stats.rolling_notional.max(accounts.buyer_stats.rolling_notional);
+        let fees = fee_split(deal.fee_bearing_notional(notional)?, market.fee_bps_for_volume(tier_volume), market)?;
+        let notional_u64: u64 = notional.try_into().map_err(|_| CoffeeError::MathOverflow)?;
+        accounts.farmer_stats.record_notional(notional_u64, now)?;
+        accounts.buyer_stats.record_notional(notional_u64, now)?;
+
+        let (farmer_fee_paid, buyer_fee_paid, operator_fee_paid) = if deal.fee_currency == FeeCurrency::Cft as u8 {
+            // farmer + protocol share and buyer share are burned in CFT at the settlement price
+            let cft_mint = accounts.cft_mint.as_ref().ok_or(CoffeeError::CftFeeAccountsMissing)?;
+            let farmer_cft = accounts.farmer_cft_ata.as_ref().ok_or(CoffeeError::CftFeeAccountsMissing)?;
+            let buyer_cft = accounts.buyer_cft_ata.as_ref().ok_or(CoffeeError::CftFeeAccountsMissing)?;
+            let quote_fee_farmer = fees.farmer_cut.checked_add(fees.protocol_cut).ok_or(CoffeeError::MathOverflow)?;
+            let farmer_burn = quote_to_cft_fee(quote_fee_farmer, price, market.price_exponent, market.cft_fee_discount_bps)?;
+            let buyer_burn = quote_to_cft_fee(fees.buyer_cut, price, market.price_exponent, market.cft_fee_discount_bps)?;
+            burn_cft_as_delegate(farmer_burn, &accounts.vault_auth, cft_mint, farmer_cft, &accounts.token_program, &deal_key)?;
+            burn_cft_as_delegate(buyer_burn, &accounts.vault_auth, cft_mint, buyer_cft, &accounts.token_program, &deal_key)?;
+            emit_event!(accounts, ctx.bumps, FeeBurnedCft {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                deal_id: deal.deal_id,
+                farmer_cft: farmer_burn,
+                buyer_cft: buyer_burn,
+                quote_equivalent: quote_fee_farmer.saturating_add(fees.buyer_cut),
+            });
+            // quote equivalents of the burned CFT; burned fees carry no operator cut
+            (quote_fee_farmer, fees.buyer_cut, 0)
+        } else {
+            let operator_cut = bps_of_u64(fees.protocol_cut, market.operator_fee_bps)?;
+            let operator = if operator_cut > 0 {
+                let treasury = accounts.operator_treasury.as_ref().ok_or(CoffeeError::OperatorTreasuryMissing)?;
+                Some((treasury, operator_cut))
+            } else {
+                None
+            };
+            legs.collect_quote_fees(&fees, &accounts.fee_treasury, operator)?
+        };
+        if operator_fee_paid > 0 {
+            emit_event!(accounts, ctx.bumps, OperatorFeePaid {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                deal_id: deal.deal_id,
+                market: market.key(),
+                operator: market.operator,
+                amount: operator_fee_paid,
+            });
+        }
+        // premium already paid at open_deal when the market collects insurance up front
+        let (insurance_from_buyer, insurance_from_farmer) = if deal.insurance_prepaid > 0 {
+            (0, 0)
+        } else {
+            legs.collect_insurance(fees.insurance_cut, &accounts.insurance_treasury)?
+        };
+        let insurance_fee = insurance_from_buyer.saturating_add(insurance_from_farmer);
+
+        deal.settle_fees = [farmer_fee_paid, buyer_fee_paid, fees.protocol_cut - operator_fee_paid, insurance_fee];
+        deal.record_fees(farmer_fee_paid.saturating_add(buyer_fee_paid).saturating_add(insurance_fee))?;
+        deal.settlement_step = SettlementStep::FeesCollected as u8;
+        steps -= 1;
+    }
+
+    // PnL leg: pay winner from loser vault; shortfalls are recorded as debt
+    if deal.settlement_step == SettlementStep::FeesCollected as u8 && steps > 0 {
+        let haircut_due = deal.adl_haircut_due(market.loss_socialization_index)?;
+        let cft_payout = deal.cft_payout && pnl_long > 0 && market.cft_sale_treasury != Pubkey::default() && !market.cft_expired(now);
+        let pnl = if cft_payout {
+            // the buyer elected CFT: whole kilograms of the winnings at the settlement price are sold out of
+            // verified-but-unminted supply, the quote going to the sale treasury; the rest pays out in quote
+            let treasury = accounts.cft_sale_treasury.as_ref().ok_or(CoffeeError::CftPayoutAccountsMissing)?;
+            let mint_auth = accounts.cft_mint_auth.as_ref().ok_or(CoffeeError::CftPayoutAccountsMissing)?;
+            require!(
+                !market.cft_allowlist_required || accounts.buyer_cft_allowlist.is_some(),
+                CoffeeError::RecipientNotAllowlisted
+            );
+            let headroom = mint_auth.total_kg_verified.saturating_sub(mint_auth.total_cft_minted);
+            let plan = plan_pnl(pnl_long, legs.available(math::Side::Short), haircut_due)?;
+            legs.pay(math::Side::Short, plan.haircut, &accounts.insurance_treasury)?;
+            cft_units = legs.pay_in_units(math::Side::Short, plan.paid_to_winner(), price, headroom, treasury, &accounts.buyer_receive)?;
+            plan
+        } else {
+            legs.pay_pnl(pnl_long, haircut_due, &accounts.farmer_receive, &accounts.buyer_receive, &accounts.insurance_treasury)?
+        };
+        if cft_units > 0 {
+            let cft_mint = accounts.cft_mint.as_ref().ok_or(CoffeeError::CftPayoutAccountsMissing)?;
+            let buyer_cft = accounts.buyer_cft_ata.as_ref().ok_or(CoffeeError::CftPayoutAccountsMissing)?;
+            let mint_auth = accounts.cft_mint_auth.as_mut().ok_or(CoffeeError::CftPayoutAccountsMissing)?;
+            mint_auth.record_mint(cft_units)?;
+            let cft_key = cft_mint.key();
+            let signer_seeds: &[&[&[u8]]] = &[&[SEED_PREFIX, CFT_AUTH_SEED, cft_key.as_ref(), &[mint_auth.bump]]];
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    accounts.token_program.to_account_info(),
+                    MintTo {
+                        mint: cft_mint.to_account_info(),
+                        to: buyer_cft.to_account_info(),
+                        authority: mint_auth.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                cft_units,
+            )?;
+            emit_event!(accounts, ctx.bumps, CftPayoutMinted {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                deal_id: deal.deal_id,
+                buyer: deal.buyer,
+                price,
+                quote_converted: cft_units * price,
+                cft_minted: cft_units,
+            });
+        }
+        if let Some(winner) = pnl.winner {
+            deal.record_realized_pnl(winner, pnl.pay, pnl.paid_to_winner())?;
+            let winner_key = match winner {
+                math::Side::Long => deal.buyer,
+                math::Side::Short => deal.farmer,
+            };
+            if pnl.haircut > 0 {
+                deal.adl_haircut = pnl.haircut;
+                emit_event!(accounts, ctx.bumps, AdlHaircutApplied { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, winner: winner_key, haircut: pnl.haircut });
+            }
+            if pnl.shortfall > 0 {
+                deal.record_shortfall(winner_key, pnl.shortfall)?;
+                emit_event!(accounts, ctx.bumps, SettlementShortfall { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, creditor: winner_key, shortfall: pnl.shortfall });
+            }
+        }
+
+        deal.settle_pnl = [pnl.paid_to_winner(), pnl.haircut, pnl.shortfall];
+        deal.settlement_step = SettlementStep::PnlPaid as u8;
+        steps -= 1;
+    }
+
+    // residual leg: hand back what is left and finish the deal
+    let finished = deal.settlement_step == SettlementStep::PnlPaid as u8 && steps > 0;
+    if finished {
+        let (sponsor_returned, sponsor_loss) = legs.return_sponsor_margin(deal.sponsored_margin, deal.farmer_own_margin, accounts.sponsor_receive.as_ref())?;
+        if deal.sponsored_margin > 0 {
+            emit_event!(accounts, ctx.bumps, SponsorMarginReturned {
+                schema_version: EVENT_SCHEMA_VERSION,
+                deal: deal_key,
+                deal_id: deal.deal_id,
+                sponsor: deal.sponsor,
+                returned: sponsor_returned,
+                loss: sponsor_loss,
+            });
+        }
+        let residuals = legs.release_residuals(
+            market.min_transfer_amount,
+            &accounts.farmer_receive,
+            &accounts.buyer_receive,
+            &accounts.insurance_treasury,
+        )?;
+        legs.check_drained()?;
+        for (vault, amount) in residuals.swept {
+            if amount > 0 {
+                emit_event!(accounts, ctx.bumps, DustSwept { schema_version: EVENT_SCHEMA_VERSION, deal: deal_key, deal_id: deal.deal_id, vault, amount });
+            }
+        }
+
+        deal.settlement_step = SettlementStep::Done as u8;
+        deal.mark_settled();
+
+        let [farmer_fee, buyer_fee, protocol_fee, insurance_fee] = deal.settle_fees;
+        let [pnl_paid, adl_haircut, pnl_shortfall] = deal.settle_pnl;
+        emit_event!(accounts, ctx.bumps, SettledCash {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            market: market.key(),
+            price,
+            pnl_long: pnl_long.try_into().map_err(|_| CoffeeError::MathOverflow)?,
+            fee_currency: deal.fee_currency,
+            farmer_fee,
+            buyer_fee,
+            protocol_fee,
+            insurance_fee,
+            pnl_paid,
+            adl_haircut,
+            pnl_shortfall,
+            farmer_residual: residuals.farmer,
+            buyer_residual: residuals.buyer,
+            dust_swept: residuals.dust,
+        });
+        record_deal_action(deal, accounts.action_log.as_deref_mut(), DealAction::Settle, Pubkey::default(), pnl_paid)?;
+    } else {
+        legs.check_solvency()?;
+        emit_event!(accounts, ctx.bumps, SettlementCheckpointed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            deal: deal_key,
+            deal_id: deal.deal_id,
+            step: deal.settlement_step,
+            price,
+        });
+    }
+
+    // the ledger follows every leg paid, so a checkpointed settlement resumes from it
+    if deal.omnibus {
+        deal.omnibus_balance = legs.balances();
+    }
+
+    accounts.market.record_cft_mint(cft_units)?;
+    if finished {
+        accounts.market.release_open_interest(&accounts.deal);
+    }
+    Ok(())
+}
+
+// `total` spread over `caps` in order, each entry taking at most its cap
+pub fn spread_in_order(total: u64, caps: &[u64]) -> Vec<u64> {
+    let mut rest = total;
+    caps.iter()
+        .map(|&cap| {
+            let take = rest.min(cap);
+            rest -= take;
+            take
+        })
+        .collect()
+}
+
+// `total` split in proportion to `weights` (rounded down); the last entry takes the remainder
+pub fn pro_rata(total: u64, weights: &[u128]) -> Result<Vec<u64>> {
+    let sum = weights.iter().try_fold(0u128, |acc, &w| acc.checked_add(w)).ok_or(CoffeeError::MathOverflow)?;
+    let mut shares = Vec::with_capacity(weights.len());
+    let mut given = 0u64;
+    for (i, &w) in weights.iter().enumerate() {
+        let share = if i + 1 == weights.len() {
+            total - given
+        } else if sum == 0 {
+            0
+        } else {
+            let s = (total as u128).checked_mul(w).ok_or(CoffeeError::MathOverflow)? / sum;
+            s as u64
+        };
+        given += share;
+        shares.push(share);
+    }
+    Ok(shares)
+}
+
+// Net cash settlement of every deal between one farmer and buyer passed in remaining_accounts, as
+// [deal, vault_auth, farmer_margin_vault, buyer_margin_vault] per deal. All deals settle at the same
+// price; their PnL is netted so only the difference crosses between the sides, and the fee, insurance,
+// PnL and residual legs each run once over the pooled vaults. The fee is charged on the pair's
+// combined notional, so it rounds up once instead of per deal. Deals must be due, quote-fee, not
+// liquidated, cross-margined, settling or hedged; anything else goes through settle_cash.
+pub fn net_settle_pair<'info>(ctx: Context<'_, '_, 'info, 'info, NetSettlePair<'info>>) -> Result<()> {
+    let accounts = ctx.accounts;
+    let rest = ctx.remaining_accounts;
+    require!(
+        !rest.is_empty() && rest.len() % NET_SETTLE_ACCOUNTS_PER_DEAL == 0,
+        CoffeeError::NetSettleAccountsInvalid
+    );
+    require!(rest.len() / NET_SETTLE_ACCOUNTS_PER_DEAL <= MAX_NET_SETTLE_DEALS, CoffeeError::TooManyNetSettleDeals);
+    let market = &accounts.market;
+    let (farmer, buyer) = (accounts.farmer.key(), accounts.buyer.key());
+    let now = Clock::get()?.unix_timestamp;
+
+    let mut deals: Vec<Account<'info, Deal>> = Vec::with_capacity(rest.len() / NET_SETTLE_ACCOUNTS_PER_DEAL);
+    let mut vault_auths: Vec<Account<'info, VaultAuth>> = Vec::with_capacity(deals.capacity());
+    let mut vaults: Vec<(Account<'info, TokenAccount>, Account<'info, TokenAccount>)> = Vec::with_capacity(deals.capacity());
+    for chunk in rest.chunks(NET_SETTLE_ACCOUNTS_PER_DEAL) {
+        require!(deals.iter().all(|d| d.key() != *chunk[0].key), CoffeeError::DuplicateNetSettleDeal);
+        let deal: Account<'info, Deal> = Account::try_from(&chunk[0])?;
+        require!(chunk[0].is_writable, CoffeeError::NetSettleAccountsInvalid);
+        require_keys_eq!(deal.market, market.key(), CoffeeError::NetSettleDealIneligible);
+        require!(deal.farmer == farmer && deal.buyer == buyer, CoffeeError::NetSettleDealIneligible);
+        require!(deal.sponsored_margin == 0, CoffeeError::DealSponsored);
+        require!(!deal.settled, CoffeeError::DealAlreadySettled);
+        require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+        require!(now >= market.settlement_ts || now >= deal.deadline_ts, CoffeeError::NotYetSettleTime);
+        require!(!market.settlement_window_missed(&deal, now), CoffeeError::SettlementWindowMissed);
+        require!(!market.settlement_awaits_final_price(&deal, now), CoffeeError::SettlementPriceNotFinal);
+        require!(
+            deal.settlement_step == SettlementStep::NotStarted as u8
+                && !deal.omnibus
+                && !deal.liquidated
+                && !deal.cft_payout
+                && deal.fee_currency != FeeCurrency::Cft as u8,
+            CoffeeError::NetSettleDealIneligible
+        );
+        if market.market_kind == MarketKind::Perpetual as u8 {
+            require!(deal.funding_checkpoint == market.cumulative_funding_per_kg, CoffeeError::FundingNotApplied);
+        }
+        require!(!deal.fx_hedge_pending(), CoffeeError::FxHedgeNotSettled);
+
+        let vault_auth: Account<'info, VaultAuth> = Account::try_from(&chunk[1])?;
+        let expected = Pubkey::create_program_address(
+            &[SEED_PREFIX, VAULT_AUTH_SEED, deal.key().as_ref(), &[vault_auth.bump]],
+            ctx.program_id,
+        )
+        .map_err(|_| CoffeeError::NetSettleAccountsInvalid)?;
+        require_keys_eq!(vault_auth.key(), expected, CoffeeError::NetSettleAccountsInvalid);
+        let farmer_vault: Account<'info, TokenAccount> = Account::try_from(&chunk[2])?;
+        let buyer_vault: Account<'info, TokenAccount> = Account::try_from(&chunk[3])?;
+        for vault in [&farmer_vault, &buyer_vault] {
+            require!(
+                vault.owner == vault_auth.key() && vault.mint == market.quote_mint,
+                CoffeeError::NetSettleAccountsInvalid
+            );
+        }
+        let [farmer_key, buyer_key] = deal_margin_vaults(deal.split_vaults, &deal.key(), &vault_auth.key(), &market.quote_mint);
+        require!(
+            farmer_vault.key() == farmer_key && buyer_vault.key() == buyer_key,
+            CoffeeError::NetSettleAccountsInvalid
+        );
+        deals.push(deal);
+        vault_auths.push(vault_auth);
+        vaults.push((farmer_vault, buyer_vault));
+    }
+
+    let strategy = SettlementStrategy::cash_for(market, &deals[0]);
+    let price = strategy.price(market, &deals[0])?;
+    let mut pnls: Vec<i128> = Vec::with_capacity(deals.len());
+    let mut notionals: Vec<u128> = Vec::with_capacity(deals.len()); // fee-bearing, after each deal's waiver
+    let (mut notional, mut fee_notional, mut insured_notional, mut net_pnl_long) = (0u128, 0u128, 0u128, 0i128);
+    for deal in deals.iter() {
+        let pnl = deal.slot_pnl(market, price, deal.quantity_kg, math::Side::Long)?;
+        let n = market.notional(deal.agreed_price_per_kg, deal.quantity_kg)?;
+        let fee_n = deal.fee_bearing_notional(n)?;
+        net_pnl_long = net_pnl_long.checked_add(pnl).ok_or(CoffeeError::MathOverflow)?;
+        notional = notional.checked_add(n).ok_or(CoffeeError::MathOverflow)?;
+        fee_notional = fee_notional.checked_add(fee_n).ok_or(CoffeeError::MathOverflow)?;
+        if deal.insurance_prepaid == 0 {
+            insured_notional = insured_notional.checked_add(fee_n).ok_or(CoffeeError::MathOverflow)?;
+        }
+        pnls.push(pnl);
+        notionals.push(fee_n);
+    }
+
+    let mut legs = PairVaults::new(&accounts.token_program);
+    for ((deal, vault_auth), (farmer_vault, buyer_vault)) in deals.iter().zip(vault_auths.iter()).zip(vaults.iter()) {
+        legs.add(vault_auth, farmer_vault, buyer_vault, deal.key());
+    }
+
+    // fee leg, as VaultLegs::collect_quote_fees but on the combined notional
+    let tier_volume = accounts.farmer_stats.rolling_notional.max(accounts.buyer_stats.rolling_notional);
+    let fee_bps = market.fee_bps_for_volume(tier_volume);
+    let fees = fee_split(fee_notional, fee_bps, market)?;
+    let insurance_cut = if insured_notional == fee_notional {
+        fees.insurance_cut
+    } else {
+        fee_split(insured_notional, fee_bps, market)?.insurance_cut
+    };
+    let notional_u64: u64 = notional.try_into().map_err(|_| CoffeeError::MathOverflow)?;
+    accounts.farmer_stats.record_notional(notional_u64, now)?;
+    accounts.buyer_stats.record_notional(notional_u64, now)?;
+
+    let farmer_fee = fees.farmer_cut.min(legs.available(math::Side::Short));
+    let farmer_fee_paid = farmer_fee.saturating_add(fees.protocol_cut).min(legs.available(math::Side::Short));
+    let operator_paid = match bps_of_u64(fees.protocol_cut, market.operator_fee_bps)? {
+        0 => 0,
+        cut => {
+            let treasury = accounts.operator_treasury.as_ref().ok_or(CoffeeError::OperatorTreasuryMissing)?;
+            let paid = cut.min(farmer_fee_paid - farmer_fee);
+            legs.pay(math::Side::Short, paid, treasury)?;
+            paid
+        }
+    };
+    legs.pay(math::Side::Short, farmer_fee_paid - operator_paid, &accounts.fee_treasury)?;
+    let buyer_fee_paid = fees.buyer_cut.min(legs.available(math::Side::Long));
+    legs.pay(math::Side::Long, buyer_fee_paid, &accounts.fee_treasury)?;
+
+    // insurance (buyer's vaults first) and the net PnL leg
+    let insurance_from_buyer = insurance_cut.min(legs.available(math::Side::Long));
+    legs.pay(math::Side::Long, insurance_from_buyer, &accounts.insurance_treasury)?;
+    let (_, insurance_from_farmer) = plan_insurance(insurance_cut, insurance_from_buyer, legs.available(math::Side::Short));
+    legs.pay(math::Side::Short, insurance_from_farmer, &accounts.insurance_treasury)?;
+
+    let winning: Vec<bool> = pnls.iter().map(|&p| p != 0 && (p > 0) == (net_pnl_long > 0)).collect();
+    let owed: Vec<u64> = pnls.iter().map(|p| p.unsigned_abs().min(u64::MAX as u128) as u64).collect();
+    let mut haircut_caps = Vec::with_capacity(deals.len());
+    for (i, deal) in deals.iter().enumerate() {
+        let cap = if winning[i] { deal.adl_haircut_due(market.loss_socialization_index)?.min(owed[i]) } else { 0 };
+        haircut_caps.push(cap);
+    }
+    let haircut_due = haircut_caps.iter().fold(0u64, |acc, &c| acc.saturating_add(c));
+    let loser_balance = match net_pnl_long {
+        p if p > 0 => legs.available(math::Side::Short),
+        p if p < 0 => legs.available(math::Side::Long),
+        _ => 0,
+    };
+    let pnl = plan_pnl(net_pnl_long, loser_balance, haircut_due)?;
+    if let Some(winner) = pnl.winner {
+        let (loser, receive) = match winner {
+            math::Side::Long => (math::Side::Short, &accounts.buyer_receive),
+            math::Side::Short => (math::Side::Long, &accounts.farmer_receive),
+        };
+        legs.pay(loser, pnl.paid_to_winner(), receive)?;
+        legs.pay(loser, pnl.haircut, &accounts.insurance_treasury)?;
+    }
+
+    // residual leg
+    let min_transfer = market.min_transfer_amount;
+    let (farmer_residual, farmer_dust) =
+        legs.release_side(math::Side::Short, min_transfer, &accounts.farmer_receive, &accounts.insurance_treasury)?;
+    let (buyer_residual, buyer_dust) =
+        legs.release_side(math::Side::Long, min_transfer, &accounts.buyer_receive, &accounts.insurance_treasury)?;
+    legs.check_drained()?;
+    let transfers = legs.transfers;
+
+    // attribute the pooled legs back to the deals: fees by notional, haircut and shortfall to the deals
+    // on the winning side in order, everything else offset in full
+    let insurance_fee = insurance_from_buyer.saturating_add(insurance_from_farmer);
+    let fee_shares = pro_rata(farmer_fee_paid.saturating_add(buyer_fee_paid).saturating_add(insurance_fee), &notionals)?;
+    let haircuts = spread_in_order(pnl.haircut, &haircut_caps);
+    let shortfall_caps: Vec<u64> = owed.iter().zip(haircuts.iter()).zip(winning.iter())
+        .map(|((&o, &h), &w)| if w { o - h } else { 0 })
+        .collect();
+    let shortfalls = spread_in_order(pnl.shortfall, &shortfall_caps);
+    for (i, deal) in deals.iter_mut().enumerate() {
+        deal.start_settling();
+        deal.final_price_per_kg = price;
+        deal.record_fees(fee_shares[i])?;
+        if pnls[i] != 0 {
+            let winner = if pnls[i] > 0 { math::Side::Long } else { math::Side::Short };
+            let paid = owed[i] - shortfalls[i];
+            deal.record_realized_pnl(winner, paid, paid - haircuts[i])?;
+            let winner_key = if winner == math::Side::Long { deal.buyer } else { deal.farmer };
+            if haircuts[i] > 0 {
+                deal.adl_haircut = haircuts[i];
+                emit_event!(accounts, ctx.bumps, AdlHaircutApplied { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, winner: winner_key, haircut: haircuts[i] });
+            }
+            if shortfalls[i] > 0 {
+                deal.record_shortfall(winner_key, shortfalls[i])?;
+                emit_event!(accounts, ctx.bumps, SettlementShortfall { schema_version: EVENT_SCHEMA_VERSION, deal: deal.key(), deal_id: deal.deal_id, creditor: winner_key, shortfall: shortfalls[i] });
+            }
+        }
+        deal.settlement_step = SettlementStep::Done as u8;
+        deal.mark_settled();
+        deal.exit(ctx.program_id)?;
+        accounts.market.release_open_interest(deal);
+    }
+
+    emit_event!(accounts, ctx.bumps, PairNetSettled {
+        schema_version: EVENT_SCHEMA_VERSION,
+        market: accounts.market.key(),
+        farmer,
+        buyer,
+        deals: deals.len() as u8,
+        price,
+        net_pnl_long: net_pnl_long.try_into().map_err(|_| CoffeeError::MathOverflow)?,
+        gross_pnl: owed.iter().fold(0u64, |acc, &o| acc.saturating_add(o)),
+        farmer_fee: farmer_fee_paid,
+        buyer_fee: buyer_fee_paid,
+        protocol_fee: farmer_fee_paid - farmer_fee - operator_paid,
+        operator_fee: operator_paid,
+        insurance_fee,
+        pnl_paid: pnl.paid_to_winner(),
+        adl_haircut: pnl.haircut,
+        pnl_shortfall: pnl.shortfall,
+        farmer_residual,
+        buyer_residual,
+        dust_swept: farmer_dust.saturating_add(buyer_dust),
+        transfers,
+    });
+    Ok(())
+}
+
+// Initial margin each side posted against the `reduce_kg` being closed (rounded down); top-ups stay
+// locked until final settlement
+pub fn reduced_margin_share(initial_margin_each: u64, reduce_kg: u64, quantity_kg: u64) -> Result<u64> {
+    require!(quantity_kg > 0, CoffeeError::ZeroQty);
+    let share = (initial_margin_each as u128)
+        .checked_mul(reduce_kg as u128)
+        .ok_or(CoffeeError::MathOverflow)?
+        / quantity_kg as u128;
+    Ok(share as u64)
+}
+
+// Margin handed back to one side on a reduction: its share, less what it paid out (PnL, break fee)
+pub fn plan_reduce_release(share: u64, charged: u64) -> u64 {
+    share.saturating_sub(charged)
+}
+
+// Partial cash settlement before expiry: `reduce_kg` closes at the mark, margin backing it is released
+pub fn reduce_cash_deal(ctx: Context<ReduceDeal>, reduce_kg: u64) -> Result<()> {
+    let accounts = ctx.accounts;
+    let deal_key = accounts.deal.key();
+    let market = &mut accounts.market;
+    let deal = &mut accounts.deal;
+
+    require!(!deal.settled && !deal.settling && !deal.voided && !deal.liquidated, CoffeeError::DealAlreadySettled);
+    require!(deal.farmer_deposited && deal.buyer_deposited, CoffeeError::DealNotLive);
+    require!(!deal.physical_delivery && !deal.prepaid, CoffeeError::WrongSettlementType);
+    require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+    require!(!deal.omnibus, CoffeeError::OmnibusUnsupported);
+    check_margin_vaults(market, deal, &accounts.vault_auth.key(), &accounts.farmer_margin_vault, &accounts.buyer_margin_vault)?;
+    require!(deal.sponsored_margin == 0, CoffeeError::DealSponsored);
+    let remaining_kg = deal.quantity_kg.checked_sub(reduce_kg).ok_or(CoffeeError::InvalidReduceQuantity)?;
+    require!(reduce_kg > 0 && remaining_kg > 0, CoffeeError::InvalidReduceQuantity);
+    require!(
+        !market.whole_contracts_only || market.contracts_for_qty(remaining_kg).is_some(),
+        CoffeeError::InvalidReduceQuantity
+    );
+    if market.market_kind == MarketKind::Perpetual as u8 {
+        require!(deal.funding_checkpoint == market.cumulative_funding_per_kg, CoffeeError::FundingNotApplied);
+    }
+
+    // the initiator's side, and whether the other side co-signed
+    let initiator = accounts.initiator.key();
+    let initiator_side = deal.side_of(&initiator).ok_or(CoffeeError::Unauthorized)?;
+    let other = deal.party(initiator_side.opposite());
+    let cosigned = accounts.counterparty.as_ref().map_or(false, |c| c.key() == other);
+    require!(cosigned || market.reduce_break_fee_bps > 0, CoffeeError::ReduceNeedsCounterparty);
+
+    let price = mark_price(market)?;
+    let pnl_long = deal.slot_pnl(market, price, reduce_kg, math::Side::Long)?;
+
+    let legs = VaultLegs::new(
+        &accounts.vault_auth,
+        &accounts.farmer_margin_vault,
+        &accounts.buyer_margin_vault,
+        &accounts.token_program,
+        deal_key,
+    );
+    let mut charged = [0u64; 2]; // [farmer, buyer] paid out of the vaults by this reduction
+
+    // PnL on the reduced kilograms; a partial close never leaves debt behind (ADL applies at final settlement)
+    let (loser, winner_receive) = if pnl_long > 0 {
+        (math::Side::Short, &accounts.buyer_receive)
+    } else {
+        (math::Side::Long, &accounts.farmer_receive)
+    };
+    let pnl = plan_pnl(pnl_long, legs.available(loser), 0)?;
+    require!(pnl.shortfall == 0, CoffeeError::ReduceUndercollateralized);
+    legs.pay(loser, pnl.pay, winner_receive)?;
+    charged[side_index(loser)] += pnl.pay;
+    if let Some(winner) = pnl.winner {
+        deal.record_realized_pnl(winner, pnl.pay, pnl.pay)?;
+    }
+
+    let counter_receive = match initiator_side {
+        math::Side::Short => &accounts.buyer_receive,
+        math::Side::Long => &accounts.farmer_receive,
+    };
+    let break_fee = if cosigned {
+        0
+    } else {
+        let reduced_notional: u64 = market.notional(deal.agreed_price_per_kg, reduce_kg)?
+            .try_into()
+            .map_err(|_| CoffeeError::MathOverflow)?;
+        let fee = bps_of_u64(reduced_notional, market.reduce_break_fee_bps)?;
+        require!(fee <= legs.available(initiator_side), CoffeeError::ReduceUndercollateralized);
+        legs.pay(initiator_side, fee, counter_receive)?;
+        charged[side_index(initiator_side)] += fee;
+        let counter_side = match initiator_side {
+            math::Side::Short => math::Side::Long,
+            math::Side::Long => math::Side::Short,
+        };
+        deal.record_realized_pnl(counter_side, fee, fee)?;
+        fee
+    };
+
+    let share = reduced_margin_share(deal.initial_margin_each, reduce_kg, deal.quantity_kg)?;
+    let farmer_released = plan_reduce_release(share, charged[0]).min(legs.available(math::Side::Short));
+    legs.pay(math::Side::Short, farmer_released, &accounts.farmer_receive)?;
+    let buyer_released = plan_reduce_release(share, charged[1]).min(legs.available(math::Side::Long));
+    legs.pay(math::Side::Long, buyer_released, &accounts.buyer_receive)?;
+    legs.check_solvency()?;
+
+    // shrink the deal and its share of the market aggregates
+    let reduced_price_qty = (deal.agreed_price_per_kg as u128).saturating_mul(reduce_kg as u128);
+    // everything that left the vaults: PnL, break fee and released margin
+    let left_vaults = charged[0] + charged[1] + farmer_released + buyer_released;
+    let margin_released = left_vaults.min(deal.margin_locked);
+    deal.quantity_kg = remaining_kg;
+    deal.initial_margin_each -= share;
+    deal.margin_locked -= margin_released;
+    market.total_open_notional_kg = market.total_open_notional_kg.saturating_sub(reduce_kg);
+    market.total_margin_locked = market.total_margin_locked.saturating_sub(margin_released);
+    market.open_price_qty_sum = market.open_price_qty_sum.saturating_sub(reduced_price_qty);
+
+    emit_event!(accounts, ctx.bumps, DealReduced {
+        schema_version: EVENT_SCHEMA_VERSION,
+        deal: deal_key,
+        deal_id: deal.deal_id,
+        initiator,
+        price,
+        reduced_kg: reduce_kg,
+        remaining_kg,
+        pnl_long: pnl_long.try_into().map_err(|_| CoffeeError::MathOverflow)?,
+        break_fee,
+        farmer_released,
+        buyer_released,
+    });
+    record_deal_action(deal, accounts.action_log.as_deref_mut(), DealAction::Reduce, initiator, reduce_kg)?;
+    Ok(())
+}
+
+// Pay an attached FX forward between the margin vaults at the oracle rate. Fixes when the deal could
+// settle (market settlement, the deal deadline, or the delivery window for physical deals); the loser
+// pays from its vault up to what it holds, and whatever leaves the vaults stops counting as margin
+pub fn settle_fx_leg(ctx: Context<SettleFxHedge>) -> Result<()> {
+    let accounts = ctx.accounts;
+    let deal_key = accounts.deal.key();
+    let market = &mut accounts.market;
+    let deal = &mut accounts.deal;
+
+    require!(deal.fx_oracle != Pubkey::default(), CoffeeError::NoFxHedge);
+    require!(!deal.fx_settled, CoffeeError::DealAlreadySettled);
+    require!(!deal.settled && !deal.settling, CoffeeError::DealAlreadySettled);
+    require!(!deal.is_cross_margined(), CoffeeError::DealCrossMargined);
+    require!(!deal.omnibus, CoffeeError::OmnibusUnsupported);
+    check_margin_vaults(market, deal, &accounts.vault_auth.key(), &accounts.farmer_margin_vault, &accounts.buyer_margin_vault)?;
+    let now = Clock::get()?.unix_timestamp;
+    let fixing = now >= market.settlement_ts
+        || now >= deal.deadline_ts
+        || (deal.physical_delivery && now >= deal.delivery_start_ts);
+    require!(fixing, CoffeeError::NotYetSettleTime);
+    let spot_rate = accounts.fx_oracle.fresh_rate(now).ok_or(CoffeeError::FxRateStale)?;
+
+    let pnl_farmer = math::fx_forward_pnl(deal.fx_notional, deal.fx_locked_rate, spot_rate).ok_or(CoffeeError::MathOverflow)?;
+    let legs = VaultLegs::new(
+        &accounts.vault_auth,
+        &accounts.farmer_margin_vault,
+        &accounts.buyer_margin_vault,
+        &accounts.token_program,
+        deal_key,
+    );
+    // in long (buyer) terms, as plan_pnl expects
+    let pnl_long = -pnl_farmer;
+    let (loser, winner_receive) = if pnl_long > 0 {
+        (math::Side::Short, &accounts.buyer_receive)
+    } else {
+        (math::Side::Long, &accounts.farmer_receive)
+    };
+    let pnl = plan_pnl(pnl_long, legs.available(loser), 0)?;
+    legs.pay(loser, pnl.pay, winner_receive)?;
+    legs.check_solvency()?;
+    if let Some(winner) = pnl.winner {
+        deal.record_realized_pnl(winner, pnl.pay, pnl.pay)?;
+    }
+
+    let released = pnl.pay.min(deal.margin_locked);
+    deal.margin_locked -= released;
+    market.total_margin_locked = market.total_margin_locked.saturating_sub(released);
+    deal.fx_settled = true;
+
+    emit_event!(accounts, ctx.bumps, FxHedgeSettled {
+        schema_version: EVENT_SCHEMA_VERSION,
+        deal: deal_key,
+        deal_id: deal.deal_id,
+        spot_rate,
+        pnl_farmer: pnl_farmer.try_into().map_err(|_| CoffeeError::MathOverflow)?,
+        paid: pnl.pay,
+        shortfall: pnl.shortfall,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_pnl() {
+        // buyer wins 3_000, farmer vault holds 2_000, 100 of socialized loss due
+        let plan = plan_pnl(3_000, 2_000, 100).unwrap();
+        assert_eq!(plan.winner, Some(math::Side::Long));
+        assert_eq!((plan.owed, plan.pay, plan.haircut, plan.shortfall), (3_000, 2_000, 100, 1_000));
+        assert_eq!(plan.paid_to_winner(), 1_900);
+
+        let plan = plan_pnl(-500, 10_000, 0).unwrap();
+        assert_eq!(plan.winner, Some(math::Side::Short));
+        assert_eq!((plan.pay, plan.shortfall), (500, 0));
+
+        assert_eq!(plan_pnl(0, 10_000, 100).unwrap(), PnlPlan::default());
+        assert!(plan_pnl(i128::MAX, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_plan_sponsor_return_first_loss() {
+        // 4_000 of the farmer's own plus a 5_000 stake, nothing lost: both come back whole
+        assert_eq!(plan_sponsor_return(5_000, 4_000, 9_000), (5_000, 0));
+        // a 2_000 loss leaves 7_000: the sponsor takes it, the farmer keeps its 4_000
+        assert_eq!(plan_sponsor_return(5_000, 4_000, 7_000), (3_000, 2_000));
+        // the stake is gone before the farmer's own margin is touched
+        assert_eq!(plan_sponsor_return(5_000, 4_000, 4_000), (0, 5_000));
+        assert_eq!(plan_sponsor_return(5_000, 4_000, 1_000), (0, 5_000));
+    }
+
+    #[test]
+    fn test_plan_insurance_buyer_first() {
+        assert_eq!(plan_insurance(500, 1_000, 1_000), (500, 0));
+        assert_eq!(plan_insurance(500, 200, 1_000), (200, 300));
+        assert_eq!(plan_insurance(500, 200, 100), (200, 100));
+    }
+
+    #[test]
+    fn test_check_invariant() {
+        assert!(check_invariant(true, "ok").is_ok());
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict-invariants"))]
+    #[should_panic(expected = "invariant violated: vault overdrawn")]
+    fn test_check_invariant_asserts_in_debug() {
+        let _ = check_invariant(false, "vault overdrawn");
+    }
+
+    #[test]
+    #[cfg(feature = "strict-invariants")]
+    fn test_check_invariant_strict_errors() {
+        assert!(check_invariant(false, "vault overdrawn").is_err());
+    }
+
+    #[test]
+    fn test_strategy_legs() {
+        assert_eq!(SettlementStrategy::physical_for(40, 100), SettlementStrategy::PhysicalPartial);
+        assert_eq!(SettlementStrategy::physical_for(100, 100), SettlementStrategy::PhysicalFull);
+        assert!(!SettlementStrategy::PhysicalPartial.is_final());
+        assert!(SettlementStrategy::PhysicalFull.is_final());
+        assert!(!SettlementStrategy::ForceMajeure.settles_pnl());
+        assert!(SettlementStrategy::Liquidation.settles_pnl() && SettlementStrategy::CashTwap.settles_pnl());
+    }
+
+    #[test]
+    fn test_plan_units() {
+        // 1_050 of winnings at 100/kg: 10 kg of CFT, 50 left in quote
+        assert_eq!(plan_units(1_050, 100, u64::MAX).unwrap(), (10, 1_000));
+        // capped by verified-but-unminted supply
+        assert_eq!(plan_units(1_050, 100, 4).unwrap(), (4, 400));
+        assert_eq!(plan_units(99, 100, u64::MAX).unwrap(), (0, 0));
+        assert!(plan_units(1_050, 0, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_net_settle_attribution() {
+        // a 700 haircut against winning deals that can bear 500, 0 and 400
+        assert_eq!(spread_in_order(700, &[500, 0, 400]), vec![500, 0, 200]);
+        assert_eq!(spread_in_order(0, &[5, 5]), vec![0, 0]);
+        // fees split by notional; rounding dust lands on the last deal
+        assert_eq!(pro_rata(100, &[1, 1, 1]).unwrap(), vec![33, 33, 34]);
+        assert_eq!(pro_rata(90, &[2_000, 1_000]).unwrap(), vec![60, 30]);
+        assert_eq!(pro_rata(7, &[0, 0]).unwrap(), vec![0, 7]);
+    }
+
+    #[test]
+    fn test_plan_reduce_release() {
+        // closing 40 of 100 kg frees 40% of each side's initial margin
+        let share = reduced_margin_share(1_000, 40, 100).unwrap();
+        assert_eq!(share, 400);
+        assert_eq!(reduced_margin_share(1_000, 33, 100).unwrap(), 330);
+        assert!(reduced_margin_share(1_000, 40, 0).is_err());
+        // winner gets its whole share; loser paid 300 of PnL out of it
+        assert_eq!(plan_reduce_release(share, 0), 400);
+        assert_eq!(plan_reduce_release(share, 300), 100);
+        // PnL beyond the share comes out of the margin backing the open remainder
+        assert_eq!(plan_reduce_release(share, 500), 0);
+    }
+}
@@ -0,0 +1,181 @@
+// Wormhole core bridge messages for cross-chain buyers.
+// A settled deal with a `crosschain_recipient` can publish its outcome as a Wormhole message, which an
+// EVM-side escrow verifies (VAA) before releasing stablecoins to the buyer there. The bridge is called
+// by raw CPI (no wormhole crate): `post_message` is instruction 1 of the core bridge, borsh-encoded as
+// (nonce: u32, payload: Vec<u8>, consistency_level: u8). The payload is big-endian for EVM decoders.
+use super::*;
+
+pub const POST_MESSAGE_IX: u8 = 1;
+pub const CONSISTENCY_FINALIZED: u8 = 1;
+pub const BRIDGE_FEE_OFFSET: usize = 4 + 8 + 4; // guardian_set_index + last_lamports + guardian_set_expiration_time
+pub const PAYLOAD_VERSION: u8 = 1;
+pub const PAYLOAD_LEN: usize = 1 + 1 + 32 + 8 + 2 + 32 + 8 + 8 + 8 + 8 + 1;
+
+// How the deal ended, as reported cross-chain
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettlementOutcome {
+    Cash = 1,
+    Physical = 2,
+    Liquidated = 3,
+    ForceMajeure = 4,
+    Voided = 5,
+}
+
+impl SettlementOutcome {
+    pub fn of(deal: &Deal) -> Self {
+        if deal.voided {
+            SettlementOutcome::Voided
+        } else if deal.force_majeure_reason != 0 {
+            SettlementOutcome::ForceMajeure
+        } else if deal.liquidated {
+            SettlementOutcome::Liquidated
+        } else if deal.physical_delivery {
+            SettlementOutcome::Physical
+        } else {
+            SettlementOutcome::Cash
+        }
+    }
+}
+
+// version | outcome | deal | deal_id | recipient chain | recipient | agreed price/kg | quantity kg |
+// delivered kg | unpaid owed | unpaid owed to the buyer (1) or farmer (0)
+pub fn settlement_payload(deal_key: &Pubkey, deal: &Deal) -> Vec<u8> {
+    let mut p = Vec::with_capacity(PAYLOAD_LEN);
+    p.push(PAYLOAD_VERSION);
+    p.push(SettlementOutcome::of(deal) as u8);
+    p.extend_from_slice(deal_key.as_ref());
+    p.extend_from_slice(&deal.deal_id.to_be_bytes());
+    p.extend_from_slice(&deal.crosschain_chain.to_be_bytes());
+    p.extend_from_slice(&deal.crosschain_recipient);
+    p.extend_from_slice(&deal.agreed_price_per_kg.to_be_bytes());
+    p.extend_from_slice(&deal.quantity_kg.to_be_bytes());
+    p.extend_from_slice(&deal.delivered_kg_total.to_be_bytes());
+    p.extend_from_slice(&deal.unpaid_owed.to_be_bytes());
+    p.push((deal.unpaid_owed > 0 && deal.debt_creditor == deal.buyer) as u8);
+    p
+}
+
+pub fn post_message_data(nonce: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + 4 + 4 + payload.len() + 1);
+    data.push(POST_MESSAGE_IX);
+    data.extend_from_slice(&nonce.to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(payload);
+    data.push(CONSISTENCY_FINALIZED);
+    data
+}
+
+// message fee from the bridge config account
+pub fn bridge_fee(bridge_data: &[u8]) -> Result<u64> {
+    let bytes = bridge_data
+        .get(BRIDGE_FEE_OFFSET..BRIDGE_FEE_OFFSET + 8)
+        .ok_or(CoffeeError::WormholeAccountInvalid)?;
+    Ok(u64::from_le_bytes(bytes.try_into().map_err(|_| CoffeeError::WormholeAccountInvalid)?))
+}
+
+// Pay the bridge fee and post `payload`; the message account and emitter are PDAs of this program
+pub fn post_settlement_message(accounts: &PostSettlementMessage, bumps: &PostSettlementMessageBumps, payload: Vec<u8>) -> Result<()> {
+    let fee = bridge_fee(&accounts.wormhole_bridge.try_borrow_data()?)?;
+    if fee > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: accounts.payer.to_account_info(),
+                    to: accounts.wormhole_fee_collector.to_account_info(),
+                },
+            ),
+            fee,
+        )?;
+    }
+
+    let ix = solana_program::instruction::Instruction {
+        program_id: accounts.wormhole_program.key(),
+        accounts: vec![
+            solana_program::instruction::AccountMeta::new(accounts.wormhole_bridge.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.wormhole_message.key(), true),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.wormhole_emitter.key(), true),
+            solana_program::instruction::AccountMeta::new(accounts.wormhole_sequence.key(), false),
+            solana_program::instruction::AccountMeta::new(accounts.payer.key(), true),
+            solana_program::instruction::AccountMeta::new(accounts.wormhole_fee_collector.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.clock.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.rent.key(), false),
+            solana_program::instruction::AccountMeta::new_readonly(accounts.system_program.key(), false),
+        ],
+        data: post_message_data(0, &payload),
+    };
+    let deal_key = accounts.deal.key();
+    solana_program::program::invoke_signed(
+        &ix,
+        &[
+            accounts.wormhole_bridge.to_account_info(),
+            accounts.wormhole_message.to_account_info(),
+            accounts.wormhole_emitter.to_account_info(),
+            accounts.wormhole_sequence.to_account_info(),
+            accounts.payer.to_account_info(),
+            accounts.wormhole_fee_collector.to_account_info(),
+            accounts.clock.to_account_info(),
+            accounts.rent.to_account_info(),
+            accounts.system_program.to_account_info(),
+        ],
+        &[
+            &[SEED_PREFIX, WORMHOLE_MSG_SEED, deal_key.as_ref(), &[bumps.wormhole_message]],
+            &[SEED_PREFIX, EMITTER_SEED, &[bumps.wormhole_emitter]],
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settlement_payload_layout() {
+        let buyer = Pubkey::new_unique();
+        let deal = Deal {
+            buyer,
+            deal_id: 7,
+            agreed_price_per_kg: 500,
+            quantity_kg: 1_000,
+            delivered_kg_total: 1_000,
+            physical_delivery: true,
+            crosschain_chain: 2, // Ethereum
+            crosschain_recipient: [0xab; 32],
+            unpaid_owed: 30,
+            debt_creditor: buyer,
+            ..Default::default()
+        };
+        let key = Pubkey::new_unique();
+        let p = settlement_payload(&key, &deal);
+        assert_eq!(p.len(), PAYLOAD_LEN);
+        assert_eq!((p[0], p[1]), (PAYLOAD_VERSION, SettlementOutcome::Physical as u8));
+        assert_eq!(&p[2..34], key.as_ref());
+        assert_eq!(&p[34..42], &7u64.to_be_bytes());
+        assert_eq!(&p[42..44], &2u16.to_be_bytes());
+        assert_eq!(&p[44..76], &[0xab; 32]);
+        assert_eq!(p[PAYLOAD_LEN - 1], 1); // shortfall owed to the buyer
+    }
+
+    #[test]
+    fn test_post_message_data_and_fee() {
+        let data = post_message_data(9, &[1, 2, 3]);
+        assert_eq!(data, vec![POST_MESSAGE_IX, 9, 0, 0, 0, 3, 0, 0, 0, 1, 2, 3, CONSISTENCY_FINALIZED]);
+
+        let mut bridge = vec![0u8; BRIDGE_FEE_OFFSET + 8];
+        bridge[BRIDGE_FEE_OFFSET..].copy_from_slice(&100u64.to_le_bytes());
+        assert_eq!(bridge_fee(&bridge).unwrap(), 100);
+        assert!(bridge_fee(&bridge[..BRIDGE_FEE_OFFSET]).is_err());
+    }
+
+    #[test]
+    fn test_settlement_outcome() {
+        let mut deal = Deal::default();
+        assert_eq!(SettlementOutcome::of(&deal), SettlementOutcome::Cash);
+        deal.liquidated = true;
+        assert_eq!(SettlementOutcome::of(&deal), SettlementOutcome::Liquidated);
+        deal.voided = true;
+        assert_eq!(SettlementOutcome::of(&deal), SettlementOutcome::Voided);
+    }
+}
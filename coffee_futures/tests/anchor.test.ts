@@ -190,6 +190,7 @@ describe("Coffee Futures – happy path", () => {
       .accounts({
         market: marketPda,
         oraclePublisher: oracleKp.publicKey,
+        accessControl: null,
       })
       .signers([oracleKp])
       .rpc();
@@ -215,13 +216,12 @@ describe("Coffee Futures – happy path", () => {
       spl.TOKEN_PROGRAM_ID,
       ASSOCIATED_TOKEN_PROGRAM_ID
     );
-    const vaultBuyerAta = await spl.getAssociatedTokenAddress(
-      quoteMint,
-      vaultAuthPda,
-      true,
-      spl.TOKEN_PROGRAM_ID,
-      ASSOCIATED_TOKEN_PROGRAM_ID
-    );
+    // the buyer's margin sits in a vault of its own, not the vault_auth ATA
+    const vaultBuyerPda = findPda([
+      SEED_PREFIX,
+      enc("buyer_vault"),
+      dealPda.toBuffer(),
+    ]);
 
     const agreedPricePerKg = new BN(1_500);
     const quantityKg = new BN(10);
@@ -238,7 +238,9 @@ describe("Coffee Futures – happy path", () => {
         [],      // asset_qty
         null,    // merkle_root
         null,    // referrer
-        null     // fee_split_bps
+        null,    // fee_split_bps
+        null,    // margin_override
+        0        // farmer_side: Sell
       )
       .accounts({
         farmer: farmerKp.publicKey,
@@ -248,7 +250,7 @@ describe("Coffee Futures – happy path", () => {
         deal: dealPda,
         vaultAuth: vaultAuthPda,
         farmerMarginVault: vaultFarmerAta,
-        buyerMarginVault: vaultBuyerAta,
+        buyerMarginVault: vaultBuyerPda,
         farmerMarginFrom: farmerQuoteAta.address,
         buyerMarginFrom: buyerQuoteAta.address,
         tokenProgram: spl.TOKEN_PROGRAM_ID,
@@ -259,10 +261,39 @@ describe("Coffee Futures – happy path", () => {
       .signers([farmerKp, buyerKp])
       .rpc();
 
+    // ---------- per-trader volume stats (fee tiers) ----------
+    const farmerStatsPda = findPda([
+      SEED_PREFIX,
+      enc("trader_stats"),
+      marketPda.toBuffer(),
+      farmerKp.publicKey.toBuffer(),
+    ]);
+    const buyerStatsPda = findPda([
+      SEED_PREFIX,
+      enc("trader_stats"),
+      marketPda.toBuffer(),
+      buyerKp.publicKey.toBuffer(),
+    ]);
+    for (const [trader, stats] of [
+      [farmerKp.publicKey, farmerStatsPda],
+      [buyerKp.publicKey, buyerStatsPda],
+    ]) {
+      await pg.program.methods
+        .initTraderStats()
+        .accounts({
+          payer: authority.publicKey,
+          market: marketPda,
+          trader,
+          traderStats: stats,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    }
+
     // push mark up so buyer wins
     await pg.program.methods
       .publishPrice(new BN(1_800), new BN(2))
-      .accounts({ market: marketPda, oraclePublisher: oracleKp.publicKey })
+      .accounts({ market: marketPda, oraclePublisher: oracleKp.publicKey, accessControl: null })
       .signers([oracleKp])
       .rpc();
 
@@ -274,12 +305,17 @@ describe("Coffee Futures – happy path", () => {
         deal: dealPda,
         vaultAuth: vaultAuthPda,
         farmerMarginVault: vaultFarmerAta,
-        buyerMarginVault: vaultBuyerAta,
+        buyerMarginVault: vaultBuyerPda,
         farmerReceive: farmerQuoteAta.address,
         buyerReceive: buyerQuoteAta.address,
         feeTreasury: feeTreasuryAta.address,
         insuranceTreasury: insuranceTreasuryAta.address,
         insuranceTreasuryAuthority: authority.publicKey,
+        farmerStats: farmerStatsPda,
+        buyerStats: buyerStatsPda,
+        cftMint: null, // fees paid in quote
+        farmerCftAta: null,
+        buyerCftAta: null,
         tokenProgram: spl.TOKEN_PROGRAM_ID,
       })
       .rpc();
@@ -299,6 +335,9 @@ describe("Coffee Futures – happy path", () => {
     assert.ok(marketAcct.cftMint.equals(cftMintKp.publicKey));
     assert.equal(marketAcct.programVersion, 1);
 
+    const buyerStats = await pg.program.account.traderStats.fetch(buyerStatsPda);
+    assert.equal(buyerStats.lifetimeNotional.toNumber(), 15_000);
+
     const buyerBal = await pg.connection.getTokenAccountBalance(
       buyerQuoteAta.address
     );
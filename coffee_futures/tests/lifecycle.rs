@@ -0,0 +1,549 @@
+// In-process lifecycle tests: the program runs natively inside solana-program-test's BanksClient, so
+// no local validator or TypeScript toolchain is needed. Run with `cargo test --test lifecycle`; the
+// dev-dependencies it uses are listed in the README (In-process lifecycle tests).
+//
+// Covers the same path as tests/anchor.test.ts and carries on into physical delivery:
+// init_cft_mint -> create_market -> publish_price -> open_deal -> mark_to_market
+//   -> settle_cash -> close_deal, and open_deal -> verify_and_settle_physical (partial, then final).
+
+use anchor_lang::{AccountDeserialize, InstructionData, ToAccountMetas};
+use coffee_futures::{accounts, instruction, pda, Deal, Market, OrderSide};
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account_info::AccountInfo,
+    clock::Clock,
+    entrypoint::ProgramResult,
+    instruction::Instruction,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_instruction, system_program, sysvar,
+    transaction::Transaction,
+};
+use spl_associated_token_account::{get_associated_token_address, instruction::create_associated_token_account};
+
+const QUOTE_DECIMALS: u8 = 6;
+const MARGIN_FUNDS: u64 = 1_000_000_000;
+const AGREED_PRICE: u64 = 1_500;
+const QTY_KG: u64 = 10;
+
+// Anchor's entrypoint ties the account slice and AccountInfo lifetimes together, which processor!
+// cannot express; the shim only relabels the lifetime.
+fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let accounts: &[AccountInfo] = unsafe { std::mem::transmute(accounts) };
+    coffee_futures::entry(program_id, accounts, data)
+}
+
+struct Trader {
+    key: Keypair,
+    quote_ata: Pubkey,
+    stats: Pubkey,
+}
+
+struct DealAccounts {
+    deal: Pubkey,
+    vault_auth: Pubkey,
+    farmer_vault: Pubkey, // ATA of vault_auth
+    buyer_vault: Pubkey,  // pda::buyer_margin_vault
+}
+
+struct Harness {
+    ctx: ProgramTestContext,
+    authority: Keypair,
+    oracle: Keypair,
+    verifier: Keypair,
+    quote_mint: Pubkey,
+    cft_mint: Pubkey,
+    cft_mint_auth: Pubkey,
+    market: Pubkey,
+    fee_treasury: Pubkey,
+    insurance_treasury: Pubkey,
+    price_nonce: u64,
+}
+
+impl Harness {
+    // CFT mint + quote mint + market with the TS test's parameters, settling 30s from now
+    async fn new() -> Self {
+        let program = ProgramTest::new("coffee_futures", coffee_futures::ID, processor!(process_instruction));
+        let ctx = program.start_with_context().await;
+        let authority = ctx.payer.insecure_clone();
+        let mut h = Harness {
+            ctx,
+            authority,
+            oracle: Keypair::new(),
+            verifier: Keypair::new(),
+            quote_mint: Pubkey::default(),
+            cft_mint: Pubkey::default(),
+            cft_mint_auth: Pubkey::default(),
+            market: Pubkey::default(),
+            fee_treasury: Pubkey::default(),
+            insurance_treasury: Pubkey::default(),
+            price_nonce: 0,
+        };
+        h.airdrop(&h.oracle.pubkey()).await;
+        h.airdrop(&h.verifier.pubkey()).await;
+
+        h.quote_mint = h.create_mint(QUOTE_DECIMALS).await;
+        h.fee_treasury = h.create_token_account(&h.authority.pubkey()).await;
+        h.insurance_treasury = h.create_token_account(&h.authority.pubkey()).await;
+
+        let cft_mint = Keypair::new();
+        h.cft_mint = cft_mint.pubkey();
+        h.cft_mint_auth = pda::cft_mint_auth(&h.cft_mint).0;
+        h.send(
+            Instruction {
+                program_id: coffee_futures::ID,
+                accounts: accounts::InitCftMint {
+                    payer: h.authority.pubkey(),
+                    cft_mint: h.cft_mint,
+                    cft_mint_auth: h.cft_mint_auth,
+                    token_program: spl_token::ID,
+                    system_program: system_program::ID,
+                    rent: sysvar::rent::ID,
+                }
+                .to_account_metas(None),
+                data: instruction::InitCftMint { decimals: 3 }.data(),
+            },
+            &[&cft_mint],
+        )
+        .await;
+
+        h.market = pda::market(&h.authority.pubkey(), &h.cft_mint, &h.quote_mint).0;
+        let settlement_ts = h.now().await + 30;
+        h.send(
+            Instruction {
+                program_id: coffee_futures::ID,
+                accounts: accounts::CreateMarket {
+                    authority: h.authority.pubkey(),
+                    verifier: h.verifier.pubkey(),
+                    oracle_publisher: h.oracle.pubkey(),
+                    cft_mint: h.cft_mint,
+                    quote_mint: h.quote_mint,
+                    insurance_treasury: h.insurance_treasury,
+                    market: h.market,
+                    market_cft_mint: None,
+                    market_cft_auth: None,
+                    token_program: spl_token::ID,
+                    system_program: system_program::ID,
+                    rent: sysvar::rent::ID,
+                }
+                .to_account_metas(None),
+                data: instruction::CreateMarket {
+                    settlement_ts,
+                    contract_size_kg: 1,
+                    initial_margin_bps: 1_000,
+                    maintenance_margin_bps: 500,
+                    fee_bps: 50,
+                    farmer_fee_bps: 25,
+                    buyer_fee_bps: 25,
+                    max_notional_per_deal: 10_000_000_000,
+                    max_qty_per_deal: 10_000,
+                    max_oracle_age_sec: 3_600,
+                    twap_window_sec: 60,
+                    insurance_bps: 100,
+                    min_transfer_amount: 0,
+                }
+                .data(),
+            },
+            &[],
+        )
+        .await;
+        h
+    }
+
+    async fn send(&mut self, ix: Instruction, signers: &[&Keypair]) {
+        self.try_send(ix, signers).await.unwrap();
+    }
+
+    async fn try_send(&mut self, ix: Instruction, signers: &[&Keypair]) -> Result<(), solana_program_test::BanksClientError> {
+        let blockhash = self.ctx.get_new_latest_blockhash().await.unwrap();
+        let mut all: Vec<&Keypair> = vec![&self.authority];
+        all.extend_from_slice(signers);
+        let tx = Transaction::new_signed_with_payer(&[ix], Some(&self.authority.pubkey()), &all, blockhash);
+        self.ctx.banks_client.process_transaction(tx).await
+    }
+
+    async fn airdrop(&mut self, to: &Pubkey) {
+        let ix = system_instruction::transfer(&self.authority.pubkey(), to, 10_000_000_000);
+        self.send(ix, &[]).await;
+    }
+
+    async fn now(&mut self) -> i64 {
+        self.ctx.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp
+    }
+
+    async fn set_time(&mut self, unix_timestamp: i64) {
+        let mut clock = self.ctx.banks_client.get_sysvar::<Clock>().await.unwrap();
+        clock.unix_timestamp = unix_timestamp;
+        self.ctx.set_sysvar(&clock);
+    }
+
+    async fn create_mint(&mut self, decimals: u8) -> Pubkey {
+        let mint = Keypair::new();
+        let rent = self.ctx.banks_client.get_rent().await.unwrap();
+        let authority = self.authority.pubkey();
+        let blockhash = self.ctx.get_new_latest_blockhash().await.unwrap();
+        let tx = Transaction::new_signed_with_payer(
+            &[
+                system_instruction::create_account(
+                    &authority,
+                    &mint.pubkey(),
+                    rent.minimum_balance(spl_token::state::Mint::LEN),
+                    spl_token::state::Mint::LEN as u64,
+                    &spl_token::ID,
+                ),
+                spl_token::instruction::initialize_mint2(&spl_token::ID, &mint.pubkey(), &authority, Some(&authority), decimals)
+                    .unwrap(),
+            ],
+            Some(&authority),
+            &[&self.authority, &mint],
+            blockhash,
+        );
+        self.ctx.banks_client.process_transaction(tx).await.unwrap();
+        mint.pubkey()
+    }
+
+    async fn create_token_account(&mut self, owner: &Pubkey) -> Pubkey {
+        let account = Keypair::new();
+        let rent = self.ctx.banks_client.get_rent().await.unwrap();
+        let authority = self.authority.pubkey();
+        let blockhash = self.ctx.get_new_latest_blockhash().await.unwrap();
+        let tx = Transaction::new_signed_with_payer(
+            &[
+                system_instruction::create_account(
+                    &authority,
+                    &account.pubkey(),
+                    rent.minimum_balance(spl_token::state::Account::LEN),
+                    spl_token::state::Account::LEN as u64,
+                    &spl_token::ID,
+                ),
+                spl_token::instruction::initialize_account3(&spl_token::ID, &account.pubkey(), &self.quote_mint, owner)
+                    .unwrap(),
+            ],
+            Some(&authority),
+            &[&self.authority, &account],
+            blockhash,
+        );
+        self.ctx.banks_client.process_transaction(tx).await.unwrap();
+        account.pubkey()
+    }
+
+    async fn token_balance(&mut self, account: &Pubkey) -> u64 {
+        match self.ctx.banks_client.get_account(*account).await.unwrap() {
+            Some(acc) => spl_token::state::Account::unpack(&acc.data).unwrap().amount,
+            None => 0,
+        }
+    }
+
+    async fn fetch<T: AccountDeserialize>(&mut self, address: &Pubkey) -> T {
+        let acc = self.ctx.banks_client.get_account(*address).await.unwrap().unwrap();
+        T::try_deserialize(&mut acc.data.as_slice()).unwrap()
+    }
+
+    // funded quote ATA plus TraderStats for a fresh wallet
+    async fn trader(&mut self) -> Trader {
+        let key = Keypair::new();
+        self.airdrop(&key.pubkey()).await;
+        let quote_ata = get_associated_token_address(&key.pubkey(), &self.quote_mint);
+        let create = create_associated_token_account(&self.authority.pubkey(), &key.pubkey(), &self.quote_mint, &spl_token::ID);
+        self.send(create, &[]).await;
+        let mint_to = spl_token::instruction::mint_to(
+            &spl_token::ID,
+            &self.quote_mint,
+            &quote_ata,
+            &self.authority.pubkey(),
+            &[],
+            MARGIN_FUNDS,
+        )
+        .unwrap();
+        self.send(mint_to, &[]).await;
+
+        let stats = pda::trader_stats(&self.market, &key.pubkey()).0;
+        self.send(
+            Instruction {
+                program_id: coffee_futures::ID,
+                accounts: accounts::InitTraderStats {
+                    payer: self.authority.pubkey(),
+                    market: self.market,
+                    trader: key.pubkey(),
+                    trader_stats: stats,
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: instruction::InitTraderStats {}.data(),
+            },
+            &[],
+        )
+        .await;
+        Trader { key, quote_ata, stats }
+    }
+
+    async fn publish_price(&mut self, price_per_kg: u64) {
+        self.price_nonce += 1;
+        let ix = Instruction {
+            program_id: coffee_futures::ID,
+            accounts: accounts::PublishPrice { market: self.market, oracle_publisher: self.oracle.pubkey(), access_control: None, session: None }
+                .to_account_metas(None),
+            data: instruction::PublishPrice { price_per_kg, nonce: self.price_nonce }.data(),
+        };
+        let oracle = self.oracle.insecure_clone();
+        self.send(ix, &[&oracle]).await;
+    }
+
+    async fn open_deal(&mut self, farmer: &Trader, buyer: &Trader, physical_delivery: bool) -> DealAccounts {
+        let deal = pda::deal(&self.market, &farmer.key.pubkey(), &buyer.key.pubkey()).0;
+        let vault_auth = pda::vault_auth(&deal).0;
+        let farmer_vault = get_associated_token_address(&vault_auth, &self.quote_mint);
+        let buyer_vault = pda::buyer_margin_vault(&deal).0;
+        // physical deals deliver the whole quantity as CFT
+        let (assets, asset_qty) = if physical_delivery { (vec![self.cft_mint], vec![QTY_KG]) } else { (vec![], vec![]) };
+        let deadline_ts = self.now().await + 300;
+        let mut metas = accounts::OpenDeal {
+            farmer: farmer.key.pubkey(),
+            buyer: buyer.key.pubkey(),
+            market: self.market,
+            quote_mint: self.quote_mint,
+            deal,
+            vault_auth,
+            farmer_margin_vault: Some(farmer_vault),
+            buyer_margin_vault: Some(buyer_vault),
+            omnibus_farmer_vault: None,
+            omnibus_buyer_vault: None,
+            farmer_margin_from: farmer.quote_ata,
+            buyer_margin_from: buyer.quote_ata,
+            approved_assets: None,
+            insurance_treasury: None,
+            risk_officer: None,
+            template: None,
+            agent: None,
+            agent_grant: None,
+            sample: None,
+            credit_line: None,
+            farmer_stats: None,
+            buyer_stats: None,
+            fee_waiver: None,
+            farmer_index: None,
+            buyer_index: None,
+            token_program: spl_token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+            system_program: system_program::ID,
+            rent: sysvar::rent::ID,
+        }
+        .to_account_metas(None);
+        // the farmer is an unchecked account (an agent may sign instead), so mark its signature here
+        metas[0].is_signer = true;
+        self.send(
+            Instruction {
+                program_id: coffee_futures::ID,
+                accounts: metas,
+                data: instruction::OpenDeal {
+                    agreed_price_per_kg: AGREED_PRICE,
+                    quantity_kg: QTY_KG,
+                    physical_delivery,
+                    deadline_ts,
+                    assets,
+                    asset_qty,
+                    merkle_root: None,
+                    referrer: None,
+                    fee_split_bps: None,
+                    margin_override: None,
+                    farmer_side: OrderSide::Sell as u8,
+                }
+                .data(),
+            },
+            &[&farmer.key, &buyer.key],
+        )
+        .await;
+        DealAccounts { deal, vault_auth, farmer_vault, buyer_vault }
+    }
+
+    async fn mark_to_market(&mut self, d: &DealAccounts) {
+        self.send(
+            Instruction {
+                program_id: coffee_futures::ID,
+                accounts: accounts::MtmCheck {
+                    market: self.market,
+                    deal: d.deal,
+                    vault_auth: d.vault_auth,
+                    farmer_margin_vault: d.farmer_vault,
+                    buyer_margin_vault: d.buyer_vault,
+                    farmer_margin_account: None,
+                    farmer_margin_pool: None,
+                    buyer_margin_account: None,
+                    buyer_margin_pool: None,
+                    liquidation_queue: None,
+                }
+                .to_account_metas(None),
+                data: instruction::MarkToMarket {}.data(),
+            },
+            &[],
+        )
+        .await;
+    }
+
+    fn settle_cash(&self, d: &DealAccounts, farmer: &Trader, buyer: &Trader) -> Instruction {
+        Instruction {
+            program_id: coffee_futures::ID,
+            accounts: accounts::SettleCash {
+                market: self.market,
+                deal: d.deal,
+                vault_auth: d.vault_auth,
+                farmer_margin_vault: d.farmer_vault,
+                buyer_margin_vault: d.buyer_vault,
+                farmer_receive: farmer.quote_ata,
+                buyer_receive: buyer.quote_ata,
+                fee_treasury: self.fee_treasury,
+                insurance_treasury: self.insurance_treasury,
+                insurance_treasury_authority: self.authority.pubkey(),
+                cft_mint: None,
+                farmer_cft_ata: None,
+                buyer_cft_ata: None,
+                cft_mint_auth: None,
+                cft_sale_treasury: None,
+                buyer_cft_allowlist: None,
+                operator_treasury: None,
+                omnibus_auth: None,
+                sponsor_receive: None,
+                farmer_stats: farmer.stats,
+                buyer_stats: buyer.stats,
+                action_log: None,
+                token_program: spl_token::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::SettleCash {}.data(),
+        }
+    }
+
+    fn verify_and_settle_physical(&self, d: &DealAccounts, farmer: &Trader, buyer: &Trader, delivered_kg: u64) -> Instruction {
+        Instruction {
+            program_id: coffee_futures::ID,
+            accounts: accounts::VerifyAndSettlePhysical {
+                market: self.market,
+                access_control: None,
+                deal: d.deal,
+                verifier: self.verifier.pubkey(),
+                cft_mint: self.cft_mint,
+                cft_mint_auth: self.cft_mint_auth,
+                buyer_cft_ata: get_associated_token_address(&buyer.key.pubkey(), &self.cft_mint),
+                vault_auth: d.vault_auth,
+                buyer_margin_vault: d.buyer_vault,
+                farmer_margin_vault: d.farmer_vault,
+                farmer_receive: farmer.quote_ata,
+                buyer_receive: buyer.quote_ata,
+                insurance_treasury: self.insurance_treasury,
+                buyer: buyer.key.pubkey(),
+                farmer_stats: farmer.stats,
+                buyer_stats: buyer.stats,
+                purchase_escrow: None,
+                buyer_cft_allowlist: None,
+                delivery_record: None,
+                quote_mint: self.quote_mint,
+                token_program: spl_token::ID,
+                associated_token_program: spl_associated_token_account::ID,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            }
+            .to_account_metas(None),
+            data: instruction::VerifyAndSettlePhysical { delivered_kg, proof_hashes: vec![], leaf: None }.data(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_cash_settlement_lifecycle() {
+    let mut h = Harness::new().await;
+    h.publish_price(AGREED_PRICE).await;
+    let farmer = h.trader().await;
+    let buyer = h.trader().await;
+
+    let d = h.open_deal(&farmer, &buyer, false).await;
+    let margin = AGREED_PRICE * QTY_KG / 10; // 10% initial margin from each side
+    assert_eq!(h.token_balance(&d.farmer_vault).await, margin);
+    assert_eq!(h.token_balance(&d.buyer_vault).await, margin);
+    let market_key = h.market;
+    let market: Market = h.fetch(&market_key).await;
+    assert_eq!(market.total_open_deals, 1);
+
+    // mark moves up 4%: buyer wins, both sides still above maintenance
+    let settle_price = 1_560;
+    h.publish_price(settle_price).await;
+    h.mark_to_market(&d).await;
+    let deal: Deal = h.fetch(&d.deal).await;
+    assert_eq!(deal.margin_call_ts, 0);
+    assert!(!deal.liquidated);
+
+    // too early: settlement_ts has not been reached
+    let ix = h.settle_cash(&d, &farmer, &buyer);
+    assert!(h.try_send(ix, &[]).await.is_err());
+
+    h.set_time(market.settlement_ts + 1).await;
+    h.publish_price(settle_price).await;
+    let ix = h.settle_cash(&d, &farmer, &buyer);
+    h.send(ix, &[]).await;
+
+    let deal: Deal = h.fetch(&d.deal).await;
+    assert!(deal.settled);
+    assert_eq!(h.token_balance(&d.farmer_vault).await, 0);
+    assert_eq!(h.token_balance(&d.buyer_vault).await, 0);
+    // 50 bps of the 15_000 notional is a 75 fee, all protocol share (the 25 bps farmer and buyer and
+    // 100 bps insurance shares of 75 round to 0), paid from the farmer vault; the buyer's 600 of PnL
+    // comes out of the farmer vault too
+    let fee = 75;
+    let pnl = (settle_price - AGREED_PRICE) * QTY_KG;
+    assert_eq!(deal.settle_fees, [fee, 0, fee, 0]);
+    assert_eq!(deal.settle_pnl, [pnl, 0, 0]);
+    assert_eq!(h.token_balance(&buyer.quote_ata).await, MARGIN_FUNDS + pnl);
+    assert_eq!(h.token_balance(&farmer.quote_ata).await, MARGIN_FUNDS - pnl - fee);
+    let (fee_treasury, insurance_treasury) = (h.fee_treasury, h.insurance_treasury);
+    assert_eq!(h.token_balance(&fee_treasury).await, fee);
+    assert_eq!(h.token_balance(&insurance_treasury).await, 0);
+    let market: Market = h.fetch(&market_key).await;
+    assert_eq!(market.total_open_deals, 0);
+
+    h.send(
+        Instruction {
+            program_id: coffee_futures::ID,
+            accounts: accounts::CloseDeal { deal: d.deal, market: h.market, receiver: h.authority.pubkey(), credit_line: None }
+                .to_account_metas(None),
+            data: instruction::CloseDeal {}.data(),
+        },
+        &[],
+    )
+    .await;
+    assert!(h.ctx.banks_client.get_account(d.deal).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_physical_settlement_lifecycle() {
+    let mut h = Harness::new().await;
+    h.publish_price(AGREED_PRICE).await;
+    let farmer = h.trader().await;
+    let buyer = h.trader().await;
+    let d = h.open_deal(&farmer, &buyer, true).await;
+    let farmer_before = h.token_balance(&farmer.quote_ata).await;
+    let verifier = h.verifier.insecure_clone();
+
+    // partial delivery mints the delivered share of CFT and keeps the deal open
+    let ix = h.verify_and_settle_physical(&d, &farmer, &buyer, 4);
+    h.send(ix, &[&verifier]).await;
+    let buyer_cft = get_associated_token_address(&buyer.key.pubkey(), &h.cft_mint);
+    assert_eq!(h.token_balance(&buyer_cft).await, 4);
+    let deal: Deal = h.fetch(&d.deal).await;
+    assert!(!deal.settled);
+    assert_eq!(deal.delivered_kg_total, 4);
+
+    // over-delivery is rejected
+    let ix = h.verify_and_settle_physical(&d, &farmer, &buyer, QTY_KG);
+    assert!(h.try_send(ix, &[&verifier]).await.is_err());
+
+    // final delivery completes the deal and empties the margin vaults
+    let ix = h.verify_and_settle_physical(&d, &farmer, &buyer, QTY_KG - 4);
+    h.send(ix, &[&verifier]).await;
+    assert_eq!(h.token_balance(&buyer_cft).await, QTY_KG);
+    let deal: Deal = h.fetch(&d.deal).await;
+    assert!(deal.settled);
+    assert_eq!(deal.delivered_kg_total, QTY_KG);
+    assert_eq!(h.token_balance(&d.farmer_vault).await, 0);
+    assert_eq!(h.token_balance(&d.buyer_vault).await, 0);
+    assert!(h.token_balance(&farmer.quote_ata).await > farmer_before);
+}